@@ -1,33 +1,62 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::{env, fmt, fs, io};
 
+#[cfg(feature = "cli")]
 use chrono::Utc;
 use color_eyre::Help;
-use dialoguer::{theme, Confirm, Input, Select};
-use directories_next::{ProjectDirs, UserDirs};
+#[cfg(feature = "cli")]
+use dialoguer::{theme, Confirm, Input, MultiSelect, Select};
+use directories_next::ProjectDirs;
+#[cfg(feature = "cli")]
+use directories_next::UserDirs;
+#[cfg(feature = "cli")]
 use hypothesis::annotations::{Annotation, Document, Permissions, Selector, Target, UserInfo};
-use hypothesis::{Hypothesis, UserAccountID};
+use hypothesis::UserAccountID;
 use serde::{Deserialize, Serialize};
 
 use crate::errors::Apologize;
+use crate::gooseberry::api::Hypothesis;
+#[cfg(feature = "cli")]
+use crate::gooseberry::bibliography::{register_citation_helpers, Bibliography};
+use crate::gooseberry::cli::Filters;
+#[cfg(feature = "cli")]
+use crate::gooseberry::epub::EpubLocation;
+use crate::gooseberry::filter::Filter;
+#[cfg(feature = "cli")]
+use crate::gooseberry::helpers::register_obsidian_helper;
+use crate::gooseberry::knowledge_base::Templates;
+#[cfg(feature = "cli")]
 use crate::gooseberry::knowledge_base::{
-    get_handlebars, AnnotationTemplate, LinkTemplate, PageTemplate, Templates,
+    get_handlebars, AnnotationTemplate, IndexTemplate, LinkTemplate, PageTemplate,
 };
-use crate::{utils, NAME};
+#[cfg(feature = "cli")]
+use crate::gooseberry::links::RelatedAnnotation;
+#[cfg(feature = "cli")]
+use crate::gooseberry::todo::TodoItem;
+#[cfg(feature = "cli")]
+use crate::utils;
+use crate::NAME;
 
-pub static DEFAULT_ANNOTATION_TEMPLATE: &str = r#"
+pub static DEFAULT_ANNOTATION_TEMPLATE: &str = r#"{{#*inline "reply"}}{{indent}}- **{{#if display_name}}{{display_name}}{{else}}{{user}}{{/if}}** ({{date_format "%c" (created)}}): {{text}}
+{{#each replies}}{{> reply}}{{/each}}{{/inline}}
 
-### {{id}}
+### {{id}}{{#if hidden}} (hidden by a moderator){{/if}}{{#if flagged}} (flagged){{/if}}
 Created: {{date_format "%c" (created)}}
-Tags: {{#each tags}}{{this}}{{#unless @last}}, {{/unless}}{{/each}}
+{{#unless mine}}Author: {{#if display_name}}{{display_name}}{{else}}{{username}}{{/if}}
+{{/unless}}Tags: {{#each tags}}{{this}}{{#unless @last}}, {{/unless}}{{/each}}
 
 {{#each highlight}}> {{this}}{{/each}}
 
 {{text}}
 
 [See in context]({{incontext}}) at [{{title}}]({{uri}})
-
+{{#if related}}
+Related annotations:
+{{#each related}}- {{#if incoming}}← {{link_type}}{{else}}{{link_type}} →{{/if}} {{#if incontext}}[{{#if text}}{{truncate text 80}}{{else}}{{#if quote}}{{truncate quote 80}}{{else}}{{id}}{{/if}}{{/if}}]({{incontext}}){{else}}{{id}}{{/if}}
+{{/each}}
+{{/if}}{{#each replies}}{{> reply}}{{/each}}
 "#;
 pub static DEFAULT_PAGE_TEMPLATE: &str = r#"
 # {{name}}
@@ -35,8 +64,162 @@ pub static DEFAULT_PAGE_TEMPLATE: &str = r#"
 
 "#;
 pub static DEFAULT_INDEX_LINK_TEMPLATE: &str = r#"- [{{name}}]({{relative_path}})"#;
+pub static DEFAULT_INDEX_TEMPLATE: &str = r#"{{#*inline "node"}}{{indent}}{{#if link}}{{link}}{{else}}- [{{name}}](){{/if}}
+{{#each children}}{{> node}}{{/each}}{{/inline}}{{#each tree}}{{> node}}{{/each}}"#;
 pub static DEFAULT_INDEX_FILENAME: &str = "SUMMARY";
 pub static DEFAULT_FILE_EXTENSION: &str = "md";
+pub static DEFAULT_HTML_LAYOUT: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{title}}</title>
+<style>{{style}}</style>
+</head>
+<body>
+<header>
+{{#if index_link}}<a href="{{index_link}}">Index</a>{{/if}}
+<input type="search" id="search" placeholder="Search annotations" autocomplete="off">
+</header>
+<ul id="search-results"></ul>
+<main>
+{{content}}
+</main>
+<script>{{script}}</script>
+</body>
+</html>
+"#;
+
+pub static DEFAULT_ORG_ANNOTATION_TEMPLATE: &str = r#"{{#*inline "reply"}}{{indent}}- *{{#if display_name}}{{display_name}}{{else}}{{user}}{{/if}}* {{date_format "[%Y-%m-%d %a %H:%M]" (created)}}: {{text}}
+{{#each replies}}{{> reply}}{{/each}}{{/inline}}
+* {{title}}{{#if tags}} :{{#each tags}}{{org_tag this}}:{{/each}}{{/if}}
+:PROPERTIES:
+:ID: {{id}}
+:CREATED: {{date_format "[%Y-%m-%d %a %H:%M]" (created)}}
+{{#unless mine}}:AUTHOR: {{#if display_name}}{{display_name}}{{else}}{{username}}{{/if}}
+{{/unless}}{{#if hidden}}:HIDDEN: t
+{{/if}}{{#if flagged}}:FLAGGED: t
+{{/if}}:END:
+
+{{#each highlight}}#+begin_quote
+{{this}}
+#+end_quote
+{{/each}}
+{{text}}
+
+[[{{incontext}}][See in context]] at [[{{uri}}][{{title}}]]
+{{#if related}}
+Related annotations:
+{{#each related}}- {{#if incoming}}← {{link_type}}{{else}}{{link_type}} →{{/if}} {{#if incontext}}[[{{incontext}}][{{#if text}}{{truncate text 80}}{{else}}{{#if quote}}{{truncate quote 80}}{{else}}{{id}}{{/if}}{{/if}}]]{{else}}[[id:{{id}}][{{id}}]]{{/if}}
+{{/each}}
+{{/if}}{{#each replies}}{{> reply}}{{/each}}
+"#;
+pub static DEFAULT_ORG_PAGE_TEMPLATE: &str = r#"#+TITLE: {{name}}
+{{#if tags}}#+FILETAGS: :{{#each tags}}{{org_tag this}}:{{/each}}{{/if}}
+{{#each annotations}}{{this}}{{/each}}
+
+"#;
+pub static DEFAULT_ORG_INDEX_LINK_TEMPLATE: &str = r#"- [[file:{{raw_relative_path}}][{{name}}]]
+"#;
+pub static DEFAULT_ORG_INDEX_TEMPLATE: &str = r#"{{#*inline "node"}}{{indent}}{{#if link}}{{link}}{{else}}- {{name}}{{/if}}
+{{#each children}}{{> node}}{{/each}}{{/inline}}{{#each tree}}{{> node}}{{/each}}"#;
+pub static DEFAULT_ORG_FILE_EXTENSION: &str = "org";
+pub static DEFAULT_FLASHCARD_TAG: &str = "flashcard";
+pub static DEFAULT_TODO_TAG: &str = "todo";
+pub static DEFAULT_DONE_TAG: &str = "done";
+pub static DEFAULT_GIT_COMMIT_MESSAGE: &str = r#"Update knowledge base: {{added}} added, {{changed}} changed, {{removed}} removed annotation(s)
+{{#if documents}}
+
+{{#each documents}}
+- {{this}}
+{{/each}}{{/if}}"#;
+
+/// Where the database is stored
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DbBackend {
+    /// `sled` files
+    Sled,
+    /// An SQLite database file, which can be queried with SQL
+    Sqlite,
+}
+
+/// Looks of the knowledge base offered by `gooseberry init`: default templates for a format,
+/// with YAML front matter on each page for the second one
+#[cfg(feature = "cli")]
+const TEMPLATE_PRESETS: &[&str] = &[
+    "Markdown",
+    "Markdown with YAML front matter (for Obsidian, Hugo, Jekyll etc.)",
+    "Org-mode",
+];
+
+/// When to color terminal output
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Only when writing to a terminal, and `NO_COLOR` isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+/// Text format of the generated knowledge base
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum KbFormat {
+    Markdown,
+    Org,
+}
+
+impl fmt::Display for KbFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KbFormat::Markdown => write!(f, "markdown"),
+            KbFormat::Org => write!(f, "org-mode"),
+        }
+    }
+}
+
+impl KbFormat {
+    /// Default annotation, page, index link, and index templates for the format
+    pub(crate) fn default_templates(self) -> Templates<'static> {
+        match self {
+            KbFormat::Markdown => Templates::default(),
+            KbFormat::Org => Templates {
+                annotation_template: DEFAULT_ORG_ANNOTATION_TEMPLATE,
+                page_template: DEFAULT_ORG_PAGE_TEMPLATE,
+                index_link_template: DEFAULT_ORG_INDEX_LINK_TEMPLATE,
+                index_template: DEFAULT_ORG_INDEX_TEMPLATE,
+            },
+        }
+    }
+
+    /// Wraps a line in a comment (used to mark editable annotations)
+    pub(crate) fn comment(self, content: &str) -> String {
+        match self {
+            KbFormat::Markdown => format!("<!-- {} -->", content),
+            KbFormat::Org => format!("# {}", content),
+        }
+    }
+
+    /// Contents of a line if it's a comment
+    pub(crate) fn uncomment(self, line: &str) -> Option<&str> {
+        let line = line.trim();
+        match self {
+            KbFormat::Markdown => line
+                .strip_prefix("<!--")
+                .and_then(|line| line.strip_suffix("-->")),
+            KbFormat::Org => line.strip_prefix('#'),
+        }
+        .map(str::trim)
+    }
+
+    /// Default file extension for the format
+    #[cfg(feature = "cli")]
+    pub(crate) fn default_file_extension(self) -> &'static str {
+        match self {
+            KbFormat::Markdown => DEFAULT_FILE_EXTENSION,
+            KbFormat::Org => DEFAULT_ORG_FILE_EXTENSION,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum OrderBy {
@@ -48,6 +231,17 @@ pub enum OrderBy {
     Empty,
     Created,
     Updated,
+    Group,
+    /// Author (display name, or username)
+    User,
+    /// Document, then position in the document (reading position for EPUBs)
+    Position,
+    /// Website domain (without `www.`)
+    Domain,
+    /// Day of creation (in the local time zone)
+    Date,
+    /// Rating in the annotation's metadata, highest first (unrated annotations last)
+    Rating,
 }
 
 impl fmt::Display for OrderBy {
@@ -61,10 +255,154 @@ impl fmt::Display for OrderBy {
             OrderBy::Empty => write!(f, "empty"),
             OrderBy::Created => write!(f, "created"),
             OrderBy::Updated => write!(f, "updated"),
+            OrderBy::Group => write!(f, "group"),
+            OrderBy::User => write!(f, "user"),
+            OrderBy::Position => write!(f, "position"),
+            OrderBy::Domain => write!(f, "domain"),
+            OrderBy::Date => write!(f, "date"),
+            OrderBy::Rating => write!(f, "rating"),
         }
     }
 }
 
+/// Templates used instead of the configured ones for annotations with a given tag or in a given group
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TemplateOverride {
+    /// Handlebars file with the annotation template
+    pub(crate) annotation: Option<PathBuf>,
+    /// Handlebars file with the page template
+    pub(crate) page: Option<PathBuf>,
+}
+
+/// Actions that can be bound to keys in the search window
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchAction {
+    /// Add tags to the selected annotations
+    Tag,
+    /// Remove tags from the selected annotations
+    Untag,
+    /// Delete the selected annotations
+    Delete,
+    /// Print the URIs of the selected annotations
+    PrintUri,
+    /// Open the selected annotations in context in the browser
+    Open,
+    /// Edit the text of the selected annotations in $EDITOR
+    Edit,
+    /// Copy the selected annotations (rendered with the annotation template) to the clipboard
+    Copy,
+    /// Open the knowledge base notes with the selected annotations in Obsidian
+    Obsidian,
+    /// Link the first selected annotation to the others
+    Link,
+    /// Narrow the annotations in the search window down with a search line (filter terms and search text)
+    Refine,
+    /// Rate the selected annotations 1 (their `rating` metadata)
+    #[serde(rename = "rate-1")]
+    Rate1,
+    /// Rate the selected annotations 2
+    #[serde(rename = "rate-2")]
+    Rate2,
+    /// Rate the selected annotations 3
+    #[serde(rename = "rate-3")]
+    Rate3,
+    /// Rate the selected annotations 4
+    #[serde(rename = "rate-4")]
+    Rate4,
+    /// Rate the selected annotations 5
+    #[serde(rename = "rate-5")]
+    Rate5,
+    /// Set the status (e.g. read, to-read) of the selected annotations, in their `status` metadata
+    Status,
+}
+
+#[cfg(feature = "cli")]
+impl SearchAction {
+    const ALL: [SearchAction; 16] = [
+        SearchAction::Tag,
+        SearchAction::Untag,
+        SearchAction::Delete,
+        SearchAction::PrintUri,
+        SearchAction::Open,
+        SearchAction::Edit,
+        SearchAction::Copy,
+        SearchAction::Obsidian,
+        SearchAction::Link,
+        SearchAction::Refine,
+        SearchAction::Rate1,
+        SearchAction::Rate2,
+        SearchAction::Rate3,
+        SearchAction::Rate4,
+        SearchAction::Rate5,
+        SearchAction::Status,
+    ];
+
+    /// Action with the given name (as used in the config file)
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|action| action.to_string() == name)
+    }
+
+    /// Rating given by the rate actions
+    pub(crate) fn rating(self) -> Option<u8> {
+        match self {
+            SearchAction::Rate1 => Some(1),
+            SearchAction::Rate2 => Some(2),
+            SearchAction::Rate3 => Some(3),
+            SearchAction::Rate4 => Some(4),
+            SearchAction::Rate5 => Some(5),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SearchAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchAction::Tag => write!(f, "tag"),
+            SearchAction::Untag => write!(f, "untag"),
+            SearchAction::Delete => write!(f, "delete"),
+            SearchAction::PrintUri => write!(f, "print-uri"),
+            SearchAction::Open => write!(f, "open"),
+            SearchAction::Edit => write!(f, "edit"),
+            SearchAction::Copy => write!(f, "copy"),
+            SearchAction::Obsidian => write!(f, "obsidian"),
+            SearchAction::Link => write!(f, "link"),
+            SearchAction::Refine => write!(f, "refine"),
+            SearchAction::Rate1 => write!(f, "rate-1"),
+            SearchAction::Rate2 => write!(f, "rate-2"),
+            SearchAction::Rate3 => write!(f, "rate-3"),
+            SearchAction::Rate4 => write!(f, "rate-4"),
+            SearchAction::Rate5 => write!(f, "rate-5"),
+            SearchAction::Status => write!(f, "status"),
+        }
+    }
+}
+
+/// Search window keybindings used if none are configured
+pub static DEFAULT_KEYBINDINGS: [(&str, SearchAction); 16] = [
+    ("enter", SearchAction::Tag),
+    ("shift-left", SearchAction::Untag),
+    ("shift-right", SearchAction::Delete),
+    ("shift-up", SearchAction::PrintUri),
+    ("shift-down", SearchAction::Open),
+    ("ctrl-e", SearchAction::Edit),
+    ("ctrl-y", SearchAction::Copy),
+    ("ctrl-o", SearchAction::Obsidian),
+    ("alt-l", SearchAction::Link),
+    ("alt-r", SearchAction::Refine),
+    // skim has no names for Alt and a digit
+    ("f1", SearchAction::Rate1),
+    ("f2", SearchAction::Rate2),
+    ("f3", SearchAction::Rate3),
+    ("f4", SearchAction::Rate4),
+    ("f5", SearchAction::Rate5),
+    ("alt-s", SearchAction::Status),
+];
+
 /// Configuration struct, asks for user input to fill in the optional values the first time gooseberry is run
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GooseberryConfig {
@@ -72,32 +410,115 @@ pub struct GooseberryConfig {
     pub(crate) hypothesis_username: Option<String>,
     /// Hypothesis personal API key
     pub(crate) hypothesis_key: Option<String>,
+    /// Hypothesis API URL, for self-hosted Hypothesis servers
+    pub(crate) hypothesis_api_url: Option<String>,
+    /// Keep the Hypothesis API key in the system keyring instead of this file
+    pub(crate) use_keyring: Option<bool>,
     /// Hypothesis group with knowledge base annotations
     pub(crate) hypothesis_group: Option<String>,
+    /// Additional Hypothesis groups to also take annotations from
+    pub(crate) hypothesis_groups: Option<Vec<String>>,
+    /// Sync and filter everyone's annotations in the groups, not just your own
+    pub(crate) all_users: Option<bool>,
 
     /// Related to tagging and editing
-    /// Directory to store `sled` database files
+    /// Directory to store the database files
     pub(crate) db_dir: PathBuf,
+    /// Storage backend of the database (`sled` or SQLite)
+    pub(crate) db_backend: Option<DbBackend>,
+    /// Keep the database encrypted (with ChaCha20-Poly1305 and a key derived from a passphrase),
+    /// decrypting it into a temporary folder while gooseberry runs
+    pub(crate) encrypt_database: Option<bool>,
+    /// Command printing the database passphrase, e.g. to read it from the system keyring
+    pub(crate) database_key_command: Option<String>,
+    /// Number of database backups to keep, taken before commands which delete or replace data
+    /// (0 turns them off)
+    pub(crate) db_backups: Option<usize>,
 
     /// Relating to the generated markdown knowledge base:
     /// Directory to write out knowledge base markdown files
     pub(crate) kb_dir: Option<PathBuf>,
+    /// Knowledge base format (markdown or org-mode), determines the default templates
+    pub(crate) kb_format: Option<KbFormat>,
     /// Handlebars annotation template
     pub(crate) annotation_template: Option<String>,
     /// Handlebars index link template
     pub(crate) index_link_template: Option<String>,
+    /// Handlebars index template, gets the rendered index links as a list and as a tree
+    pub(crate) index_template: Option<String>,
     /// Handlebars page template
     pub(crate) page_template: Option<String>,
     /// Handlebars index file name
     pub(crate) index_name: Option<String>,
     /// Wiki file extension
     pub(crate) file_extension: Option<String>,
+    /// Handlebars template for the names of the pages (without the extension), instead of the value they're grouped by
+    pub(crate) file_name_template: Option<String>,
+    /// Start each page with YAML front matter: its title, document URI, tags, dates, and annotation IDs
+    pub(crate) front_matter: Option<bool>,
+    /// Handlebars template for the YAML front matter (without the `---` lines), instead of the generated one
+    pub(crate) front_matter_template: Option<String>,
+    /// Handlebars layout of the pages of an HTML knowledge base, with the page as `content`
+    pub(crate) html_layout: Option<String>,
+    /// Name of the Obsidian vault the knowledge base is in, for `obsidian://` links
+    /// (the name of the knowledge base directory if not set)
+    pub(crate) obsidian_vault: Option<String>,
     /// Define the hierarchy of folders
     pub(crate) hierarchy: Option<Vec<OrderBy>>,
     /// Define how annotations on a page are sorted
     pub(crate) sort: Option<Vec<OrderBy>>,
     /// Define tags to ignore
     pub(crate) ignore_tags: Option<Vec<String>>,
+    /// Filter expressions (e.g. `site:mail.google.com`) for annotations to keep out of the database
+    /// and the knowledge base
+    pub(crate) ignore: Option<Vec<String>>,
+    /// Separates the levels of hierarchical tags (e.g. "/" for biology/genetics/crispr)
+    pub(crate) tag_delimiter: Option<String>,
+    /// Mark annotations in knowledge base files so that edits to their text and tags can be pushed
+    /// back to Hypothesis with `sync --push`
+    pub(crate) editable: Option<bool>,
+    /// Commit the knowledge base directory to git after each `make`
+    pub(crate) git_commit: Option<bool>,
+    /// Push the commit to the `origin` remote after committing
+    pub(crate) git_push: Option<bool>,
+    /// Handlebars template for the commit message, with the `added`, `changed`, and `removed` annotation counts,
+    /// the `documents` with added or changed annotations, and the number of `files` written or deleted
+    pub(crate) git_commit_message: Option<String>,
+    /// Fetch the `<title>` of annotated pages without a document title in Hypothesis during sync
+    pub(crate) fetch_titles: Option<bool>,
+    /// Archive the readable text of annotated pages during sync, for the `context` and `page_text` template keys
+    /// and full-text search
+    pub(crate) archive_pages: Option<bool>,
+    /// Shell command the markdown of the search window preview is piped to on stdin
+    /// (e.g. `glow -s dark -`), or passed to as a file path in place of `{}`, instead of rendering it in-process
+    #[serde(alias = "previewer")]
+    pub(crate) preview_command: Option<String>,
+    /// When to color terminal output: search windows, prompts, and printed annotations
+    pub(crate) color: Option<ColorMode>,
+    /// BibTeX or CSL JSON file (e.g. a Zotero Better BibTeX export) for the `cite` and `citekey` template helpers
+    pub(crate) bibliography: Option<PathBuf>,
+    /// Tag marking annotations to export as flashcards with `gooseberry export anki`
+    pub(crate) flashcard_tag: Option<String>,
+    /// Tag marking annotations as tasks for `gooseberry todo`
+    pub(crate) todo_tag: Option<String>,
+    /// Tag replacing the todo tag on tasks marked as done
+    pub(crate) done_tag: Option<String>,
+    /// Readwise access token for `gooseberry export readwise`
+    pub(crate) readwise_token: Option<String>,
+    /// Tagging rules: tag to add to new and updated annotations matching a filter expression during sync
+    /// Kept last (along with the views) since TOML tables have to come after all other keys
+    pub(crate) rules: Option<BTreeMap<String, String>>,
+    /// Tag or group ID: templates to use for its annotations (and for pages with only its annotations)
+    pub(crate) templates: Option<BTreeMap<String, TemplateOverride>>,
+    /// Search window key: action
+    pub(crate) keybindings: Option<BTreeMap<String, SearchAction>>,
+    /// Saved filter expressions (smart views) by name
+    pub(crate) views: Option<BTreeMap<String, String>>,
+    /// Named profiles: settings used instead of the ones above with `--profile <name>`
+    pub(crate) profiles: Option<BTreeMap<String, toml::value::Table>>,
+    /// Profile in use, changes to the configuration are stored in it
+    #[serde(skip)]
+    pub(crate) profile: Option<String>,
 }
 
 /// Main project directory, cross-platform
@@ -110,19 +531,55 @@ impl Default for GooseberryConfig {
         let config = Self {
             hypothesis_username: None,
             hypothesis_key: None,
+            hypothesis_api_url: None,
+            use_keyring: None,
             hypothesis_group: None,
+            hypothesis_groups: None,
+            all_users: None,
             db_dir: get_project_dir()
                 .map(|dir| dir.data_dir().join("gooseberry_db"))
                 .expect("Couldn't make database directory"),
+            db_backend: None,
+            encrypt_database: None,
+            database_key_command: None,
+            db_backups: None,
             kb_dir: None,
+            kb_format: None,
             annotation_template: None,
             page_template: None,
             index_link_template: None,
+            index_template: None,
             index_name: None,
             file_extension: None,
+            file_name_template: None,
+            front_matter: None,
+            front_matter_template: None,
+            html_layout: None,
+            obsidian_vault: None,
             hierarchy: None,
             sort: None,
             ignore_tags: None,
+            ignore: None,
+            tag_delimiter: None,
+            editable: None,
+            git_commit: None,
+            git_push: None,
+            git_commit_message: None,
+            fetch_titles: None,
+            archive_pages: None,
+            preview_command: None,
+            color: None,
+            bibliography: None,
+            flashcard_tag: None,
+            todo_tag: None,
+            done_tag: None,
+            readwise_token: None,
+            rules: None,
+            templates: None,
+            keybindings: None,
+            views: None,
+            profiles: None,
+            profile: None,
         };
         config.make_dirs().unwrap();
         config
@@ -140,21 +597,92 @@ impl GooseberryConfig {
             r#"
 hypothesis_username = '<Hypothesis username>'
 hypothesis_key = '<Hypothesis personal API key>'
+# hypothesis_api_url = 'https://api.hypothes.is/api'
+use_keyring = false
 hypothesis_group = '<Hypothesis group ID to take annotations from>'
+hypothesis_groups = []
+all_users = false
 db_dir = '<full path to database folder>'
+db_backend = 'Sled'
+encrypt_database = false
+# database_key_command = 'secret-tool lookup service gooseberry'
+db_backups = 5
 kb_dir = '<knowledge-base folder>'
+kb_format = 'Markdown'
 hierarchy = ['Tag']
 sort = ['Created']
 ignore_tags = []
+# ignore = ['site:mail.google.com', 'tag:scratch']
+# tag_delimiter = '/'
+editable = false
+git_commit = false
+git_push = false
+# git_commit_message = 'Update knowledge base: {{{{added}}}} added, {{{{changed}}}} changed, {{{{removed}}}} removed annotation(s)'
+fetch_titles = true
+archive_pages = false
+# preview_command = 'glow -s dark -'
+color = 'Auto'
+# bibliography = '<BibTeX or CSL JSON file>'
+flashcard_tag = '{}'
+todo_tag = '{}'
+done_tag = '{}'
+# readwise_token = '<Readwise access token>'
 annotation_template = '''{}'''
 page_template = '''{}'''
 index_link_template = '''{}'''
+index_template = '''{}'''
 index_name = '{}'
 file_extension = '{}'
+# file_name_template = '{{{{date_format created "%Y%m%d%H%M"}}}}-{{{{slugify title}}}}'
+front_matter = false
+# front_matter_template = '''title: "{{{{title}}}}"
+# aliases: ["{{{{name}}}}"]'''
+# html_layout = '''<base layout of the HTML knowledge base, see the README>'''
+# obsidian_vault = '<Obsidian vault name>'
+
+[rules]
+# paper = 'uri:*arxiv.org*'
+# todo = 'text:TODO'
+
+[templates]
+# paper = {{ annotation = '<annotation template file>', page = '<page template file>' }}
+
+[keybindings]
+# enter = 'tag'
+# shift-left = 'untag'
+# ctrl-d = 'delete'
+# shift-up = 'print-uri'
+# shift-down = 'open'
+# ctrl-e = 'edit'
+# ctrl-y = 'copy'
+# ctrl-o = 'obsidian'
+# alt-l = 'link'
+# alt-r = 'refine'
+# f1 = 'rate-1'
+# f2 = 'rate-2'
+# f3 = 'rate-3'
+# f4 = 'rate-4'
+# f5 = 'rate-5'
+# alt-s = 'status'
+
+[views]
+# example = 'tags:[project-x] AND after:2023-01-01 AND NOT uri:*wikipedia.org*'
+
+# Use with `gooseberry --profile work ...`
+# [profiles.work]
+# hypothesis_username = '<Hypothesis username>'
+# hypothesis_key = '<Hypothesis personal API key>'
+# hypothesis_group = '<Hypothesis group ID>'
+# db_dir = '<full path to database folder>'
+# kb_dir = '<knowledge-base folder>'
 "#,
+            DEFAULT_FLASHCARD_TAG,
+            DEFAULT_TODO_TAG,
+            DEFAULT_DONE_TAG,
             DEFAULT_ANNOTATION_TEMPLATE,
             DEFAULT_PAGE_TEMPLATE,
             DEFAULT_INDEX_LINK_TEMPLATE,
+            DEFAULT_INDEX_TEMPLATE,
             DEFAULT_INDEX_FILENAME,
             DEFAULT_FILE_EXTENSION
         );
@@ -248,6 +776,36 @@ file_extension = '{}'
 
     /// Read config from default location
     pub async fn load(config_file: Option<&Path>) -> color_eyre::Result<Self> {
+        Self::load_profile(config_file, None).await
+    }
+
+    /// Read config from default location, with the settings of a named profile (`[profiles.<name>]`) if given
+    pub async fn load_profile(
+        config_file: Option<&Path>,
+        profile: Option<&str>,
+    ) -> color_eyre::Result<Self> {
+        let mut config = Self::read(config_file, profile)?;
+        config.read_keyring();
+        if config.hypothesis_username.is_none()
+            || config.hypothesis_key.is_none()
+            || !config
+                .authorize(
+                    config.hypothesis_username.as_deref().unwrap(),
+                    config.hypothesis_key.as_deref().unwrap(),
+                )
+                .await?
+        {
+            config.set_credentials().await?;
+        }
+
+        if config.hypothesis_group.is_none() {
+            config.set_group().await?;
+        }
+        Ok(config)
+    }
+
+    /// Read config from default location (with a profile's settings) without checking the Hypothesis credentials
+    pub fn read(config_file: Option<&Path>, profile: Option<&str>) -> color_eyre::Result<Self> {
         // Reads the GOOSEBERRY_CONFIG environment variable to get config file location
         let mut config = match config_file {
             Some(path) => {
@@ -274,38 +832,219 @@ file_extension = '{}'
                 })?)
             },
         }?;
+        if let Some(name) = profile {
+            config = config.with_profile(name)?;
+            config.make_dirs()?;
+        }
+        Ok(config)
+    }
 
-        if config.hypothesis_username.is_none()
-            || config.hypothesis_key.is_none()
-            || !Self::authorize(
-                config.hypothesis_username.as_deref().unwrap(),
-                config.hypothesis_key.as_deref().unwrap(),
-            )
-            .await?
+    /// Replaces settings with the ones in a profile.
+    /// Profiles without their own database and knowledge base directories get ones next to the top-level ones,
+    /// named after the profile (e.g. `gooseberry_db-work`), so that accounts don't share a database
+    fn with_profile(self, name: &str) -> color_eyre::Result<Self> {
+        let profile = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .cloned()
+            .ok_or(Apologize::ConfigError {
+                message: format!("No profile named {:?}", name),
+            })
+            .suggestion(format!(
+                "Add a [profiles.{}] table to the config file (see `gooseberry config default`)",
+                name
+            ))?;
+        let next_to = |dir: &Path| {
+            let dir_name = dir.file_name().unwrap_or_default().to_string_lossy();
+            dir.with_file_name(format!("{}-{}", dir_name, name))
+        };
+        let db_dir = next_to(&self.db_dir);
+        let kb_dir = self.kb_dir.as_deref().map(next_to);
+        let mut settings = toml::Value::try_from(&self)?;
+        let table = settings.as_table_mut().ok_or(Apologize::OutOfCheeseError {
+            message: "Configuration isn't a table".into(),
+        })?;
+        for (key, value) in profile {
+            if key != "profiles" {
+                table.insert(key, value);
+            }
+        }
+        let mut config: Self = settings.try_into()?;
+        if !config.profile_has("db_dir", name) {
+            config.db_dir = db_dir;
+        }
+        if !config.profile_has("kb_dir", name) {
+            config.kb_dir = kb_dir;
+        }
+        config.profile = Some(name.to_owned());
+        Ok(config)
+    }
+
+    /// Whether a profile has its own setting for a key
+    fn profile_has(&self, key: &str, name: &str) -> bool {
+        self.profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .is_some_and(|profile| profile.contains_key(key))
+    }
+
+    /// The configuration to write to the file when a profile is in use: settings which the profile has,
+    /// or which were changed since loading it, go in the profile
+    fn stored_in_profile(&self, name: &str, file: Option<&Path>) -> color_eyre::Result<Self> {
+        let mut stored: Self = match file {
+            Some(file) => confy::load_path(file)?,
+            None => confy::load(NAME)?,
+        };
+        // What the profile currently gives, including the directories named after it
+        let loaded = match stored.clone().with_profile(name) {
+            Ok(loaded) => toml::Value::try_from(loaded)?,
+            Err(_) => toml::Value::try_from(&stored)?,
+        };
+        let settings = toml::Value::try_from(self)?;
+        let (settings, loaded) = match (settings.as_table(), loaded.as_table()) {
+            (Some(settings), Some(loaded)) => (settings, loaded),
+            _ => {
+                return Err(Apologize::OutOfCheeseError {
+                    message: "Configuration isn't a table".into(),
+                }
+                .into())
+            }
+        };
+        let mut profile = stored
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .cloned()
+            .unwrap_or_default();
+        let keys: Vec<_> = settings.keys().chain(profile.keys()).cloned().collect();
+        for key in keys {
+            if key == "profiles" {
+                continue;
+            }
+            match settings.get(&key) {
+                Some(value) if profile.contains_key(&key) || loaded.get(&key) != Some(value) => {
+                    profile.insert(key, value.clone());
+                }
+                Some(_) => {}
+                None => {
+                    profile.remove(&key);
+                }
+            }
+        }
+        stored
+            .profiles
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_owned(), profile);
+        Ok(stored)
+    }
+
+    /// First-run setup: asks for the Hypothesis credentials (checking them against the API),
+    /// the group to take annotations from, the knowledge base directory, and a template preset,
+    /// and writes them to the config file (starting from the defaults if it doesn't exist yet).
+    /// Asks before setting up a config file which already has an account and group, unless forced
+    #[cfg(feature = "cli")]
+    pub async fn init(
+        config_file: Option<&Path>,
+        profile: Option<&str>,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        if let Some(path) = config_file {
+            // `store` writes to the file in GOOSEBERRY_CONFIG
+            env::set_var("GOOSEBERRY_CONFIG", path);
+        }
+        let mut config = match config_file {
+            Some(path) if !path.exists() => {
+                let config = Self::default();
+                config.make_dirs()?;
+                config
+            }
+            _ => Self::read(config_file, profile)?,
+        };
+        config.read_keyring();
+        if !force
+            && config.hypothesis_username.is_some()
+            && config.hypothesis_group.is_some()
+            && !utils::confirm(
+                &format!(
+                    "{:?} is already set up, set it up again?",
+                    Self::location(config_file)?
+                ),
+                false,
+            )?
         {
-            config.set_credentials().await?;
+            let error: color_eyre::Result<()> = Err(Apologize::DoingNothing.into());
+            return error.suggestion("Change single options with `gooseberry config ...`");
         }
 
-        if config.hypothesis_group.is_none() {
-            config.set_group().await?;
+        println!("1. Hypothesis account (the API key is at https://hypothes.is/account/developer)");
+        let keep_account = match (&config.hypothesis_username, &config.hypothesis_key) {
+            (Some(name), Some(key)) if config.authorize(name, key).await? => {
+                Confirm::with_theme(&theme::ColorfulTheme::default())
+                    .with_prompt(format!("Keep using the Hypothesis account {}?", name))
+                    .default(true)
+                    .interact()?
+            }
+            _ => false,
+        };
+        if !keep_account {
+            config.use_keyring = Some(
+                Confirm::with_theme(&theme::ColorfulTheme::default())
+                    .with_prompt(
+                        "Store the API key in the system keyring instead of the config file?",
+                    )
+                    .default(config.use_keyring.unwrap_or(false))
+                    .interact()?,
+            );
+            config.request_credentials().await?;
         }
-        Ok(config)
+
+        println!("\n2. Hypothesis group");
+        config.set_group().await?;
+        if Confirm::with_theme(&theme::ColorfulTheme::default())
+            .with_prompt("Take annotations from other groups too?")
+            .default(false)
+            .interact()?
+        {
+            config.set_groups().await?;
+        }
+
+        println!("\n3. Knowledge base directory");
+        config.set_kb_dir()?;
+
+        println!("\n4. Knowledge base templates");
+        config.set_template_preset()?;
+
+        println!(
+            "\nWrote the configuration to {:?}.\n\
+            Run `gooseberry sync` to fetch your annotations and `gooseberry make` to build the knowledge base.\n\
+            `gooseberry config kb all` goes through the rest of the knowledge base options.",
+            Self::location(config_file)?
+        );
+        Ok(())
     }
 
     /// Queries and sets all knowledge base related configuration options
+    #[cfg(feature = "cli")]
     pub fn set_kb_all(&mut self) -> color_eyre::Result<()> {
         self.set_kb_dir()?;
+        self.set_kb_format()?;
         self.set_annotation_template()?;
         self.set_page_template()?;
         self.set_index_link_template()?;
+        self.set_index_template()?;
         self.set_index_name()?;
         self.set_file_extension()?;
         self.set_hierarchy()?;
         self.set_sort()?;
+        self.set_tag_delimiter()?;
+        self.set_editable()?;
+        self.set_bibliography()?;
         Ok(())
     }
 
     /// Sets the knowledge base directory
+    #[cfg(feature = "cli")]
     pub fn set_kb_dir(&mut self) -> color_eyre::Result<()> {
         let default = UserDirs::new()
             .ok_or(Apologize::Homeless)?
@@ -332,6 +1071,65 @@ file_extension = '{}'
         Ok(())
     }
 
+    /// Sets the knowledge base format,
+    /// optionally switching the templates and file extension to the defaults of the new format
+    #[cfg(feature = "cli")]
+    pub fn set_kb_format(&mut self) -> color_eyre::Result<()> {
+        let selections = [KbFormat::Markdown, KbFormat::Org];
+        let current = self.kb_format();
+        let selection = Select::with_theme(&theme::ColorfulTheme::default())
+            .with_prompt("Which format should the knowledge base use?")
+            .items(&selections[..])
+            .default(selections.iter().position(|&f| f == current).unwrap_or(0))
+            .interact()?;
+        let format = selections[selection];
+        if format != current
+            && Confirm::with_theme(&theme::ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Use default {} templates and file extension?",
+                    format
+                ))
+                .default(true)
+                .interact()?
+        {
+            let templates = format.default_templates();
+            self.annotation_template = Some(templates.annotation_template.to_string());
+            self.page_template = Some(templates.page_template.to_string());
+            self.index_link_template = Some(templates.index_link_template.to_string());
+            self.index_template = Some(templates.index_template.to_string());
+            self.file_extension = Some(format.default_file_extension().to_string());
+        }
+        self.kb_format = Some(format);
+        self.store()?;
+        Ok(())
+    }
+
+    /// Sets the knowledge base format, templates, file extension, and front matter to one of `TEMPLATE_PRESETS`
+    #[cfg(feature = "cli")]
+    pub fn set_template_preset(&mut self) -> color_eyre::Result<()> {
+        let selection = Select::with_theme(&theme::ColorfulTheme::default())
+            .with_prompt("How should the knowledge base look?")
+            .items(TEMPLATE_PRESETS)
+            .default(0)
+            .interact()?;
+        let format = if selection == 2 {
+            KbFormat::Org
+        } else {
+            KbFormat::Markdown
+        };
+        let templates = format.default_templates();
+        self.kb_format = Some(format);
+        self.annotation_template = Some(templates.annotation_template.to_string());
+        self.page_template = Some(templates.page_template.to_string());
+        self.index_link_template = Some(templates.index_link_template.to_string());
+        self.index_template = Some(templates.index_template.to_string());
+        self.file_extension = Some(format.default_file_extension().to_string());
+        self.front_matter = Some(selection == 1);
+        self.store()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
     fn get_order_bys(selections: Vec<OrderBy>) -> color_eyre::Result<Vec<OrderBy>> {
         let mut selections = selections;
         let selection = Select::with_theme(&theme::ColorfulTheme::default())
@@ -368,6 +1166,7 @@ file_extension = '{}'
     }
 
     /// Sets the hierarchy fields which determines the folder hierarchy
+    #[cfg(feature = "cli")]
     pub fn set_hierarchy(&mut self) -> color_eyre::Result<()> {
         println!("Set folder hierarchy order");
         let selections = vec![
@@ -377,6 +1176,10 @@ file_extension = '{}'
             OrderBy::BaseURI,
             OrderBy::Title,
             OrderBy::ID,
+            OrderBy::Group,
+            OrderBy::User,
+            OrderBy::Domain,
+            OrderBy::Date,
         ];
         let order = Self::get_order_bys(selections)?;
         if order.is_empty() {
@@ -402,6 +1205,7 @@ file_extension = '{}'
     }
 
     /// Sets the sort order for annotations within a page
+    #[cfg(feature = "cli")]
     pub fn set_sort(&mut self) -> color_eyre::Result<()> {
         println!("Set sort order for annotations within a page");
         let selections = vec![
@@ -412,6 +1216,10 @@ file_extension = '{}'
             OrderBy::Title,
             OrderBy::Created,
             OrderBy::Updated,
+            OrderBy::Group,
+            OrderBy::User,
+            OrderBy::Position,
+            OrderBy::Rating,
         ];
         let order = Self::get_order_bys(selections)?;
 
@@ -429,6 +1237,85 @@ file_extension = '{}'
         Ok(())
     }
 
+    #[cfg(feature = "cli")]
+    pub fn set_tag_delimiter(&mut self) -> color_eyre::Result<()> {
+        println!(
+            "Set the delimiter between levels of hierarchical tags (leave empty for flat tags)"
+        );
+        let delimiter = utils::user_input(
+            "Tag delimiter",
+            Some(self.tag_delimiter.as_deref().unwrap_or("")),
+            true,
+            true,
+        )?;
+        self.tag_delimiter = if delimiter.is_empty() {
+            None
+        } else {
+            Some(delimiter)
+        };
+        self.store()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn set_editable(&mut self) -> color_eyre::Result<()> {
+        self.editable = Some(
+            Confirm::with_theme(&theme::ColorfulTheme::default())
+                .with_prompt(
+                    "Mark annotations in knowledge base files so that edits can be pushed back with 'gooseberry sync --push'?",
+                )
+                .default(self.editable.unwrap_or(false))
+                .interact()?,
+        );
+        self.store()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn set_bibliography(&mut self) -> color_eyre::Result<()> {
+        println!("Set the BibTeX or CSL JSON bibliography used by the cite and citekey template helpers (leave empty for none)");
+        let path = utils::user_input(
+            "Bibliography file",
+            Some(
+                self.bibliography
+                    .as_deref()
+                    .map(|path| path.to_string_lossy())
+                    .as_deref()
+                    .unwrap_or(""),
+            ),
+            true,
+            true,
+        )?;
+        self.bibliography = if path.is_empty() {
+            None
+        } else {
+            let path = PathBuf::from(path);
+            if !path.exists() {
+                return Err(Apologize::ConfigError {
+                    message: format!("{:?} doesn't exist", path),
+                }
+                .into());
+            }
+            Some(path)
+        };
+        self.store()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn set_flashcard_tag(&mut self) -> color_eyre::Result<()> {
+        println!("Set the tag marking annotations to export with 'gooseberry export anki'");
+        self.flashcard_tag = Some(utils::user_input(
+            "Flashcard tag",
+            Some(self.flashcard_tag()),
+            true,
+            false,
+        )?);
+        self.store()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
     pub fn set_ignore_tags(&mut self) -> color_eyre::Result<()> {
         println!("Set tags to ignore during knowledge base generation");
         let ignore_tags: String = Input::with_theme(&theme::ColorfulTheme::default())
@@ -456,22 +1343,58 @@ file_extension = '{}'
     }
 
     pub(crate) fn get_templates(&self) -> Templates {
+        let defaults = self.default_templates();
         Templates {
             annotation_template: self
                 .annotation_template
                 .as_deref()
-                .unwrap_or(DEFAULT_ANNOTATION_TEMPLATE),
+                .unwrap_or(defaults.annotation_template),
             page_template: self
                 .page_template
                 .as_deref()
-                .unwrap_or(DEFAULT_PAGE_TEMPLATE),
+                .unwrap_or(defaults.page_template),
             index_link_template: self
                 .index_link_template
                 .as_deref()
-                .unwrap_or(DEFAULT_INDEX_LINK_TEMPLATE),
+                .unwrap_or(defaults.index_link_template),
+            index_template: self
+                .index_template
+                .as_deref()
+                .unwrap_or(defaults.index_template),
+        }
+    }
+
+    /// Configured database backend (`sled` if not set)
+    pub(crate) fn db_backend(&self) -> DbBackend {
+        self.db_backend.unwrap_or(DbBackend::Sled)
+    }
+
+    /// Configured color mode (`Auto` if not set)
+    #[cfg(feature = "cli")]
+    pub(crate) fn color(&self) -> ColorMode {
+        self.color.unwrap_or(ColorMode::Auto)
+    }
+
+    /// Configured knowledge base format (markdown if not set)
+    pub(crate) fn kb_format(&self) -> KbFormat {
+        self.kb_format.unwrap_or(KbFormat::Markdown)
+    }
+
+    /// Knowledge base format, if annotations should be marked as editable in it
+    pub(crate) fn editable_format(&self) -> Option<KbFormat> {
+        if self.editable.unwrap_or(false) {
+            Some(self.kb_format())
+        } else {
+            None
         }
     }
+
+    /// Default templates for the configured knowledge base format
+    pub(crate) fn default_templates(&self) -> Templates<'static> {
+        self.kb_format().default_templates()
+    }
     /// Sets the annotation template in Handlebars format.
+    #[cfg(feature = "cli")]
     pub fn set_annotation_template(&mut self) -> color_eyre::Result<()> {
         let selections = &[
             "Use default annotation template",
@@ -483,7 +1406,8 @@ file_extension = '{}'
             .items(&selections[..])
             .interact()?;
         if selection == 0 {
-            self.annotation_template = Some(DEFAULT_ANNOTATION_TEMPLATE.to_string());
+            self.annotation_template =
+                Some(self.default_templates().annotation_template.to_string());
         } else {
             let test_annotation = Annotation {
                 id: "test".to_string(),
@@ -533,7 +1457,7 @@ file_extension = '{}'
                     Some(
                         self.annotation_template
                             .as_deref()
-                            .unwrap_or(DEFAULT_ANNOTATION_TEMPLATE),
+                            .unwrap_or(self.default_templates().annotation_template),
                     ),
                     ".hbs",
                 )?;
@@ -566,6 +1490,7 @@ file_extension = '{}'
     }
 
     /// Sets the annotation template in Handlebars format.
+    #[cfg(feature = "cli")]
     pub fn set_page_template(&mut self) -> color_eyre::Result<()> {
         let selections = &["Use default page template", "Edit page template"];
 
@@ -574,7 +1499,7 @@ file_extension = '{}'
             .items(&selections[..])
             .interact()?;
         if selection == 0 {
-            self.page_template = Some(DEFAULT_PAGE_TEMPLATE.to_string());
+            self.page_template = Some(self.default_templates().page_template.to_string());
         } else {
             let test_annotation_1 = Annotation {
                 id: "test".to_string(),
@@ -631,8 +1556,12 @@ file_extension = '{}'
                 link_data: LinkTemplate {
                     name: "page_name".to_string(),
                     relative_path: "relative/path/to/page.md".to_string(),
+                    raw_relative_path: "relative/path/to/page.md".to_string(),
                     absolute_path: "absolute/path/to/page.md".to_string(),
+                    title: Some("Web page title".to_string()),
+                    group_key: Some("tag1".to_string()),
                 },
+                tags: vec!["tag1".to_string(), "tag2".to_string()],
                 annotations: vec![test_annotation_1.clone(), test_annotation_2.clone()]
                     .into_iter()
                     .map(|a| hbs.render("annotation", &AnnotationTemplate::from_annotation(a)))
@@ -648,7 +1577,7 @@ file_extension = '{}'
                     Some(
                         self.page_template
                             .as_deref()
-                            .unwrap_or(DEFAULT_PAGE_TEMPLATE),
+                            .unwrap_or(self.default_templates().page_template),
                     ),
                     ".hbs",
                 )?;
@@ -679,6 +1608,7 @@ file_extension = '{}'
     }
 
     /// Sets the annotation template in Handlebars format.
+    #[cfg(feature = "cli")]
     pub fn set_index_link_template(&mut self) -> color_eyre::Result<()> {
         let selections = &[
             "Use default index link template",
@@ -690,14 +1620,15 @@ file_extension = '{}'
             .items(&selections[..])
             .interact()?;
         if selection == 0 {
-            self.index_link_template = Some(DEFAULT_INDEX_LINK_TEMPLATE.to_string());
+            self.index_link_template =
+                Some(self.default_templates().index_link_template.to_string());
         } else {
             self.index_link_template = loop {
                 let template = utils::external_editor_input(
                     Some(
                         self.index_link_template
                             .as_deref()
-                            .unwrap_or(DEFAULT_INDEX_LINK_TEMPLATE),
+                            .unwrap_or(self.default_templates().index_link_template),
                     ),
                     ".hbs",
                 )?;
@@ -716,6 +1647,265 @@ file_extension = '{}'
         Ok(())
     }
 
+    /// Sets the index template in Handlebars format.
+    #[cfg(feature = "cli")]
+    pub fn set_index_template(&mut self) -> color_eyre::Result<()> {
+        let selections = &["Use default index template", "Edit index template"];
+
+        let selection = Select::with_theme(&theme::ColorfulTheme::default())
+            .with_prompt("How should gooseberry arrange the links in the Index file?")
+            .items(&selections[..])
+            .interact()?;
+        if selection == 0 {
+            self.index_template = Some(self.default_templates().index_template.to_string());
+        } else {
+            self.index_template = loop {
+                let template = utils::external_editor_input(
+                    Some(
+                        self.index_template
+                            .as_deref()
+                            .unwrap_or(self.default_templates().index_template),
+                    ),
+                    ".hbs",
+                )?;
+                let templates = Templates {
+                    index_template: &template,
+                    ..Default::default()
+                };
+                if let Err(e) = get_handlebars(templates) {
+                    eprintln!("TemplateRenderError: {}\n Try again.", e);
+                    continue;
+                }
+                break Some(template);
+            };
+        }
+        self.store()?;
+        Ok(())
+    }
+
+    /// Annotation with every template key set (including an EPUB location and a reply), to check templates with
+    #[cfg(feature = "cli")]
+    fn sample_annotation() -> color_eyre::Result<AnnotationTemplate> {
+        let annotation = Annotation {
+            id: "sample".to_string(),
+            created: Utc::now(),
+            updated: Utc::now(),
+            user: Default::default(),
+            uri: "https://github.com/out-of-cheese-error/gooseberry".to_string(),
+            text: "Sample annotation".to_string(),
+            tags: vec!["tag1".to_string(), "tag2".to_string()],
+            group: "group_id".to_string(),
+            permissions: Permissions {
+                read: vec![],
+                delete: vec![],
+                admin: vec![],
+                update: vec![],
+            },
+            target: vec![Target::builder()
+                .source("https://github.com/out-of-cheese-error/gooseberry")
+                .selector(vec![Selector::new_quote(
+                    "exact text in website to highlight\nmore text",
+                    "prefix of text",
+                    "suffix of text",
+                )])
+                .build()?],
+            links: vec![(
+                "incontext".to_string(),
+                "https://incontext_link.com".to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            hidden: false,
+            flagged: false,
+            document: Some(Document {
+                title: vec!["Web page title".into()],
+                dc: None,
+                highwire: None,
+                link: vec![],
+            }),
+            references: vec![],
+            user_info: Some(UserInfo {
+                display_name: Some("Sample display name".to_string()),
+            }),
+        };
+        let mut reply = annotation.clone();
+        reply.id = "sample_reply".to_string();
+        reply.text = "Sample reply".to_string();
+        reply.references = vec![annotation.id.clone()];
+        let mut reply = AnnotationTemplate::from_annotation(reply);
+        reply.indent = "  ".to_string();
+
+        let mut sample = AnnotationTemplate::from_annotation(annotation);
+        sample.context = Some("The paragraph around the highlight".to_string());
+        sample.page_text = Some("Readable text of the annotated page".to_string());
+        sample.archive_url = Some(
+            "https://web.archive.org/web/https://github.com/out-of-cheese-error/gooseberry"
+                .to_string(),
+        );
+        sample.epub = Some(EpubLocation {
+            position: vec![6, 4, 4, 10, 3],
+            offset: 10,
+            chapter: 2,
+            chapter_id: Some("chapter01".to_string()),
+            chapter_title: Some("Chapter title".to_string()),
+            cfi: "epubcfi(/6/4[chapter01]!/4/10/3:10)".to_string(),
+        });
+        sample.replies = vec![reply];
+        sample.related = vec![RelatedAnnotation {
+            id: "sample_related".to_string(),
+            link_type: "supports".to_string(),
+            incoming: false,
+            title: "Related page title".to_string(),
+            text: "Sample related annotation".to_string(),
+            quote: "Related highlight".to_string(),
+            incontext: "https://hyp.is/sample_related".to_string(),
+        }];
+        sample.meta = vec![
+            ("rating".to_string(), "5".to_string()),
+            ("status".to_string(), "read".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        Ok(sample)
+    }
+
+    /// Renders the annotation, page, index link, and index templates, along with the templates configured
+    /// for tags and groups, for a sample annotation. Unknown keys are errors (which Handlebars otherwise
+    /// renders as empty), with the line and column they're on
+    #[cfg(feature = "cli")]
+    pub(crate) fn render_templates(
+        &self,
+    ) -> color_eyre::Result<Vec<(String, Result<String, String>)>> {
+        let templates = self.get_templates();
+        let mut sources = vec![
+            (
+                "annotation".to_string(),
+                Ok(templates.annotation_template.to_string()),
+            ),
+            ("page".to_string(), Ok(templates.page_template.to_string())),
+            (
+                "index_link".to_string(),
+                Ok(templates.index_link_template.to_string()),
+            ),
+            (
+                "index".to_string(),
+                Ok(templates.index_template.to_string()),
+            ),
+        ];
+        for (key, template_override) in self.templates.iter().flatten() {
+            let files = [
+                ("annotation", &template_override.annotation),
+                ("page", &template_override.page),
+            ];
+            for (kind, path) in files.iter() {
+                if let Some(path) = path {
+                    sources.push((
+                        format!("{}/{}", kind, key),
+                        fs::read_to_string(path)
+                            .map_err(|e| format!("Couldn't read {:?}: {}", path, e)),
+                    ));
+                }
+            }
+        }
+
+        let mut hbs = get_handlebars(Templates::default())?;
+        if let Some(path) = &self.bibliography {
+            register_citation_helpers(&mut hbs, Bibliography::from_file(path)?);
+        }
+        register_obsidian_helper(&mut hbs, self.obsidian_vault());
+        hbs.set_strict_mode(true);
+        let sources: Vec<_> = sources
+            .into_iter()
+            .map(|(name, source)| {
+                let registered = source.and_then(|source| {
+                    hbs.register_template_string(&name, source)
+                        .map_err(|e| e.to_string())
+                });
+                (name, registered)
+            })
+            .collect();
+
+        let annotation = Self::sample_annotation()?;
+        let link_data = LinkTemplate {
+            name: "page_name".to_string(),
+            relative_path: "relative/path/to/page_name.md".to_string(),
+            raw_relative_path: "relative/path/to/page_name.md".to_string(),
+            absolute_path: "absolute/path/to/page_name.md".to_string(),
+            title: Some(annotation.title.clone()),
+            group_key: Some("tag1".to_string()),
+        };
+        let page_data = PageTemplate {
+            link_data: link_data.clone(),
+            tags: annotation.annotation.tags.clone(),
+            annotations: vec![hbs
+                .render("annotation", &annotation)
+                .unwrap_or_else(|_| annotation.annotation.text.clone())],
+            raw_annotations: vec![annotation.clone()],
+        };
+        let index_link = hbs
+            .render("index_link", &link_data)
+            .unwrap_or_else(|_| link_data.name.clone());
+        let mut index_data =
+            IndexTemplate::new(vec![(link_data.raw_relative_path.clone(), index_link)]);
+        index_data.group_by = Some(OrderBy::Tag.to_string());
+        index_data.todos = vec![TodoItem {
+            id: annotation.annotation.id.clone(),
+            text: annotation.annotation.text.clone(),
+            quote: annotation.highlight.first().cloned().unwrap_or_default(),
+            title: annotation.title.clone(),
+            uri: annotation.annotation.uri.clone(),
+            incontext: annotation.incontext.clone(),
+            created: annotation.annotation.created,
+            age: "3d".to_string(),
+            relative_path: Some(link_data.relative_path.clone()),
+        }];
+
+        Ok(sources
+            .into_iter()
+            .map(|(name, registered)| {
+                let rendered = registered.and_then(|_| {
+                    match name.split('/').next() {
+                        Some("annotation") => hbs.render(&name, &annotation),
+                        Some("page") => hbs.render(&name, &page_data),
+                        Some("index_link") => hbs.render(&name, &link_data),
+                        _ => hbs.render(&name, &index_data),
+                    }
+                    .map_err(|e| e.to_string())
+                });
+                (name, rendered)
+            })
+            .collect())
+    }
+
+    /// Checks the templates by rendering them for a sample annotation, reporting errors with their line numbers.
+    /// Prints what each template renders to with `preview`
+    #[cfg(feature = "cli")]
+    pub fn check_templates(&self, preview: bool) -> color_eyre::Result<()> {
+        let mut errors = 0;
+        for (name, rendered) in self.render_templates()? {
+            match rendered {
+                Ok(rendered) if preview => {
+                    println!("{} template:\n\n{}\n", name, rendered.trim_end());
+                }
+                Ok(_) => println!("{} template: OK", name),
+                Err(e) => {
+                    errors += 1;
+                    eprintln!("{} template: {}", name, e);
+                }
+            }
+        }
+        if errors > 0 {
+            return Err(Apologize::ConfigError {
+                message: format!("{} template(s) have errors", errors),
+            })
+            .suggestion(
+                "Change them with `gooseberry config kb` (or in the [templates] table of the config file)",
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
     pub fn set_index_name(&mut self) -> color_eyre::Result<()> {
         self.index_name = Some(utils::user_input(
             "What name should gooseberry use for the index file",
@@ -727,13 +1917,14 @@ file_extension = '{}'
         Ok(())
     }
 
+    #[cfg(feature = "cli")]
     pub fn set_file_extension(&mut self) -> color_eyre::Result<()> {
         self.file_extension = Some(utils::user_input(
             "What extension should gooseberry use for wiki files",
             Some(
                 self.file_extension
                     .as_deref()
-                    .unwrap_or(DEFAULT_FILE_EXTENSION),
+                    .unwrap_or(self.kb_format().default_file_extension()),
             ),
             true,
             false,
@@ -745,6 +1936,7 @@ file_extension = '{}'
     /// Sets the Hypothesis group used for Gooseberry annotations
     /// This opens a command-line prompt wherein the user can select creating a new group or
     /// using an existing group by ID
+    #[cfg(feature = "cli")]
     pub async fn set_group(&mut self) -> color_eyre::Result<()> {
         let selections = &[
             "Create a new Hypothesis group",
@@ -762,6 +1954,7 @@ file_extension = '{}'
                 let group_id = Hypothesis::new(
                     self.hypothesis_username.as_deref().unwrap(),
                     self.hypothesis_key.as_deref().unwrap(),
+                    &self.api_url(),
                 )?
                 .create_group(&group_name, Some("Gooseberry knowledge base annotations"))
                 .await?
@@ -771,6 +1964,7 @@ file_extension = '{}'
                 let api = Hypothesis::new(
                     self.hypothesis_username.as_deref().unwrap(),
                     self.hypothesis_key.as_deref().unwrap(),
+                    &self.api_url(),
                 )?;
                 let groups = api
                     .get_groups(&hypothesis::groups::GroupFilters::default())
@@ -800,16 +1994,294 @@ file_extension = '{}'
         Ok(())
     }
 
-    /// Check if user can be authorized
-    pub async fn authorize(name: &str, key: &str) -> color_eyre::Result<bool> {
-        Ok(Hypothesis::new(name, key)?
-            .fetch_user_profile()
+    /// Without the `cli` feature the group can't be asked for, so it has to be in the config file
+    #[cfg(not(feature = "cli"))]
+    pub async fn set_group(&mut self) -> color_eyre::Result<()> {
+        let error: color_eyre::Result<()> = Err(Apologize::ConfigError {
+            message: "Hypothesis group isn't set".into(),
+        }
+        .into());
+        error.suggestion("Set hypothesis_group in the configuration file")
+    }
+
+    /// Sets additional Hypothesis groups to take annotations from
+    /// This opens a command-line prompt to select from the user's existing groups
+    #[cfg(feature = "cli")]
+    pub async fn set_groups(&mut self) -> color_eyre::Result<()> {
+        let api = Hypothesis::new(
+            self.hypothesis_username.as_deref().unwrap(),
+            self.hypothesis_key.as_deref().unwrap(),
+            &self.api_url(),
+        )?;
+        let groups: Vec<_> = api
+            .get_groups(&hypothesis::groups::GroupFilters::default())
             .await?
-            .userid
-            == Some(UserAccountID(format!("acct:{}@hypothes.is", name))))
+            .into_iter()
+            .filter(|g| Some(&g.id) != self.hypothesis_group.as_ref())
+            .collect();
+        if groups.is_empty() {
+            println!("No other groups found");
+            return Ok(());
+        }
+        let current = self.hypothesis_groups.clone().unwrap_or_default();
+        let group_selection: Vec<_> = groups
+            .iter()
+            .map(|g| format!("{}: {}", g.id, g.name))
+            .collect();
+        let defaults: Vec<_> = groups.iter().map(|g| current.contains(&g.id)).collect();
+        let selected = MultiSelect::with_theme(&theme::ColorfulTheme::default())
+            .with_prompt(
+                "Which other groups should gooseberry take annotations from? (Space to select)",
+            )
+            .items(&group_selection[..])
+            .defaults(&defaults[..])
+            .interact()?;
+        self.hypothesis_groups = Some(
+            selected
+                .into_iter()
+                .map(|index| groups[index].id.to_owned())
+                .collect(),
+        );
+        self.store()?;
+        Ok(())
+    }
+
+    /// Search window key: action bindings, the defaults if none are configured.
+    /// Key names are lowercased to match `skim`'s
+    #[cfg(feature = "cli")]
+    pub(crate) fn get_keybindings(&self) -> BTreeMap<String, SearchAction> {
+        match &self.keybindings {
+            Some(keybindings) => keybindings
+                .iter()
+                .map(|(key, action)| (key.to_lowercase(), *action))
+                .collect(),
+            None => DEFAULT_KEYBINDINGS
+                .iter()
+                .map(|(key, action)| ((*key).to_owned(), *action))
+                .collect(),
+        }
+    }
+
+    /// Template for the message of the commits made after `make` with `git_commit` on
+    pub(crate) fn git_commit_message(&self) -> &str {
+        self.git_commit_message
+            .as_deref()
+            .unwrap_or(DEFAULT_GIT_COMMIT_MESSAGE)
+    }
+
+    /// Obsidian vault of the knowledge base: `obsidian_vault`, or the name of the knowledge base directory
+    pub(crate) fn obsidian_vault(&self) -> Option<String> {
+        self.obsidian_vault.clone().or_else(|| {
+            self.kb_dir
+                .as_ref()
+                .and_then(|dir| dir.file_name())
+                .map(|name| name.to_string_lossy().to_string())
+        })
+    }
+
+    /// Tag marking annotations to export as flashcards
+    pub(crate) fn flashcard_tag(&self) -> &str {
+        self.flashcard_tag
+            .as_deref()
+            .unwrap_or(DEFAULT_FLASHCARD_TAG)
+    }
+
+    /// Tag marking annotations as tasks
+    pub(crate) fn todo_tag(&self) -> &str {
+        self.todo_tag.as_deref().unwrap_or(DEFAULT_TODO_TAG)
+    }
+
+    /// Tag given to tasks marked as done
+    pub(crate) fn done_tag(&self) -> &str {
+        self.done_tag.as_deref().unwrap_or(DEFAULT_DONE_TAG)
+    }
+
+    /// Whether everyone's annotations in the groups are synced, not just your own
+    pub(crate) fn all_users(&self) -> bool {
+        self.all_users.unwrap_or(false)
+    }
+
+    /// All groups gooseberry takes annotations from, the main group first
+    pub(crate) fn groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self.hypothesis_group.iter().cloned().collect();
+        for group in self.hypothesis_groups.iter().flatten() {
+            if !groups.contains(group) {
+                groups.push(group.to_owned());
+            }
+        }
+        groups
+    }
+
+    /// Filters to apply locally: the saved view in the filters along with their hierarchical tag prefixes
+    /// (which are taken out of the filters, since the Hypothesis API only matches whole tags),
+    /// and the filter matching the annotations left out by `--exclude-tags` and `--exclude-uri` (also taken out)
+    pub(crate) fn get_local_filter(
+        &self,
+        filters: &mut Filters,
+    ) -> color_eyre::Result<(Filter, Filter)> {
+        let mut local = filters.take_tag_prefixes(self.tag_delimiter.as_deref());
+        local.push(self.get_view(filters.view.as_deref())?);
+        let exclusions = filters.take_exclusions(self.tag_delimiter.as_deref());
+        Ok((Filter::And(local), exclusions))
+    }
+
+    /// Parses a saved view by name (matches everything if no name is given)
+    pub(crate) fn get_view(&self, name: Option<&str>) -> color_eyre::Result<Filter> {
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(Filter::And(Vec::new())),
+        };
+        match self.views.as_ref().and_then(|views| views.get(name)) {
+            Some(expression) => Filter::parse(expression, self.tag_delimiter.as_deref()),
+            None => {
+                let error: color_eyre::Result<Filter> = Err(Apologize::ConfigError {
+                    message: format!("No saved view named {:?}", name),
+                }
+                .into());
+                error.suggestion(
+                    "Add it to the [views] table of the config file (found with `gooseberry config where`)",
+                )
+            }
+        }
+    }
+
+    /// Parses the tagging rules: tag to add and the filter an annotation has to match
+    pub(crate) fn get_rules(&self) -> color_eyre::Result<Vec<(String, Filter)>> {
+        self.rules
+            .iter()
+            .flatten()
+            .map(|(tag, expression)| {
+                let filter = Filter::parse(expression, self.tag_delimiter.as_deref()).suggestion(
+                    format!(
+                        "Fix the rule for {:?} in the [rules] table of the config file",
+                        tag
+                    ),
+                )?;
+                Ok((tag.to_owned(), filter))
+            })
+            .collect()
+    }
+
+    /// Parses the `ignore` filter expressions into a filter matching the annotations to leave out,
+    /// if there are any
+    pub(crate) fn get_ignore_filter(&self) -> color_eyre::Result<Option<Filter>> {
+        let filters = self
+            .ignore
+            .iter()
+            .flatten()
+            .map(|expression| {
+                Filter::parse(expression, self.tag_delimiter.as_deref()).suggestion(format!(
+                    "Fix {:?} in the ignore list of the config file",
+                    expression
+                ))
+            })
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+        Ok(if filters.is_empty() {
+            None
+        } else {
+            Some(Filter::Or(filters))
+        })
+    }
+
+    /// Base URL of the Hypothesis API: the `HYPOTHESIS_API_URL` environment variable if set,
+    /// then `hypothesis_api_url`, then hypothes.is
+    pub fn api_url(&self) -> String {
+        env::var("HYPOTHESIS_API_URL")
+            .ok()
+            .or_else(|| self.hypothesis_api_url.clone())
+            .unwrap_or_else(|| hypothesis::API_URL.to_owned())
+            .trim_end_matches('/')
+            .to_owned()
+    }
+
+    /// Check if user can be authorized (on the Hypothesis server at the API URL)
+    pub async fn authorize(&self, name: &str, key: &str) -> color_eyre::Result<bool> {
+        let profile = Hypothesis::new(name, key, &self.api_url())?
+            .fetch_user_profile()
+            .await?;
+        Ok(profile.userid
+            == Some(UserAccountID(format!(
+                "acct:{}@{}",
+                name, profile.authority
+            ))))
+    }
+
+    /// Keyring entry storing the Hypothesis API key of a user
+    fn keyring_entry(username: &str) -> color_eyre::Result<keyring::Entry> {
+        Ok(keyring::Entry::new(NAME, username)?)
+    }
+
+    /// Reads the Hypothesis API key from the system keyring, with `use_keyring` on.
+    /// If it can't be read the key is asked for (or taken from `HYPOTHESIS_KEY`) as if it wasn't stored
+    pub(crate) fn read_keyring(&mut self) {
+        if !self.use_keyring.unwrap_or(false) || self.hypothesis_key.is_some() {
+            return;
+        }
+        if let Some(username) = &self.hypothesis_username {
+            match Self::keyring_entry(username).and_then(|entry| Ok(entry.get_password()?)) {
+                Ok(key) => self.hypothesis_key = Some(key),
+                Err(error) => eprintln!(
+                    "Couldn't read the Hypothesis API key from the keyring: {}",
+                    error
+                ),
+            }
+        }
+    }
+
+    /// Stores Hypothesis credentials, with the key going to the system keyring if `use_keyring` is on
+    fn store_credentials(&mut self, name: String, key: String) -> color_eyre::Result<()> {
+        if self.use_keyring.unwrap_or(false) {
+            Self::keyring_entry(&name)?.set_password(&key)?;
+        }
+        self.hypothesis_username = Some(name);
+        self.hypothesis_key = Some(key);
+        self.store()
+    }
+
+    /// Changes the Hypothesis API key (keeping the current one if nothing is entered)
+    /// and where it's stored: in the system keyring (Secret Service, Keychain, or Credential Manager),
+    /// or in the configuration file
+    #[cfg(feature = "cli")]
+    pub async fn set_token(&mut self, keyring: bool) -> color_eyre::Result<()> {
+        let name = self
+            .hypothesis_username
+            .clone()
+            .ok_or(Apologize::ConfigError {
+                message: "Hypothesis username isn't stored".into(),
+            })?;
+        let key = loop {
+            let key = dialoguer::Password::with_theme(&dialoguer::theme::ColorfulTheme::default())
+                .with_prompt("Hypothesis developer API key (empty to keep the current one)")
+                .allow_empty_password(true)
+                .interact()?;
+            let key = if key.is_empty() {
+                self.hypothesis_key.clone().unwrap_or_default()
+            } else {
+                key
+            };
+            if self.authorize(&name, &key).await? {
+                break key;
+            }
+            println!("Could not authorize your Hypothesis credentials, please try again.");
+        };
+        if !keyring && self.use_keyring.unwrap_or(false) {
+            match Self::keyring_entry(&name)?.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => (),
+                Err(error) => return Err(error.into()),
+            }
+        }
+        self.use_keyring = Some(keyring);
+        self.store_credentials(name, key)?;
+        if keyring {
+            println!("Stored the Hypothesis API key in the system keyring");
+        } else {
+            println!("Stored the Hypothesis API key in the configuration file");
+        }
+        Ok(())
     }
 
     /// Asks user for Hypothesis credentials and sets them in the config
+    #[cfg(feature = "cli")]
     pub async fn request_credentials(&mut self) -> color_eyre::Result<()> {
         let mut name = String::new();
         let mut key;
@@ -823,16 +2295,28 @@ file_extension = '{}'
             key = dialoguer::Password::with_theme(&dialoguer::theme::ColorfulTheme::default())
                 .with_prompt("Hypothesis developer API key")
                 .interact()?;
-            if Self::authorize(&name, &key).await? {
-                self.hypothesis_username = Some(name);
-                self.hypothesis_key = Some(key);
-                self.store()?;
-                return Ok(());
+            if self.authorize(&name, &key).await? {
+                return self.store_credentials(name, key);
             } else {
                 println!("Could not authorize your Hypothesis credentials, please try again.");
             }
         }
     }
+
+    /// Without the `cli` feature credentials can't be asked for, so they have to come from
+    /// the environment or the config file
+    #[cfg(not(feature = "cli"))]
+    pub async fn request_credentials(&mut self) -> color_eyre::Result<()> {
+        let error: color_eyre::Result<()> = Err(Apologize::ConfigError {
+            message: "Hypothesis credentials missing or invalid".into(),
+        }
+        .into());
+        error.suggestion(
+            "Set the HYPOTHESIS_NAME and HYPOTHESIS_KEY environment variables, \
+            or hypothesis_username and hypothesis_key in the configuration file",
+        )
+    }
+
     /// Reads the `HYPOTHESIS_NAME` and `HYPOTHESIS_KEY` environment variables to get Hypothesis credentials.
     /// If not present or invalid, requests credentials from user.
     pub async fn set_credentials(&mut self) -> color_eyre::Result<()> {
@@ -841,10 +2325,8 @@ file_extension = '{}'
             env::var("HYPOTHESIS_KEY").ok(),
         );
         if let (Some(n), Some(k)) = (&name, &key) {
-            if Self::authorize(n, k).await? {
-                self.hypothesis_username = Some(n.to_owned());
-                self.hypothesis_key = Some(k.to_owned());
-                self.store()?;
+            if self.authorize(n, k).await? {
+                self.store_credentials(n.to_owned(), k.to_owned())?;
             } else {
                 println!(
                     "Authorization with environment variables did not work. Enter details below"
@@ -858,15 +2340,23 @@ file_extension = '{}'
     }
 
     /// Write possibly modified config
+    /// (without the Hypothesis API key if it's in the system keyring)
     pub fn store(&self) -> color_eyre::Result<()> {
+        let mut config = self.clone();
+        if config.use_keyring.unwrap_or(false) {
+            config.hypothesis_key = None;
+        }
         // Reads the GOOSEBERRY_CONFIG environment variable to get config file location
         let config_file = env::var("GOOSEBERRY_CONFIG").ok();
+        if let Some(name) = &self.profile {
+            config = config.stored_in_profile(name, config_file.as_deref().map(Path::new))?;
+        }
         match config_file {
-            Some(file) => confy::store_path(Path::new(&file), &(*self).clone()).suggestion(Apologize::ConfigError {
+            Some(file) => confy::store_path(Path::new(&file), &config).suggestion(Apologize::ConfigError {
                 message: "The current config_file location does not seem to have write access. \
                    Use `export GOOSEBERRY_CONFIG=<full/path/to/config_file.toml>` to set a new location".into()
             })?,
-            None => confy::store(NAME, &(*self).clone()).suggestion(Apologize::ConfigError {
+            None => confy::store(NAME, &config).suggestion(Apologize::ConfigError {
                 message: "The current config_file location does not seem to have write access. \
                     Use `export GOOSEBERRY_CONFIG=<full/path/to/config_file.toml>` to set a new location".into()
             })?,
@@ -2,7 +2,11 @@ use thiserror::Error;
 
 /// "It claimed to have 15 functions, although it appeared that at least ten were apologizing for
 /// the useless manner in which it performed the others." - [Dis-organizer](https://wiki.lspace.org/mediawiki/Dis-organiser)
-#[derive(Debug, Error)]
+///
+/// Serialized (for `--error-format json`) with the snake_case name of the variant as its `code`,
+/// which stays the same across versions, along with its fields
+#[derive(Debug, Error, Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
 pub enum Apologize {
     /// Thrown when trying to access an unrecorded tag
     #[error("You haven't tagged anything as {tag:?} yet.")]
@@ -13,6 +17,27 @@ pub enum Apologize {
     /// Thrown when trying to access an unrecorded tag
     #[error("Couldn't find group {id:?}. The Group ID can be found in the URL of the group: https://hypothes.is/groups/<group_id>/<group_name>")]
     GroupNotFound { id: String },
+    /// Errors related to encrypting and decrypting the database
+    #[error("EncryptionError: {message:?}")]
+    EncryptionError { message: String },
+    /// Thrown when the encrypted database can't be decrypted
+    #[error("Couldn't decrypt the database: wrong passphrase, or the encrypted file is damaged")]
+    WrongPassphrase,
+    /// Thrown when annotations couldn't be fetched from the Hypothesis API during sync
+    #[error("SyncError: {message:?}")]
+    SyncError { message: String },
+    /// Thrown when an export file of another tool can't be imported
+    #[error("ImportError: {message:?}")]
+    ImportError { message: String },
+    /// Thrown when a database mirror written by `gooseberry db export` can't be imported
+    #[error("MirrorError: {message:?}")]
+    MirrorError { message: String },
+    /// Thrown when the database is from a newer version of gooseberry, or couldn't be upgraded from an older one
+    #[error("MigrationError: {message:?}")]
+    MigrationError { message: String },
+    /// Thrown when highlights couldn't be sent to Readwise
+    #[error("ReadwiseError: {message:?}")]
+    ReadwiseError { message: String },
     /// Thrown when explicit Y not received from user for destructive things
     #[error("I'm a coward. Doing nothing.")]
     DoingNothing,
@@ -25,13 +50,120 @@ pub enum Apologize {
     /// Errors related to changing the configuration file
     #[error("ConfigError: {message:?}")]
     ConfigError { message: String },
+    /// Thrown when a filter expression (e.g. in a saved view) can't be parsed
+    #[error("FilterError: {message:?}")]
+    FilterError { message: String },
     /// Errors related to making the knowledge base
     #[error("KBError: {message:?}")]
+    #[serde(rename = "kb_error")]
     KBError { message: String },
+    /// Thrown when the knowledge base couldn't be committed or pushed to git
+    #[error("GitError: {message:?}")]
+    GitError { message: String },
+    /// Thrown when nothing matches the filters in non-interactive output
+    #[error("No matching annotations")]
+    NoMatches,
     /// Thrown when no text is returned from an external editor
     #[error("EditorError")]
     EditorError,
+    /// Thrown when a URL couldn't be opened in the browser
+    #[error("BrowserError: Couldn't open {url:?}")]
+    BrowserError { url: String },
+    /// Thrown when text couldn't be copied to the clipboard
+    #[error("ClipboardError: Couldn't copy to the clipboard")]
+    ClipboardError,
+    /// Thrown when logging can't be set up
+    #[error("LogError: {message:?}")]
+    LogError { message: String },
+    /// Thrown when `gooseberry doctor` finds problems with the setup
+    #[error("DoctorError: {failed} check(s) failed")]
+    DoctorError { failed: usize },
+    /// Thrown when a request to `gooseberry serve` has an invalid query
+    #[error("RequestError: {message:?}")]
+    RequestError { message: String },
+    /// Thrown when `gooseberry serve` has no endpoint for a request
+    #[error("No endpoint for {method} {path}")]
+    RouteNotFound { method: String, path: String },
+    /// Thrown when `gooseberry digest --sendmail` couldn't send the digest
+    #[error("DigestError: {message:?}")]
+    DigestError { message: String },
+    /// Thrown when annotations can't be linked with `gooseberry link`
+    #[error("LinkError: {message:?}")]
+    LinkError { message: String },
+    /// Thrown when annotation metadata given to `gooseberry meta` isn't `key=value` pairs
+    #[error("MetaError: {message:?}")]
+    MetaError { message: String },
+    /// Thrown when `--dry-run` is given to a command that would make changes without honoring it
+    #[error("{command} doesn't support --dry-run")]
+    DryRunError { command: String },
+    /// Thrown when some of the annotations changed in bulk couldn't be updated in Hypothesis
+    #[error("UpdateError: {failed} of {total} annotation(s) couldn't be updated")]
+    UpdateError { failed: usize, total: usize },
     /// Catch-all for stuff that should never happen
     #[error("OutOfCheeseError: {message:?}\nRedo from start.")]
     OutOfCheeseError { message: String },
 }
+
+/// Code of errors which aren't gooseberry's own: those of the Hypothesis API, the network, files, and JSON
+fn external_code(error: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    if error.is::<hypothesis::errors::HypothesisError>() {
+        Some("hypothesis_api_error")
+    } else if error.is::<reqwest::Error>() {
+        Some("network_error")
+    } else if error.is::<std::io::Error>() {
+        Some("io_error")
+    } else if error.is::<serde_json::Error>() {
+        Some("json_error")
+    } else {
+        None
+    }
+}
+
+/// Suggestions on how to fix an error, taken from its report without colors
+fn suggestions(report: &color_eyre::Report) -> Vec<String> {
+    let colors = regex::Regex::new("\x1b\\[[0-9;]*m").expect("valid regex");
+    colors
+        .replace_all(&format!("{:?}", report), "")
+        .lines()
+        .filter_map(|line| line.strip_prefix("Suggestion: "))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// An error as JSON, for scripts and editor plugins: its `code` (`other_error` if it isn't a known one),
+/// `message`, the fields of gooseberry's errors as `details`, the messages of the errors which caused it
+/// as `causes`, and the `suggestions` on how to fix it (as the report shows them, without colors)
+pub fn error_json(report: &color_eyre::Report) -> serde_json::Value {
+    let (code, details) = match report
+        .chain()
+        .find_map(|error| error.downcast_ref::<Apologize>())
+    {
+        Some(apology) => {
+            let mut fields = match serde_json::to_value(apology) {
+                Ok(serde_json::Value::Object(fields)) => fields,
+                _ => serde_json::Map::new(),
+            };
+            let code = fields
+                .remove("code")
+                .unwrap_or_else(|| "other_error".into());
+            (code, serde_json::Value::Object(fields))
+        }
+        None => (
+            report
+                .chain()
+                .find_map(external_code)
+                .unwrap_or("other_error")
+                .into(),
+            serde_json::json!({}),
+        ),
+    };
+    serde_json::json!({
+        "error": {
+            "code": code,
+            "message": report.to_string(),
+            "details": details,
+            "causes": report.chain().skip(1).map(|error| error.to_string()).collect::<Vec<_>>(),
+            "suggestions": suggestions(report),
+        }
+    })
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use futures::future::try_join_all;
+use hypothesis::annotations::{Annotation, InputAnnotation, Permissions, SearchQuery};
+use hypothesis::errors::HypothesisError;
+use hypothesis::groups::{Expand, Group, GroupFilters, Member};
+use hypothesis::profile::UserProfile;
+use hypothesis::UserAccountID;
+use reqwest::{header, RequestBuilder};
+use serde::de::DeserializeOwned;
+use url::Url;
+
+/// Hypothesis API client for the configured API URL (see `GooseberryConfig::api_url`).
+///
+/// Has the methods of the `hypothesis` crate's client that gooseberry uses, which always talks to
+/// hypothes.is: with a self-hosted server configured, every request (and the developer key sent with it)
+/// goes to that server instead
+pub struct Hypothesis {
+    /// Authenticated user
+    pub username: String,
+    /// "acct:{username}@{authority}", the authority being the API's domain without `api.`
+    /// (e.g. hypothes.is)
+    pub user: UserAccountID,
+    /// Base URL of the API, without a trailing `/`
+    pub api_url: String,
+    /// reqwest async client sending the developer key
+    client: reqwest::Client,
+}
+
+impl Hypothesis {
+    /// Makes a new client with your username and developer key, for the API at `api_url`
+    pub fn new(username: &str, developer_key: &str, api_url: &str) -> color_eyre::Result<Self> {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            header::HeaderValue::from_str(&format!("Bearer {}", developer_key))?,
+        );
+        headers.insert(
+            header::ACCEPT,
+            header::HeaderValue::from_static("application/vnd.hypothesis.v1+json"),
+        );
+        let api_url = api_url.trim_end_matches('/').to_owned();
+        let authority = Url::parse(&api_url)?
+            .host_str()
+            .map(|host| host.strip_prefix("api.").unwrap_or(host).to_owned())
+            .unwrap_or_default();
+        Ok(Self {
+            username: username.into(),
+            user: UserAccountID(format!("acct:{}@{}", username, authority)),
+            api_url,
+            client: reqwest::Client::builder()
+                .default_headers(headers)
+                .build()?,
+        })
+    }
+
+    /// Sends a request and parses the response
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, HypothesisError> {
+        let text = request
+            .send()
+            .await
+            .map_err(HypothesisError::ReqwestError)?
+            .text()
+            .await
+            .map_err(HypothesisError::ReqwestError)?;
+        hypothesis::serde_parse(&text)
+    }
+
+    /// Adds a JSON body to a request
+    fn with_json<T: serde::Serialize>(
+        request: RequestBuilder,
+        body: &T,
+    ) -> Result<RequestBuilder, HypothesisError> {
+        Ok(request
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(body).map_err(HypothesisError::SerdeError)?))
+    }
+
+    /// URL with the query parameters of a serializable query (e.g. `SearchQuery`)
+    fn url_with_query<T: serde::Serialize>(
+        &self,
+        path: &str,
+        query: &T,
+    ) -> Result<Url, HypothesisError> {
+        let query: HashMap<String, serde_json::Value> = serde_json::from_value(
+            serde_json::to_value(query).map_err(HypothesisError::SerdeError)?,
+        )
+        .map_err(HypothesisError::SerdeError)?;
+        Url::parse_with_params(
+            &format!("{}/{}", self.api_url, path),
+            query
+                .into_iter()
+                .map(|(k, v)| (k, v.to_string().replace('"', ""))),
+        )
+        .map_err(HypothesisError::URLError)
+    }
+
+    /// Creates an annotation
+    pub async fn create_annotation(
+        &self,
+        annotation: &InputAnnotation,
+    ) -> Result<Annotation, HypothesisError> {
+        let request = self.client.post(format!("{}/annotations", self.api_url));
+        Self::send(Self::with_json(request, annotation)?).await
+    }
+
+    /// Creates an annotation with the given permissions, which `InputAnnotation` can't hold
+    /// (e.g. to keep the sharing of an annotation restored from the trash or copied to another group)
+    pub async fn create_annotation_with_permissions(
+        &self,
+        annotation: &InputAnnotation,
+        permissions: &Permissions,
+    ) -> Result<Annotation, HypothesisError> {
+        let mut body = serde_json::to_value(annotation).map_err(HypothesisError::SerdeError)?;
+        body["permissions"] =
+            serde_json::to_value(permissions).map_err(HypothesisError::SerdeError)?;
+        let request = self.client.post(format!("{}/annotations", self.api_url));
+        Self::send(Self::with_json(request, &body)?).await
+    }
+
+    /// Creates many annotations at once
+    pub async fn create_annotations(
+        &self,
+        annotations: &[InputAnnotation],
+    ) -> Result<Vec<Annotation>, HypothesisError> {
+        try_join_all(annotations.iter().map(|a| self.create_annotation(a))).await
+    }
+
+    /// Updates an annotation to match the one given
+    pub async fn update_annotation(
+        &self,
+        annotation: &Annotation,
+    ) -> Result<Annotation, HypothesisError> {
+        let request = self
+            .client
+            .patch(format!("{}/annotations/{}", self.api_url, annotation.id));
+        Self::send(Self::with_json(request, annotation)?).await
+    }
+
+    /// Updates many annotations at once
+    pub async fn update_annotations(
+        &self,
+        annotations: &[Annotation],
+    ) -> Result<Vec<Annotation>, HypothesisError> {
+        try_join_all(annotations.iter().map(|a| self.update_annotation(a))).await
+    }
+
+    /// Page of annotations matching a search query
+    pub async fn search_annotations(
+        &self,
+        query: &SearchQuery,
+    ) -> Result<Vec<Annotation>, HypothesisError> {
+        #[derive(Deserialize)]
+        struct SearchResult {
+            rows: Vec<Annotation>,
+        }
+        let url = self.url_with_query("search", query)?;
+        Ok(Self::send::<SearchResult>(self.client.get(url)).await?.rows)
+    }
+
+    /// All annotations matching a search query, a page at a time
+    pub async fn search_annotations_return_all(
+        &self,
+        query: &mut SearchQuery,
+    ) -> Result<Vec<Annotation>, HypothesisError> {
+        let mut annotations = Vec::new();
+        loop {
+            let next = self.search_annotations(query).await?;
+            match next.last() {
+                Some(last) => query.search_after = last.updated.to_rfc3339(),
+                None => break,
+            }
+            annotations.extend(next);
+        }
+        Ok(annotations)
+    }
+
+    /// Fetches an annotation by ID
+    pub async fn fetch_annotation(&self, id: &str) -> Result<Annotation, HypothesisError> {
+        Self::send(
+            self.client
+                .get(format!("{}/annotations/{}", self.api_url, id)),
+        )
+        .await
+    }
+
+    /// Deletes an annotation by ID, returning whether it was deleted
+    pub async fn delete_annotation(&self, id: &str) -> Result<bool, HypothesisError> {
+        #[derive(Deserialize)]
+        struct DeletionResult {
+            deleted: bool,
+        }
+        let request = self
+            .client
+            .delete(format!("{}/annotations/{}", self.api_url, id));
+        Ok(Self::send::<DeletionResult>(request).await?.deleted)
+    }
+
+    /// Deletes many annotations at once
+    pub async fn delete_annotations(&self, ids: &[String]) -> Result<Vec<bool>, HypothesisError> {
+        try_join_all(ids.iter().map(|id| self.delete_annotation(id))).await
+    }
+
+    /// Groups of the user
+    pub async fn get_groups(&self, query: &GroupFilters) -> Result<Vec<Group>, HypothesisError> {
+        let url = self.url_with_query("groups", query)?;
+        Self::send(self.client.get(url)).await
+    }
+
+    /// Creates a private group
+    pub async fn create_group(
+        &self,
+        name: &str,
+        description: Option<&str>,
+    ) -> Result<Group, HypothesisError> {
+        let mut params = HashMap::new();
+        params.insert("name", name);
+        if let Some(description) = description {
+            params.insert("description", description);
+        }
+        let request = self.client.post(format!("{}/groups", self.api_url));
+        Self::send(Self::with_json(request, &params)?).await
+    }
+
+    /// Fetches a group by ID
+    pub async fn fetch_group(
+        &self,
+        id: &str,
+        expand: Vec<Expand>,
+    ) -> Result<Group, HypothesisError> {
+        let expand = expand
+            .into_iter()
+            .map(|e| serde_json::to_value(&e).map(|v| ("expand", v.to_string().replace('"', ""))))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(HypothesisError::SerdeError)?;
+        let request = self
+            .client
+            .get(format!("{}/groups/{}", self.api_url, id))
+            .query(&expand);
+        Self::send(request).await
+    }
+
+    /// Members of a group
+    pub async fn get_group_members(&self, id: &str) -> Result<Vec<Member>, HypothesisError> {
+        Self::send(
+            self.client
+                .get(format!("{}/groups/{}/members", self.api_url, id)),
+        )
+        .await
+    }
+
+    /// Removes the user from a group
+    pub async fn leave_group(&self, id: &str) -> Result<(), HypothesisError> {
+        let text = self
+            .client
+            .delete(format!("{}/groups/{}/members/me", self.api_url, id))
+            .send()
+            .await
+            .map_err(HypothesisError::ReqwestError)?
+            .text()
+            .await
+            .map_err(HypothesisError::ReqwestError)?;
+        match serde_json::from_str::<hypothesis::errors::APIError>(&text) {
+            Ok(source) => Err(HypothesisError::APIError {
+                source,
+                raw_text: text,
+                serde_error: None,
+            }),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Profile of the user
+    pub async fn fetch_user_profile(&self) -> Result<UserProfile, HypothesisError> {
+        Self::send(self.client.get(format!("{}/profile", self.api_url))).await
+    }
+}
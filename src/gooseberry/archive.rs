@@ -0,0 +1,337 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::stream::{self, StreamExt};
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::titles::{http_client, CONCURRENT_FETCHES, FETCH_TIMEOUT};
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Elements whose contents are never part of the article
+const SKIPPED_ELEMENTS: [&str; 12] = [
+    "script", "style", "noscript", "template", "svg", "iframe", "nav", "header", "footer", "aside",
+    "form", "button",
+];
+
+/// Elements which start a new paragraph
+const BLOCK_ELEMENTS: [&str; 25] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "blockquote",
+    "pre",
+    "br",
+    "hr",
+    "table",
+    "tr",
+    "figcaption",
+    "caption",
+];
+
+/// Save Page Now endpoint of the Internet Archive's Wayback Machine
+const SAVE_PAGE_NOW: &str = "https://web.archive.org/save/";
+const WAYBACK_MACHINE: &str = "https://web.archive.org";
+/// Saving a page can take a while, as the Wayback Machine loads it like a browser
+const SAVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Paragraphs with fewer words are menus, buttons, bylines etc. rather than article text
+const MIN_PARAGRAPH_WORDS: usize = 5;
+
+/// The contents of the first `<name ...>` element (up to its last closing tag), if there is one
+fn element<'a>(html: &'a str, lowercase: &str, name: &str) -> Option<&'a str> {
+    let start = lowercase.find(&format!("<{}", name))?;
+    let start = start + lowercase[start..].find('>')? + 1;
+    let end = lowercase
+        .rfind(&format!("</{}", name))
+        .filter(|&end| end >= start)?;
+    Some(&html[start..end])
+}
+
+/// Extracts the readable text of a web page: the paragraphs of its `<article>` (or `<main>`, or `<body>`),
+/// leaving out scripts, navigation, headers, footers, and short fragments like menu items.
+/// Paragraphs are separated by blank lines
+pub(crate) fn extract_article(html: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets the same
+    let lowercase = html.to_ascii_lowercase();
+    let content = ["article", "main", "body"]
+        .iter()
+        .find_map(|name| element(html, &lowercase, name))
+        .unwrap_or(html);
+    let lowercase = content.to_ascii_lowercase();
+
+    let mut text = String::with_capacity(content.len() / 2);
+    let mut position = 0;
+    loop {
+        let start = match content[position..].find('<') {
+            Some(offset) => position + offset,
+            None => {
+                text.push_str(&content[position..]);
+                break;
+            }
+        };
+        text.push_str(&content[position..start]);
+        if lowercase[start..].starts_with("<!--") {
+            position = match lowercase[start..].find("-->") {
+                Some(end) => start + end + 3,
+                None => content.len(),
+            };
+            continue;
+        }
+        let end = match lowercase[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let tag = &lowercase[start + 1..end - 1];
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+        position = end;
+        if !closing && !tag.ends_with('/') && SKIPPED_ELEMENTS.contains(&name.as_str()) {
+            position = match lowercase[end..].find(&format!("</{}", name)) {
+                Some(close) => {
+                    let close = end + close;
+                    close + lowercase[close..].find('>').map_or(0, |c| c + 1)
+                }
+                None => content.len(),
+            };
+        } else if BLOCK_ELEMENTS.contains(&name.as_str()) {
+            text.push_str("\n\n");
+        }
+    }
+
+    let paragraphs: Vec<_> = text
+        .split("\n\n")
+        .map(|paragraph| {
+            utils::decode_entities(&paragraph.split_whitespace().collect::<Vec<_>>().join(" "))
+        })
+        .filter(|paragraph| paragraph.split_whitespace().count() >= MIN_PARAGRAPH_WORDS)
+        .collect();
+    if paragraphs.is_empty() {
+        None
+    } else {
+        Some(paragraphs.join("\n\n"))
+    }
+}
+
+/// The paragraph of an archived page with the highlighted quote in it.
+/// Falls back to the start of the quote for highlights spanning paragraphs
+pub(crate) fn quote_context(article: &str, quote: &str) -> Option<String> {
+    let quote = quote.split_whitespace().collect::<Vec<_>>().join(" ");
+    if quote.is_empty() {
+        return None;
+    }
+    let start_of_quote: String = quote.chars().take(50).collect();
+    [quote.as_str(), start_of_quote.as_str()]
+        .iter()
+        .find_map(|needle| {
+            article
+                .split("\n\n")
+                .find(|paragraph| paragraph.contains(needle))
+        })
+        .map(str::to_owned)
+}
+
+/// Fetches an HTML page and extracts its readable text.
+/// Only connection errors are errors, pages which aren't HTML (or don't exist) give `None`
+async fn fetch_article(client: &reqwest::Client, uri: &str) -> reqwest::Result<Option<String>> {
+    let response = client.get(uri).send().await?;
+    let is_html = match response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+    {
+        Some(content_type) => content_type.contains("html"),
+        None => true,
+    };
+    if !response.status().is_success() || !is_html {
+        return Ok(None);
+    }
+    Ok(extract_article(&response.text().await?))
+}
+
+/// Asks the Wayback Machine to save a page, returning the URL of the snapshot.
+/// Pages which can't be saved give `None`, being rate limited is an error (with a status code)
+async fn save_snapshot(client: &reqwest::Client, uri: &str) -> reqwest::Result<Option<String>> {
+    let response = client
+        .get(format!("{}{}", SAVE_PAGE_NOW, uri))
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        response.error_for_status_ref()?;
+    }
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    // The snapshot's path is in Content-Location, or the request was redirected to it
+    Ok(response
+        .headers()
+        .get(reqwest::header::CONTENT_LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .filter(|location| location.starts_with("/web/"))
+        .map(|location| format!("{}{}", WAYBACK_MACHINE, location))
+        .or_else(|| {
+            Some(response.url())
+                .filter(|url| url.path().starts_with("/web/"))
+                .map(|url| url.to_string())
+        }))
+}
+
+/// ## Archive
+/// Readable snapshots of annotated pages, kept in the URI: text tree
+/// so that annotations keep their context when pages change or disappear,
+/// and Wayback Machine snapshots, kept in the URI: snapshot URL tree
+impl Gooseberry {
+    /// Fetches and stores the text of annotated web pages which haven't been archived yet.
+    ///
+    /// Pages without readable text are stored as empty so that they aren't fetched again,
+    /// pages which couldn't be reached are tried again next time.
+    /// Returns the number of pages archived
+    pub(crate) async fn archive_pages(
+        &self,
+        annotations: &[Annotation],
+    ) -> color_eyre::Result<usize> {
+        let archive = self.uri_to_archive()?;
+        let mut to_fetch = HashSet::new();
+        for annotation in annotations {
+            if annotation.uri.starts_with("http")
+                && !archive.contains_key(annotation.uri.as_bytes())?
+            {
+                to_fetch.insert(annotation.uri.as_str());
+            }
+        }
+        if to_fetch.is_empty() {
+            return Ok(0);
+        }
+        let client = http_client(FETCH_TIMEOUT)?;
+        let fetched: Vec<_> = stream::iter(to_fetch)
+            .map(|uri| {
+                let client = &client;
+                async move { (uri, fetch_article(client, uri).await) }
+            })
+            .buffer_unordered(CONCURRENT_FETCHES)
+            .collect()
+            .await;
+        let mut archived = 0;
+        for (uri, article) in fetched {
+            if let Ok(article) = article {
+                if article.is_some() {
+                    archived += 1;
+                }
+                archive.insert(uri.as_bytes(), article.unwrap_or_default().as_bytes())?;
+            }
+        }
+        archive.flush()?;
+        Ok(archived)
+    }
+
+    /// Archived text of a page, if it has been archived and has any
+    pub(crate) fn get_archive(&self, uri: &str) -> color_eyre::Result<Option<String>> {
+        Ok(self
+            .uri_to_archive()?
+            .get(uri.as_bytes())?
+            .map(|text| String::from_utf8_lossy(&text).to_string())
+            .filter(|text| !text.is_empty()))
+    }
+
+    /// Submits annotated web pages without a snapshot to the Wayback Machine's Save Page Now
+    /// and stores the snapshot URLs. Pages are submitted one at a time, stopping if the Wayback Machine
+    /// rate limits them (the rest are submitted next time). Progress is shown in the spinner.
+    /// Returns the number of pages saved
+    pub(crate) async fn save_snapshots(
+        &self,
+        annotations: &[Annotation],
+        spinner: &indicatif::ProgressBar,
+    ) -> color_eyre::Result<usize> {
+        let snapshots = self.uri_to_snapshot()?;
+        let mut seen = HashSet::new();
+        let mut to_save = Vec::new();
+        for annotation in annotations {
+            let uri = annotation.uri.as_str();
+            if uri.starts_with("http")
+                && seen.insert(uri)
+                && !snapshots.contains_key(uri.as_bytes())?
+            {
+                to_save.push(uri);
+            }
+        }
+        if to_save.is_empty() {
+            return Ok(0);
+        }
+        let client = http_client(SAVE_TIMEOUT)?;
+        let mut saved = 0;
+        for (i, uri) in to_save.iter().enumerate() {
+            spinner.set_message(&format!(
+                "Saving page {} of {}: {}",
+                i + 1,
+                to_save.len(),
+                uri
+            ));
+            match save_snapshot(&client, uri).await {
+                Ok(Some(snapshot)) => {
+                    snapshots.insert(uri.as_bytes(), snapshot.as_bytes())?;
+                    saved += 1;
+                }
+                Ok(None) => (),
+                Err(error) if error.status().is_some() => {
+                    spinner.println(format!(
+                        "The Wayback Machine is rate limiting requests, the other {} page(s) will be saved next time",
+                        to_save.len() - i
+                    ));
+                    snapshots.flush()?;
+                    return Ok(saved);
+                }
+                // Tried again next time
+                Err(_) => (),
+            }
+        }
+        snapshots.flush()?;
+        Ok(saved)
+    }
+
+    /// Wayback Machine snapshot of a page, if one was saved
+    pub(crate) fn get_snapshot(&self, uri: &str) -> color_eyre::Result<Option<String>> {
+        Ok(self
+            .uri_to_snapshot()?
+            .get(uri.as_bytes())?
+            .map(|snapshot| String::from_utf8_lossy(&snapshot).to_string()))
+    }
+
+    /// Archives the pages of (optionally filtered) annotations: saves them in the Wayback Machine
+    /// (unless `text_only`) and stores their readable text, adding it to the full-text index.
+    /// Only pages which haven't been archived yet are fetched
+    pub async fn archive(
+        &self,
+        annotations: Vec<Annotation>,
+        text_only: bool,
+    ) -> color_eyre::Result<()> {
+        let spinner = utils::get_spinner("Archiving pages...");
+        if !text_only {
+            let saved = self.save_snapshots(&annotations, &spinner).await?;
+            spinner.println(format!("Saved {} page(s) in the Wayback Machine", saved));
+            spinner.set_message("Archiving pages...");
+        }
+        let archived = self.archive_pages(&annotations).await?;
+        self.index_annotations(&annotations)?;
+        spinner.finish_with_message("Done!");
+        println!("Archived {} page(s)", archived);
+        Ok(())
+    }
+}
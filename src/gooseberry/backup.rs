@@ -0,0 +1,202 @@
+use std::fs;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use chrono::Local;
+use color_eyre::Help;
+
+use crate::errors::Apologize;
+use crate::gooseberry::mirror::read_mirror;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Number of backups kept if `db_backups` isn't set
+const DEFAULT_BACKUPS: usize = 5;
+
+/// Deleting at least this many annotations at once backs up the database first
+pub(crate) const BULK_DELETE: usize = 10;
+
+/// Format of the timestamps naming the backups, e.g. 20210116-111249
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// Extension of backups, with `.enc` after it for encrypted ones
+const BACKUP_EXTENSION: &str = "jsonl";
+
+/// A backup in the backup folder
+struct Backup {
+    timestamp: String,
+    path: PathBuf,
+    encrypted: bool,
+}
+
+/// ## Backups
+/// Snapshots of the database (as JSON Lines mirrors) taken before commands which delete or replace data,
+/// so that a bad bulk delete or a broken database doesn't mean syncing everything again
+impl Gooseberry {
+    /// Folder with the backups, next to the database folder
+    fn backup_dir(&self) -> PathBuf {
+        self.config.db_dir.with_extension("backups")
+    }
+
+    /// Backups in the backup folder, oldest first
+    fn backups(&self) -> color_eyre::Result<Vec<Backup>> {
+        let backup_dir = self.backup_dir();
+        if !backup_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(backup_dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let (name, encrypted) = match name.strip_suffix(".enc") {
+                Some(name) => (name.to_owned(), true),
+                None => (name, false),
+            };
+            if let Some(timestamp) = name.strip_suffix(&format!(".{}", BACKUP_EXTENSION)) {
+                backups.push(Backup {
+                    timestamp: timestamp.to_owned(),
+                    path,
+                    encrypted,
+                });
+            }
+        }
+        backups.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(backups)
+    }
+
+    /// Snapshots the database into the backup folder before a destructive `command`,
+    /// encrypted with `encrypt_database` on, and removes the oldest backups beyond `db_backups`.
+    /// Does nothing with `db_backups = 0`, or if there are no annotations (synced or in the trash) to lose
+    pub(crate) fn backup_db(&self, command: &str) -> color_eyre::Result<()> {
+        let keep = self.config.db_backups.unwrap_or(DEFAULT_BACKUPS);
+        if keep == 0 || (self.annotation_to_tags()?.is_empty()? && self.trash()?.is_empty()?) {
+            return Ok(());
+        }
+        let mut contents = Vec::new();
+        self.write_mirror(&mut contents)?;
+        let mut backups = self.backups()?;
+        // numbered if there's already a backup from the same second
+        let now = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        let mut timestamp = now.clone();
+        let mut number = 2;
+        while backups.iter().any(|backup| backup.timestamp == timestamp) {
+            timestamp = format!("{}-{}", now, number);
+            number += 1;
+        }
+        let mut file_name = format!("{}.{}", timestamp, BACKUP_EXTENSION);
+        if let Some(working_copy) = &self.working_copy {
+            contents = working_copy.seal(&contents)?;
+            file_name.push_str(".enc");
+        }
+        let backup_dir = self.backup_dir();
+        fs::create_dir_all(&backup_dir)?;
+        let path = backup_dir.join(file_name);
+        fs::write(&path, contents)?;
+        backups.push(Backup {
+            timestamp: timestamp.clone(),
+            path,
+            encrypted: self.working_copy.is_some(),
+        });
+        for backup in &backups[..backups.len().saturating_sub(keep)] {
+            fs::remove_file(&backup.path)?;
+        }
+        eprintln!(
+            "Backed up the database before {} (restore with 'gooseberry db restore {}')",
+            command, timestamp
+        );
+        Ok(())
+    }
+
+    /// Takes a backup of the database now
+    pub fn backup_now(&self) -> color_eyre::Result<()> {
+        if self.config.db_backups == Some(0) {
+            return Err(Apologize::ConfigError {
+                message: "Backups are turned off".into(),
+            })
+            .suggestion("Set db_backups in the config to the number of backups to keep");
+        }
+        self.backup_db("db backup")
+    }
+
+    /// Prints the timestamp, size, and path of each backup, oldest first
+    pub fn list_backups(&self) -> color_eyre::Result<()> {
+        let backups = self.backups()?;
+        if backups.is_empty() {
+            println!("No backups in {:?}", self.backup_dir());
+        }
+        for backup in backups {
+            println!(
+                "{}\t{} KiB\t{}{}",
+                backup.timestamp,
+                fs::metadata(&backup.path)?.len().div_ceil(1024),
+                backup.path.display(),
+                if backup.encrypted {
+                    "\t(encrypted)"
+                } else {
+                    ""
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Replaces the database with a backup (`latest` for the most recent one),
+    /// backing up the current database first so that the restore can be undone
+    pub fn restore_backup(&self, timestamp: &str, force: bool) -> color_eyre::Result<()> {
+        let backups = self.backups()?;
+        let backup = if timestamp == "latest" {
+            backups.last()
+        } else {
+            backups.iter().find(|backup| backup.timestamp == timestamp)
+        }
+        .ok_or(Apologize::MirrorError {
+            message: format!("No backup {:?} in {:?}", timestamp, self.backup_dir()),
+        })
+        .suggestion("List backups with 'gooseberry db backups'")?;
+        let mirror = if backup.encrypted {
+            let working_copy = self
+                .working_copy
+                .as_ref()
+                .ok_or(Apologize::EncryptionError {
+                    message: format!("{:?} is encrypted", backup.path),
+                })
+                .suggestion("Turn encrypt_database on to restore it")?;
+            read_mirror(&working_copy.unseal(&fs::read(&backup.path)?)?[..])?
+        } else {
+            read_mirror(BufReader::new(fs::File::open(&backup.path)?))?
+        };
+        if self.dry_run {
+            println!(
+                "Would replace the database ({} annotation(s)) with the backup from {} ({} annotation(s)) (dry run)",
+                self.annotation_to_tags()?.len()?,
+                backup.timestamp,
+                mirror.annotations.len()
+            );
+            return Ok(());
+        }
+        if !(force
+            || utils::confirm(
+                &format!(
+                    "Replace all gooseberry data with the backup from {}?",
+                    backup.timestamp
+                ),
+                false,
+            )?)
+        {
+            let error: color_eyre::Result<()> = Err(Apologize::DoingNothing.into());
+            return error.suggestion("Press Y next time!");
+        }
+        let timestamp = backup.timestamp.clone();
+        self.backup_db("db restore")?;
+        self.replace_db(&mirror)?;
+        println!(
+            "Restored the backup from {} ({} annotation(s))",
+            timestamp,
+            mirror.annotations.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use color_eyre::Help;
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value as Json;
+
+use crate::errors::Apologize;
+use crate::utils::clean_uri;
+
+/// Citation keys of a BibTeX or CSL JSON bibliography (e.g. a Zotero Better BibTeX export),
+/// looked up by DOI or by URL
+#[derive(Debug, Default, Clone)]
+pub struct Bibliography {
+    by_doi: HashMap<String, String>,
+    by_url: HashMap<String, String>,
+}
+
+impl Bibliography {
+    /// Reads a `.json` file as CSL JSON and anything else as BibTeX
+    pub fn from_file(path: &Path) -> color_eyre::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Apologize::ConfigError {
+                message: format!("Couldn't read bibliography {:?}: {}", path, e),
+            })
+            .suggestion("Set the bibliography file using 'gooseberry config kb bibliography'")?;
+        let mut bibliography = Bibliography::default();
+        if path.extension() == Some(OsStr::new("json")) {
+            let entries: Vec<Json> = serde_json::from_str(&contents)?;
+            for entry in entries {
+                if let Some(key) = entry["id"].as_str() {
+                    bibliography.add(key, entry["URL"].as_str(), entry["DOI"].as_str());
+                }
+            }
+        } else {
+            for (key, fields) in parse_bibtex(&contents) {
+                bibliography.add(
+                    &key,
+                    fields.get("url").map(String::as_str),
+                    fields.get("doi").map(String::as_str),
+                );
+            }
+        }
+        Ok(bibliography)
+    }
+
+    fn add(&mut self, key: &str, url: Option<&str>, doi: Option<&str>) {
+        if let Some(doi) = doi.and_then(find_doi).or_else(|| url.and_then(find_doi)) {
+            self.by_doi.insert(doi, key.to_owned());
+        }
+        if let Some(url) = url {
+            self.by_url.insert(clean_uri(url), key.to_owned());
+        }
+    }
+
+    /// Citation key of the bibliography entry with the same DOI or URL as a URI
+    pub fn citation_key(&self, uri: &str) -> Option<&str> {
+        find_doi(uri)
+            .and_then(|doi| self.by_doi.get(&doi))
+            .or_else(|| self.by_url.get(&clean_uri(uri)))
+            .map(String::as_str)
+    }
+}
+
+/// Finds a DOI (10.<registrant>/<suffix>) in a URI or a bibliography field, lowercased
+/// e.g. https://doi.org/10.1371/journal.pone.0070182 or https://journals.plos.org/plosone/article?id=10.1371/journal.pone.0070182
+fn find_doi(text: &str) -> Option<String> {
+    let text = text.replace("%2F", "/").replace("%2f", "/");
+    text.match_indices("10.").find_map(|(start, _)| {
+        let rest = &text[start + 3..];
+        let registrant = rest.find('/')?;
+        if registrant < 4 || !rest[..registrant].chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let end = rest[registrant + 1..]
+            .find(|c: char| c.is_whitespace() || "?#&\"".contains(c))
+            .map_or(rest.len(), |end| registrant + 1 + end);
+        if end == registrant + 1 {
+            return None;
+        }
+        Some(format!("10.{}", &rest[..end]).to_lowercase())
+    })
+}
+
+/// Reads the citation keys and (lowercased) fields of the entries in a BibTeX file.
+/// Braces and `\` escapes are removed from field values
+fn parse_bibtex(contents: &str) -> Vec<(String, HashMap<String, String>)> {
+    let mut entries = Vec::new();
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '@' {
+            continue;
+        }
+        let entry_type: String = chars.by_ref().take_while(|&c| c != '{').collect();
+        let entry_type = entry_type.trim().to_lowercase();
+        if ["comment", "string", "preamble"].contains(&entry_type.as_str()) {
+            read_braced(&mut chars);
+            continue;
+        }
+        let key: String = chars.by_ref().take_while(|&c| c != ',').collect();
+        let mut fields = HashMap::new();
+        loop {
+            while matches!(chars.peek(), Some(c) if c.is_whitespace() || *c == ',') {
+                chars.next();
+            }
+            match chars.peek() {
+                None => break,
+                Some('}') => {
+                    chars.next();
+                    break;
+                }
+                _ => {}
+            }
+            let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+            while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+                chars.next();
+            }
+            let value = match chars.peek() {
+                Some('{') => {
+                    chars.next();
+                    read_braced(&mut chars)
+                }
+                Some('"') => {
+                    chars.next();
+                    let mut value = String::new();
+                    let mut depth = 0;
+                    for c in chars.by_ref() {
+                        match c {
+                            '{' => depth += 1,
+                            '}' => depth -= 1,
+                            '"' if depth == 0 => break,
+                            _ => {}
+                        }
+                        value.push(c);
+                    }
+                    value
+                }
+                _ => {
+                    let mut value = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == ',' || c == '}' {
+                            break;
+                        }
+                        value.push(c);
+                        chars.next();
+                    }
+                    value
+                }
+            };
+            let value: String = value.chars().filter(|&c| c != '{' && c != '}').collect();
+            fields.insert(
+                name.trim().to_lowercase(),
+                value.replace('\\', "").trim().to_owned(),
+            );
+        }
+        entries.push((key.trim().to_owned(), fields));
+    }
+    entries
+}
+
+/// Reads up to the brace closing an already opened one
+fn read_braced(chars: &mut impl Iterator<Item = char>) -> String {
+    let mut value = String::new();
+    let mut depth = 0;
+    for c in chars {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => break,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        value.push(c);
+    }
+    value
+}
+
+/// `{{cite uri}}` renders a pandoc citation (`[@key]`) for a URI, or the URI itself if it's not in the bibliography.
+/// `{{citekey uri}}` renders just the key, or nothing
+struct CiteHelper {
+    bibliography: Arc<Bibliography>,
+    pandoc: bool,
+}
+
+impl HelperDef for CiteHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let uri = h
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or_else(|| RenderError::new(format!("{} needs a URI", h.name())))?;
+        let cited = match (self.bibliography.citation_key(uri), self.pandoc) {
+            (Some(key), true) => format!("[@{}]", key),
+            (Some(key), false) => key.to_owned(),
+            (None, true) => uri.to_owned(),
+            (None, false) => String::new(),
+        };
+        Ok(Some(ScopedJson::Derived(Json::String(cited))))
+    }
+}
+
+/// Registers the `cite` and `citekey` helpers
+pub(crate) fn register_citation_helpers(hbs: &mut Handlebars, bibliography: Bibliography) {
+    let bibliography = Arc::new(bibliography);
+    hbs.register_helper(
+        "cite",
+        Box::new(CiteHelper {
+            bibliography: Arc::clone(&bibliography),
+            pandoc: true,
+        }),
+    );
+    hbs.register_helper(
+        "citekey",
+        Box::new(CiteHelper {
+            bibliography,
+            pandoc: false,
+        }),
+    );
+}
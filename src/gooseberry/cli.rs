@@ -1,16 +1,25 @@
-use std::io;
-use std::path::{Path, PathBuf};
+#[cfg(feature = "cli")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use hypothesis::annotations::{Order, SearchQuery, Sort};
+use regex::Regex;
+use structopt::clap::arg_enum;
+#[cfg(feature = "cli")]
 use structopt::clap::AppSettings;
 use structopt::clap::Shell;
 use structopt::StructOpt;
 
+#[cfg(feature = "cli")]
 use crate::configuration::GooseberryConfig;
+use crate::configuration::{ColorMode, OrderBy};
+#[cfg(feature = "cli")]
+use crate::gooseberry::completion;
 use crate::utils;
-use crate::NAME;
 
+#[cfg(feature = "cli")]
 #[derive(Debug, StructOpt)]
 #[structopt(
 name = "gooseberry",
@@ -23,6 +32,34 @@ pub struct GooseberryCLI {
     /// Location of config file (uses default XDG location or environment variable if not given)
     #[structopt(short, long, parse(from_os_str), env = "GOOSEBERRY_CONFIG")]
     pub(crate) config: Option<PathBuf>,
+    /// Use the settings (account, groups, database, knowledge base, templates etc.)
+    /// of a profile in the `profiles` table of the config file
+    #[structopt(long, env = "GOOSEBERRY_PROFILE")]
+    pub(crate) profile: Option<String>,
+    /// Show what `tag`, `delete`, `sync --push`, `clear`, `make`, `dedupe`, `import`, `restore`,
+    /// and `group create|leave` would change (annotations, API calls, and files) without changing anything.
+    /// Commands that can't preview their changes refuse it
+    #[structopt(long)]
+    pub(crate) dry_run: bool,
+    /// When to color the output (overrides `color` in the config file).
+    /// `auto` colors it in a terminal, unless the `NO_COLOR` environment variable is set
+    #[structopt(long, possible_values = & ColorChoice::variants(), case_insensitive = true)]
+    pub(crate) color: Option<ColorChoice>,
+    /// Log what gooseberry does to stderr: -v for syncs, makes, and database changes,
+    /// -vv to also log each Hypothesis API request, -vvv for everything
+    #[structopt(short, long, parse(from_occurrences))]
+    pub(crate) verbose: u8,
+    /// Log to a file in this folder instead of stderr, starting a new one every day
+    /// (at -vv unless --verbose is given)
+    #[structopt(long, parse(from_os_str), env = "GOOSEBERRY_LOG_DIR")]
+    pub(crate) log_dir: Option<PathBuf>,
+    /// Name of the Obsidian vault the knowledge base is in, for the `obsidian_uri` template helper
+    /// and the `obsidian` search action (overrides `obsidian_vault` in the config file)
+    #[structopt(long)]
+    pub(crate) obsidian_vault: Option<String>,
+    /// Print errors as text, or as JSON with a stable error code (for scripts and editor plugins)
+    #[structopt(long, default_value = "text", possible_values = & ErrorFormat::variants(), case_insensitive = true)]
+    pub(crate) error_format: ErrorFormat,
     #[structopt(subcommand)]
     pub(crate) cmd: GooseberrySubcommand,
 }
@@ -30,7 +67,30 @@ pub struct GooseberryCLI {
 #[derive(StructOpt, Debug)]
 pub enum GooseberrySubcommand {
     /// Sync newly added or updated Hypothesis annotations.
-    Sync,
+    Sync {
+        /// First push edits to annotation text and tags made in the knowledge base files
+        /// (needs `editable = true` in the config)
+        #[structopt(long)]
+        push: bool,
+        /// Show the tags that the rules in the config would add to new and updated annotations,
+        /// without syncing
+        #[structopt(long, conflicts_with = "push")]
+        dry_run: bool,
+        /// Archive the pages of new and updated annotations (see `gooseberry archive`)
+        #[structopt(long, conflicts_with = "dry-run")]
+        archive: bool,
+        /// Print what changed since the last sync (new annotations, edited text, added and removed tags,
+        /// and deletions) instead of the number of added and updated annotations
+        #[structopt(long, conflicts_with = "dry-run")]
+        diff: bool,
+        /// Format of the diff
+        #[structopt(long, default_value = "markdown", possible_values = & DiffFormat::variants(), case_insensitive = true)]
+        format: DiffFormat,
+        /// Append the diff to this file (as a JSON object per line with `--format json`) if anything changed,
+        /// with or without `--diff`
+        #[structopt(long, parse(from_os_str), conflicts_with = "dry-run")]
+        changelog: Option<PathBuf>,
+    },
     /// Opens a search buffer to see, filter, delete, add tags to and delete tags from annotations
     Search {
         #[structopt(flatten)]
@@ -38,6 +98,37 @@ pub enum GooseberrySubcommand {
         /// Toggle fuzzy search
         #[structopt(short, long)]
         fuzzy: bool,
+        /// Ranked full-text query over the quote, text, tags, and URI of synced annotations
+        ///
+        /// Works offline. Supports phrases ("..."), boolean operators (AND, OR, NOT)
+        /// and field-specific terms (e.g. tags:rust)
+        #[structopt(short, long)]
+        query: Option<String>,
+        /// Print the IDs of matching annotations (one per line) instead of opening a search window
+        ///
+        /// e.g. `gooseberry search -q rust --print-ids | gooseberry tag --ids - rust`
+        #[structopt(long)]
+        print_ids: bool,
+        /// Exit after the first action instead of going back to the search window
+        #[structopt(long)]
+        once: bool,
+        /// Search line: `key:value` terms filter the annotations, e.g. `tag:crispr site:nature.com after:2024-01`
+        /// (`-key:value` leaves matches out, after a `--`), and the other words start off the search text
+        terms: Vec<String>,
+        /// Overwrite annotations changed in Hypothesis since they were fetched, without asking
+        #[structopt(long)]
+        force: bool,
+        #[structopt(flatten)]
+        sort: SortOptions,
+    },
+    /// Opens a dashboard with a tag tree, the annotation list, and a preview,
+    /// to tag, delete, edit, and sync (optionally filtered) annotations without leaving it
+    Tui {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Overwrite annotations changed in Hypothesis since they were fetched, without asking
+        #[structopt(long)]
+        force: bool,
     },
     /// Tag annotations according to topic.
     Tag {
@@ -49,6 +140,19 @@ pub enum GooseberrySubcommand {
         /// The tags to add to / remove from the filtered annotations (comma-separated)
         #[structopt(use_delimiter = true)]
         tag: Vec<String>,
+        /// Overwrite annotations changed in Hypothesis since they were fetched, without asking
+        #[structopt(long)]
+        force: bool,
+        #[structopt(subcommand)]
+        cmd: Option<TagCommand>,
+    },
+    /// Go through untagged (or all filtered) annotations one at a time to add tags to, skip, or delete each
+    Review {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Review all annotations matching the filters, not only untagged ones
+        #[structopt(long)]
+        all: bool,
     },
     /// Delete annotations in bulk
     Delete {
@@ -58,6 +162,103 @@ pub enum GooseberrySubcommand {
         #[structopt(short, long)]
         force: bool,
     },
+    /// Share (optionally filtered) annotations with the members of their group, or move them to another group
+    ///
+    /// Annotations in the public group (`__world__`) become readable by everyone
+    Publish {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Group to share the annotations in, e.g. to move private notes to a group (their own group if not given)
+        #[structopt(long)]
+        to_group: Option<String>,
+        /// Don't ask for confirmation
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// Make (optionally filtered) annotations private, readable only by you
+    Unpublish {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Don't ask for confirmation
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// Archive the pages of (optionally filtered) annotations
+    ///
+    /// Saves them in the Internet Archive's Wayback Machine (available as {{archive_url}} in templates)
+    /// and keeps their readable text. Pages which are already archived are skipped
+    Archive {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Only keep the readable text, without saving the pages in the Wayback Machine
+        #[structopt(long)]
+        text_only: bool,
+    },
+    /// Find (optionally filtered) annotations with the same URI and quote (or the same text, for page notes)
+    /// and merge or delete the duplicates
+    Dedupe {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Merge every set of duplicates without asking
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// Re-create deleted annotations from the trash (they get new IDs and the default sharing)
+    Restore {
+        /// IDs of the deleted annotations (comma-separated), as shown by `gooseberry trash list`
+        #[structopt(name = "id", required = true, use_delimiter = true)]
+        ids: Vec<String>,
+    },
+    /// Manage annotations deleted with gooseberry
+    Trash {
+        #[structopt(subcommand)]
+        cmd: TrashCommand,
+    },
+    /// Show the previous versions of an annotation (kept whenever sync finds that its text or tags changed)
+    /// as a timeline of text and tag edits
+    History {
+        /// ID of the annotation
+        id: String,
+    },
+    /// Treat annotations with the todo tag (`todo_tag` in the config, `todo` by default) as tasks
+    Todo {
+        #[structopt(subcommand)]
+        cmd: TodoCommand,
+    },
+    /// Link an annotation to another with a type (e.g. supports, contradicts, followup),
+    /// shown under "Related annotations" in the knowledge base. Lists its links if no other annotation is given
+    Link {
+        /// ID of the annotation to link from
+        from: String,
+        /// ID of the annotation to link to
+        to: Option<String>,
+        /// Type of the link
+        #[structopt(short = "t", long = "type", default_value = "related")]
+        link_type: String,
+        /// Remove the link between the two annotations instead
+        #[structopt(long, requires = "to")]
+        remove: bool,
+    },
+    /// Set local metadata of an annotation, e.g. `gooseberry meta <id> rating=5 status=read`.
+    /// Lists its metadata if no fields are given
+    ///
+    /// Only stored in the database, for filtering with `--meta` and for templates (as `{{meta.rating}}`)
+    Meta {
+        /// ID of the annotation
+        id: String,
+        /// `key=value` pairs to set (`key=` removes the key)
+        fields: Vec<String>,
+    },
+    /// List, create, leave, and switch between Hypothesis groups
+    Group {
+        #[structopt(subcommand)]
+        cmd: GroupCommand,
+    },
+    /// List annotated PDFs and give them friendly titles, by fingerprint
+    Pdf {
+        #[structopt(subcommand)]
+        cmd: PdfCommand,
+    },
     /// View (optionally filtered) annotations
     View {
         #[structopt(flatten)]
@@ -65,20 +266,244 @@ pub enum GooseberrySubcommand {
         /// View annotation by ID
         #[structopt(conflicts_with = "filters")]
         id: Option<String>,
+        /// Print in this format without any interaction (for scripts), fails if nothing matches
+        #[structopt(long, possible_values = & ViewFormat::variants(), case_insensitive = true)]
+        format: Option<ViewFormat>,
+        /// Copy the annotations, rendered with the annotation template, to the clipboard instead
+        #[structopt(long, conflicts_with = "format")]
+        clipboard: bool,
+        #[structopt(flatten)]
+        sort: SortOptions,
+    },
+    /// Show statistics about (optionally filtered) annotations
+    ///
+    /// Counts per tag, domain, document, and month, average text and quote lengths, and untagged annotations
+    Stats {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Output format (json has all tags, domains, and documents)
+        #[structopt(long, default_value = "text", possible_values = & StatsFormat::variants(), case_insensitive = true)]
+        format: StatsFormat,
+        /// Number of tags, domains, and documents to show
+        #[structopt(long, default_value = "10")]
+        top: usize,
+    },
+    /// Graph of (optionally filtered) annotations, their documents, and their tags, for Graphviz, Mermaid,
+    /// or other graph tools
+    ///
+    /// Documents contain annotations, annotations are linked to their tags,
+    /// and tags used on the same annotations are linked to each other
+    Graph {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Output format
+        #[structopt(long, default_value = "dot", possible_values = & GraphFormat::variants(), case_insensitive = true)]
+        format: GraphFormat,
+        /// Link documents to the tags of their annotations directly, without a node per annotation
+        #[structopt(long)]
+        no_annotations: bool,
     },
     /// Get the set of URIs from a list of (optionally filtered) annotations
     Uri {
         #[structopt(flatten)]
         filters: Filters,
         /// list of comma-separated annotation IDs
-        #[structopt(use_delimiter = true)]
+        #[structopt(name = "id", use_delimiter = true)]
+        ids: Vec<String>,
+        #[structopt(flatten)]
+        options: UriOptions,
+        /// Copy the URIs to the clipboard instead of printing them
+        #[structopt(long)]
+        clipboard: bool,
+    },
+    /// Open (optionally filtered) annotations in context in the browser,
+    /// on the annotated page with the Hypothesis sidebar
+    Open {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// list of comma-separated annotation IDs
+        #[structopt(name = "id", use_delimiter = true)]
         ids: Vec<String>,
+        /// Print the links instead of opening them
+        #[structopt(long)]
+        print: bool,
+        /// Open more than 10 annotations without asking
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// Export (optionally filtered) annotations as JSON or CSV,
+    /// or those with the flashcard tag as Anki-importable TSV
+    Export {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Output format
+        #[structopt(default_value = "json", possible_values = & ExportFormat::variants(), case_insensitive = true)]
+        format: ExportFormat,
+        /// File to write to (writes to stdout if not given)
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Atom feed of the most recent (optionally filtered) annotations, with their quotes, notes, tags,
+    /// and links to see them in context
+    Feed {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Number of annotations in the feed
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: usize,
+        /// Title of the feed (`--title` filters by document title)
+        #[structopt(long)]
+        feed_title: Option<String>,
+        /// Link of the feed, e.g. to your website (the Hypothesis page of the gooseberry group if not given)
+        #[structopt(long)]
+        link: Option<String>,
+        /// File to write to (writes to stdout if not given), e.g. feed.xml
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Summarize recent annotations, grouped by tag and domain, for emailing or posting
+    ///
+    /// Covers the last week unless dates are given with --since, --from, --before, or --today
+    Digest {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Format of the digest
+        #[structopt(long, default_value = "markdown", possible_values = & DigestFormat::variants(), case_insensitive = true)]
+        format: DigestFormat,
+        /// Title of the digest (`--title` filters by document title)
+        #[structopt(long)]
+        digest_title: Option<String>,
+        /// File to write to (writes to stdout if not given), e.g. digest.html
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Pipe the digest to this command instead, e.g. "mail -s 'Reading digest' me@example.com"
+        #[structopt(long)]
+        sendmail: Option<String>,
+    },
+    /// Create a new annotation (or page note, if no quote is given) on a URI
+    Annotate {
+        /// URI to annotate
+        uri: String,
+        /// Highlighted text to attach the annotation to (makes a page note if not given)
+        #[structopt(short, long)]
+        quote: Option<String>,
+        /// Annotation text (markdown)
+        #[structopt(long)]
+        text: Option<String>,
+        /// Compose the annotation text in $EDITOR (starting with --text, if given)
+        #[structopt(short, long)]
+        editor: bool,
+        /// Tags to add to the annotation (comma-separated)
+        #[structopt(short, long, use_delimiter = true)]
+        tags: Vec<String>,
+        /// Group to create the annotation in (uses the gooseberry group if not given)
+        #[structopt(short, long)]
+        group: Option<String>,
+    },
+    /// Import highlights and bookmarks from other tools as Hypothesis page notes
+    ///
+    /// Shows the entries to import and asks for confirmation first
+    Import {
+        /// Tool the file was exported from
+        #[structopt(possible_values = & ImportFormat::variants(), case_insensitive = true)]
+        format: ImportFormat,
+        /// Export file (Readwise, Raindrop, or Pocket CSV, Pocket HTML, or Kindle `My Clippings.txt`)
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Tags to add to every imported page note (comma-separated)
+        #[structopt(short, long, use_delimiter = true)]
+        tags: Vec<String>,
+        /// Group to import into (uses the gooseberry group if not given)
+        #[structopt(short, long)]
+        group: Option<String>,
+        /// Only show the entries to import
+        #[structopt(long)]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[structopt(short, long)]
+        force: bool,
     },
     /// Create your knowledge-base text files
     Make {
         /// Don't ask for confirmation before clearing knowledge base directory
         #[structopt(short, long)]
         force: bool,
+        /// Clear the knowledge base directory and rewrite every file
+        /// (by default only files whose contents changed are rewritten)
+        #[structopt(short, long)]
+        rebuild: bool,
+        /// Only use annotations from this Hypothesis group (uses all configured groups if not given)
+        #[structopt(long)]
+        group: Option<String>,
+        /// Only use annotations matching this saved view (defined in the `views` table of the config file)
+        #[structopt(long)]
+        view: Option<String>,
+        /// Make an Obsidian / Logseq style vault instead of following the hierarchy
+        ///
+        /// Makes a page per tag and per source document, renders tags and references as [[wikilinks]],
+        /// and adds a "Backlinks" section to each page
+        #[structopt(short, long)]
+        wiki_links: bool,
+        /// Make the content folder of a static site instead, or a standalone HTML knowledge base
+        ///
+        /// Writes pages with front matter (title, dates, and tags as taxonomy terms) at slugified paths,
+        /// along with `_index.md` section files (Zola and Hugo) or a SUMMARY.md (mdBook).
+        /// `html` writes styled HTML pages and an index.html with a search box instead
+        #[structopt(long, possible_values = & SiteFormat::variants(), case_insensitive = true, conflicts_with = "wiki-links")]
+        site: Option<SiteFormat>,
+        /// Also render replies by others under the annotations they reply to, fetched from Hypothesis
+        #[structopt(long)]
+        include_replies: bool,
+        /// Sort the annotations on each page by this field instead of the `sort` config option
+        #[structopt(long, possible_values = & SortField::variants(), case_insensitive = true)]
+        sort: Option<SortField>,
+        /// Reverse the order of the annotations on each page
+        #[structopt(long)]
+        reverse: bool,
+        /// Make a page per website domain, URI, tag, or day instead of following the `hierarchy` config option
+        #[structopt(long, possible_values = & GroupField::variants(), case_insensitive = true, conflicts_with = "wiki-links")]
+        group_by: Option<GroupField>,
+        /// Start each page with YAML front matter (title, URI, tags, dates, and annotation IDs),
+        /// even if the `front_matter` config option isn't set
+        #[structopt(long, conflicts_with = "site")]
+        front_matter: bool,
+        /// Write the knowledge base to this file instead, as one document with a table of contents
+        /// and a heading per page (or folder) of the hierarchy (a standalone HTML page for a .html file)
+        #[structopt(long, parse(from_os_str), conflicts_with_all = &["wiki-links", "site", "front-matter"])]
+        single_file: Option<PathBuf>,
+    },
+    /// Sync (and make the knowledge base) on a schedule
+    Watch {
+        /// Time between syncs, e.g. 30s, 5m, 1h
+        #[structopt(short, long, default_value = "5m", parse(try_from_str = humantime::parse_duration))]
+        interval: Duration,
+        /// Only sync, don't make the knowledge base
+        #[structopt(long)]
+        no_make: bool,
+        /// Make an Obsidian / Logseq style vault (see `make --wiki-links`)
+        #[structopt(short, long)]
+        wiki_links: bool,
+        /// Keep running in the background, writing output to the log file
+        #[structopt(long)]
+        daemonize: bool,
+        /// Log file used with --daemonize (watch.log in the gooseberry data directory if not given)
+        #[structopt(long, parse(from_os_str))]
+        log: Option<PathBuf>,
+    },
+    /// Serve a small HTTP API on localhost for editor plugins and scripts
+    ///
+    /// `POST /sync`, `GET /annotations?tag=...`, `GET /render/<id>`, and `GET /events`
+    /// streaming what each sync changes as server-sent events
+    Serve {
+        /// Port to listen on (on 127.0.0.1 only)
+        #[structopt(short, long, default_value = "8040")]
+        port: u16,
+        /// Also sync every interval, e.g. 30s, 5m, 1h, instead of only on `POST /sync`
+        #[structopt(short, long, parse(try_from_str = humantime::parse_duration))]
+        interval: Option<Duration>,
+        /// Let web pages from this origin read the responses, e.g. http://localhost:3000
+        #[structopt(long)]
+        allow_origin: Option<String>,
     },
     /// Generate shell completions
     Complete {
@@ -86,6 +511,22 @@ pub enum GooseberrySubcommand {
         #[structopt(possible_values = & Shell::variants())]
         shell: Shell,
     },
+    /// Print the tags starting with the last (comma-separated) tag in the given word, for the completion scripts
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    CompleteTags {
+        /// Word being completed
+        #[structopt(default_value)]
+        current: String,
+    },
+    /// Set up gooseberry: Hypothesis account, group, knowledge base directory, and templates
+    Init {
+        /// Don't ask before setting up a config file which is already set up
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// Check the setup: Hypothesis account and groups, database, knowledge base directory, templates,
+    /// and the external commands gooseberry runs, with how to fix what doesn't work
+    Doctor,
     /// Manage configuration
     Config {
         #[structopt(subcommand)]
@@ -95,16 +536,44 @@ pub enum GooseberrySubcommand {
     ///
     /// "ob oggle sobble obble"
     Clear {
+        /// Only clear the database (synced annotations and everything else gooseberry stores), keeping
+        /// the knowledge base files and the config. This is the default
+        #[structopt(long, conflicts_with_all = &["all", "kb-only", "tag"])]
+        db_only: bool,
+        /// Also delete the knowledge base files written by `make`
+        #[structopt(long, conflicts_with_all = &["kb-only", "tag"])]
+        all: bool,
+        /// Only delete the knowledge base files written by `make`, keeping the database
+        #[structopt(long, conflicts_with = "tag")]
+        kb_only: bool,
+        /// Only forget the annotations with this tag from the database (the next sync fetches them again)
+        #[structopt(long)]
+        tag: Option<String>,
         /// Don't ask for confirmation
         #[structopt(short, long)]
         force: bool,
     },
-    /// Move (optionally filtered) annotations from a different hypothesis group to Gooseberry's
+    /// Export, import, back up, and restore all gooseberry data
+    Db {
+        #[structopt(subcommand)]
+        cmd: DbCommand,
+    },
+    /// Copy (optionally filtered) annotations from one Hypothesis group to another, optionally deleting the originals
     ///
-    /// Only moves annotations created by the current user
+    /// Only moves annotations created by the current user. The copies get new IDs
     Move {
+        /// Group ID to move from (same as --from-group)
+        #[structopt(conflicts_with = "from-group")]
+        group_id: Option<String>,
         /// Group ID to move from
-        group_id: String,
+        #[structopt(long, required_unless = "group-id")]
+        from_group: Option<String>,
+        /// Group ID to move to (uses the gooseberry group if not given)
+        #[structopt(long)]
+        to_group: Option<String>,
+        /// Delete the originals once they're copied (they go to the trash)
+        #[structopt(long)]
+        delete: bool,
         #[structopt(flatten)]
         filters: Filters,
         /// Open a search buffer to see and search filtered annotations to further filter them
@@ -116,66 +585,562 @@ pub enum GooseberrySubcommand {
     },
 }
 
+#[cfg(feature = "cli")]
+impl GooseberrySubcommand {
+    /// Name of the command if it would change annotations, the database, or the config file
+    /// without honoring `--dry-run`, which it then refuses instead of silently ignoring
+    pub(crate) fn ignores_dry_run(&self) -> Option<&'static str> {
+        match self {
+            Self::Search {
+                print_ids: false, ..
+            } => Some("search"),
+            Self::Tui { .. } => Some("tui"),
+            Self::Archive { .. } => Some("archive"),
+            Self::Link { .. } => Some("link"),
+            Self::Meta { fields, .. } if !fields.is_empty() => Some("meta"),
+            Self::Group {
+                cmd: GroupCommand::Switch,
+            } => Some("group switch"),
+            Self::Pdf {
+                cmd: PdfCommand::Title { .. },
+            } => Some("pdf title"),
+            Self::Export {
+                format: ExportFormat::Readwise,
+                ..
+            } => Some("export readwise"),
+            Self::Annotate { .. } => Some("annotate"),
+            Self::Watch { .. } => Some("watch"),
+            Self::Serve { .. } => Some("serve"),
+            Self::Init { .. } => Some("init"),
+            Self::Config { cmd } => match cmd {
+                ConfigCommand::Get | ConfigCommand::Where | ConfigCommand::Template { .. } => None,
+                _ => Some("config"),
+            },
+            Self::Db { cmd } => match cmd {
+                DbCommand::Export { .. } | DbCommand::Backups => None,
+                _ => Some("db"),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Commands for the trash of deleted annotations
+#[derive(StructOpt, Debug)]
+pub enum TrashCommand {
+    /// List deleted annotations (ID, creation date, URI, and quote or text)
+    List,
+}
+
+/// Commands for the JSON Lines mirror of the database
+#[derive(StructOpt, Debug)]
+pub enum DbCommand {
+    /// Write the annotations, tags, sync times, trash, history etc. to a JSON Lines file
+    Export {
+        /// File to write to, e.g. gooseberry.jsonl
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Replace all gooseberry data with a file written by `gooseberry db export`
+    Import {
+        /// File to read from
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Don't ask for confirmation
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// Back up the database now (backups are also taken before `clear`, large deletes, `db import`, and `db restore`)
+    Backup,
+    /// List the backups of the database, oldest first
+    Backups,
+    /// Replace all gooseberry data with a backup
+    Restore {
+        /// Timestamp of the backup, as shown by `gooseberry db backups`, or `latest`
+        timestamp: String,
+        /// Don't ask for confirmation
+        #[structopt(short, long)]
+        force: bool,
+    },
+}
+
+/// Commands wrapping the Hypothesis groups API
+#[derive(StructOpt, Debug)]
+pub enum GroupCommand {
+    /// List your groups (ID, name, and type), marking the ones gooseberry takes annotations from
+    List,
+    /// Create a private group
+    Create {
+        /// Name of the group
+        name: String,
+        /// Description of the group
+        #[structopt(short, long)]
+        description: Option<String>,
+    },
+    /// Leave a group
+    Leave {
+        /// Group ID
+        id: String,
+        /// Don't ask for confirmation
+        #[structopt(short, long)]
+        force: bool,
+    },
+    /// List the members of a group
+    Members {
+        /// Group ID (the gooseberry group if not given)
+        id: Option<String>,
+    },
+    /// Choose the group gooseberry takes annotations from
+    Switch,
+}
+
+/// Commands for annotated PDFs, which Hypothesis identifies by fingerprint (`urn:x-pdf:...`)
+// Only made once, when parsing the arguments
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug)]
+pub enum PdfCommand {
+    /// List (optionally filtered) annotated PDFs: fingerprint, title, number of annotations, and URIs
+    List {
+        #[structopt(flatten)]
+        filters: Filters,
+    },
+    /// Register the title of a PDF, used in templates, filters, and the knowledge base index
+    Title {
+        /// Fingerprint of the PDF, with or without the `urn:x-pdf:` prefix
+        fingerprint: String,
+        /// Title of the PDF (removes the registered title if not given)
+        title: Option<String>,
+    },
+}
+
+/// Commands for annotations with the todo tag
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug)]
+pub enum TodoCommand {
+    /// List (optionally filtered) tasks, oldest first, with how long ago they were made
+    List {
+        #[structopt(flatten)]
+        filters: Filters,
+        /// Also list snoozed tasks
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Mark tasks as done, swapping the todo tag for the done tag (`done_tag` in the config, `done` by default)
+    Done {
+        /// IDs of the tasks (`-` reads them from stdin)
+        #[structopt(required = true)]
+        ids: Vec<String>,
+    },
+    /// Hide tasks from `todo list` and the agenda in the index for a while
+    Snooze {
+        /// IDs of the tasks (`-` reads them from stdin)
+        #[structopt(required = true)]
+        ids: Vec<String>,
+        /// How long to snooze them for, e.g. 3days, 2w
+        #[structopt(long = "for", default_value = "1w", parse(try_from_str = humantime::parse_duration))]
+        duration: Duration,
+    },
+}
+
+/// Commands acting on a tag across all annotations
+#[derive(StructOpt, Debug)]
+pub enum TagCommand {
+    /// Rename a tag on every annotation that has it
+    Rename {
+        /// Current name of the tag
+        old: String,
+        /// New name of the tag
+        new: String,
+    },
+    /// Merge tags into a single (new or existing) tag
+    Merge {
+        /// Tags to merge
+        #[structopt(required = true)]
+        tags: Vec<String>,
+        /// Tag to merge them into
+        #[structopt(long)]
+        into: String,
+    },
+    /// List all tags with their number of annotations, most used first
+    List {
+        /// Show hierarchical tags as a tree (needs `tag_delimiter` in the config)
+        #[structopt(long)]
+        tree: bool,
+        /// Output format
+        #[structopt(long, default_value = "text", possible_values = & StatsFormat::variants(), case_insensitive = true)]
+        format: StatsFormat,
+    },
+}
+
+/// CLI options for printing URIs
+#[derive(StructOpt, Debug, Default, Clone, Copy)]
+pub struct UriOptions {
+    /// Print each URI's page title after it (tab-separated)
+    #[structopt(short, long)]
+    pub titles: bool,
+    /// Print markdown links with page titles, `- [Title](uri)`
+    #[structopt(short, long)]
+    pub markdown: bool,
+    /// Print URIs differing only in scheme, `www.`, fragment, or trailing slash once
+    #[structopt(short, long)]
+    pub dedup: bool,
+    /// Sort by domain (without `www.`), instead of by URI
+    #[structopt(long)]
+    pub by_domain: bool,
+    /// Group URIs by the tags of their annotations
+    #[structopt(long)]
+    pub by_tag: bool,
+}
+
+/// CLI options for the order of annotations
+#[derive(StructOpt, Debug, Default, Clone, Copy)]
+pub struct SortOptions {
+    /// Sort annotations by this field (`position` puts the annotations on each page in document order,
+    /// `rating` puts the highest rated first)
+    #[structopt(long, possible_values = & SortField::variants(), case_insensitive = true)]
+    pub sort: Option<SortField>,
+    /// Reverse the order of the annotations
+    #[structopt(long)]
+    pub reverse: bool,
+}
+
 /// CLI options for filtering annotations
-#[derive(StructOpt, Debug, Default)]
+#[derive(StructOpt, Debug, Default, Clone)]
 pub struct Filters {
-    /// Only annotations created after this date and time
+    /// Only annotations created (or updated, see --date-field) after this date and time
     ///
     /// Can be colloquial, e.g. "last Friday 8pm"
-    #[structopt(long, parse(try_from_str = utils::parse_datetime))]
+    #[structopt(long, visible_alias = "after", parse(try_from_str = utils::parse_datetime))]
     pub from: Option<DateTime<Utc>>,
-    /// Only annotations created before this date and time
+    /// Only annotations created (or updated, see --date-field) before this date and time
     ///
     /// Can be colloquial, e.g. "last Friday 8pm"
-    #[structopt(long, parse(try_from_str = utils::parse_datetime), conflicts_with = "from")]
+    #[structopt(long, parse(try_from_str = utils::parse_datetime))]
     pub before: Option<DateTime<Utc>>,
-    /// If true, includes annotations updated after --from or before --before (instead of just created)
+    /// Only annotations created (or updated) in this last span of time, e.g. 2w, 3days, 12h
+    #[structopt(long, parse(try_from_str = utils::parse_since), conflicts_with = "from")]
+    pub since: Option<DateTime<Utc>>,
+    /// Only annotations created (or updated) today
+    #[structopt(long, conflicts_with_all = &["from", "since"])]
+    pub today: bool,
+    /// Which date the date filters (--from, --before, --since, --today) apply to
+    #[structopt(long, default_value = "created", possible_values = & DateField::variants(), case_insensitive = true)]
+    pub date_field: DateField,
+    /// Same as --date-field updated
     #[structopt(short, long)]
     pub include_updated: bool,
     /// Only annotations with this pattern in their URL
     ///
-    /// Doesn't have to be the full URL, e.g. "wikipedia"
+    /// Doesn't have to be the full URL, e.g. "wikipedia". Can be a glob with `*` and `?`,
+    /// e.g. "https://arxiv.org/abs/2101.*" for URLs starting with it
     #[structopt(default_value, long)]
     pub uri: String,
+    /// Only annotations whose URL matches this regex, e.g. "\.gov/"
+    #[structopt(long)]
+    pub uri_pattern: Option<Regex>,
+    /// Only annotations whose quote matches this regex, e.g. "(?i)co2|carbon dioxide"
+    #[structopt(long)]
+    pub quote_pattern: Option<Regex>,
+    /// Only annotations whose text matches this regex
+    #[structopt(long)]
+    pub text_pattern: Option<Regex>,
+    /// Only annotations with this pattern (case-insensitive) in the title of their document
+    ///
+    /// Uses the titles registered for PDFs with `gooseberry pdf title`
+    #[structopt(default_value, long)]
+    pub title: String,
     /// Only annotations with this pattern in their `quote`, `tags`, `text`, or `uri`
     #[structopt(default_value, long)]
     pub any: String,
     /// Only annotations with these tags
     #[structopt(long)]
     pub tags: Vec<String>,
+    /// Only annotations with at least one of the --tags (instead of all of them)
+    #[structopt(long, conflicts_with = "all-tags")]
+    pub any_tags: bool,
+    /// Only annotations with all of the --tags (the default)
+    #[structopt(long)]
+    pub all_tags: bool,
+    /// Leave out annotations with any of these tags
+    ///
+    /// With a tag delimiter configured, `private/` also leaves out all tags nested under `private`
+    #[structopt(long)]
+    pub exclude_tags: Vec<String>,
+    /// Leave out annotations with this pattern in their URL (`*` and `?` wildcards match the whole URL),
+    /// e.g. "twitter.com"
+    #[structopt(long)]
+    pub exclude_uri: Vec<String>,
+    /// Leave out the annotations matching the other filters instead of keeping them
+    ///
+    /// Date filters, --group, and the reply options still limit which annotations are considered,
+    /// e.g. `--since 1w --not --tags private` is last week's annotations not tagged private
+    #[structopt(long)]
+    pub not: bool,
+    /// Only annotations from this Hypothesis group (uses all configured groups if not given)
+    #[structopt(long)]
+    pub group: Option<String>,
+    /// Only annotations by this user: a username (e.g. alice), or an account ID (acct:alice@hypothes.is)
+    #[structopt(long, conflicts_with = "mine")]
+    pub user: Option<String>,
+    /// Only your own annotations, with `all_users` on (otherwise only yours are used anyway)
+    #[structopt(long)]
+    pub mine: bool,
+    /// Only annotations matching this saved view (defined in the `views` table of the config file)
+    #[structopt(long)]
+    pub view: Option<String>,
+    /// Only annotations with this local metadata (set with `gooseberry meta`), e.g. status=read,
+    /// or with any value for a key given alone
+    #[structopt(long)]
+    pub meta: Vec<String>,
+    /// Only annotations with these IDs (comma-separated)
+    ///
+    /// Use `-` to read IDs from stdin, one per line
+    #[structopt(long, use_delimiter = true, number_of_values = 1)]
+    pub ids: Vec<String>,
+    /// Only annotations hidden by a group moderator (Hypothesis only tells moderators)
+    #[structopt(long)]
+    pub hidden: bool,
+    /// Only annotations flagged for moderation (Hypothesis only tells moderators)
+    #[structopt(long)]
+    pub flagged: bool,
+    /// Only annotations readable by: only you (private), the members of their group (group), or everyone (world)
+    #[structopt(long, possible_values = &Visibility::variants(), case_insensitive = true)]
+    pub visibility: Option<Visibility>,
+    /// Also include replies (by anyone) to the matching annotations, fetched from Hypothesis
+    #[structopt(long, conflicts_with = "top-level-only")]
+    pub include_replies: bool,
+    /// Leave out replies
+    #[structopt(long)]
+    pub top_level_only: bool,
+}
+
+arg_enum! {
+    /// Annotation dates the date filters can apply to
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DateField {
+        Created,
+        Updated,
+    }
+}
+
+// `arg_enum!` doesn't allow `#[default]` on a variant
+#[allow(clippy::derivable_impls)]
+impl Default for DateField {
+    fn default() -> Self {
+        DateField::Created
+    }
+}
+
+arg_enum! {
+    /// Who can read an annotation, for `--visibility`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Visibility {
+        Private,
+        Group,
+        World,
+    }
+}
+
+arg_enum! {
+    /// When to color the output, with `--color`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ColorChoice {
+        Auto,
+        Always,
+        Never,
+    }
+}
+
+arg_enum! {
+    /// How errors are printed, with `--error-format`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorFormat {
+        Text,
+        Json,
+    }
+}
+
+impl From<ColorChoice> for ColorMode {
+    fn from(choice: ColorChoice) -> Self {
+        match choice {
+            ColorChoice::Auto => ColorMode::Auto,
+            ColorChoice::Always => ColorMode::Always,
+            ColorChoice::Never => ColorMode::Never,
+        }
+    }
+}
+
+arg_enum! {
+    /// Fields annotations can be sorted by with `--sort`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SortField {
+        Created,
+        Updated,
+        Uri,
+        Position,
+        Tag,
+        Rating,
+    }
+}
+
+impl From<SortField> for OrderBy {
+    fn from(field: SortField) -> Self {
+        match field {
+            SortField::Created => OrderBy::Created,
+            SortField::Updated => OrderBy::Updated,
+            SortField::Uri => OrderBy::URI,
+            SortField::Position => OrderBy::Position,
+            SortField::Tag => OrderBy::Tag,
+            SortField::Rating => OrderBy::Rating,
+        }
+    }
+}
+
+arg_enum! {
+    /// Fields the knowledge base can be grouped into pages by with `make --group-by`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum GroupField {
+        Domain,
+        Uri,
+        Tag,
+        Date,
+        User,
+    }
+}
+
+impl From<GroupField> for OrderBy {
+    fn from(field: GroupField) -> Self {
+        match field {
+            GroupField::Domain => OrderBy::Domain,
+            GroupField::Uri => OrderBy::URI,
+            GroupField::Tag => OrderBy::Tag,
+            GroupField::Date => OrderBy::Date,
+            GroupField::User => OrderBy::User,
+        }
+    }
+}
+
+arg_enum! {
+    /// Output formats for `gooseberry export`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ExportFormat {
+        Json,
+        Csv,
+        Anki,
+        Readwise,
+    }
+}
+
+arg_enum! {
+    /// Tools `gooseberry import` reads export files of
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ImportFormat {
+        Readwise,
+        Raindrop,
+        Pocket,
+        Kindle,
+    }
+}
+
+arg_enum! {
+    /// Output formats for `gooseberry stats`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum StatsFormat {
+        Text,
+        Json,
+    }
+}
+
+arg_enum! {
+    /// Output formats of `gooseberry graph`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum GraphFormat {
+        Dot,
+        Mermaid,
+        Json,
+    }
+}
+
+arg_enum! {
+    /// Output formats of `gooseberry sync --diff`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DiffFormat {
+        Markdown,
+        Json,
+    }
+}
+
+arg_enum! {
+    /// Formats of `gooseberry digest`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DigestFormat {
+        Markdown,
+        Html,
+    }
+}
+
+arg_enum! {
+    /// Static site generators `gooseberry make --site` can make content for (or standalone HTML)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SiteFormat {
+        Zola,
+        Hugo,
+        Mdbook,
+        Html,
+    }
+}
+
+arg_enum! {
+    /// Non-interactive output formats for `gooseberry view`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ViewFormat {
+        Json,
+        Ids,
+        Uris,
+        Plain,
+        Quickfix,
+    }
 }
 
 impl From<Filters> for SearchQuery {
     fn from(filters: Filters) -> SearchQuery {
+        let after = filters.after();
+        let date_field = filters.date_field();
         SearchQuery {
             limit: 200,
-            search_after: match (filters.from, filters.before) {
-                (Some(date), None) | (None, Some(date)) => date.to_rfc3339(),
+            // With both, annotations after the start are searched for and the end is checked locally
+            search_after: match (after, filters.before) {
+                (Some(date), _) | (None, Some(date)) => date.to_rfc3339(),
                 (None, None) => crate::MIN_DATE.to_string(),
-                _ => panic!("can't use both --from and --before"),
             },
             uri_parts: filters.uri,
             any: filters.any,
             tags: filters.tags,
-            order: if filters.before.is_some() {
+            group: filters.group.unwrap_or_default(),
+            order: if after.is_none() && filters.before.is_some() {
                 Order::Desc
             } else {
                 Order::Asc
             },
-            sort: if filters.include_updated {
-                Sort::Updated
-            } else {
-                Sort::Created
+            sort: match date_field {
+                DateField::Created => Sort::Created,
+                DateField::Updated => Sort::Updated,
             },
             ..SearchQuery::default()
         }
     }
 }
 
+#[cfg(feature = "cli")]
 impl GooseberryCLI {
     /// Generate shell completions for gooseberry
     pub fn complete(shell: Shell) {
-        Self::clap().gen_completions_to(NAME, shell, &mut io::stdout());
+        println!("{}", completion::completion_script(shell));
+    }
+
+    /// Whether errors should be printed as JSON (`--error-format json`)
+    pub fn json_errors(&self) -> bool {
+        self.error_format == ErrorFormat::Json
     }
 }
 
@@ -197,10 +1162,33 @@ pub enum ConfigCommand {
     Where,
     /// Change Hypothesis credentials
     Authorize,
+    /// Change the Hypothesis API key and where it's stored
+    Token {
+        /// Store the key in the system keyring (Secret Service, Keychain, or Credential Manager)
+        /// instead of the configuration file
+        #[structopt(long)]
+        keyring: bool,
+    },
     /// Change the group used for Hypothesis annotations
     Group,
+    /// Change additional groups to take annotations from
+    Groups,
+    /// Change the tag marking annotations to export as flashcards
+    Flashcard,
     /// Change options related to the knowledge base
     Kb(KbConfigCommand),
+    /// Check the knowledge base templates by rendering them for a sample annotation
+    ///
+    /// Reports unknown keys and Handlebars errors with their line numbers,
+    /// and prints what each template renders to (unless --check is given)
+    Template {
+        /// Only check the templates, without printing them
+        #[structopt(long, conflicts_with = "preview")]
+        check: bool,
+        /// Print what each template renders to (the default)
+        #[structopt(long)]
+        preview: bool,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -209,6 +1197,8 @@ pub enum KbConfigCommand {
     All,
     /// Change knowledge base directory
     Directory,
+    /// Change knowledge base format (markdown or org-mode)
+    Format,
     /// Change annotation handlebars template
     Annotation,
     /// Change page handlebars template
@@ -225,35 +1215,65 @@ pub enum KbConfigCommand {
     Sort,
     /// Set which tags to ignore
     Ignore,
+    /// Change index handlebars template
+    IndexTemplate,
+    /// Change the delimiter of hierarchical tags
+    Delimiter,
+    /// Change whether edits to knowledge base files can be pushed back to Hypothesis
+    Editable,
+    /// Change the bibliography file used to cite annotated documents in templates
+    Bibliography,
 }
 
+#[cfg(feature = "cli")]
 impl ConfigCommand {
     /// Handle config related commands
-    pub async fn run(&self, config_file: Option<&Path>) -> color_eyre::Result<()> {
+    pub async fn run(
+        &self,
+        config_file: Option<&Path>,
+        profile: Option<&str>,
+    ) -> color_eyre::Result<()> {
         match self {
             Self::Default { file } => {
                 GooseberryConfig::default_config(file.as_deref())?;
             }
             Self::Get => {
-                GooseberryConfig::load(config_file).await?;
+                GooseberryConfig::load_profile(config_file, profile).await?;
                 println!("{}", GooseberryConfig::get(config_file)?);
             }
             Self::Where => {
                 GooseberryConfig::print_location(config_file)?;
             }
             Self::Authorize => {
-                let mut config = GooseberryConfig::load(config_file).await?;
+                let mut config = GooseberryConfig::load_profile(config_file, profile).await?;
                 config.request_credentials().await?;
             }
+            Self::Token { keyring } => {
+                let mut config = GooseberryConfig::load_profile(config_file, profile).await?;
+                config.set_token(*keyring).await?;
+            }
             Self::Group => {
-                let mut config = GooseberryConfig::load(config_file).await?;
+                let mut config = GooseberryConfig::load_profile(config_file, profile).await?;
                 config.set_group().await?;
             }
+            Self::Groups => {
+                let mut config = GooseberryConfig::load_profile(config_file, profile).await?;
+                config.set_groups().await?;
+            }
+            Self::Flashcard => {
+                let mut config = GooseberryConfig::load_profile(config_file, profile).await?;
+                config.set_flashcard_tag()?;
+            }
+            Self::Template { check, .. } => {
+                let config = GooseberryConfig::load_profile(config_file, profile).await?;
+                config.check_templates(!check)?;
+            }
             Self::Kb(cmd) => {
-                let mut config = GooseberryConfig::load(config_file).await?;
+                let mut config = GooseberryConfig::load_profile(config_file, profile).await?;
                 match cmd {
                     KbConfigCommand::All => config.set_kb_all()?,
                     KbConfigCommand::Directory => config.set_kb_dir()?,
+                    KbConfigCommand::Format => config.set_kb_format()?,
                     KbConfigCommand::Annotation => config.set_annotation_template()?,
                     KbConfigCommand::Page => config.set_page_template()?,
                     KbConfigCommand::Link => config.set_index_link_template()?,
@@ -262,6 +1282,10 @@ impl ConfigCommand {
                     KbConfigCommand::Hierarchy => config.set_hierarchy()?,
                     KbConfigCommand::Sort => config.set_sort()?,
                     KbConfigCommand::Ignore => config.set_ignore_tags()?,
+                    KbConfigCommand::IndexTemplate => config.set_index_template()?,
+                    KbConfigCommand::Delimiter => config.set_tag_delimiter()?,
+                    KbConfigCommand::Editable => config.set_editable()?,
+                    KbConfigCommand::Bibliography => config.set_bibliography()?,
                 };
             }
         }
@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+use crate::configuration::GooseberryConfig;
+use crate::gooseberry::cli::GooseberryCLI;
+use crate::gooseberry::storage;
+use crate::gooseberry::Gooseberry;
+use crate::{EMPTY_TAG, NAME};
+
+/// Completes tags for the options and arguments taking them, and uses the generated completion otherwise
+const TAGS_BASH: &str = r#"
+_gooseberry_tags() {
+    local cur prev words
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+    # Subcommands and options before the word being completed
+    words=" ${COMP_WORDS[*]:1:COMP_CWORD-1} "
+    if [[ ${prev} == --tags || ${prev} == --exclude-tags || ${prev} == --into
+        || (${prev} == -t && (${words} == *" annotate "* || ${words} == *" import "*))
+        || (${words} == *" tag "* && ${cur} != -* && (${prev} != -* || ${prev} == -d || ${prev} == --delete)) ]]; then
+        local IFS=$'\n'
+        COMPREPLY=( $(gooseberry complete-tags -- "${cur}" 2>/dev/null) )
+        return 0
+    fi
+    _gooseberry
+}
+
+complete -F _gooseberry_tags -o bashdefault -o default gooseberry
+"#;
+
+/// Tag completion function for the options and arguments taking tags
+const TAGS_ZSH: &str = r#"
+(( $+functions[_gooseberry_tags] )) ||
+_gooseberry_tags() {
+    local -a tags
+    tags=(${(f)"$(gooseberry complete-tags -- "${PREFIX}" 2>/dev/null)"})
+    compadd -Q -- ${tags}
+}
+"#;
+
+/// Tag completion of the positional arguments of `tag`
+const TAGS_FISH: &str = r#"complete -c gooseberry -n "__fish_seen_subcommand_from tag rename merge" -f -a "(gooseberry complete-tags -- (commandline -ct))"
+"#;
+
+/// Whether a line of a completion script offers the hidden `complete-tags` command, which clap doesn't leave out
+fn offers_complete_tags(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("\"complete-tags:")
+        || line.starts_with("cand complete-tags ")
+        || line.contains("-f -a \"complete-tags\"")
+        || line.contains("::new('complete-tags'")
+}
+
+/// Shell completion script for gooseberry. Bash, zsh, and fish scripts also complete tag names,
+/// by calling the hidden `complete-tags` command
+pub(crate) fn completion_script(shell: Shell) -> String {
+    let mut script = Vec::new();
+    GooseberryCLI::clap().gen_completions_to(NAME, shell, &mut script);
+    let script = String::from_utf8_lossy(&script);
+    let mut lines = script
+        .lines()
+        .filter(|line| !offers_complete_tags(line))
+        .map(|line| match shell {
+            Shell::Bash => line.replacen(" complete-tags ", " ", 1),
+            Shell::Zsh
+                if line.starts_with("'*--tags=[")
+                    || line.starts_with("'--tags=[")
+                    || line.starts_with("'*--exclude-tags=[")
+                    || line.starts_with("'*-t+[")
+                    || line.starts_with("'--into=[") =>
+            {
+                line.replacen("]' \\", "]:tag:_gooseberry_tags' \\", 1)
+            }
+            Shell::Zsh
+                if line.starts_with("'::tag -- ")
+                    || line.starts_with("':old -- ")
+                    || line.starts_with("':tags -- ") =>
+            {
+                line.replacen(":_files' \\", ":_gooseberry_tags' \\", 1)
+            }
+            Shell::Fish
+                if line.contains(" -l tags ")
+                    || line.contains(" -l exclude-tags ")
+                    || line.contains(" -l into ") =>
+            {
+                format!(
+                    "{} -x -a \"(gooseberry complete-tags -- (commandline -ct))\"",
+                    line
+                )
+            }
+            _ => line.to_owned(),
+        })
+        .collect::<Vec<_>>();
+    match shell {
+        Shell::Bash => lines.push(TAGS_BASH.to_owned()),
+        // Has to be defined before `_gooseberry` is called on the last line
+        Shell::Zsh => lines.insert(lines.len().saturating_sub(1), TAGS_ZSH.to_owned()),
+        Shell::Fish => lines.push(TAGS_FISH.to_owned()),
+        _ => {}
+    }
+    lines.join("\n")
+}
+
+/// Prints the tags in the database starting with the last comma-separated tag in `current`, one per line,
+/// for shell completion. Works offline, without checking the Hypothesis credentials.
+///
+/// Prints nothing with `encrypt_database` on, so that completing doesn't ask for a passphrase,
+/// and nothing while another gooseberry process is using the database, so that completing doesn't wait for it.
+/// The database is only read: never made, or moved over to another `db_backend`
+pub(crate) fn print_tags(
+    config_file: Option<&Path>,
+    profile: Option<&str>,
+    current: &str,
+) -> color_eyre::Result<()> {
+    let config = GooseberryConfig::read(config_file, profile)?;
+    if config.encrypt_database.unwrap_or(false) || !config.db_dir.exists() {
+        return Ok(());
+    }
+    let _lock = match Gooseberry::try_lock_db(&config.db_dir)? {
+        Some(lock) => lock,
+        None => return Ok(()),
+    };
+    let db = match storage::open_read_only(&config.db_dir, config.db_backend())? {
+        Some(db) => db,
+        None => return Ok(()),
+    };
+    if !db
+        .tree_names()?
+        .iter()
+        .any(|name| name == "tag_to_annotations")
+    {
+        return Ok(());
+    }
+    let (before, prefix) = match current.rfind(',') {
+        Some(index) => current.split_at(index + 1),
+        None => ("", current),
+    };
+    for tag_key in db
+        .open_tree("tag_to_annotations")?
+        .scan_prefix(prefix)
+        .keys()
+    {
+        let tag_key = tag_key?;
+        let tag = String::from_utf8_lossy(&tag_key);
+        if tag != EMPTY_TAG {
+            println!("{}{}", before, tag);
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,37 @@
+use std::fs;
 use std::path::Path;
 
+use fs2::FileExt;
 use hypothesis::annotations::Annotation;
 
+use crate::configuration::DbBackend;
 use crate::errors::Apologize;
+use crate::gooseberry::encryption::WorkingCopy;
+use crate::gooseberry::fetch::SyncProgress;
+use crate::gooseberry::storage::{self, Batch, Store, Tree, DEFAULT_TREE};
 use crate::gooseberry::Gooseberry;
 use crate::utils;
 use crate::{EMPTY_TAG, MIN_DATE};
 
+/// Prefix of the keys storing the time of the last sync of each group
+const SYNC_TIME_KEY: &str = "last_sync_time";
+
+/// Prefix of the keys storing the progress of an unfinished sync of each group
+const SYNC_PROGRESS_KEY: &str = "sync_progress";
+
+/// Key storing whether the last sync was of everyone's annotations (`all_users`)
+const ALL_USERS_KEY: &str = "synced_all_users";
+
+/// Key storing the time of the last sync of a group
+fn sync_time_key(group: &str) -> String {
+    format!("{}:{}", SYNC_TIME_KEY, group)
+}
+
+/// Key storing the progress of an unfinished sync of a group
+fn sync_progress_key(group: &str) -> String {
+    format!("{}:{}", SYNC_PROGRESS_KEY, group)
+}
+
 /// If key exists, add value to existing values - join with a semicolon
 pub fn merge_index(_key: &[u8], old_indices: Option<&[u8]>, new_index: &[u8]) -> Option<Vec<u8>> {
     let mut ret = old_indices.map_or_else(Vec::new, |old| old.to_vec());
@@ -18,53 +43,246 @@ pub fn merge_index(_key: &[u8], old_indices: Option<&[u8]>, new_index: &[u8]) ->
 }
 
 /// ## Database
-/// `sled` database related functions to create, manipulate, and retrieve information in
-/// the annotation ID: (tags IDs) tree and the tag ID: (annotation IDs) tree.
-/// Also stores and updates the time of the last sync.
+/// Database related functions to create, manipulate, and retrieve information in
+/// the annotation ID: (tags IDs) tree, the tag ID: (annotation IDs) tree,
+/// the annotation ID: group ID tree, and the annotation ID: (reply IDs) tree.
+/// Also stores and updates the time of the last sync of each group, and the progress of unfinished syncs.
 impl Gooseberry {
-    /// Gets the `sled` database with all gooseberry info.
+    /// Gets the database with all gooseberry info, stored with the given backend.
     /// Makes a new one the first time round
-    pub fn get_db(db_dir: &Path) -> color_eyre::Result<sled::Db> {
-        Ok(sled::open(db_dir)?)
+    pub fn get_db(db_dir: &Path, backend: DbBackend) -> color_eyre::Result<Box<dyn Store>> {
+        storage::open(db_dir, backend)
     }
 
-    /// Merge function for appending items to an existing key, uses semicolons
-    pub fn set_merge(&self) -> color_eyre::Result<()> {
-        self.tag_to_annotations()?.set_merge_operator(merge_index);
-        self.annotation_to_tags()?.set_merge_operator(merge_index);
-        Ok(())
+    /// Folder with the database files: the database folder,
+    /// or the decrypted copy of it with `encrypt_database` on
+    pub fn db_dir(&self) -> &Path {
+        self.working_copy
+            .as_ref()
+            .map_or(self.config.db_dir.as_path(), WorkingCopy::dir)
+    }
+
+    /// Locks the file next to the database folder, waiting for other gooseberry processes to finish.
+    /// The lock is released when the returned file is dropped (or the process exits)
+    pub fn lock_db(db_dir: &Path) -> color_eyre::Result<fs::File> {
+        let lock_file = Self::lock_file(db_dir)?;
+        if lock_file.try_lock_exclusive().is_err() {
+            eprintln!("Waiting for another gooseberry process to finish...");
+            lock_file.lock_exclusive()?;
+        }
+        Ok(lock_file)
+    }
+
+    /// Locks the file next to the database folder like `lock_db`, but without waiting:
+    /// `None` if another gooseberry process is using the database
+    pub fn try_lock_db(db_dir: &Path) -> color_eyre::Result<Option<fs::File>> {
+        let lock_file = Self::lock_file(db_dir)?;
+        Ok(lock_file.try_lock_exclusive().is_ok().then_some(lock_file))
     }
 
-    /// (re)sets time of last sync to way in the past
+    /// File next to the database folder that gooseberry processes lock
+    fn lock_file(db_dir: &Path) -> color_eyre::Result<fs::File> {
+        Ok(fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(db_dir.with_extension("lock"))?)
+    }
+
+    /// (re)sets time of last sync to way in the past, for all groups,
+    /// forgetting any unfinished syncs
     pub fn reset_sync_time(&self) -> color_eyre::Result<()> {
-        self.db.insert("last_sync_time", MIN_DATE.as_bytes())?;
+        for prefix in &[SYNC_TIME_KEY, SYNC_PROGRESS_KEY] {
+            for key in self.default_tree()?.scan_prefix(prefix).keys() {
+                self.default_tree()?.remove(key?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the last sync was of everyone's annotations in the groups (`all_users`)
+    pub fn synced_all_users(&self) -> color_eyre::Result<bool> {
+        Ok(self.default_tree()?.get(ALL_USERS_KEY)?.is_some())
+    }
+
+    /// Records whether syncs are of everyone's annotations in the groups
+    pub fn set_synced_all_users(&self, all_users: bool) -> color_eyre::Result<()> {
+        if all_users {
+            self.default_tree()?.insert(ALL_USERS_KEY, [])?;
+        } else {
+            self.default_tree()?.remove(ALL_USERS_KEY)?;
+        }
         Ok(())
     }
 
-    /// Update last sync time after sync
-    pub fn set_sync_time(&self, datetime: &str) -> color_eyre::Result<()> {
-        self.db.insert("last_sync_time", datetime.as_bytes())?;
+    /// Update last sync time of a group after sync
+    pub fn set_sync_time(&self, group: &str, datetime: &str) -> color_eyre::Result<()> {
+        self.default_tree()?
+            .insert(sync_time_key(group), datetime.as_bytes())?;
         Ok(())
     }
 
-    /// Get time of last sync
-    pub fn get_sync_time(&self) -> color_eyre::Result<String> {
-        match self.db.get("last_sync_time")? {
+    /// Get time of last sync of a group
+    pub fn get_sync_time(&self, group: &str) -> color_eyre::Result<String> {
+        match self.default_tree()?.get(sync_time_key(group))? {
             Some(date_bytes) => Ok(std::str::from_utf8(&date_bytes)?.to_owned()),
             None => Ok(MIN_DATE.to_owned()),
         }
     }
 
+    /// Store the progress of a sync of a group, after each round of pages
+    pub fn set_sync_progress(
+        &self,
+        group: &str,
+        progress: &SyncProgress,
+    ) -> color_eyre::Result<()> {
+        let tree = self.default_tree()?;
+        tree.insert(sync_progress_key(group), serde_json::to_vec(progress)?)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Get the progress of an interrupted sync of a group, if there is one
+    pub fn get_sync_progress(&self, group: &str) -> color_eyre::Result<Option<SyncProgress>> {
+        match self.default_tree()?.get(sync_progress_key(group))? {
+            Some(progress) => Ok(Some(serde_json::from_slice(&progress)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the progress of a sync of a group, once it's finished
+    pub fn remove_sync_progress(&self, group: &str) -> color_eyre::Result<()> {
+        self.default_tree()?.remove(sync_progress_key(group))?;
+        Ok(())
+    }
+
+    /// Tree storing the time of the last sync and the progress of unfinished syncs of each group,
+    /// and the schema version of the database
+    pub fn default_tree(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree(DEFAULT_TREE)
+    }
+
     /// Tree storing annotation id: (tags ...)
     /// Referred to as the annotation tree
-    pub fn annotation_to_tags(&self) -> color_eyre::Result<sled::Tree> {
-        Ok(self.db.open_tree("annotation_to_tags")?)
+    pub fn annotation_to_tags(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_to_tags")
     }
 
     /// Tree storing tag: ( annotation IDs ...)
     /// Referred to as the tags tree
-    pub fn tag_to_annotations(&self) -> color_eyre::Result<sled::Tree> {
-        Ok(self.db.open_tree("tag_to_annotations")?)
+    pub fn tag_to_annotations(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("tag_to_annotations")
+    }
+
+    /// Tree storing annotation id: group id
+    /// Referred to as the group tree
+    pub fn annotation_to_group(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_to_group")
+    }
+
+    /// Tree storing knowledge base file path: content hash
+    /// Used to only rewrite changed files on `make`
+    pub fn kb_files(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("kb_files")
+    }
+
+    /// Tree storing annotation ID: update time of the annotation when it was last written to the knowledge base
+    /// Used to summarize the changes made by `make` in git commit messages
+    pub fn kb_annotations(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("kb_annotations")
+    }
+
+    /// Tree storing annotation ID: path of the knowledge base file it was written to in the last `make`
+    /// Used to open the note with an annotation in Obsidian
+    pub fn kb_locations(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("kb_locations")
+    }
+
+    /// Tree storing annotation ID\0linked annotation ID: link type, made with `gooseberry link`
+    pub fn annotation_links(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_links")
+    }
+
+    /// Tree storing annotation ID: RFC 3339 time until which the task is snoozed, set with `gooseberry todo snooze`
+    pub fn todo_snoozed(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("todo_snoozed")
+    }
+
+    /// Tree storing annotation ID: account ID of its author (`acct:<username>@<authority>`)
+    pub fn annotation_to_user(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_to_user")
+    }
+
+    /// Tree storing annotation ID: `hidden`, `flagged`, or both (space-separated),
+    /// for annotations a group moderator hid or someone flagged, as of the last sync
+    pub fn annotation_moderation(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_moderation")
+    }
+
+    /// Tree storing account ID: display name of the user, cached during sync
+    /// (an empty name means the user doesn't have one)
+    pub fn display_names(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("display_names")
+    }
+
+    /// Tree storing URI: page title, cached during sync
+    /// (an empty title means the page doesn't have one)
+    pub fn uri_to_title(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("uri_to_title")
+    }
+
+    /// Tree storing URI: readable text of the page, archived during sync if `archive_pages` is set
+    /// (empty if the page has no readable text)
+    pub fn uri_to_archive(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("uri_to_archive")
+    }
+
+    /// Tree storing URI: URL of the page's snapshot in the Wayback Machine
+    pub fn uri_to_snapshot(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("uri_to_snapshot")
+    }
+
+    /// Tree storing annotation ID: hash of the text and tags last rendered in the knowledge base,
+    /// followed by the annotation's update time
+    pub fn annotation_hashes(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_hashes")
+    }
+
+    /// Tree storing annotation ID: semicolon-separated IDs of its direct replies
+    pub fn annotation_to_replies(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_to_replies")
+    }
+
+    /// Tree storing annotation ID: update time of the annotation when it was last sent to Readwise
+    pub fn annotation_to_readwise(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_to_readwise")
+    }
+
+    /// Tree storing PDF fingerprint URN: title registered for the PDF
+    pub fn fingerprint_to_title(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("fingerprint_to_title")
+    }
+
+    /// Tree storing annotation ID: annotation JSON, for annotations deleted from Hypothesis
+    /// Referred to as the trash
+    pub fn trash(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("trash")
+    }
+
+    /// Tree storing annotation ID and update time (separated by a null byte): annotation JSON,
+    /// for previous versions of annotations whose text or tags changed, recorded during sync
+    pub fn annotation_history(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_history")
+    }
+
+    /// Tree storing annotation ID: its metadata set with `gooseberry meta`, as a JSON object
+    pub fn annotation_meta(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("annotation_meta")
+    }
+
+    /// Tree storing IDs of annotations deleted with gooseberry since the last sync, for `sync --diff`
+    pub fn deleted_since_sync(&self) -> color_eyre::Result<Tree> {
+        self.db.open_tree("deleted_since_sync")
     }
 
     pub fn add_to_tag(&self, tag_key: &[u8], annotation_key: &[u8]) -> color_eyre::Result<()> {
@@ -77,10 +295,15 @@ impl Gooseberry {
     pub fn add_annotation(
         &self,
         annotation: &Annotation,
-        annotation_batch: &mut sled::Batch,
+        annotation_batch: &mut Batch,
     ) -> color_eyre::Result<()> {
         let annotation_key = annotation.id.as_bytes();
         annotation_batch.insert(annotation_key, utils::join_ids(&annotation.tags)?);
+        self.annotation_to_group()?
+            .insert(annotation_key, annotation.group.as_bytes())?;
+        self.annotation_to_user()?
+            .insert(annotation_key, annotation.user.0.as_bytes())?;
+        self.add_reply(annotation)?;
         if annotation.tags.is_empty()
             || annotation
                 .tags
@@ -102,12 +325,13 @@ impl Gooseberry {
     }
 
     /// add or update annotations from the Hypothesis API
+    #[tracing::instrument(level = "debug", skip_all, fields(annotations = annotations.len()))]
     pub fn sync_annotations(
         &self,
         annotations: &[Annotation],
     ) -> color_eyre::Result<(usize, usize)> {
         let (mut added, mut updated) = (0, 0);
-        let mut annotation_batch = sled::Batch::default();
+        let mut annotation_batch = Batch::default();
         for annotation in annotations {
             let annotation_key = annotation.id.as_bytes();
             if self.annotation_to_tags()?.contains_key(annotation_key)? {
@@ -120,9 +344,45 @@ impl Gooseberry {
             }
         }
         self.annotation_to_tags()?.apply_batch(annotation_batch)?;
+        tracing::debug!(added, updated, "stored annotations");
         Ok((added, updated))
     }
 
+    /// Stores whether synced annotations are hidden or flagged.
+    /// Returns the number of them which were hidden or flagged since the last sync
+    pub fn store_moderation(&self, annotations: &[Annotation]) -> color_eyre::Result<usize> {
+        let moderation = self.annotation_moderation()?;
+        let mut batch = Batch::default();
+        let mut moderated = 0;
+        for annotation in annotations {
+            let state: Vec<_> = [
+                ("hidden", annotation.hidden),
+                ("flagged", annotation.flagged),
+            ]
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| *name)
+            .collect();
+            let previous = match moderation.get(annotation.id.as_bytes())? {
+                Some(previous) => std::str::from_utf8(&previous)?.to_owned(),
+                None => String::new(),
+            };
+            if state
+                .iter()
+                .any(|name| !previous.split(' ').any(|p| p == *name))
+            {
+                moderated += 1;
+            }
+            if state.is_empty() {
+                batch.remove(annotation.id.as_bytes());
+            } else {
+                batch.insert(annotation.id.as_bytes(), state.join(" ").as_bytes());
+            }
+        }
+        moderation.apply_batch(batch)?;
+        Ok(moderated)
+    }
+
     /// Delete an annotation index from the tag tree
     pub fn delete_from_tag(&self, tag_key: &[u8], annotation_id: &str) -> color_eyre::Result<()> {
         let new_indices: Vec<_> =
@@ -143,9 +403,11 @@ impl Gooseberry {
         Ok(())
     }
 
-    /// Delete an annotation ID from the annotation tree
+    /// Delete an annotation ID from the annotation, group, and user trees
     pub fn delete_from_annotations(&self, id: &str) -> color_eyre::Result<Vec<String>> {
         let annotation_key = id.as_bytes();
+        self.annotation_to_group()?.remove(annotation_key)?;
+        self.annotation_to_user()?.remove(annotation_key)?;
         Ok(utils::split_ids(
             &self
                 .annotation_to_tags()?
@@ -167,12 +429,16 @@ impl Gooseberry {
     }
 
     /// Delete multiple annotations
+    #[tracing::instrument(level = "debug", skip_all, fields(annotations = ids.len()))]
     pub fn delete_annotations(&self, ids: &[String]) -> color_eyre::Result<Vec<Vec<String>>> {
-        let mut annotation_batch = sled::Batch::default();
+        let mut annotation_batch = Batch::default();
         let mut tags_list = Vec::with_capacity(ids.len());
         for id in ids {
             let tags = self.get_annotation_tags(id)?;
             annotation_batch.remove(id.as_bytes());
+            self.annotation_to_group()?.remove(id.as_bytes())?;
+            self.annotation_to_user()?.remove(id.as_bytes())?;
+            self.annotation_moderation()?.remove(id.as_bytes())?;
             for tag in &tags {
                 self.delete_from_tag(tag.as_bytes(), id)?;
             }
@@ -182,6 +448,27 @@ impl Gooseberry {
         Ok(tags_list)
     }
 
+    /// Adds a reply to the replies of the annotation it replies to (the last of its references)
+    pub fn add_reply(&self, annotation: &Annotation) -> color_eyre::Result<()> {
+        if let Some(parent) = annotation.references.last() {
+            let mut replies = self.get_replies(parent)?;
+            if !replies.contains(&annotation.id) {
+                replies.push(annotation.id.to_owned());
+                self.annotation_to_replies()?
+                    .insert(parent.as_bytes(), utils::join_ids(&replies)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Retrieve the IDs of the direct replies to an annotation
+    pub fn get_replies(&self, id: &str) -> color_eyre::Result<Vec<String>> {
+        match self.annotation_to_replies()?.get(id.as_bytes())? {
+            Some(replies) => utils::split_ids(&replies),
+            None => Ok(Vec::new()),
+        }
+    }
+
     /// Retrieve annotations tagged with a given tag
     pub fn get_tagged_annotations(&self, tag: &str) -> color_eyre::Result<Vec<String>> {
         utils::split_ids(&self.tag_to_annotations()?.get(tag.as_bytes())?.ok_or(
@@ -191,6 +478,17 @@ impl Gooseberry {
         )?)
     }
 
+    /// Retrieve the group an annotation belongs to
+    pub fn get_annotation_group(&self, id: &str) -> color_eyre::Result<String> {
+        Ok(std::str::from_utf8(
+            &self
+                .annotation_to_group()?
+                .get(id.as_bytes())?
+                .ok_or(Apologize::AnnotationNotFound { id: id.to_owned() })?,
+        )?
+        .to_owned())
+    }
+
     /// Retrieve tags associated with an annotation
     pub fn get_annotation_tags(&self, id: &str) -> color_eyre::Result<Vec<String>> {
         let annotation_key = id.as_bytes();
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::dry_run;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// What to do with a set of duplicate annotations
+const MERGE: usize = 0;
+const DELETE: usize = 1;
+const ACTIONS: [&str; 3] = [
+    "Merge into the oldest (keeping all tags and notes)",
+    "Keep the oldest and delete the others",
+    "Skip",
+];
+
+/// Annotations are duplicates if they have the same URI and quote,
+/// or, for page notes, the same URI and text (ignoring differences in whitespace)
+fn duplicate_key(annotation: &Annotation) -> (String, bool, String) {
+    let quote = utils::get_quotes(annotation).join(" ");
+    let highlight = !quote.trim().is_empty();
+    let content = if highlight { &quote } else { &annotation.text };
+    (
+        utils::clean_uri(&annotation.uri),
+        highlight,
+        content.split_whitespace().collect::<Vec<_>>().join(" "),
+    )
+}
+
+/// Sets of duplicate annotations, each sorted from oldest to newest
+fn find_duplicates(annotations: Vec<Annotation>) -> Vec<Vec<Annotation>> {
+    let mut keys = Vec::new();
+    let mut groups: HashMap<_, Vec<Annotation>> = HashMap::new();
+    for annotation in annotations {
+        let key = duplicate_key(&annotation);
+        if !groups.contains_key(&key) {
+            keys.push(key.clone());
+        }
+        groups.entry(key).or_default().push(annotation);
+    }
+    keys.into_iter()
+        .filter_map(|key| groups.remove(&key))
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort_by_key(|a| a.created);
+            group
+        })
+        .collect()
+}
+
+/// The oldest annotation with the tags of all of them, and their (different) texts one after the other
+fn merge(duplicates: &[Annotation]) -> Annotation {
+    let mut merged = duplicates[0].clone();
+    for duplicate in &duplicates[1..] {
+        for tag in &duplicate.tags {
+            if !merged.tags.contains(tag) {
+                merged.tags.push(tag.to_owned());
+            }
+        }
+        let text = duplicate.text.trim();
+        if !text.is_empty() && !merged.text.contains(text) {
+            merged.text = if merged.text.trim().is_empty() {
+                text.to_owned()
+            } else {
+                format!("{}\n\n{}", merged.text.trim(), text)
+            };
+        }
+    }
+    merged
+}
+
+/// ## Dedupe
+/// Finding and cleaning up annotations made more than once on the same passage
+impl Gooseberry {
+    /// Shows each set of duplicates and asks whether to merge them, delete all but the oldest, or skip them.
+    /// With `force`, every set is merged without asking.
+    /// With `--dry-run`, the merges and deletions are only shown.
+    /// Merges into annotations changed in Hypothesis since they were synced are checked for (see `skip_conflicts`).
+    /// Deleted duplicates go to the trash
+    pub async fn dedupe(
+        &self,
+        annotations: Vec<Annotation>,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        let duplicates = find_duplicates(annotations);
+        if duplicates.is_empty() {
+            println!("No duplicate annotations");
+            return Ok(());
+        }
+        let num_sets = duplicates.len();
+        let mut originals = Vec::new();
+        // merged annotations, with the duplicates merged into them
+        let mut merges = Vec::new();
+        let mut to_delete = Vec::new();
+        for (i, group) in duplicates.into_iter().enumerate() {
+            let action = if force {
+                Some(MERGE)
+            } else {
+                println!("\n{} ({} duplicates)", group[0].uri, group.len());
+                for annotation in &group {
+                    println!(
+                        "  {}  {}  [{}]  {}",
+                        annotation.id,
+                        annotation.created.format("%Y-%m-%d %H:%M"),
+                        annotation.tags.join(", "),
+                        annotation.text.replace('\n', " ")
+                    );
+                }
+                utils::select(&format!("Set {} of {}", i + 1, num_sets), &ACTIONS)?
+            };
+            match action {
+                Some(MERGE) => {
+                    let annotation = merge(&group);
+                    if annotation.tags != group[0].tags || annotation.text != group[0].text {
+                        originals.push(group[0].clone());
+                        merges.push((annotation, group.into_iter().skip(1).collect::<Vec<_>>()));
+                    } else {
+                        to_delete.extend(group.into_iter().skip(1));
+                    }
+                }
+                Some(DELETE) => to_delete.extend(group.into_iter().skip(1)),
+                _ => (),
+            }
+        }
+        let merged: Vec<_> = merges
+            .iter()
+            .map(|(annotation, _)| annotation.clone())
+            .collect();
+        if !merged.is_empty() && self.dry_run {
+            dry_run::print_updates(&originals, &merged);
+            to_delete.extend(merges.into_iter().flat_map(|(_, duplicates)| duplicates));
+        } else if !merged.is_empty() {
+            let kept = self.skip_conflicts(&merged).await?;
+            // the duplicates of a skipped merge are kept, so that nothing of them is lost
+            for (annotation, duplicates) in merges {
+                if kept.iter().any(|a| a.id == annotation.id) {
+                    to_delete.extend(duplicates);
+                }
+            }
+            println!("Merging into {} annotation(s)", kept.len());
+            self.send_updates(&kept).await?;
+        }
+        if !to_delete.is_empty() {
+            self.delete(to_delete, true).await?;
+        }
+        if !merged.is_empty() && !self.dry_run {
+            self.sync().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::cli::DiffFormat;
+use crate::gooseberry::helpers::truncate;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Characters of an annotation's text (or quote) shown in the markdown diff
+const EXCERPT_LENGTH: usize = 80;
+
+/// An annotation in the diff between two syncs
+#[derive(Debug, Serialize)]
+pub struct DiffEntry {
+    pub id: String,
+    pub uri: String,
+    /// Resolved title of the annotated document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub text: String,
+    pub tags: Vec<String>,
+    /// Text before the sync, for annotations with edited text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_text: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added_tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_tags: Vec<String>,
+    /// Quote of the annotation, used when it has no text
+    #[serde(skip)]
+    quote: String,
+}
+
+/// What changed since the last sync: new annotations, annotations with edited text or tags,
+/// and annotations deleted with gooseberry
+#[derive(Debug, Serialize)]
+pub struct SyncDiff {
+    pub synced: DateTime<Utc>,
+    pub added: Vec<DiffEntry>,
+    pub edited: Vec<DiffEntry>,
+    pub retagged: Vec<DiffEntry>,
+    pub deleted: Vec<DiffEntry>,
+}
+
+/// Tags and text of an annotation before it's synced, if it was already stored
+pub(crate) struct Previous {
+    tags: Vec<String>,
+    /// `None` if the annotation isn't in the full-text index
+    text: Option<String>,
+}
+
+impl Default for SyncDiff {
+    fn default() -> Self {
+        SyncDiff {
+            synced: Utc::now(),
+            added: Vec::new(),
+            edited: Vec::new(),
+            retagged: Vec::new(),
+            deleted: Vec::new(),
+        }
+    }
+}
+
+/// Single-line excerpt of a text, in quotes
+fn excerpt(text: &str) -> String {
+    format!(
+        "\"{}\"",
+        truncate(
+            &text.split_whitespace().collect::<Vec<_>>().join(" "),
+            EXCERPT_LENGTH
+        )
+    )
+}
+
+/// Tags as inline code, e.g. `` `rust`, `cli` ``
+fn code_list(tags: &[String]) -> String {
+    tags.iter()
+        .map(|tag| format!("`{}`", tag))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl DiffEntry {
+    fn new(annotation: &Annotation, title: Option<String>) -> Self {
+        DiffEntry {
+            id: annotation.id.to_owned(),
+            uri: annotation.uri.to_owned(),
+            title,
+            text: annotation.text.to_owned(),
+            tags: annotation
+                .tags
+                .iter()
+                .filter(|tag| !tag.trim().is_empty())
+                .cloned()
+                .collect(),
+            previous_text: None,
+            added_tags: Vec::new(),
+            removed_tags: Vec::new(),
+            quote: utils::get_quotes(annotation).join(" "),
+        }
+    }
+
+    /// Link to the annotated document, with its title if it has one
+    fn link(&self) -> String {
+        format!(
+            "[{}]({})",
+            self.title
+                .as_deref()
+                .unwrap_or(&self.uri)
+                .replace(']', "\\]"),
+            self.uri
+        )
+    }
+
+    /// Text of the annotation, or its quote if it has no text
+    fn summary(&self) -> String {
+        if self.text.trim().is_empty() {
+            excerpt(&self.quote)
+        } else {
+            excerpt(&self.text)
+        }
+    }
+}
+
+impl SyncDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.edited.is_empty()
+            && self.retagged.is_empty()
+            && self.deleted.is_empty()
+    }
+
+    /// Markdown digest with a section per kind of change (leaving out the empty ones)
+    pub fn to_markdown(&self) -> String {
+        let mut lines = vec![format!(
+            "## Sync of {}",
+            self.synced
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+        )];
+        let mut section =
+            |heading: &str, entries: &[DiffEntry], line: &dyn Fn(&DiffEntry) -> String| {
+                if entries.is_empty() {
+                    return;
+                }
+                lines.push(String::new());
+                lines.push(format!("### {} ({})", heading, entries.len()));
+                lines.push(String::new());
+                lines.extend(
+                    entries
+                        .iter()
+                        .map(|entry| format!("- {}: {}", entry.link(), line(entry))),
+                );
+            };
+        section("New annotations", &self.added, &|entry| {
+            if entry.tags.is_empty() {
+                entry.summary()
+            } else {
+                format!("{} ({})", entry.summary(), code_list(&entry.tags))
+            }
+        });
+        section("Edited text", &self.edited, &|entry| {
+            format!(
+                "{} → {}",
+                excerpt(entry.previous_text.as_deref().unwrap_or_default()),
+                excerpt(&entry.text)
+            )
+        });
+        section("Changed tags", &self.retagged, &|entry| {
+            let mut changes = Vec::new();
+            if !entry.added_tags.is_empty() {
+                changes.push(format!("added {}", code_list(&entry.added_tags)));
+            }
+            if !entry.removed_tags.is_empty() {
+                changes.push(format!("removed {}", code_list(&entry.removed_tags)));
+            }
+            format!("{} ({})", entry.summary(), changes.join(", "))
+        });
+        section("Deleted", &self.deleted, &|entry| entry.summary());
+        lines.join("\n")
+    }
+
+    /// The diff in a format, as printed
+    pub fn format(&self, format: DiffFormat) -> color_eyre::Result<String> {
+        Ok(match format {
+            DiffFormat::Markdown => self.to_markdown(),
+            DiffFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    /// Appends the diff to a changelog file: markdown separated by a blank line,
+    /// or JSON on a single line
+    pub fn append_to(&self, file: &Path, format: DiffFormat) -> color_eyre::Result<()> {
+        let entry = match format {
+            DiffFormat::Markdown => format!("{}\n\n", self.to_markdown()),
+            DiffFormat::Json => format!("{}\n", serde_json::to_string(self)?),
+        };
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?
+            .write_all(entry.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// ## Sync diff
+/// What changed in the synced annotations since the last sync
+impl Gooseberry {
+    /// Stored tags and `indexed` text of the annotations about to be synced, for the ones already in the database
+    pub(crate) fn previous_versions(
+        &self,
+        annotations: &[Annotation],
+        indexed: &HashMap<String, Annotation>,
+    ) -> color_eyre::Result<HashMap<String, Previous>> {
+        let annotation_to_tags = self.annotation_to_tags()?;
+        let mut previous = HashMap::new();
+        for annotation in annotations {
+            if annotation_to_tags.contains_key(annotation.id.as_bytes())? {
+                previous.insert(
+                    annotation.id.to_owned(),
+                    Previous {
+                        tags: self.get_annotation_tags(&annotation.id)?,
+                        text: indexed.get(&annotation.id).map(|a| a.text.to_owned()),
+                    },
+                );
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Adds synced annotations to the diff, comparing them with their `previous` versions.
+    /// Updated annotations without changes to their text or tags are left out
+    pub(crate) fn add_to_diff(
+        &self,
+        annotations: &[Annotation],
+        previous: &HashMap<String, Previous>,
+        diff: &mut SyncDiff,
+    ) -> color_eyre::Result<()> {
+        for annotation in annotations {
+            let mut entry = DiffEntry::new(annotation, self.resolved_title(annotation)?);
+            let previous = match previous.get(&annotation.id) {
+                Some(previous) => previous,
+                None => {
+                    diff.added.push(entry);
+                    continue;
+                }
+            };
+            entry.added_tags = entry
+                .tags
+                .iter()
+                .filter(|tag| !previous.tags.contains(tag))
+                .cloned()
+                .collect();
+            entry.removed_tags = previous
+                .tags
+                .iter()
+                .filter(|tag| !tag.trim().is_empty() && !entry.tags.contains(tag))
+                .cloned()
+                .collect();
+            let retagged = !entry.added_tags.is_empty() || !entry.removed_tags.is_empty();
+            let edited = matches!(&previous.text, Some(text) if *text != annotation.text);
+            if edited {
+                entry.previous_text = previous.text.clone();
+            }
+            match (edited, retagged) {
+                (true, true) => {
+                    let mut tag_entry = DiffEntry::new(annotation, entry.title.clone());
+                    tag_entry.added_tags = std::mem::take(&mut entry.added_tags);
+                    tag_entry.removed_tags = std::mem::take(&mut entry.removed_tags);
+                    diff.edited.push(entry);
+                    diff.retagged.push(tag_entry);
+                }
+                (true, false) => diff.edited.push(entry),
+                (false, true) => diff.retagged.push(entry),
+                (false, false) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the annotations deleted with gooseberry since the last sync to the diff (if they're still in the trash),
+    /// and starts over for the next sync
+    pub(crate) fn add_deletions_to_diff(&self, diff: &mut SyncDiff) -> color_eyre::Result<()> {
+        let deletions = self.deleted_since_sync()?;
+        let trash = self.trash()?;
+        for id in deletions.iter().keys() {
+            if let Some(json) = trash.get(id?)? {
+                let annotation: Annotation = serde_json::from_slice(&json)?;
+                diff.deleted.push(DiffEntry::new(
+                    &annotation,
+                    self.resolved_title(&annotation)?,
+                ));
+            }
+        }
+        deletions.clear()?;
+        Ok(())
+    }
+}
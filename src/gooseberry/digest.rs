@@ -0,0 +1,240 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::Help;
+
+use crate::errors::Apologize;
+use crate::gooseberry::cli::{DigestFormat, Filters};
+use crate::gooseberry::feed::{paragraphs, xml_escape};
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::stats;
+use crate::gooseberry::Gooseberry;
+
+/// Span of time a digest covers if no dates are given
+const DEFAULT_DIGEST_DAYS: i64 = 7;
+/// Heading for annotations without tags, listed after the tags
+const UNTAGGED: &str = "Untagged";
+/// Inline style of highlights in HTML digests
+const QUOTE_STYLE: &str =
+    "border-left: 3px solid #ccc; margin: 0.5em 0; padding-left: 1em; color: #444";
+
+/// Annotations of a tag grouped by domain
+type Domains = BTreeMap<String, Vec<AnnotationTemplate>>;
+
+/// Annotations of a digest grouped by tag (most annotated first), then by domain
+struct Digest {
+    title: String,
+    /// Summary line, e.g. "12 annotations on 5 pages, 2026-10-09 to 2026-10-16"
+    summary: String,
+    sections: Vec<(String, Domains)>,
+}
+
+impl Digest {
+    fn new(
+        title: String,
+        annotations: Vec<AnnotationTemplate>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Self {
+        let pages: HashSet<_> = annotations.iter().map(|a| &a.annotation.uri).collect();
+        let summary = format!(
+            "{} annotation(s) on {} page(s), {} to {}",
+            annotations.len(),
+            pages.len(),
+            from.with_timezone(&chrono::Local).format("%Y-%m-%d"),
+            to.with_timezone(&chrono::Local).format("%Y-%m-%d")
+        );
+        let mut tags: BTreeMap<String, Domains> = BTreeMap::new();
+        for annotation in annotations {
+            let domain = stats::domain(&annotation.annotation.uri);
+            let keys = if annotation.annotation.tags.is_empty() {
+                vec![UNTAGGED.to_owned()]
+            } else {
+                annotation.annotation.tags.clone()
+            };
+            for tag in keys {
+                tags.entry(tag)
+                    .or_default()
+                    .entry(domain.clone())
+                    .or_default()
+                    .push(annotation.clone());
+            }
+        }
+        let mut sections: Vec<_> = tags.into_iter().collect();
+        let count = |domains: &Domains| -> usize { domains.values().map(Vec::len).sum() };
+        // Stable sort, so tags with as many annotations stay in alphabetical order
+        sections.sort_by_key(|(tag, domains)| (tag == UNTAGGED, Reverse(count(domains))));
+        Digest {
+            title,
+            summary,
+            sections,
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut lines = vec![
+            format!("# {}", self.title),
+            String::new(),
+            format!("{}.", self.summary),
+        ];
+        for (tag, domains) in &self.sections {
+            lines.push(String::new());
+            lines.push(format!("## {}", tag));
+            for (domain, annotations) in domains {
+                lines.push(String::new());
+                lines.push(format!("### {}", domain));
+                lines.push(String::new());
+                for annotation in annotations {
+                    lines.push(format!(
+                        "- **[{}]({})**",
+                        annotation.title, annotation.incontext
+                    ));
+                    for quote in &annotation.highlight {
+                        lines.push(String::new());
+                        lines.extend(quote.lines().map(|line| format!("  > {}", line)));
+                    }
+                    if !annotation.annotation.text.trim().is_empty() {
+                        lines.push(String::new());
+                        lines.extend(
+                            annotation
+                                .annotation
+                                .text
+                                .trim()
+                                .lines()
+                                .map(|line| format!("  {}", line).trim_end().to_owned()),
+                        );
+                    }
+                }
+            }
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// A standalone HTML page with inline styles, which email clients keep
+    fn to_html(&self) -> String {
+        let mut body = format!(
+            "<h1>{}</h1>\n<p>{}.</p>\n",
+            xml_escape(&self.title),
+            xml_escape(&self.summary)
+        );
+        for (tag, domains) in &self.sections {
+            body.push_str(&format!("<h2>{}</h2>\n", xml_escape(tag)));
+            for (domain, annotations) in domains {
+                body.push_str(&format!(
+                    "<h3 style=\"color: #666\">{}</h3>\n<ul>\n",
+                    xml_escape(domain)
+                ));
+                for annotation in annotations {
+                    body.push_str(&format!(
+                        "<li><a href=\"{}\"><strong>{}</strong></a>",
+                        xml_escape(&annotation.incontext),
+                        xml_escape(&annotation.title)
+                    ));
+                    for quote in &annotation.highlight {
+                        body.push_str(&format!(
+                            "<blockquote style=\"{}\">{}</blockquote>",
+                            QUOTE_STYLE,
+                            paragraphs(quote)
+                        ));
+                    }
+                    body.push_str(&paragraphs(&annotation.annotation.text));
+                    body.push_str("</li>\n");
+                }
+                body.push_str("</ul>\n");
+            }
+        }
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{}</title>
+</head>
+<body style="font-family: sans-serif; line-height: 1.5; max-width: 42em; margin: 0 auto; padding: 1em">
+{}</body>
+</html>
+"#,
+            xml_escape(&self.title),
+            body
+        )
+    }
+}
+
+/// Pipes the digest to a shell command, e.g. `mail -s "Reading digest" me@example.com`
+fn send(command: &str, digest: &str) -> color_eyre::Result<()> {
+    let mut child = crate::utils::shell_command(command)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or(Apologize::DigestError {
+            message: format!("Couldn't write to {:?}", command),
+        })?
+        .write_all(digest.as_bytes())?;
+    if !child.wait()?.success() {
+        return Err(Apologize::DigestError {
+            message: format!("{:?} failed", command),
+        })
+        .suggestion("Check that the command works with e.g. echo test | <command>")?;
+    }
+    Ok(())
+}
+
+/// ## Digest
+/// Summaries of recent annotations grouped by tag and domain, for emailing or posting
+impl Gooseberry {
+    /// Writes a digest of the annotations matching the filters (those made in the last week if no dates are given)
+    /// to a file or stdout, or pipes it to the `sendmail` command
+    pub async fn digest(
+        &self,
+        mut filters: Filters,
+        format: DigestFormat,
+        title: Option<String>,
+        file: Option<&Path>,
+        sendmail: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        if filters.after().is_none() && filters.before.is_none() {
+            filters.since = Some(Utc::now() - Duration::days(DEFAULT_DIGEST_DAYS));
+        }
+        let to = filters.before.unwrap_or_else(Utc::now);
+        let annotations = self.knowledge_base_annotations(filters.clone()).await?;
+        let from = filters
+            .after()
+            .or_else(|| annotations.iter().map(|a| a.annotation.created).min())
+            .unwrap_or(to);
+        if annotations.is_empty() {
+            eprintln!("No annotations to put in the digest");
+            return Ok(());
+        }
+        let digest = Digest::new(
+            title.unwrap_or_else(|| "Reading digest".to_owned()),
+            annotations,
+            from,
+            to,
+        );
+        let digest = match format {
+            DigestFormat::Markdown => digest.to_markdown(),
+            DigestFormat::Html => digest.to_html(),
+        };
+        match (file, sendmail) {
+            (_, Some(command)) if !self.dry_run => {
+                if let Some(file) = file {
+                    fs::write(file, &digest)?;
+                }
+                send(command, &digest)?;
+                println!("Sent the digest with {:?}", command);
+            }
+            (_, Some(command)) => println!("Would send the digest with {:?}", command),
+            (Some(file), None) => fs::write(file, &digest)?,
+            (None, None) => print!("{}", digest),
+        }
+        Ok(())
+    }
+}
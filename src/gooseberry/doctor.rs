@@ -0,0 +1,439 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use dialoguer::console::style;
+use fs2::FileExt;
+
+use crate::configuration::GooseberryConfig;
+use crate::errors::Apologize;
+use crate::gooseberry::api::Hypothesis;
+use crate::gooseberry::encryption::{self, WorkingCopy};
+use crate::gooseberry::migration::{SCHEMA_VERSION, SCHEMA_VERSION_KEY};
+use crate::gooseberry::storage::{self, DEFAULT_TREE};
+use crate::gooseberry::Gooseberry;
+use crate::EMPTY_TAG;
+
+/// File written to (and deleted from) the knowledge base directory to check that it's writable
+const WRITE_CHECK_FILE: &str = ".gooseberry-doctor";
+
+/// How a check turned out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    /// Works, but something may not: e.g. a command used by only some features is missing
+    Warning,
+    Failed,
+}
+
+/// Outcome of checking one part of the setup, with how to fix it if it isn't OK
+struct Check {
+    name: &'static str,
+    status: Status,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn failed(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Failed,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn print(&self) {
+        let mark = match self.status {
+            Status::Ok => style("ok").green(),
+            Status::Warning => style("warning").yellow(),
+            Status::Failed => style("failed").red(),
+        };
+        println!("[{}] {}: {}", mark, style(self.name).bold(), self.message);
+        if let Some(fix) = &self.fix {
+            println!("    {} {}", style("fix:").dim(), fix);
+        }
+    }
+}
+
+/// Full path of a command found on `PATH`
+fn find_command(command: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path).find_map(|dir| {
+        let candidate = dir.join(command);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        let candidate = candidate.with_extension("exe");
+        candidate.is_file().then_some(candidate)
+    })
+}
+
+/// ## Doctor
+/// Checks each part of the setup (account, groups, database, knowledge base, templates, and the external
+/// commands gooseberry runs), without changing anything, and says how to fix what doesn't work
+impl Gooseberry {
+    /// Runs all the checks and prints how each turned out. Fails if any of them failed
+    pub async fn doctor(
+        config_file: Option<&Path>,
+        profile: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let mut checks = Vec::new();
+        let location = GooseberryConfig::location(config_file)?;
+        match GooseberryConfig::read(config_file, profile) {
+            Ok(mut config) => {
+                checks.push(Check::ok("config", format!("read {:?}", location)));
+                config.read_keyring();
+                checks.extend(Self::check_account(&config).await);
+                checks.push(Self::check_database(&config));
+                checks.push(Self::check_kb_dir(&config));
+                checks.push(Self::check_template_rendering(&config));
+                checks.extend(Self::check_commands(&config));
+            }
+            Err(error) => checks.push(Check::failed(
+                "config",
+                format!("couldn't read {:?}: {}", location, error),
+                "Set it up with `gooseberry init`, or write out the default configuration with \
+                `gooseberry config default <file>` and modify it",
+            )),
+        }
+
+        for check in &checks {
+            check.print();
+        }
+        let failed = checks
+            .iter()
+            .filter(|check| check.status == Status::Failed)
+            .count();
+        if failed > 0 {
+            return Err(Apologize::DoctorError { failed }.into());
+        }
+        println!("\nEverything gooseberry needs works");
+        Ok(())
+    }
+
+    /// Checks that the Hypothesis credentials are valid,
+    /// and that the account is a member of the groups annotations are taken from
+    async fn check_account(config: &GooseberryConfig) -> Vec<Check> {
+        let (username, key) = match (&config.hypothesis_username, &config.hypothesis_key) {
+            (Some(username), Some(key)) => (username, key),
+            _ => {
+                return vec![Check::failed(
+                    "account",
+                    "Hypothesis username or developer API key isn't stored",
+                    "Set them with `gooseberry config authorize`",
+                )]
+            }
+        };
+        match config.authorize(username, key).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return vec![Check::failed(
+                    "account",
+                    format!("the developer API key isn't valid for {}", username),
+                    "Generate a new key at https://hypothes.is/account/developer \
+                    and set it with `gooseberry config authorize`",
+                )]
+            }
+            Err(error) => {
+                return vec![Check::failed(
+                    "account",
+                    format!("couldn't reach Hypothesis: {}", error),
+                    "Check your internet connection",
+                )]
+            }
+        }
+        let mut checks = vec![Check::ok("account", format!("authorized as {}", username))];
+
+        if config.hypothesis_group.is_none() {
+            checks.push(Check::failed(
+                "group",
+                "no Hypothesis group is set",
+                "Choose one with `gooseberry config group`",
+            ));
+            return checks;
+        }
+        let groups = match Hypothesis::new(username, key, &config.api_url()) {
+            Ok(api) => api
+                .get_groups(&hypothesis::groups::GroupFilters::default())
+                .await
+                .map_err(|error| error.to_string()),
+            Err(error) => Err(error.to_string()),
+        };
+        let groups = match groups {
+            Ok(groups) => groups,
+            Err(error) => {
+                checks.push(Check::failed(
+                    "group",
+                    format!("couldn't list your Hypothesis groups: {}", error),
+                    "Check your internet connection",
+                ));
+                return checks;
+            }
+        };
+        for id in config.groups() {
+            checks.push(match groups.iter().find(|group| group.id == id) {
+                Some(group) => Check::ok("group", format!("{} ({})", group.name, id)),
+                None => Check::failed(
+                    "group",
+                    format!("you aren't a member of group {}", id),
+                    "Join it on Hypothesis, or change the groups with `gooseberry config group` \
+                    and `gooseberry config groups`",
+                ),
+            });
+        }
+        checks
+    }
+
+    /// Checks that the database opens and that this version of gooseberry can read it,
+    /// and counts what's in it. Skipped while another gooseberry process is using it
+    fn check_database(config: &GooseberryConfig) -> Check {
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(config.db_dir.with_extension("lock"));
+        let lock = match lock {
+            Ok(lock) => lock,
+            Err(error) => {
+                return Check::failed(
+                    "database",
+                    format!("couldn't create the lock file next to {:?}: {}", config.db_dir, error),
+                    "Make sure the database directory's parent is writable, or change `db_dir` in the config file",
+                )
+            }
+        };
+        if lock.try_lock_exclusive().is_err() {
+            return Check::warning(
+                "database",
+                "in use by another gooseberry process, so it wasn't checked",
+                "Run `gooseberry doctor` again once it's finished",
+            );
+        }
+
+        let encrypted = config.encrypt_database.unwrap_or(false);
+        if encrypted && !encryption::encrypted_path(&config.db_dir).exists() {
+            return Check::ok(
+                "database",
+                "encrypt_database is on, so it will be encrypted the next time it's opened",
+            );
+        }
+        let working_copy = if encrypted {
+            match WorkingCopy::open(config) {
+                Ok(working_copy) => Some(working_copy),
+                Err(error) => {
+                    return Check::failed(
+                        "database",
+                        format!("couldn't decrypt it: {}", error),
+                        format!(
+                            "Enter the right passphrase (or set {})",
+                            encryption::PASSPHRASE_VARIABLE
+                        ),
+                    )
+                }
+            }
+        } else {
+            None
+        };
+        let db_dir = working_copy
+            .as_ref()
+            .map_or(config.db_dir.as_path(), WorkingCopy::dir);
+
+        // a database left by the other backend is what gets moved over next time
+        let backend =
+            storage::other_backend(db_dir, config.db_backend()).unwrap_or(config.db_backend());
+        let counts = storage::open(db_dir, backend).and_then(|db| {
+            let version = match db.open_tree(DEFAULT_TREE)?.get(SCHEMA_VERSION_KEY)? {
+                Some(version) => Some(std::str::from_utf8(&version)?.parse::<u32>()?),
+                None => None,
+            };
+            let annotations = db.open_tree("annotation_to_tags")?.len()?;
+            let tags = db
+                .open_tree("tag_to_annotations")?
+                .iter()
+                .keys()
+                .filter(|key| !matches!(key, Ok(key) if key == EMPTY_TAG.as_bytes()))
+                .count();
+            let trash = db.open_tree("trash")?.len()?;
+            Ok((version, annotations, tags, trash))
+        });
+        match counts {
+            Ok((Some(version), ..)) if version > SCHEMA_VERSION => Check::failed(
+                "database",
+                format!(
+                    "it has schema version {}, but this version of gooseberry only knows up to {}",
+                    version, SCHEMA_VERSION
+                ),
+                "Update gooseberry, or restore an older backup with `gooseberry db restore`",
+            ),
+            Ok((version, annotations, tags, trash)) => {
+                let message = format!(
+                    "{:?} ({:?} backend): {} annotations, {} tags, {} in the trash",
+                    config.db_dir,
+                    config.db_backend(),
+                    annotations,
+                    tags,
+                    trash
+                );
+                if version.unwrap_or(0) < SCHEMA_VERSION && annotations + trash > 0 {
+                    Check::warning(
+                        "database",
+                        message,
+                        "It's from an older version of gooseberry, and will be upgraded (after a backup) \
+                        the next time it's opened",
+                    )
+                } else {
+                    Check::ok("database", message)
+                }
+            }
+            Err(error) => Check::failed(
+                "database",
+                format!("couldn't open {:?}: {}", config.db_dir, error),
+                "Restore a backup with `gooseberry db restore`, or start over with `gooseberry clear`",
+            ),
+        }
+    }
+
+    /// Checks that the knowledge base directory is set and writable
+    fn check_kb_dir(config: &GooseberryConfig) -> Check {
+        let kb_dir = match &config.kb_dir {
+            Some(kb_dir) => kb_dir,
+            None => {
+                return Check::failed(
+                    "knowledge base",
+                    "no knowledge base directory is set",
+                    "Set it with `gooseberry config kb directory`",
+                )
+            }
+        };
+        if !kb_dir.is_dir() {
+            return Check::failed(
+                "knowledge base",
+                format!("{:?} isn't a directory", kb_dir),
+                "Create it, or change it with `gooseberry config kb directory`",
+            );
+        }
+        let probe = kb_dir.join(WRITE_CHECK_FILE);
+        match fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+            Ok(()) => Check::ok("knowledge base", format!("{:?} is writable", kb_dir)),
+            Err(error) => Check::failed(
+                "knowledge base",
+                format!("couldn't write to {:?}: {}", kb_dir, error),
+                "Give yourself write access to it, or change it with `gooseberry config kb directory`",
+            ),
+        }
+    }
+
+    /// Checks that the templates compile and render for a sample annotation
+    fn check_template_rendering(config: &GooseberryConfig) -> Check {
+        let rendered = match config.render_templates() {
+            Ok(rendered) => rendered,
+            Err(error) => {
+                return Check::failed(
+                    "templates",
+                    format!("couldn't be loaded: {}", error),
+                    "Check the `bibliography` file in the config file",
+                )
+            }
+        };
+        let broken: Vec<_> = rendered
+            .iter()
+            .filter(|(_, rendered)| rendered.is_err())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if broken.is_empty() {
+            Check::ok(
+                "templates",
+                format!("all {} render for a sample annotation", rendered.len()),
+            )
+        } else {
+            Check::failed(
+                "templates",
+                format!("errors in {}", broken.join(", ")),
+                "See them with `gooseberry config template --check`, \
+                and change the templates with `gooseberry config kb`",
+            )
+        }
+    }
+
+    /// Checks that the external commands gooseberry runs are installed:
+    /// the search window preview command, the editor, and the command opening the browser
+    fn check_commands(config: &GooseberryConfig) -> Vec<Check> {
+        let mut checks = Vec::new();
+        if let Some(preview_command) = &config.preview_command {
+            let command = preview_command
+                .split_whitespace()
+                .next()
+                .unwrap_or_default();
+            checks.push(match find_command(command) {
+                Some(path) => Check::ok("preview command", format!("{} ({:?})", command, path)),
+                None => Check::failed(
+                    "preview command",
+                    format!("`{}` isn't installed (or isn't on PATH)", command),
+                    "Install it, or remove `preview_command` from the config file to render previews in gooseberry",
+                ),
+            });
+        }
+
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .ok()
+            .filter(|editor| !editor.trim().is_empty());
+        checks.push(match editor {
+            Some(editor) => {
+                let command = editor.split_whitespace().next().unwrap_or_default();
+                match find_command(command) {
+                    Some(_) => Check::ok("editor", editor.as_str()),
+                    None => Check::warning(
+                        "editor",
+                        format!("`{}` isn't installed (or isn't on PATH)", command),
+                        "Set $EDITOR (or $VISUAL) to an installed editor, for editing annotations and templates",
+                    ),
+                }
+            }
+            None => Check::warning(
+                "editor",
+                "neither $VISUAL nor $EDITOR is set",
+                "Set $EDITOR to your editor, for editing annotations and templates",
+            ),
+        });
+
+        let opener = if cfg!(target_os = "macos") {
+            Some("open")
+        } else if cfg!(target_os = "windows") {
+            None
+        } else {
+            Some("xdg-open")
+        };
+        if let Some(opener) = opener {
+            checks.push(match find_command(opener) {
+                Some(_) => Check::ok("browser", format!("opened with {}", opener)),
+                None => Check::warning(
+                    "browser",
+                    format!("`{}` isn't installed", opener),
+                    "Install it (e.g. with xdg-utils) to open annotations with `gooseberry open`",
+                ),
+            });
+        }
+        checks
+    }
+}
@@ -0,0 +1,56 @@
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::visibility::visibility;
+
+/// Prints the API calls updating annotations would make, with the tags each would gain (+) and lose (-)
+/// and whether its text, group, or visibility would change
+pub(crate) fn print_updates(before: &[Annotation], after: &[Annotation]) {
+    for (old, new) in before.iter().zip(after) {
+        let mut changes: Vec<_> = new
+            .tags
+            .iter()
+            .filter(|tag| !old.tags.contains(tag))
+            .map(|tag| format!("+{}", tag))
+            .collect();
+        changes.extend(
+            old.tags
+                .iter()
+                .filter(|tag| !new.tags.contains(tag))
+                .map(|tag| format!("-{}", tag)),
+        );
+        if old.text != new.text {
+            changes.push("text edited".to_owned());
+        }
+        if old.group != new.group {
+            changes.push(format!("moved to group {}", new.group));
+        }
+        if old.permissions.read != new.permissions.read {
+            changes
+                .push(format!("{} -> {}", visibility(old), visibility(new)).to_ascii_lowercase());
+        }
+        println!(
+            "PATCH /api/annotations/{} ({}): {}",
+            new.id,
+            new.uri,
+            changes.join(", ")
+        );
+    }
+    println!(
+        "Would update {} annotation(s) on Hypothesis (dry run)",
+        after.len()
+    );
+}
+
+/// Prints the API calls deleting annotations would make
+pub(crate) fn print_deletes(annotations: &[Annotation]) {
+    for annotation in annotations {
+        println!(
+            "DELETE /api/annotations/{} ({})",
+            annotation.id, annotation.uri
+        );
+    }
+    println!(
+        "Would delete {} annotation(s) from Hypothesis and move them to the trash (dry run)",
+        annotations.len()
+    );
+}
@@ -0,0 +1,307 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use color_eyre::Help;
+
+use crate::configuration::GooseberryConfig;
+use crate::errors::Apologize;
+use crate::utils;
+
+/// Start of encrypted database files, with the version of the format
+const MAGIC: &[u8] = b"gooseberry-db\x01";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+/// Environment variable with the database passphrase,
+/// used instead of `database_key_command` or asking for it
+pub const PASSPHRASE_VARIABLE: &str = "GOOSEBERRY_PASSPHRASE";
+
+/// Paths (relative, with `/` separators) and contents of the files in the database folder
+type Files = Vec<(String, Vec<u8>)>;
+
+/// File storing the encrypted database, next to the database folder
+pub fn encrypted_path(db_dir: &Path) -> PathBuf {
+    db_dir.with_extension("enc")
+}
+
+/// Deletes the files and folders in a folder, keeping the folder
+pub fn remove_contents(dir: &Path) -> color_eyre::Result<()> {
+    for path in fs::read_dir(dir)? {
+        let path = path?.path();
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Gets the passphrase from the environment, from `database_key_command`, or by asking for it
+/// (twice, if it's for a `new` encrypted database)
+fn get_passphrase(config: &GooseberryConfig, new: bool) -> color_eyre::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VARIABLE) {
+        return Ok(passphrase);
+    }
+    let passphrase = match &config.database_key_command {
+        Some(key_command) => {
+            let output = utils::shell_command(key_command).output()?;
+            if !output.status.success() {
+                return Err(Apologize::EncryptionError {
+                    message: format!("database_key_command {:?} failed", key_command),
+                }
+                .into());
+            }
+            String::from_utf8(output.stdout)?
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned()
+        }
+        None => utils::password("Database passphrase", new)?
+            .ok_or(Apologize::EncryptionError {
+                message: "No database passphrase".into(),
+            })
+            .suggestion(format!(
+                "Set {} or database_key_command in the config",
+                PASSPHRASE_VARIABLE
+            ))?,
+    };
+    if passphrase.is_empty() {
+        return Err(Apologize::EncryptionError {
+            message: "Empty database passphrase".into(),
+        }
+        .into());
+    }
+    Ok(passphrase)
+}
+
+/// Derives the encryption key from a passphrase with Argon2
+fn derive_key(passphrase: &str, salt: &[u8]) -> color_eyre::Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|error| Apologize::EncryptionError {
+            message: error.to_string(),
+        })?;
+    Ok(key)
+}
+
+/// Files in a folder and its subfolders
+fn pack(dir: &Path) -> color_eyre::Result<Files> {
+    let mut files = Vec::new();
+    let mut folders = vec![dir.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        for path in fs::read_dir(&folder)? {
+            let path = path?.path();
+            if path.is_dir() {
+                folders.push(path);
+            } else {
+                let name = path
+                    .strip_prefix(dir)?
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.push((name, fs::read(&path)?));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Writes packed files into a folder
+fn unpack(files: Files, dir: &Path) -> color_eyre::Result<()> {
+    for (name, contents) in files {
+        if name.split('/').any(|part| part.is_empty() || part == "..") {
+            continue;
+        }
+        let path: PathBuf = dir.join(name.split('/').collect::<PathBuf>());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+/// Reads and decrypts an encrypted database file, returning the salt and the key
+/// along with the packed files
+fn read_encrypted(
+    config: &GooseberryConfig,
+    encrypted: &Path,
+) -> color_eyre::Result<([u8; SALT_LENGTH], Key, Files)> {
+    let data = fs::read(encrypted)?;
+    if data.len() < MAGIC.len() + SALT_LENGTH + NONCE_LENGTH || !data.starts_with(MAGIC) {
+        return Err(Apologize::EncryptionError {
+            message: format!("{:?} isn't an encrypted gooseberry database", encrypted),
+        }
+        .into());
+    }
+    let (salt, rest) = data[MAGIC.len()..].split_at(SALT_LENGTH);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+    let key = derive_key(&get_passphrase(config, false)?, salt)?;
+    let plaintext = ChaCha20Poly1305::new(&key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: MAGIC,
+            },
+        )
+        .map_err(|_| Apologize::WrongPassphrase)?;
+    let mut salt_bytes = [0; SALT_LENGTH];
+    salt_bytes.copy_from_slice(salt);
+    Ok((salt_bytes, key, bincode::deserialize(&plaintext)?))
+}
+
+/// New private temporary folder, in memory (under `/dev/shm`) where available
+fn temporary_dir() -> color_eyre::Result<PathBuf> {
+    let shm = Path::new("/dev/shm");
+    let parent = if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    };
+    let dir = parent.join(format!("gooseberry-{:016x}", OsRng.next_u64()));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir)?;
+    Ok(dir)
+}
+
+/// Decrypts an encrypted database back into the database folder and deletes the encrypted file,
+/// for when `encrypt_database` is turned off. Does nothing if there's no encrypted database
+pub fn decrypt_database(config: &GooseberryConfig) -> color_eyre::Result<()> {
+    let encrypted = encrypted_path(&config.db_dir);
+    if !encrypted.exists() {
+        return Ok(());
+    }
+    let (_, _, files) = read_encrypted(config, &encrypted)?;
+    remove_contents(&config.db_dir)?;
+    unpack(files, &config.db_dir)?;
+    fs::remove_file(encrypted)?;
+    eprintln!("Decrypted the database, since encrypt_database is off");
+    Ok(())
+}
+
+/// Decrypted copy of the database folder in a temporary folder, deleted on drop
+pub struct WorkingCopy {
+    /// Temporary folder with the decrypted database
+    dir: PathBuf,
+    /// Encrypted database file
+    encrypted: PathBuf,
+    salt: [u8; SALT_LENGTH],
+    key: Key,
+}
+
+impl WorkingCopy {
+    /// Decrypts the database into a new temporary folder.
+    ///
+    /// The first time round, encrypts the files in the database folder instead,
+    /// and deletes them once they're encrypted
+    pub fn open(config: &GooseberryConfig) -> color_eyre::Result<Self> {
+        let encrypted = encrypted_path(&config.db_dir);
+        if encrypted.exists() {
+            let (salt, key, files) = read_encrypted(config, &encrypted)?;
+            let working_copy = WorkingCopy {
+                dir: temporary_dir()?,
+                encrypted,
+                salt,
+                key,
+            };
+            unpack(files, &working_copy.dir)?;
+            Ok(working_copy)
+        } else {
+            let mut salt = [0; SALT_LENGTH];
+            OsRng.fill_bytes(&mut salt);
+            let key = derive_key(&get_passphrase(config, true)?, &salt)?;
+            let working_copy = WorkingCopy {
+                dir: temporary_dir()?,
+                encrypted,
+                salt,
+                key,
+            };
+            unpack(pack(&config.db_dir)?, &working_copy.dir)?;
+            working_copy.save()?;
+            remove_contents(&config.db_dir)?;
+            Ok(working_copy)
+        }
+    }
+
+    /// Folder with the decrypted database
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Encrypts data with the database key, in the format of the encrypted database file
+    pub fn seal(&self, plaintext: &[u8]) -> color_eyre::Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| Apologize::EncryptionError {
+                message: "Couldn't encrypt the database".into(),
+            })?;
+        let mut data =
+            Vec::with_capacity(MAGIC.len() + SALT_LENGTH + NONCE_LENGTH + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.salt);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    /// Decrypts data sealed with the database key (with `seal`)
+    pub fn unseal(&self, data: &[u8]) -> color_eyre::Result<Vec<u8>> {
+        if data.len() < MAGIC.len() + SALT_LENGTH + NONCE_LENGTH || !data.starts_with(MAGIC) {
+            return Err(Apologize::EncryptionError {
+                message: "Not encrypted by gooseberry".into(),
+            }
+            .into());
+        }
+        let (salt, rest) = data[MAGIC.len()..].split_at(SALT_LENGTH);
+        if salt != self.salt {
+            return Err(Apologize::EncryptionError {
+                message: "Encrypted with a different passphrase".into(),
+            }
+            .into());
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+        Ok(ChaCha20Poly1305::new(&self.key)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| Apologize::WrongPassphrase)?)
+    }
+
+    /// Encrypts the working copy into the encrypted database file,
+    /// replacing the previous one only once the new one is written
+    pub fn save(&self) -> color_eyre::Result<()> {
+        let data = self.seal(&bincode::serialize(&pack(&self.dir)?)?)?;
+        let partial = self.encrypted.with_extension("enc.partial");
+        fs::write(&partial, data)?;
+        fs::rename(partial, &self.encrypted)?;
+        Ok(())
+    }
+}
+
+impl Drop for WorkingCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,163 @@
+use hypothesis::annotations::Annotation;
+use serde_json::Value as Json;
+
+/// `conformsTo` of fragment selectors holding an EPUB CFI
+const CFI_SPEC: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+
+/// Where an annotation is in an EPUB, from the canonical fragment identifier (CFI) of its selectors.
+///
+/// Ordered by reading position
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EpubLocation {
+    /// Step indices of the CFI path, from the package document down to the highlighted text
+    pub position: Vec<u32>,
+    /// Character offset in the text the path ends at
+    pub offset: u32,
+    /// Spine position of the chapter (content document), starting from 1
+    pub chapter: u32,
+    /// ID of the chapter's spine item, if the CFI has one
+    pub chapter_id: Option<String>,
+    /// Title of the chapter, if Hypothesis has one
+    pub chapter_title: Option<String>,
+    /// The CFI itself
+    pub cfi: String,
+}
+
+/// A parsed CFI path
+#[derive(Debug, Default)]
+struct CfiPath {
+    steps: Vec<u32>,
+    /// Step indices of the part before the first indirection (`!`), in the package document
+    package_steps: usize,
+    /// ID assertion (`[id]`) of the last step in the package document
+    spine_id: Option<String>,
+    offset: u32,
+}
+
+/// Parses the path of a CFI, e.g. `epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)` or `/6/4`.
+/// Ranges (`epubcfi(/6/4!/4/10,/2/1:3,/2/1:7)`) give the path of their start.
+/// Assertions other than the spine item ID are skipped, along with temporal and spatial offsets
+fn parse_cfi(cfi: &str) -> Option<CfiPath> {
+    let cfi = cfi.trim();
+    let cfi = cfi
+        .strip_prefix("epubcfi(")
+        .and_then(|cfi| cfi.strip_suffix(')'))
+        .unwrap_or(cfi);
+    let mut path = CfiPath::default();
+    let mut indirected = false;
+    let mut chars = cfi.chars().peekable();
+    // Commas separate the parent path of a range from its start and end
+    let mut commas = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '/' => {
+                let mut number = String::new();
+                while let Some(digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    number.push(*digit);
+                    chars.next();
+                }
+                path.steps.push(number.parse().ok()?);
+                if !indirected {
+                    path.package_steps = path.steps.len();
+                }
+            }
+            '!' => indirected = true,
+            '[' => {
+                let mut assertion = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '^' => assertion.extend(chars.next()),
+                        ']' => break,
+                        c => assertion.push(c),
+                    }
+                }
+                if !indirected && path.steps.len() == path.package_steps {
+                    // Only the ID, without parameters like `;s=b`
+                    let id = assertion.split(';').next().unwrap_or_default();
+                    path.spine_id = Some(id.to_owned()).filter(|id| !id.is_empty());
+                }
+            }
+            ':' => {
+                let mut number = String::new();
+                while let Some(digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    number.push(*digit);
+                    chars.next();
+                }
+                path.offset = number.parse().ok()?;
+            }
+            ',' => {
+                commas += 1;
+                if commas > 1 {
+                    break;
+                }
+            }
+            // Temporal and spatial offsets
+            '~' | '@' => {
+                while chars
+                    .peek()
+                    .filter(|c| !matches!(c, '/' | '!' | '[' | ','))
+                    .is_some()
+                {
+                    chars.next();
+                }
+            }
+            c if c.is_whitespace() => {}
+            _ => return None,
+        }
+    }
+    if path.steps.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Location of an annotation made on an EPUB, if it has a CFI.
+///
+/// Uses the full CFI of an EPUB CFI fragment selector if there is one, and otherwise the chapter CFI
+/// of the `EPUBContentSelector` Hypothesis adds to EPUB annotations, with the text position as the offset
+pub(crate) fn epub_location(annotation: &Annotation) -> Option<EpubLocation> {
+    let targets = serde_json::to_value(&annotation.target).ok()?;
+    let selectors: Vec<&Json> = targets
+        .as_array()?
+        .iter()
+        .filter_map(|target| target.get("selector")?.as_array())
+        .flatten()
+        .collect();
+    let of_type = |selector_type: &'static str| {
+        selectors.iter().copied().filter(move |selector| {
+            selector.get("type").and_then(Json::as_str) == Some(selector_type)
+        })
+    };
+    let content = of_type("EPUBContentSelector").next();
+    let chapter_title = content
+        .and_then(|selector| selector.get("title")?.as_str())
+        .map(str::to_owned);
+    let fragment_cfi = of_type("FragmentSelector").find_map(|selector| {
+        let value = selector.get("value")?.as_str()?;
+        let conforms_to = selector.get("conformsTo").and_then(Json::as_str);
+        Some(value).filter(|value| value.starts_with("epubcfi(") || conforms_to == Some(CFI_SPEC))
+    });
+    let (cfi, mut path) = match fragment_cfi {
+        Some(cfi) => (cfi, parse_cfi(cfi)?),
+        None => {
+            let cfi = content?.get("cfi")?.as_str()?;
+            let mut path = parse_cfi(cfi)?;
+            // The chapter CFI doesn't go into the chapter, so the whole path is in the package document
+            path.package_steps = path.steps.len();
+            path.offset = of_type("TextPositionSelector")
+                .find_map(|selector| selector.get("start")?.as_u64())
+                .unwrap_or_default() as u32;
+            (cfi, path)
+        }
+    };
+    let chapter = path.steps[path.package_steps.max(1) - 1] / 2;
+    Some(EpubLocation {
+        offset: path.offset,
+        chapter,
+        chapter_id: path.spine_id.take(),
+        chapter_title,
+        cfi: cfi.to_owned(),
+        position: path.steps,
+    })
+}
@@ -0,0 +1,241 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::Help;
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::cli::{ExportFormat, ViewFormat};
+use crate::gooseberry::helpers::truncate;
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::Gooseberry;
+
+/// A flattened annotation, one per CSV row
+#[derive(Debug, Serialize)]
+struct AnnotationRecord {
+    id: String,
+    created: String,
+    updated: String,
+    uri: String,
+    title: String,
+    group: String,
+    /// Semicolon-separated tags
+    tags: String,
+    /// Newline-separated highlighted quotes
+    highlight: String,
+    text: String,
+    incontext: String,
+}
+
+impl From<AnnotationTemplate> for AnnotationRecord {
+    fn from(annotation: AnnotationTemplate) -> Self {
+        AnnotationRecord {
+            created: annotation.annotation.created.to_rfc3339(),
+            updated: annotation.annotation.updated.to_rfc3339(),
+            tags: annotation.annotation.tags.join(";"),
+            highlight: annotation.highlight.join("\n"),
+            title: annotation.title,
+            incontext: annotation.incontext,
+            id: annotation.annotation.id,
+            uri: annotation.annotation.uri,
+            group: annotation.annotation.group,
+            text: annotation.annotation.text,
+        }
+    }
+}
+
+/// Makes text safe for an HTML field of a tab-separated Anki import file
+fn anki_field(text: &str) -> String {
+    text.trim()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\t', " ")
+        .replace('\n', "<br>")
+}
+
+/// Characters of an annotation's text (or quote) in quickfix entries
+const QUICKFIX_LENGTH: usize = 100;
+
+/// First line of an annotation's text, or of its quote if it has none
+fn first_line(annotation: &AnnotationTemplate) -> Option<&str> {
+    let text = annotation.annotation.text.trim();
+    let text = if text.is_empty() {
+        annotation.highlight.first().map(|quote| quote.trim())?
+    } else {
+        text
+    };
+    text.lines().map(str::trim).find(|line| !line.is_empty())
+}
+
+/// Line (starting at 1) of an annotation in a knowledge base file:
+/// the first with its ID (as in the default templates), else the first with its text or quote, else the first line
+fn annotation_line(annotation: &AnnotationTemplate, lines: &[String]) -> usize {
+    let find = |pattern: &str| lines.iter().position(|line| line.contains(pattern));
+    find(&annotation.annotation.id)
+        .or_else(|| first_line(annotation).and_then(find))
+        .map_or(1, |index| index + 1)
+}
+
+/// ## Export
+/// Dump annotations in machine-readable formats
+impl Gooseberry {
+    /// Export annotations to a file (or stdout if no file is given)
+    ///
+    /// JSON output has the same keys as those available in the annotation template
+    pub fn export(
+        &self,
+        annotations: Vec<Annotation>,
+        format: ExportFormat,
+        file: Option<&Path>,
+    ) -> color_eyre::Result<()> {
+        let writer: Box<dyn io::Write> = match file {
+            Some(file) => Box::new(fs::File::create(file)?),
+            None => Box::new(io::stdout()),
+        };
+        let mut buffered = io::BufWriter::new(writer);
+        let annotations = annotations
+            .into_iter()
+            .map(|a| self.annotation_template(a))
+            .collect::<color_eyre::Result<Vec<_>>>()?
+            .into_iter();
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_writer_pretty(&mut buffered, &annotations.collect::<Vec<_>>())?;
+                writeln!(&mut buffered)?;
+                buffered.flush()?;
+            }
+            ExportFormat::Csv => {
+                let mut csv_writer = csv::Writer::from_writer(buffered);
+                for annotation in annotations {
+                    csv_writer.serialize(AnnotationRecord::from(annotation))?;
+                }
+                csv_writer.flush()?;
+            }
+            ExportFormat::Anki => {
+                // Header lines tell Anki how to read the columns.
+                // Annotation IDs are used as note GUIDs so that re-importing updates existing cards
+                writeln!(
+                    &mut buffered,
+                    "#separator:tab\n#html:true\n#guid column:1\n#tags column:4"
+                )?;
+                let flashcard_tag = self.config.flashcard_tag();
+                for annotation in annotations.filter(|a| {
+                    !a.highlight.is_empty() && a.annotation.tags.iter().any(|t| t == flashcard_tag)
+                }) {
+                    // Anki tags can't have spaces
+                    let tags: Vec<_> = annotation
+                        .annotation
+                        .tags
+                        .iter()
+                        .filter(|t| t.as_str() != flashcard_tag)
+                        .map(|t| t.replace(' ', "_"))
+                        .collect();
+                    writeln!(
+                        &mut buffered,
+                        "{}\t{}\t{}\t{}",
+                        annotation.annotation.id,
+                        anki_field(&annotation.highlight.join("\n")),
+                        anki_field(&annotation.annotation.text),
+                        anki_field(&tags.join(" "))
+                    )?;
+                }
+                buffered.flush()?;
+            }
+            ExportFormat::Readwise => {
+                return Err(Apologize::ReadwiseError {
+                    message: "Readwise exports are sent to the Readwise API with `export_readwise`"
+                        .into(),
+                }
+                .into())
+            }
+        }
+        Ok(())
+    }
+    /// Print annotations without any interaction, e.g. for scripts
+    ///
+    /// Fails if there are no annotations, so that scripts can tell when nothing matched
+    pub fn print_annotations(
+        &self,
+        annotations: Vec<Annotation>,
+        format: ViewFormat,
+    ) -> color_eyre::Result<()> {
+        if annotations.is_empty() {
+            return Err(Apologize::NoMatches.into());
+        }
+        match format {
+            ViewFormat::Json => self.export(annotations, ExportFormat::Json, None)?,
+            ViewFormat::Ids => {
+                for annotation in annotations {
+                    println!("{}", annotation.id);
+                }
+            }
+            ViewFormat::Uris => {
+                let uris: BTreeSet<_> = annotations.into_iter().map(|a| a.uri).collect();
+                for uri in uris {
+                    println!("{}", uri);
+                }
+            }
+            ViewFormat::Quickfix => self.print_quickfix(annotations)?,
+            ViewFormat::Plain => {
+                let hbs = self.get_handlebars()?;
+                for annotation in annotations {
+                    print!(
+                        "{}",
+                        hbs.render("annotation", &self.annotation_template(annotation)?)?
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Prints a `file:line: text` entry per annotation, pointing into the knowledge base file
+    /// it was written to by the last `make`, for the quickfix / location lists of editors
+    fn print_quickfix(&self, annotations: Vec<Annotation>) -> color_eyre::Result<()> {
+        let locations = self.kb_locations()?;
+        let mut files: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        let (mut printed, mut missing) = (0, 0);
+        for annotation in annotations {
+            let path = match locations.get(&annotation.id)? {
+                Some(path) => PathBuf::from(std::str::from_utf8(&path)?),
+                None => {
+                    missing += 1;
+                    continue;
+                }
+            };
+            if !files.contains_key(&path) {
+                let lines = match fs::read_to_string(&path) {
+                    Ok(contents) => contents.lines().map(str::to_owned).collect(),
+                    Err(_) => {
+                        missing += 1;
+                        continue;
+                    }
+                };
+                files.insert(path.clone(), lines);
+            }
+            let annotation = AnnotationTemplate::from_annotation(annotation);
+            println!(
+                "{}:{}: {}",
+                path.display(),
+                annotation_line(&annotation, &files[&path]),
+                truncate(first_line(&annotation).unwrap_or_default(), QUICKFIX_LENGTH)
+            );
+            printed += 1;
+        }
+        if printed == 0 {
+            return Err(Apologize::KBError {
+                message: "None of the annotations are in the knowledge base".into(),
+            })
+            .suggestion("Add them with 'gooseberry make'");
+        }
+        if missing > 0 {
+            eprintln!(
+                "{} annotation(s) aren't in the knowledge base, add them with 'gooseberry make'",
+                missing
+            );
+        }
+        Ok(())
+    }
+}
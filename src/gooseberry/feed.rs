@@ -0,0 +1,135 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::Utc;
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::Gooseberry;
+
+/// Escapes text for XML element content and attribute values
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Paragraphs of plain text as HTML, with line breaks kept
+pub(crate) fn paragraphs(text: &str) -> String {
+    text.split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| format!("<p>{}</p>", xml_escape(paragraph).replace('\n', "<br>")))
+        .collect()
+}
+
+/// An Atom `<entry>` for an annotation: the document title and in-context link,
+/// the quote, note, and tags as HTML content, and the tags as categories
+fn entry(annotation: &AnnotationTemplate) -> String {
+    let mut content = String::new();
+    for quote in &annotation.highlight {
+        content.push_str(&format!("<blockquote>{}</blockquote>", paragraphs(quote)));
+    }
+    content.push_str(&paragraphs(&annotation.annotation.text));
+    if !annotation.annotation.tags.is_empty() {
+        content.push_str(&format!(
+            "<p>Tags: {}</p>",
+            xml_escape(&annotation.annotation.tags.join(", "))
+        ));
+    }
+    let author = annotation
+        .display_name
+        .clone()
+        .unwrap_or_else(|| annotation.username.to_owned());
+    let categories: String = annotation
+        .annotation
+        .tags
+        .iter()
+        .map(|tag| format!("\n    <category term=\"{}\"/>", xml_escape(tag)))
+        .collect();
+    format!(
+        r#"  <entry>
+    <id>https://hypothes.is/a/{}</id>
+    <title>{}</title>
+    <link rel="alternate" href="{}"/>
+    <published>{}</published>
+    <updated>{}</updated>
+    <author><name>{}</name></author>{}
+    <content type="html">{}</content>
+  </entry>
+"#,
+        xml_escape(&annotation.annotation.id),
+        xml_escape(&annotation.title),
+        xml_escape(&annotation.incontext),
+        annotation.annotation.created.to_rfc3339(),
+        annotation.annotation.updated.to_rfc3339(),
+        xml_escape(&author),
+        categories,
+        xml_escape(&content),
+    )
+}
+
+/// ## Feed
+/// Publishing annotations as an Atom feed
+impl Gooseberry {
+    /// Writes the `limit` most recently created of the given annotations as an Atom feed to a file (or stdout).
+    ///
+    /// The feed links to `link` (the page of the gooseberry group on Hypothesis if not given),
+    /// and each entry to the annotation in context
+    pub fn feed(
+        &self,
+        mut annotations: Vec<Annotation>,
+        limit: usize,
+        title: Option<String>,
+        link: Option<String>,
+        file: Option<&Path>,
+    ) -> color_eyre::Result<()> {
+        annotations.sort_by_key(|a| Reverse(a.created));
+        annotations.truncate(limit);
+        let link = link.unwrap_or_else(|| {
+            format!(
+                "https://hypothes.is/groups/{}",
+                self.config
+                    .hypothesis_group
+                    .as_deref()
+                    .unwrap_or("__world__")
+            )
+        });
+        let title = title.unwrap_or_else(|| "Annotations".to_owned());
+        let updated = annotations
+            .iter()
+            .map(|a| a.updated)
+            .max()
+            .unwrap_or_else(Utc::now);
+        let mut entries = String::new();
+        for annotation in annotations {
+            entries.push_str(&entry(&self.annotation_template(annotation)?));
+        }
+        let writer: Box<dyn io::Write> = match file {
+            Some(file) => Box::new(fs::File::create(file)?),
+            None => Box::new(io::stdout()),
+        };
+        let mut buffered = io::BufWriter::new(writer);
+        write!(
+            &mut buffered,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>{0}</id>
+  <title>{1}</title>
+  <link rel="alternate" href="{0}"/>
+  <updated>{2}</updated>
+  <generator>gooseberry</generator>
+{3}</feed>
+"#,
+            xml_escape(&link),
+            xml_escape(&title),
+            updated.to_rfc3339(),
+            entries
+        )?;
+        buffered.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use hypothesis::annotations::{Annotation, SearchQuery};
+
+use crate::errors::Apologize;
+use crate::gooseberry::titles::http_client;
+use crate::gooseberry::Gooseberry;
+
+/// Largest page of annotations the Hypothesis search API returns
+const PAGE_SIZE: usize = 200;
+/// Number of requests to the Hypothesis API in flight at the same time
+const CONCURRENT_REQUESTS: usize = 4;
+/// Largest number of time windows the annotations to sync are split into
+const MAX_WINDOWS: usize = 32;
+/// Number of times a request is retried after a transient failure
+pub(crate) const MAX_RETRIES: u32 = 5;
+/// Wait before the first retry, doubled for each retry after it
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest wait before a retry, even if the API asks for more
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Time to wait for a page of annotations
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A window of time whose annotations are being fetched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncWindow {
+    /// `search_after` value of the next page: the update time of the last annotation fetched
+    cursor: String,
+    /// End of the window, `None` for the last one
+    end: Option<DateTime<Utc>>,
+}
+
+/// Progress of the sync of a group, stored after each round of pages
+/// so that an interrupted sync continues where it stopped
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncProgress {
+    /// Windows with annotations left to fetch
+    windows: Vec<SyncWindow>,
+    /// Latest update time of the annotations fetched so far, where the next sync starts
+    pub(crate) latest: Option<DateTime<Utc>>,
+    /// Number of annotations fetched so far
+    pub(crate) fetched: usize,
+    /// Number of annotations to fetch, as of the first page
+    pub(crate) total: usize,
+}
+
+impl SyncProgress {
+    /// Whether all annotations have been fetched
+    pub(crate) fn is_done(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Counts fetched annotations and moves `latest` forward
+    fn add(&mut self, annotations: &[Annotation]) {
+        self.fetched += annotations.len();
+        self.latest = annotations
+            .iter()
+            .map(|annotation| annotation.updated)
+            .chain(self.latest)
+            .max();
+    }
+}
+
+/// A page of search results
+#[derive(Debug, Deserialize)]
+struct SearchResults {
+    /// Number of annotations matching the search (not just the ones in the page)
+    total: usize,
+    rows: Vec<Annotation>,
+}
+
+/// Whether a failed request is worth trying again: rate limiting and server errors
+pub(crate) fn is_transient(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Time to wait before trying again: the seconds asked for in the `Retry-After` header,
+/// or exponential backoff if there isn't one
+pub(crate) fn retry_delay(response: Option<&reqwest::Response>, attempt: u32) -> Duration {
+    response
+        .and_then(|response| response.headers().get(reqwest::header::RETRY_AFTER))
+        .and_then(|retry_after| retry_after.to_str().ok())
+        .and_then(|retry_after| retry_after.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_else(|| INITIAL_BACKOFF * 2u32.pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+/// Search API requests for the annotations of a user (or everyone) in a group, oldest update first
+struct Searcher<'a> {
+    client: reqwest::Client,
+    api_url: String,
+    key: &'a str,
+    /// `None` for everyone's annotations
+    user: Option<String>,
+    group: &'a str,
+}
+
+impl Searcher<'_> {
+    /// Page of annotations updated after `search_after`.
+    /// Retries rate-limited requests, server errors, and connection problems
+    #[tracing::instrument(level = "debug", skip(self), fields(group = self.group))]
+    async fn page(&self, search_after: &str) -> color_eyre::Result<SearchResults> {
+        let mut attempt = 0;
+        loop {
+            let limit = PAGE_SIZE.to_string();
+            let mut query = vec![
+                ("limit", limit.as_str()),
+                ("sort", "updated"),
+                ("order", "asc"),
+                ("search_after", search_after),
+                ("group", self.group),
+            ];
+            if let Some(user) = &self.user {
+                query.push(("user", user));
+            }
+            let result = self
+                .client
+                .get(format!("{}/search", self.api_url))
+                .bearer_auth(self.key)
+                .query(&query)
+                .send()
+                .await;
+            let delay = match result {
+                Ok(response) if response.status().is_success() => {
+                    let results: SearchResults = serde_json::from_slice(&response.bytes().await?)?;
+                    tracing::debug!(
+                        rows = results.rows.len(),
+                        total = results.total,
+                        "fetched a page"
+                    );
+                    return Ok(results);
+                }
+                Ok(response) if is_transient(response.status()) && attempt < MAX_RETRIES => {
+                    tracing::warn!(status = %response.status(), attempt, "retrying the request");
+                    retry_delay(Some(&response), attempt)
+                }
+                Ok(response) => {
+                    return Err(Apologize::SyncError {
+                        message: format!(
+                            "The Hypothesis API returned {}: {}",
+                            response.status(),
+                            response.text().await.unwrap_or_default()
+                        ),
+                    }
+                    .into())
+                }
+                Err(error)
+                    if (error.is_timeout() || error.is_connect() || error.is_request())
+                        && attempt < MAX_RETRIES =>
+                {
+                    tracing::warn!(%error, attempt, "retrying the request");
+                    retry_delay(None, attempt)
+                }
+                Err(error) => return Err(error.into()),
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Next page of a window: its annotations, and the rest of the window (`None` once it's done)
+    async fn next_page(
+        &self,
+        window: SyncWindow,
+    ) -> color_eyre::Result<(Vec<Annotation>, Option<SyncWindow>)> {
+        let page = self.page(&window.cursor).await?;
+        let full = page.rows.len() == PAGE_SIZE;
+        let last = match page.rows.last() {
+            Some(annotation) => annotation.updated,
+            None => return Ok((Vec::new(), None)),
+        };
+        let end = window.end;
+        let annotations = page
+            .rows
+            .into_iter()
+            .filter(|annotation| !matches!(end, Some(end) if annotation.updated >= end))
+            .collect();
+        let rest = if !full || matches!(end, Some(end) if last >= end) {
+            None
+        } else {
+            Some(SyncWindow {
+                cursor: last.to_rfc3339(),
+                end,
+            })
+        };
+        Ok((annotations, rest))
+    }
+}
+
+/// ## Fetching
+/// Fetching the annotations to sync from the Hypothesis search API,
+/// with requests in parallel and retried after transient failures
+impl Gooseberry {
+    /// Account ID of the user on the Hypothesis server at the configured API URL.
+    /// The API client guesses the authority of accounts from the API's domain,
+    /// so for servers other than hypothes.is it's taken from the user's profile
+    async fn user_id(&self) -> color_eyre::Result<String> {
+        if self.api.api_url == hypothesis::API_URL {
+            return Ok(self.api.user.0.to_owned());
+        }
+        Ok(self
+            .api
+            .fetch_user_profile()
+            .await?
+            .userid
+            .map_or_else(|| self.api.user.0.to_owned(), |id| id.0))
+    }
+
+    async fn searcher<'a>(&'a self, group: &'a str) -> color_eyre::Result<Searcher<'a>> {
+        let key = self
+            .config
+            .hypothesis_key
+            .as_deref()
+            .ok_or(Apologize::ConfigError {
+                message: "Hypothesis developer API key isn't stored".into(),
+            })?;
+        Ok(Searcher {
+            client: http_client(REQUEST_TIMEOUT)?,
+            user: if self.config.all_users() {
+                None
+            } else {
+                Some(self.user_id().await?)
+            },
+            api_url: self.api.api_url.clone(),
+            key,
+            group,
+        })
+    }
+
+    /// First page of the annotations in a group added or updated since its last sync,
+    /// and the progress of fetching the rest.
+    ///
+    /// If there's more than one page of them, the time from the end of the first page until now
+    /// is split into windows, which `fetch_batch` fetches in parallel
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn start_fetch(
+        &self,
+        group: &str,
+    ) -> color_eyre::Result<(Vec<Annotation>, SyncProgress)> {
+        let first = self
+            .searcher(group)
+            .await?
+            .page(&self.get_sync_time(group)?)
+            .await?;
+        let mut progress = SyncProgress {
+            total: first.total,
+            ..SyncProgress::default()
+        };
+        progress.add(&first.rows);
+        let start = match first.rows.last() {
+            Some(last) if first.rows.len() == PAGE_SIZE => last.updated,
+            _ => return Ok((first.rows, progress)),
+        };
+        let windows = first
+            .total
+            .saturating_sub(PAGE_SIZE)
+            .div_ceil(PAGE_SIZE)
+            .clamp(1, MAX_WINDOWS) as i32;
+        tracing::info!(
+            total = first.total,
+            windows,
+            "fetching the rest in parallel windows"
+        );
+        let step = (Utc::now() - start) / windows;
+        // Windows start just before the end of the previous one,
+        // so that annotations updated exactly at the boundary aren't missed
+        progress.windows = (0..windows)
+            .map(|i| SyncWindow {
+                cursor: (start + step * i - chrono::Duration::microseconds(1)).to_rfc3339(),
+                end: if i + 1 == windows {
+                    None
+                } else {
+                    Some(start + step * (i + 1))
+                },
+            })
+            .collect();
+        Ok((first.rows, progress))
+    }
+
+    /// Fetches the next page of each window in parallel, with at most `CONCURRENT_REQUESTS` requests in flight.
+    /// Returns the annotations (oldest update first) and updates the progress
+    #[tracing::instrument(level = "debug", skip(self, progress), fields(windows = progress.windows.len()))]
+    pub(crate) async fn fetch_batch(
+        &self,
+        group: &str,
+        progress: &mut SyncProgress,
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        let searcher = self.searcher(group).await?;
+        let pages: Vec<_> = stream::iter(std::mem::take(&mut progress.windows))
+            .map(|window| searcher.next_page(window))
+            .buffered(CONCURRENT_REQUESTS)
+            .collect()
+            .await;
+        let mut annotations = HashMap::new();
+        for page in pages {
+            let (page, rest) = page?;
+            for annotation in page {
+                annotations.insert(annotation.id.to_owned(), annotation);
+            }
+            progress.windows.extend(rest);
+        }
+        let mut annotations: Vec<_> = annotations.into_values().collect();
+        annotations.sort_by_key(|annotation| annotation.updated);
+        progress.add(&annotations);
+        Ok(annotations)
+    }
+
+    /// All annotations in a group added or updated since its last sync, without storing any progress
+    pub(crate) async fn fetch_since_sync(
+        &self,
+        group: &str,
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        let (mut annotations, mut progress) = self.start_fetch(group).await?;
+        while !progress.is_done() {
+            annotations.extend(self.fetch_batch(group, &mut progress).await?);
+        }
+        Ok(annotations)
+    }
+
+    /// Replies (by anyone) to annotations, from Hypothesis: the whole thread of each annotation,
+    /// with up to `CONCURRENT_REQUESTS` threads fetched at a time. Stores them in the reply tree
+    #[tracing::instrument(skip_all, fields(annotations = annotations.len()))]
+    pub(crate) async fn fetch_replies(
+        &self,
+        annotations: &[Annotation],
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        // The first reference of a reply is the start of its thread
+        let threads: HashSet<_> = annotations
+            .iter()
+            .map(|a| (a.references.first().unwrap_or(&a.id), &a.group))
+            .collect();
+        let pages: Vec<_> = stream::iter(threads)
+            .map(|(id, group)| async move {
+                let mut query = SearchQuery {
+                    limit: PAGE_SIZE as u8,
+                    references: id.to_owned(),
+                    group: group.to_owned(),
+                    ..SearchQuery::default()
+                };
+                self.api.search_annotations_return_all(&mut query).await
+            })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .collect()
+            .await;
+        let mut replies = HashMap::new();
+        for page in pages {
+            for reply in page? {
+                self.add_reply(&reply)?;
+                replies.insert(reply.id.to_owned(), reply);
+            }
+        }
+        let mut replies: Vec<_> = replies.into_values().collect();
+        replies.sort_by_key(|reply| reply.created);
+        Ok(replies)
+    }
+}
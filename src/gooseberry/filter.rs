@@ -0,0 +1,607 @@
+use std::io::{self, BufRead};
+
+use chrono::{DateTime, Utc};
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::cli::{DateField, Filters, Visibility};
+use crate::gooseberry::knowledge_base::document_title;
+use crate::gooseberry::stats;
+use crate::gooseberry::visibility::visibility;
+use crate::utils;
+
+/// A parsed filter expression, e.g. `tags:[project-x] AND after:2023-01-01`
+///
+/// Terms are `key:value` pairs with values optionally quoted (`any:"two words"`) or given as a
+/// list (`tags:[a, b]`). Terms can be combined with `AND` (or just whitespace), `OR`, `NOT` and parentheses.
+/// Available keys:
+/// * `tag`/`tags` - annotation has all of these tags
+///   (with a tag delimiter configured, `biology/` matches `biology` and all tags nested under it)
+/// * `uri` - URI matches this glob (`*` and `?` wildcards), or contains it if there are no wildcards
+/// * `site` - annotated page is on this site or one of its subdomains, e.g. `site:nature.com`
+/// * `after`/`before` - annotation created after/before this date (can be colloquial, e.g. "last Friday 8pm",
+///   or a month like `2024-01`)
+/// * `any` - quote, text, tags, or URI contain this pattern (case-insensitive)
+/// * `group` - annotation is in this Hypothesis group
+/// * `title` - document title contains this pattern (case-insensitive), using the titles registered for PDFs
+/// * `is` - `is:hidden` or `is:flagged` for annotations hidden or flagged by moderators
+/// * `visibility` - `private`, `group`, or `world`: who can read the annotation
+/// * `user` - annotation is by this user (username or account ID), with `all_users` on
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Tags(Vec<String>),
+    /// Hierarchical tag and all tags nested under it (tag, delimiter)
+    TagPrefix(String, String),
+    Uri(String),
+    /// Site (host) of the annotated page, including its subdomains
+    Site(String),
+    After(DateTime<Utc>),
+    Before(DateTime<Utc>),
+    Any(String),
+    Group(String),
+    /// Case-insensitive pattern in the document title
+    Title(String),
+    /// Hidden by a group moderator
+    Hidden,
+    /// Flagged for moderation
+    Flagged,
+    Visibility(Visibility),
+    /// Username or account ID of the author
+    User(String),
+    Not(Box<Filter>),
+    /// Matches everything if empty
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Term(String, Vec<String>),
+}
+
+/// Keys of filter terms
+const KEYS: [&str; 14] = [
+    "tag",
+    "tags",
+    "uri",
+    "site",
+    "after",
+    "from",
+    "before",
+    "any",
+    "text",
+    "group",
+    "title",
+    "is",
+    "visibility",
+    "user",
+];
+
+fn filter_error(message: String) -> Apologize {
+    Apologize::FilterError { message }
+}
+
+/// Splits a filter expression into parentheses, operators, and `key:value` terms
+fn tokenize(expression: &str) -> color_eyre::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    let is_delimiter = |c: char| c.is_whitespace() || c == '(' || c == ')';
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::LeftParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::RightParen);
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if is_delimiter(c) || c == ':' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            if chars.peek() != Some(&':') {
+                tokens.push(match word.to_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => {
+                        return Err(filter_error(format!(
+                            "Expected key:value, AND, OR, or NOT, found {:?}",
+                            word
+                        ))
+                        .into())
+                    }
+                });
+                continue;
+            }
+            chars.next();
+            let values = match chars.peek() {
+                Some('"') => {
+                    chars.next();
+                    let value: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                    vec![value]
+                }
+                Some('[') => {
+                    chars.next();
+                    let list: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                    list.split(',')
+                        .map(|v| v.trim().trim_matches('"').to_owned())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                }
+                _ => {
+                    let mut value = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if is_delimiter(c) {
+                            break;
+                        }
+                        value.push(c);
+                        chars.next();
+                    }
+                    vec![value]
+                }
+            };
+            tokens.push(Token::Term(word.to_lowercase(), values));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over filter tokens
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Configured delimiter of hierarchical tags
+    delimiter: Option<&'a str>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> color_eyre::Result<Filter> {
+        let mut filters = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            filters.push(self.parse_and()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.remove(0)
+        } else {
+            Filter::Or(filters)
+        })
+    }
+
+    fn parse_and(&mut self) -> color_eyre::Result<Filter> {
+        let mut filters = vec![self.parse_not()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                Some(Token::Not) | Some(Token::LeftParen) | Some(Token::Term(_, _)) => (),
+                _ => break,
+            }
+            filters.push(self.parse_not()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.remove(0)
+        } else {
+            Filter::And(filters)
+        })
+    }
+
+    fn parse_not(&mut self) -> color_eyre::Result<Filter> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Filter::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> color_eyre::Result<Filter> {
+        match self.next() {
+            Some(Token::LeftParen) => {
+                let filter = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(filter),
+                    _ => Err(filter_error("Missing closing parenthesis".into()).into()),
+                }
+            }
+            Some(Token::Term(key, values)) => Filter::from_term(&key, values, self.delimiter),
+            Some(token) => Err(filter_error(format!("Unexpected {:?}", token)).into()),
+            None => Err(filter_error("Unexpected end of expression".into()).into()),
+        }
+    }
+}
+
+/// Whether a URI pattern has glob wildcards
+pub(crate) fn is_glob(pattern: &str) -> bool {
+    pattern.contains(&['*', '?'][..])
+}
+
+/// Matches a URI against a glob pattern (e.g. a prefix, `https://arxiv.org/abs/2101.*`),
+/// or checks that it contains the pattern if there are no wildcards
+pub(crate) fn uri_matches(pattern: &str, uri: &str) -> bool {
+    if is_glob(pattern) {
+        glob_match(pattern, uri)
+    } else {
+        uri.contains(pattern)
+    }
+}
+
+/// Matches text against a glob pattern with `*` (any number of characters) and `?` (one character)
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<_>, Vec<_>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Splits off tags ending with the tag delimiter, which match all tags nested under them
+fn split_tag_prefixes(tags: Vec<String>, delimiter: Option<&str>) -> (Vec<String>, Vec<Filter>) {
+    match delimiter {
+        Some(delimiter) => {
+            let (prefixes, tags): (Vec<_>, Vec<_>) =
+                tags.into_iter().partition(|tag| tag.ends_with(delimiter));
+            let prefixes = prefixes
+                .into_iter()
+                .map(|tag| {
+                    Filter::TagPrefix(
+                        tag.trim_end_matches(delimiter).to_owned(),
+                        delimiter.to_owned(),
+                    )
+                })
+                .collect();
+            (tags, prefixes)
+        }
+        None => (tags, Vec::new()),
+    }
+}
+
+/// Whether a URI is on a site (given without `www.`) or one of its subdomains
+fn site_matches(site: &str, uri: &str) -> bool {
+    let domain = stats::domain(uri).to_lowercase();
+    domain == site
+        || domain
+            .strip_suffix(site)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+/// Splits a search line like `tag:crispr site:nature.com after:2024-01 gene drive` into a filter expression
+/// made of its `key:value` terms (`-key:value` leaves matching annotations out) and the rest of the words,
+/// the text to search for
+pub fn split_query(line: &str) -> (String, String) {
+    let (mut terms, mut words) = (Vec::new(), Vec::new());
+    for word in split_words(line) {
+        let (negated, term) = match word.strip_prefix('-') {
+            Some(term) => (true, term),
+            None => (false, word.as_str()),
+        };
+        match term.split_once(':') {
+            Some((key, value))
+                if !value.is_empty() && KEYS.contains(&key.to_lowercase().as_str()) =>
+            {
+                terms.push(if negated {
+                    format!("NOT {}", term)
+                } else {
+                    term.to_owned()
+                })
+            }
+            _ => words.push(word.trim_matches('"').to_owned()),
+        }
+    }
+    (terms.join(" AND "), words.join(" "))
+}
+
+/// Splits a line at whitespace outside of quotes and `[...]` lists
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let (mut quoted, mut listed) = (false, false);
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '[' if !quoted => listed = true,
+            ']' if !quoted => listed = false,
+            c if c.is_whitespace() && !quoted && !listed => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            _ => (),
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Case-insensitive search for a pattern in an annotation's quote, text, tags, or URI
+pub(crate) fn matches_any(annotation: &Annotation, pattern: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let contains = |field: &str| field.to_lowercase().contains(&pattern);
+    contains(&annotation.text)
+        || contains(&annotation.uri)
+        || annotation.tags.iter().any(|tag| contains(tag))
+        || utils::get_quotes(annotation).into_iter().any(contains)
+}
+
+/// Case-insensitive search for a pattern in a document title (documents without one never match)
+fn title_matches(title: Option<&str>, pattern: &str) -> bool {
+    title.is_some_and(|title| title.to_lowercase().contains(&pattern.to_lowercase()))
+}
+
+impl Filter {
+    /// Parses a filter expression, with tags ending in `delimiter` matching all tags nested under them
+    pub fn parse(expression: &str, delimiter: Option<&str>) -> color_eyre::Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            position: 0,
+            delimiter,
+        };
+        if parser.peek().is_none() {
+            return Ok(Filter::And(Vec::new()));
+        }
+        let filter = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(filter_error(format!("Unexpected {:?}", token)).into()),
+        }
+    }
+
+    fn from_term(
+        key: &str,
+        mut values: Vec<String>,
+        delimiter: Option<&str>,
+    ) -> color_eyre::Result<Self> {
+        if key == "tag" || key == "tags" {
+            let (tags, mut prefixes) = split_tag_prefixes(values, delimiter);
+            if prefixes.is_empty() {
+                return Ok(Filter::Tags(tags));
+            }
+            if !tags.is_empty() {
+                prefixes.push(Filter::Tags(tags));
+            }
+            return Ok(Filter::And(prefixes));
+        }
+        if values.len() != 1 {
+            return Err(filter_error(format!("{} takes a single value", key)).into());
+        }
+        let value = values.remove(0);
+        let parse_date = |value: &str| {
+            utils::parse_datetime(value)
+                .map_err(|e| filter_error(format!("Couldn't parse date {:?}: {}", value, e)))
+        };
+        Ok(match key {
+            "uri" => Filter::Uri(value),
+            "site" => Filter::Site(value.trim_start_matches("www.").to_lowercase()),
+            "after" | "from" => Filter::After(parse_date(&value)?),
+            "before" => Filter::Before(parse_date(&value)?),
+            "any" | "text" => Filter::Any(value),
+            "group" => Filter::Group(value),
+            "title" => Filter::Title(value),
+            "is" => match value.as_str() {
+                "hidden" => Filter::Hidden,
+                "flagged" => Filter::Flagged,
+                _ => {
+                    return Err(filter_error(format!(
+                        "Unknown value {:?} of is, use is:hidden or is:flagged",
+                        value
+                    ))
+                    .into())
+                }
+            },
+            "user" => Filter::User(value),
+            "visibility" => Filter::Visibility(value.parse().map_err(|_| {
+                filter_error(format!(
+                    "Unknown visibility {:?}, use private, group, or world",
+                    value
+                ))
+            })?),
+            _ => {
+                return Err(filter_error(format!(
+                "Unknown key {:?}, use one of tag(s), uri, site, after, before, any, group, title, is, visibility, user",
+                key
+            ))
+                .into())
+            }
+        })
+    }
+
+    /// Checks if an annotation matches the filter, using the title Hypothesis has for its document
+    pub fn matches(&self, annotation: &Annotation) -> bool {
+        self.matches_titled(annotation, document_title(annotation))
+    }
+
+    /// Checks if an annotation matches the filter, given the title of its document
+    pub fn matches_titled(&self, annotation: &Annotation, title: Option<&str>) -> bool {
+        match self {
+            Filter::Tags(tags) => tags.iter().all(|tag| annotation.tags.contains(tag)),
+            Filter::TagPrefix(parent, delimiter) => annotation.tags.iter().any(|tag| {
+                tag == parent
+                    || (tag.starts_with(parent.as_str())
+                        && tag[parent.len()..].starts_with(delimiter.as_str()))
+            }),
+            Filter::Uri(pattern) => uri_matches(pattern, &annotation.uri),
+            Filter::Site(site) => site_matches(site, &annotation.uri),
+            Filter::After(date) => annotation.created > *date,
+            Filter::Before(date) => annotation.created < *date,
+            Filter::Any(pattern) => matches_any(annotation, pattern),
+            Filter::Group(group) => &annotation.group == group,
+            Filter::Title(pattern) => title_matches(title, pattern),
+            Filter::Hidden => annotation.hidden,
+            Filter::Flagged => annotation.flagged,
+            Filter::Visibility(wanted) => visibility(annotation) == *wanted,
+            Filter::User(user) => {
+                &annotation.user.0 == user || utils::username(&annotation.user.0) == user
+            }
+            Filter::Not(filter) => !filter.matches_titled(annotation, title),
+            Filter::And(filters) => filters.iter().all(|f| f.matches_titled(annotation, title)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches_titled(annotation, title)),
+        }
+    }
+}
+
+impl Filters {
+    /// Removes tags ending with the tag delimiter (the Hypothesis API only matches whole tags)
+    /// and returns filters matching them and all tags nested under them.
+    /// With `--any-tags`, all the tags are removed when there's more than one
+    /// (the Hypothesis API only matches annotations with all of them) and a single filter matching any of them is returned
+    pub(crate) fn take_tag_prefixes(&mut self, delimiter: Option<&str>) -> Vec<Filter> {
+        let (tags, mut prefixes) = split_tag_prefixes(std::mem::take(&mut self.tags), delimiter);
+        if !self.any_tags || tags.len() + prefixes.len() < 2 {
+            self.tags = tags;
+            return prefixes;
+        }
+        prefixes.extend(tags.into_iter().map(|tag| Filter::Tags(vec![tag])));
+        vec![Filter::Or(prefixes)]
+    }
+
+    /// Replaces `-` in the annotation IDs with the IDs read from stdin (one per line)
+    pub(crate) fn read_stdin_ids(&mut self) -> color_eyre::Result<()> {
+        if self.ids.iter().any(|id| id == "-") {
+            self.ids.retain(|id| id != "-");
+            for line in io::stdin().lock().lines() {
+                let line = line?;
+                let id = line.trim();
+                if !id.is_empty() {
+                    self.ids.push(id.to_owned());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start of the date range, from --from, --since, or --today
+    pub(crate) fn after(&self) -> Option<DateTime<Utc>> {
+        self.from.or(self.since).or_else(|| {
+            if self.today {
+                Some(utils::start_of_today())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Date the date filters apply to (--include-updated is the same as --date-field updated)
+    pub(crate) fn date_field(&self) -> DateField {
+        if self.include_updated {
+            DateField::Updated
+        } else {
+            self.date_field
+        }
+    }
+
+    /// Checks the filters against an annotation locally (instead of via the Hypothesis API),
+    /// using the title Hypothesis has for its document
+    pub fn matches(&self, annotation: &Annotation) -> bool {
+        self.matches_titled(annotation, document_title(annotation))
+    }
+
+    /// Checks the filters against an annotation locally, given the title of its document
+    pub fn matches_titled(&self, annotation: &Annotation, title: Option<&str>) -> bool {
+        self.matches_scope(annotation)
+            && self.matches_content(annotation, title) != self.not
+            && !self.exclusions(None).matches_titled(annotation, title)
+    }
+
+    /// Checks the filters that `--not` doesn't apply to: the dates and the group
+    pub(crate) fn matches_scope(&self, annotation: &Annotation) -> bool {
+        let date = match self.date_field() {
+            DateField::Created => annotation.created,
+            DateField::Updated => annotation.updated,
+        };
+        !(matches!(self.after(), Some(after) if date < after)
+            || matches!(self.before, Some(before) if date > before)
+            || matches!(&self.group, Some(group) if group != &annotation.group))
+    }
+
+    /// Filter matching the annotations left out by `--exclude-tags` and `--exclude-uri`,
+    /// with tags ending with the tag delimiter matching all tags nested under them
+    pub(crate) fn exclusions(&self, delimiter: Option<&str>) -> Filter {
+        let (tags, mut excluded) = split_tag_prefixes(self.exclude_tags.clone(), delimiter);
+        excluded.extend(tags.into_iter().map(|tag| Filter::Tags(vec![tag])));
+        excluded.extend(self.exclude_uri.iter().cloned().map(Filter::Uri));
+        Filter::Or(excluded)
+    }
+
+    /// Removes `--exclude-tags` and `--exclude-uri` and returns the filter matching the annotations they leave out
+    pub(crate) fn take_exclusions(&mut self, delimiter: Option<&str>) -> Filter {
+        let exclusions = self.exclusions(delimiter);
+        self.exclude_tags.clear();
+        self.exclude_uri.clear();
+        exclusions
+    }
+
+    /// Checks the filters on what an annotation is (the ones `--not` applies to), given the title of its document
+    pub(crate) fn matches_content(&self, annotation: &Annotation, title: Option<&str>) -> bool {
+        if !self.title.is_empty() && !title_matches(title, &self.title) {
+            return false;
+        }
+        if matches!(&self.uri_pattern, Some(pattern) if !pattern.is_match(&annotation.uri))
+            || matches!(&self.quote_pattern, Some(pattern) if !pattern.is_match(&utils::get_quotes(annotation).join(" ")))
+            || matches!(&self.text_pattern, Some(pattern) if !pattern.is_match(&annotation.text))
+        {
+            return false;
+        }
+        if !uri_matches(&self.uri, &annotation.uri) {
+            return false;
+        }
+        let has_tag = |tag: &String| annotation.tags.contains(tag);
+        if !self.tags.is_empty()
+            && !(if self.any_tags {
+                self.tags.iter().any(has_tag)
+            } else {
+                self.tags.iter().all(has_tag)
+            })
+        {
+            return false;
+        }
+        if !self.ids.is_empty() && !self.ids.contains(&annotation.id) {
+            return false;
+        }
+        if (self.hidden && !annotation.hidden) || (self.flagged && !annotation.flagged) {
+            return false;
+        }
+        if matches!(self.visibility, Some(wanted) if visibility(annotation) != wanted) {
+            return false;
+        }
+        self.any.is_empty() || matches_any(annotation, &self.any)
+    }
+}
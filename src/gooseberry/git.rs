@@ -0,0 +1,194 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use color_eyre::Help;
+use git2::{
+    Cred, CredentialType, IndexAddOption, Oid, PushOptions, RemoteCallbacks, Repository, Signature,
+};
+
+use crate::errors::Apologize;
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::Gooseberry;
+use crate::NAME;
+
+/// Remote the knowledge base is pushed to with `git_push`
+const REMOTE: &str = "origin";
+
+/// What changed in the knowledge base since the last `make`, for the commit message template
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct KbChanges {
+    /// Number of annotations new to the knowledge base
+    pub(crate) added: usize,
+    /// Number of annotations updated since they were last written
+    pub(crate) changed: usize,
+    /// Number of annotations no longer in the knowledge base
+    pub(crate) removed: usize,
+    /// Titles of the documents with added or changed annotations
+    pub(crate) documents: BTreeSet<String>,
+    /// Number of files written or deleted
+    pub(crate) files: usize,
+}
+
+/// Opens the repository the knowledge base folder is in, or makes one in the folder
+fn open_or_init(kb_dir: &Path) -> color_eyre::Result<Repository> {
+    match Repository::discover(kb_dir) {
+        Ok(repo) => Ok(repo),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            println!("Initializing a git repository in {:?}", kb_dir);
+            Ok(Repository::init(kb_dir)?)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Author and committer of the commits: `user.name` and `user.email` from the git configuration,
+/// or gooseberry if they're not set
+fn signature(repo: &Repository) -> color_eyre::Result<Signature<'static>> {
+    match repo.signature() {
+        Ok(signature) => Ok(signature.to_owned()),
+        Err(_) => Ok(Signature::now(NAME, &format!("{}@localhost", NAME))?),
+    }
+}
+
+/// Stages everything in the knowledge base folder (including deleted files) and commits it with `message`,
+/// optionally pushing the current branch to `origin`.
+/// Returns the ID of the commit, or `None` if nothing changed since the last commit
+pub(crate) fn commit(kb_dir: &Path, message: &str, push: bool) -> color_eyre::Result<Option<Oid>> {
+    let repo = open_or_init(kb_dir)?;
+    let workdir = repo.workdir().ok_or(Apologize::GitError {
+        message: format!("The git repository of {:?} is bare", kb_dir),
+    })?;
+    let relative = kb_dir
+        .canonicalize()?
+        .strip_prefix(workdir.canonicalize()?)?
+        .to_path_buf();
+    let pathspec = if relative.as_os_str().is_empty() {
+        "*".to_owned()
+    } else {
+        relative.to_string_lossy().replace('\\', "/")
+    };
+    let mut index = repo.index()?;
+    index.add_all([&pathspec], IndexAddOption::DEFAULT, None)?;
+    index.update_all([&pathspec], None)?;
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(e)
+            if e.code() == git2::ErrorCode::UnbornBranch
+                || e.code() == git2::ErrorCode::NotFound =>
+        {
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if parent.as_ref().map(|p| p.tree_id()) == Some(tree.id()) {
+        return Ok(None);
+    }
+    let signature = signature(&repo)?;
+    let parents: Vec<_> = parent.iter().collect();
+    let id = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )?;
+    if push {
+        push_head(&repo)?;
+    }
+    Ok(Some(id))
+}
+
+/// Pushes the current branch to `origin`, authenticating with the SSH agent or the git credential helper
+fn push_head(repo: &Repository) -> color_eyre::Result<()> {
+    let mut remote = repo
+        .find_remote(REMOTE)
+        .map_err(|_| Apologize::GitError {
+            message: format!("The knowledge base repository has no {:?} remote", REMOTE),
+        })
+        .suggestion("Add one with 'git remote add origin <url>' in the knowledge base folder, or set git_push to false")?;
+    let head = repo.head()?;
+    let branch = head.name().ok_or(Apologize::GitError {
+        message: "The current branch name isn't valid UTF-8".into(),
+    })?;
+    let config = repo.config()?;
+    // libgit2 asks again when credentials are rejected, so each kind is only tried once
+    let (tried_agent, tried_helper) = (Cell::new(false), Cell::new(false));
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) && !tried_agent.replace(true) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT)
+            && !tried_helper.replace(true)
+        {
+            Cred::credential_helper(&config, url, username)
+        } else if allowed.contains(CredentialType::DEFAULT) {
+            Cred::default()
+        } else {
+            Err(git2::Error::from_str("no usable credentials"))
+        }
+    });
+    callbacks.push_update_reference(|reference, status| match status {
+        Some(status) => Err(git2::Error::from_str(&format!(
+            "{} was rejected: {}",
+            reference, status
+        ))),
+        None => Ok(()),
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    remote
+        .push(&[format!("{0}:{0}", branch)], Some(&mut options))
+        .map_err(|e| Apologize::GitError {
+            message: format!("Couldn't push to {}: {}", REMOTE, e.message()),
+        })
+        .suggestion("Check that 'git push' works in the knowledge base folder")?;
+    println!(
+        "Pushed {} to {}",
+        head.shorthand().unwrap_or(branch),
+        REMOTE
+    );
+    Ok(())
+}
+
+/// ## Git
+/// Committing the knowledge base after `make`
+impl Gooseberry {
+    /// Compares the annotations written to the knowledge base with the ones written by the last `make`,
+    /// and records them for the next one (unless it's a dry run)
+    pub(crate) fn kb_changes(
+        &self,
+        annotations: &[AnnotationTemplate],
+    ) -> color_eyre::Result<KbChanges> {
+        let tree = self.kb_annotations()?;
+        let mut changes = KbChanges::default();
+        let mut seen = BTreeSet::new();
+        for annotation in annotations {
+            let id = annotation.annotation.id.as_bytes();
+            let updated = annotation.annotation.updated.to_rfc3339();
+            seen.insert(id.to_vec());
+            match tree.get(id)? {
+                Some(previous) if previous.as_slice() == updated.as_bytes() => continue,
+                Some(_) => changes.changed += 1,
+                None => changes.added += 1,
+            }
+            changes.documents.insert(annotation.title.to_owned());
+            if !self.dry_run {
+                tree.insert(id, updated.as_bytes())?;
+            }
+        }
+        for id in tree.iter().keys() {
+            let id = id?;
+            if !seen.contains(&id) {
+                changes.removed += 1;
+                if !self.dry_run {
+                    tree.remove(id)?;
+                }
+            }
+        }
+        Ok(changes)
+    }
+}
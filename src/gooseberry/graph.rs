@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, HashMap};
+
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::cli::GraphFormat;
+use crate::gooseberry::helpers::truncate;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Characters of an annotation's quote (or text) used as its label
+const LABEL_LENGTH: usize = 40;
+
+/// What a node of the graph stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Document,
+    Annotation,
+    Tag,
+}
+
+/// What an edge of the graph stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EdgeKind {
+    /// From a document to one of its annotations
+    Contains,
+    /// From an annotation (or a document, without annotation nodes) to one of its tags
+    Tagged,
+    /// Between two tags used on the same annotations
+    CoOccurs,
+}
+
+/// A document, annotation, or tag
+#[derive(Debug, Serialize)]
+pub struct Node {
+    /// `document:<uri>`, `annotation:<id>`, or `tag:<name>`
+    pub id: String,
+    pub kind: NodeKind,
+    /// Title of a document (or its URI), start of an annotation's quote or text, or tag name
+    pub label: String,
+    /// URI of a document or of the annotated page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+}
+
+/// A link between two nodes, by ID
+#[derive(Debug, Serialize)]
+pub struct Edge {
+    pub source: String,
+    pub target: String,
+    pub kind: EdgeKind,
+    /// Number of annotations behind the edge (1 between a document and an annotation or an annotation and a tag)
+    pub weight: usize,
+}
+
+/// Tags, documents, and annotations, linked by containment, tagging, and tag co-occurrence
+#[derive(Debug, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+/// Escapes a label for a double-quoted Graphviz string
+fn dot_escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', " ")
+}
+
+/// Escapes a label for a double-quoted Mermaid string
+fn mermaid_escape(label: &str) -> String {
+    label.replace('"', "#quot;").replace('\n', " ")
+}
+
+impl Graph {
+    /// Builds the graph of annotations, with `titles` for their documents.
+    /// Without `with_annotations`, documents are linked to the tags of their annotations directly
+    fn new(
+        annotations: &[Annotation],
+        titles: &BTreeMap<String, Option<String>>,
+        with_annotations: bool,
+    ) -> Self {
+        let mut nodes = Vec::new();
+        let mut edges = Vec::new();
+        let mut documents = BTreeMap::new();
+        let mut tags = BTreeMap::new();
+        let mut document_tags: BTreeMap<(String, String), usize> = BTreeMap::new();
+        let mut co_occurrences: HashMap<(String, String), usize> = HashMap::new();
+        for annotation in annotations {
+            let document = format!("document:{}", annotation.uri);
+            documents.entry(document.clone()).or_insert_with(|| Node {
+                id: document.clone(),
+                kind: NodeKind::Document,
+                label: titles
+                    .get(&annotation.uri)
+                    .cloned()
+                    .flatten()
+                    .unwrap_or_else(|| annotation.uri.to_owned()),
+                uri: Some(annotation.uri.to_owned()),
+            });
+            let mut annotation_tags: Vec<_> = annotation
+                .tags
+                .iter()
+                .filter(|tag| !tag.trim().is_empty())
+                .collect();
+            annotation_tags.sort();
+            annotation_tags.dedup();
+            for tag in &annotation_tags {
+                tags.entry(format!("tag:{}", tag)).or_insert_with(|| Node {
+                    id: format!("tag:{}", tag),
+                    kind: NodeKind::Tag,
+                    label: (*tag).to_owned(),
+                    uri: None,
+                });
+            }
+            for (index, tag) in annotation_tags.iter().enumerate() {
+                for other in &annotation_tags[index + 1..] {
+                    *co_occurrences
+                        .entry((format!("tag:{}", tag), format!("tag:{}", other)))
+                        .or_default() += 1;
+                }
+            }
+            if with_annotations {
+                let id = format!("annotation:{}", annotation.id);
+                let quote = utils::get_quotes(annotation).join(" ");
+                let label = if quote.trim().is_empty() {
+                    &annotation.text
+                } else {
+                    &quote
+                };
+                nodes.push(Node {
+                    id: id.clone(),
+                    kind: NodeKind::Annotation,
+                    label: truncate(label.trim(), LABEL_LENGTH),
+                    uri: Some(annotation.uri.to_owned()),
+                });
+                edges.push(Edge {
+                    source: document.clone(),
+                    target: id.clone(),
+                    kind: EdgeKind::Contains,
+                    weight: 1,
+                });
+                for tag in &annotation_tags {
+                    edges.push(Edge {
+                        source: id.clone(),
+                        target: format!("tag:{}", tag),
+                        kind: EdgeKind::Tagged,
+                        weight: 1,
+                    });
+                }
+            } else {
+                for tag in &annotation_tags {
+                    *document_tags
+                        .entry((document.clone(), format!("tag:{}", tag)))
+                        .or_default() += 1;
+                }
+            }
+        }
+        edges.extend(
+            document_tags
+                .into_iter()
+                .map(|((source, target), weight)| Edge {
+                    source,
+                    target,
+                    kind: EdgeKind::Tagged,
+                    weight,
+                }),
+        );
+        let mut co_occurrences: Vec<_> = co_occurrences.into_iter().collect();
+        co_occurrences.sort();
+        edges.extend(
+            co_occurrences
+                .into_iter()
+                .map(|((source, target), weight)| Edge {
+                    source,
+                    target,
+                    kind: EdgeKind::CoOccurs,
+                    weight,
+                }),
+        );
+        let mut all_nodes: Vec<_> = documents.into_values().collect();
+        all_nodes.extend(nodes);
+        all_nodes.extend(tags.into_values());
+        Self {
+            nodes: all_nodes,
+            edges,
+        }
+    }
+
+    /// Graphviz graph, with documents as notes, annotations as boxes, tags as ellipses,
+    /// and tag co-occurrences as dashed edges labelled with their number of annotations
+    fn to_dot(&self) -> String {
+        let mut dot = vec!["graph gooseberry {".to_owned(), "  rankdir=LR;".to_owned()];
+        for node in &self.nodes {
+            let shape = match node.kind {
+                NodeKind::Document => "note",
+                NodeKind::Annotation => "box",
+                NodeKind::Tag => "ellipse",
+            };
+            let url = node
+                .uri
+                .as_deref()
+                .filter(|_| node.kind == NodeKind::Document)
+                .map(|uri| format!(", URL=\"{}\"", dot_escape(uri)))
+                .unwrap_or_default();
+            dot.push(format!(
+                "  \"{}\" [label=\"{}\", shape={}{}];",
+                dot_escape(&node.id),
+                dot_escape(&node.label),
+                shape,
+                url
+            ));
+        }
+        for edge in &self.edges {
+            let attributes = match edge.kind {
+                EdgeKind::CoOccurs => {
+                    format!(" [style=dashed, weight={0}, label=\"{0}\"]", edge.weight)
+                }
+                _ if edge.weight > 1 => format!(" [weight={0}, label=\"{0}\"]", edge.weight),
+                _ => String::new(),
+            };
+            dot.push(format!(
+                "  \"{}\" -- \"{}\"{};",
+                dot_escape(&edge.source),
+                dot_escape(&edge.target),
+                attributes
+            ));
+        }
+        dot.push("}".to_owned());
+        dot.join("\n")
+    }
+
+    /// Mermaid flowchart, with documents as rectangles, annotations as rounded rectangles, tags as circles,
+    /// and tag co-occurrences as dotted links labelled with their number of annotations
+    fn to_mermaid(&self) -> String {
+        let ids: HashMap<_, _> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.id.as_str(), format!("n{}", index)))
+            .collect();
+        let mut mermaid = vec!["graph LR".to_owned()];
+        for node in &self.nodes {
+            let label = mermaid_escape(&node.label);
+            mermaid.push(match node.kind {
+                NodeKind::Document => format!("  {}[\"{}\"]", ids[node.id.as_str()], label),
+                NodeKind::Annotation => format!("  {}(\"{}\")", ids[node.id.as_str()], label),
+                NodeKind::Tag => format!("  {}((\"{}\"))", ids[node.id.as_str()], label),
+            });
+        }
+        for edge in &self.edges {
+            let (source, target) = (&ids[edge.source.as_str()], &ids[edge.target.as_str()]);
+            mermaid.push(match edge.kind {
+                EdgeKind::CoOccurs => format!("  {} -. {} .- {}", source, edge.weight, target),
+                _ if edge.weight > 1 => format!("  {} -- {} --- {}", source, edge.weight, target),
+                _ => format!("  {} --- {}", source, target),
+            });
+        }
+        mermaid.join("\n")
+    }
+}
+
+/// ## Graph
+/// The structure of the knowledge base as a graph
+impl Gooseberry {
+    /// Prints a graph of (optionally filtered) annotations, their documents, and their tags
+    /// in Graphviz DOT, Mermaid, or JSON (`{"nodes": [...], "edges": [...]}`).
+    /// Without `with_annotations`, documents are linked to tags directly, weighted by their number of annotations
+    pub fn graph(
+        &self,
+        annotations: Vec<Annotation>,
+        format: GraphFormat,
+        with_annotations: bool,
+    ) -> color_eyre::Result<()> {
+        let titles = self.uri_titles(annotations.clone())?;
+        let graph = Graph::new(&annotations, &titles, with_annotations);
+        match format {
+            GraphFormat::Dot => println!("{}", graph.to_dot()),
+            GraphFormat::Mermaid => println!("{}", graph.to_mermaid()),
+            GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use hypothesis::groups::{Group, GroupFilters};
+
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Lowercase name of a group's type (private, open, or restricted)
+fn group_type(group: &Group) -> String {
+    format!("{:?}", group.group_type).to_lowercase()
+}
+
+/// ## Groups
+/// Managing Hypothesis groups, so that group IDs don't have to be looked up in the web UI
+impl Gooseberry {
+    /// Groups the user belongs to
+    async fn get_user_groups(&self) -> color_eyre::Result<Vec<Group>> {
+        Ok(self.api.get_groups(&GroupFilters::default()).await?)
+    }
+
+    /// Prints the ID, name, and type of each of the user's groups, marking the ones gooseberry takes annotations from
+    pub async fn list_groups(&self) -> color_eyre::Result<()> {
+        for group in self.get_user_groups().await? {
+            let marker = if self.config.hypothesis_group.as_ref() == Some(&group.id) {
+                "\t(gooseberry group)"
+            } else if self.config.groups().contains(&group.id) {
+                "\t(synced)"
+            } else {
+                ""
+            };
+            println!(
+                "{}\t{}\t{}{}",
+                group.id,
+                group.name,
+                group_type(&group),
+                marker
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates a private group, printing its ID
+    pub async fn create_group(
+        &self,
+        name: &str,
+        description: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        if self.dry_run {
+            println!("POST /api/groups ({})", name);
+            println!("Would create group {} on Hypothesis (dry run)", name);
+            return Ok(());
+        }
+        let group = self.api.create_group(name, description).await?;
+        println!("Created group {} with ID {}", group.name, group.id);
+        println!("Use `gooseberry group switch` to take annotations from it");
+        Ok(())
+    }
+
+    /// Leaves a group, removing it from the additional groups gooseberry syncs
+    pub async fn leave_group(&mut self, id: &str, force: bool) -> color_eyre::Result<()> {
+        if self.dry_run {
+            println!("DELETE /api/groups/{}/members/me", id);
+            println!("Would leave group {} (dry run)", id);
+            return Ok(());
+        }
+        if !force && !utils::confirm(&format!("Leave group {}?", id), false)? {
+            return Ok(());
+        }
+        self.api.leave_group(id).await?;
+        println!("Left group {}", id);
+        if let Some(groups) = &mut self.config.hypothesis_groups {
+            if groups.iter().any(|group| group == id) {
+                groups.retain(|group| group != id);
+                self.config.store()?;
+            }
+        }
+        if self.config.hypothesis_group.as_deref() == Some(id) {
+            println!(
+                "This was the gooseberry group, use `gooseberry group switch` to choose another one"
+            );
+        }
+        Ok(())
+    }
+
+    /// Prints the username and display name of each member of a group (the gooseberry group if not given)
+    pub async fn list_group_members(&self, id: Option<String>) -> color_eyre::Result<()> {
+        let id = match id.or_else(|| self.config.hypothesis_group.clone()) {
+            Some(id) => id,
+            None => {
+                println!("No group given");
+                return Ok(());
+            }
+        };
+        for member in self.api.get_group_members(&id).await? {
+            println!(
+                "{}\t{}",
+                member.username,
+                member.display_name.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    /// Asks which of the user's groups gooseberry should take annotations from and stores it in the config
+    pub async fn switch_group(&mut self) -> color_eyre::Result<()> {
+        let groups = self.get_user_groups().await?;
+        let options: Vec<_> = groups
+            .iter()
+            .map(|group| {
+                let marker = if self.config.hypothesis_group.as_ref() == Some(&group.id) {
+                    " (current)"
+                } else {
+                    ""
+                };
+                format!("{}: {}{}", group.id, group.name, marker)
+            })
+            .collect();
+        let options: Vec<_> = options.iter().map(String::as_str).collect();
+        match utils::select("Which group should gooseberry use?", &options)? {
+            Some(index) => {
+                let group = &groups[index];
+                self.config.hypothesis_group = Some(group.id.to_owned());
+                self.config.store()?;
+                println!("Switched to group {} ({})", group.name, group.id);
+            }
+            None => println!("No group selected"),
+        }
+        Ok(())
+    }
+}
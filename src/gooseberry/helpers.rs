@@ -0,0 +1,225 @@
+use chrono::{DateTime, Utc};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    ScopedJson,
+};
+use regex::Regex;
+use serde_json::Value as Json;
+
+use crate::gooseberry::site::slugify;
+
+/// Formats a date (as serialized in the templates) with a `strftime` format
+pub(crate) fn format_date<E: AsRef<str>>(
+    format: E,
+    date: &Json,
+) -> Result<String, serde_json::Error> {
+    let date: DateTime<Utc> = serde_json::from_value(date.clone())?;
+    Ok(format!("{}", date.format(format.as_ref())))
+}
+
+/// Formats a date given either as `date format` or `format date` (the format is the one with a `%`)
+fn format_date_either(first: &Json, second: &Json) -> Result<String, serde_json::Error> {
+    match first.as_str() {
+        Some(format) if format.contains('%') => format_date(format, second),
+        _ => format_date(second.as_str().unwrap_or_default(), first),
+    }
+}
+
+/// First `length` characters of a text, with an ellipsis if it was cut
+pub(crate) fn truncate(text: &str, length: usize) -> String {
+    if text.chars().count() <= length {
+        return text.to_owned();
+    }
+    let truncated: String = text.chars().take(length).collect();
+    format!("{}…", truncated.trim_end())
+}
+
+/// Turns `\n`, `\t`, and `\\` in template string literals into the characters they stand for
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Checks a text against a regex
+fn regex_match(text: &str, pattern: &str) -> Result<bool, RenderError> {
+    Regex::new(pattern)
+        .map(|regex| regex.is_match(text))
+        .map_err(|e| RenderError::new(format!("Invalid regex {:?} in template: {}", pattern, e)))
+}
+
+/// The words of a text, with single spaces in between
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The last `length` characters of the text before a highlight, starting at a word, with an ellipsis if it was cut
+fn context_before(prefix: &str, length: usize) -> String {
+    if length == 0 {
+        return String::new();
+    }
+    let space = if prefix.ends_with(char::is_whitespace) {
+        " "
+    } else {
+        ""
+    };
+    let prefix = collapse_whitespace(prefix);
+    let count = prefix.chars().count();
+    if count <= length {
+        return format!("{}{}", prefix, space);
+    }
+    let cut: String = prefix.chars().skip(count - length).collect();
+    let cut = match cut.split_once(' ') {
+        Some((_, rest)) if !rest.is_empty() => rest,
+        _ => &cut,
+    };
+    format!("…{}{}", cut, space)
+}
+
+/// The first `length` characters of the text after a highlight, ending at a word, with an ellipsis if it was cut
+fn context_after(suffix: &str, length: usize) -> String {
+    if length == 0 {
+        return String::new();
+    }
+    let space = if suffix.starts_with(char::is_whitespace) {
+        " "
+    } else {
+        ""
+    };
+    let suffix = collapse_whitespace(suffix);
+    if suffix.chars().count() <= length {
+        return format!("{}{}", space, suffix);
+    }
+    let cut: String = suffix.chars().take(length).collect();
+    let cut = match cut.rsplit_once(' ') {
+        Some((rest, _)) if !rest.is_empty() => rest,
+        _ => &cut,
+    };
+    format!("{}{}…", space, cut)
+}
+
+/// `{{quote_with_context 40}}`: the first highlight of the annotation being rendered,
+/// with up to 40 characters of the text around it in the document (from its `prefix` and `suffix`).
+/// `mark="**"` puts the string on both sides of the highlight, to set it apart from the context
+fn quote_with_context(
+    h: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let length = h
+        .param(0)
+        .and_then(|param| param.value().as_u64())
+        .ok_or_else(|| RenderError::new("quote_with_context needs a number of characters"))?
+        as usize;
+    let mark = h
+        .hash_get("mark")
+        .and_then(|mark| mark.value().as_str())
+        .unwrap_or_default();
+    let data = ctx.data();
+    let field = |key: &str| data.get(key).and_then(Json::as_str).unwrap_or_default();
+    let quote = data
+        .get("highlight")
+        .and_then(|highlight| highlight.get(0))
+        .and_then(Json::as_str)
+        .unwrap_or_default();
+    if quote.is_empty() {
+        return Ok(());
+    }
+    out.write(&format!(
+        "{}{}{}{}{}",
+        context_before(field("prefix"), length),
+        mark,
+        collapse_whitespace(quote),
+        mark,
+        context_after(field("suffix"), length)
+    ))?;
+    Ok(())
+}
+
+handlebars_helper!(date_format: |first: Json, second: Json| format_date_either(first, second).map_err(|e| RenderError::from_error("serde_json", e))?);
+handlebars_helper!(truncate_helper: |text: str, length: u64| truncate(text, length as usize));
+handlebars_helper!(slugify_helper: |text: str| slugify(text));
+handlebars_helper!(replace_helper: |text: str, from: str, to: str| text.replace(&unescape(from), &unescape(to)));
+handlebars_helper!(match_helper: |text: str, pattern: str| regex_match(text, pattern)?);
+
+/// Registers the formatting helpers:
+/// * `{{date_format created "%Y-%m-%d"}}` (or `{{date_format "%Y-%m-%d" created}}`) - formats a date
+/// * `{{truncate text 200}}` - first 200 characters, with an ellipsis if the text was cut
+/// * `{{slugify uri}}` - lowercase letters and numbers with dashes in between
+/// * `{{replace text "\n" " "}}` - replaces all occurrences of a string (`\n` and `\t` stand for newlines and tabs)
+/// * `{{match uri "regex"}}` - whether the text matches a regex, for conditionals (`{{#if (match uri "arxiv")}}`)
+/// * `{{quote_with_context 40}}` - the highlight with up to 40 characters of the text around it (in annotation templates)
+pub(crate) fn register_helpers(hbs: &mut Handlebars) {
+    hbs.register_helper("date_format", Box::new(date_format));
+    hbs.register_helper("truncate", Box::new(truncate_helper));
+    hbs.register_helper("slugify", Box::new(slugify_helper));
+    hbs.register_helper("replace", Box::new(replace_helper));
+    hbs.register_helper("match", Box::new(match_helper));
+    hbs.register_helper("quote_with_context", Box::new(quote_with_context));
+}
+
+/// Link opening a file of an Obsidian vault in Obsidian, e.g. `obsidian://open?vault=notes&file=tags%2Fbiology.md`
+pub(crate) fn obsidian_uri(vault: &str, file: &str) -> String {
+    // Obsidian decodes `+` as a plus sign, not a space
+    let encode = |text: &str| {
+        url::form_urlencoded::byte_serialize(text.as_bytes())
+            .collect::<String>()
+            .replace('+', "%20")
+    };
+    format!(
+        "obsidian://open?vault={}&file={}",
+        encode(vault),
+        encode(&file.replace('\\', "/"))
+    )
+}
+
+/// `{{obsidian_uri relative_path}}` renders a link opening the file (a path from the root of the vault) in Obsidian
+struct ObsidianHelper {
+    vault: Option<String>,
+}
+
+impl HelperDef for ObsidianHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let file = h
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or_else(|| RenderError::new("obsidian_uri needs a file path"))?;
+        let vault = self.vault.as_deref().ok_or_else(|| {
+            RenderError::new(
+                "obsidian_uri needs the obsidian_vault config option (or --obsidian-vault)",
+            )
+        })?;
+        Ok(Some(ScopedJson::Derived(Json::String(obsidian_uri(
+            vault, file,
+        )))))
+    }
+}
+
+/// Registers the `obsidian_uri` helper, linking to files of the given Obsidian vault
+pub(crate) fn register_obsidian_helper(hbs: &mut Handlebars, vault: Option<String>) {
+    hbs.register_helper("obsidian_uri", Box::new(ObsidianHelper { vault }));
+}
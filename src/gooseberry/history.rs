@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use difference::{Changeset, Difference};
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::Gooseberry;
+
+/// Key of a version of an annotation in the history tree: its ID and update time, separated by a null byte
+fn history_key(annotation: &Annotation) -> Vec<u8> {
+    format!("{}\0{}", annotation.id, annotation.updated.to_rfc3339()).into_bytes()
+}
+
+/// Tags of an annotation, without empty ones
+fn tags(annotation: &Annotation) -> Vec<&str> {
+    annotation
+        .tags
+        .iter()
+        .map(String::as_str)
+        .filter(|tag| !tag.trim().is_empty())
+        .collect()
+}
+
+/// Lines of a text, each with a prefix
+fn prefixed(text: &str, prefix: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| format!("{}{}", prefix, line))
+        .collect()
+}
+
+/// Tags added (`+`) and removed (`-`) between two versions, or `None` if they're the same
+fn tag_changes(old: &Annotation, new: &Annotation) -> Option<String> {
+    let (old, new) = (tags(old), tags(new));
+    let changes: Vec<_> = new
+        .iter()
+        .filter(|tag| !old.contains(tag))
+        .map(|tag| format!("+{}", tag))
+        .chain(
+            old.iter()
+                .filter(|tag| !new.contains(tag))
+                .map(|tag| format!("-{}", tag)),
+        )
+        .collect();
+    if changes.is_empty() {
+        None
+    } else {
+        Some(changes.join(", "))
+    }
+}
+
+/// Line diff of the text of two versions, with unchanged lines indented and the others marked with `+` and `-`
+fn text_diff(old: &str, new: &str) -> Vec<String> {
+    Changeset::new(old, new, "\n")
+        .diffs
+        .iter()
+        .flat_map(|difference| match difference {
+            Difference::Same(text) => prefixed(text, "  "),
+            Difference::Add(text) => prefixed(text, "+ "),
+            Difference::Rem(text) => prefixed(text, "- "),
+        })
+        .collect()
+}
+
+/// ## History
+/// Previous versions of annotations, kept during sync since Hypothesis doesn't keep them
+impl Gooseberry {
+    /// Stores the `previous` (last synced) versions of synced annotations whose text or tags changed
+    pub(crate) fn record_history(
+        &self,
+        annotations: &[Annotation],
+        previous: &HashMap<String, Annotation>,
+    ) -> color_eyre::Result<()> {
+        let history = self.annotation_history()?;
+        for annotation in annotations {
+            if let Some(previous) = previous.get(&annotation.id) {
+                if previous.text != annotation.text || tags(previous) != tags(annotation) {
+                    history.insert(history_key(previous), serde_json::to_vec(previous)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Previous versions of an annotation, oldest first
+    pub(crate) fn get_history(&self, id: &str) -> color_eyre::Result<Vec<Annotation>> {
+        let mut versions = self
+            .annotation_history()?
+            .scan_prefix(format!("{}\0", id).as_bytes())
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect::<color_eyre::Result<Vec<Annotation>>>()?;
+        versions.sort_by_key(|a| a.updated);
+        Ok(versions)
+    }
+
+    /// Prints the timeline of an annotation's versions: the oldest one recorded in full,
+    /// then the text (as a line diff) and tag changes of each later version, up to the one from the last sync
+    /// (or the one in the trash, if the annotation was deleted)
+    pub fn history(&self, id: &str) -> color_eyre::Result<()> {
+        let mut versions = self.get_history(id)?;
+        let (current, deleted) = match self.indexed_annotations(&[id])?.remove(id) {
+            Some(annotation) => (Some(annotation), false),
+            None => match self.trash()?.get(id.as_bytes())? {
+                Some(json) => (Some(serde_json::from_slice(&json)?), true),
+                None => (None, false),
+            },
+        };
+        versions.extend(current);
+        let first = versions
+            .first()
+            .ok_or(Apologize::AnnotationNotFound { id: id.to_owned() })?;
+        println!("Annotation {} on {}", id, first.uri);
+        let label = match (versions.len(), deleted) {
+            (1, true) => "Only recorded version (deleted since)",
+            (1, false) => "Only recorded version (current)",
+            _ => "First recorded version",
+        };
+        println!("\n{}  {}", first.updated.format("%Y-%m-%d %H:%M"), label);
+        if !tags(first).is_empty() {
+            println!("  tags: {}", tags(first).join(", "));
+        }
+        for line in prefixed(&first.text, "  ") {
+            println!("{}", line);
+        }
+        for (index, pair) in versions.windows(2).enumerate() {
+            let (old, new) = (&pair[0], &pair[1]);
+            let label = match (index + 2 == versions.len(), deleted) {
+                (true, true) => "Edited (deleted since)",
+                (true, false) => "Edited (current)",
+                _ => "Edited",
+            };
+            println!("\n{}  {}", new.updated.format("%Y-%m-%d %H:%M"), label);
+            if let Some(changes) = tag_changes(old, new) {
+                println!("  tags: {}", changes);
+            }
+            if old.text != new.text {
+                for line in text_diff(&old.text, &new.text) {
+                    println!("{}", line);
+                }
+            }
+        }
+        Ok(())
+    }
+}
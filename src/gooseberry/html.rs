@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+
+use handlebars::Handlebars;
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::configuration::DEFAULT_HTML_LAYOUT;
+use crate::gooseberry::feed::xml_escape;
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::site::{nested_list, SitePage};
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Styles of the pages, `{{style}}` in the layout
+const STYLE: &str = r#"
+body { max-width: 48rem; margin: 0 auto; padding: 1rem; font-family: system-ui, sans-serif; line-height: 1.5; color: #222; }
+header { display: flex; gap: 1rem; align-items: center; position: sticky; top: 0; padding: 0.5rem 0; background: #fff; }
+#search { flex: 1; padding: 0.4rem 0.6rem; font-size: 1rem; border: 1px solid #ccc; border-radius: 4px; }
+#search-results:empty { display: none; }
+#search-results { padding: 0.5rem 1.5rem; background: #f6f6f6; border-radius: 4px; }
+article.annotation { padding: 0.5rem 1rem; margin: 1rem 0; border-left: 3px solid #e0b000; background: #fafafa; }
+article.annotation:target { background: #fff6d0; }
+blockquote { margin: 0.5rem 0; padding-left: 1rem; border-left: 3px solid #ccc; color: #555; }
+a { color: #0b5fa5; }
+pre, code { background: #f0f0f0; border-radius: 3px; }
+"#;
+
+/// Hides the annotations on the page which don't contain the text in the search box,
+/// and lists the matching annotations of the whole knowledge base if there's a search index (on the index page).
+/// `{{script}}` in the layout
+const SCRIPT: &str = r#"
+(function () {
+  var search = document.getElementById("search");
+  var results = document.getElementById("search-results");
+  if (!search) { return; }
+  var index = typeof GOOSEBERRY_INDEX === "undefined" ? [] : GOOSEBERRY_INDEX;
+  search.addEventListener("input", function () {
+    var query = search.value.trim().toLowerCase();
+    document.querySelectorAll("article.annotation").forEach(function (article) {
+      article.hidden = query !== "" && article.textContent.toLowerCase().indexOf(query) === -1;
+    });
+    if (!results) { return; }
+    results.innerHTML = "";
+    if (query === "") { return; }
+    index.filter(function (entry) { return entry.text.toLowerCase().indexOf(query) !== -1; })
+      .slice(0, 50)
+      .forEach(function (entry) {
+        var item = document.createElement("li");
+        var link = document.createElement("a");
+        link.href = entry.url;
+        link.textContent = entry.page + ": " + entry.text.slice(0, 120);
+        item.appendChild(link);
+        results.appendChild(item);
+      });
+  });
+})();
+"#;
+
+/// An annotation in the search index of the index page
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SearchEntry {
+    /// Path of the page (relative to the knowledge base directory) and anchor of the annotation
+    url: String,
+    /// Name of the page
+    page: String,
+    /// Quote, text, and tags of the annotation
+    text: String,
+}
+
+/// Data for the HTML layout template
+#[derive(Debug, Serialize)]
+pub struct LayoutTemplate {
+    /// Title of the page, HTML-escaped
+    pub title: String,
+    /// The page, converted to HTML
+    pub content: String,
+    /// Relative link to the index page, for pages other than the index
+    pub index_link: Option<String>,
+    pub style: &'static str,
+    /// Search script, with the search index of the knowledge base on the index page
+    pub script: String,
+}
+
+/// Converts markdown (with tables, footnotes, strikethrough, and task lists) to HTML
+pub(crate) fn markdown_to_html(markdown: &str) -> String {
+    let parser = Parser::new_ext(markdown, Options::all());
+    let mut converted = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut converted, parser);
+    converted
+}
+
+/// Wraps a rendered annotation in an `<article>` with its ID as anchor,
+/// separated by blank lines so that the markdown in it is still converted
+pub(crate) fn annotation_article(id: &str, rendered: &str) -> String {
+    format!(
+        "<article class=\"annotation\" id=\"{}\">\n\n{}\n\n</article>\n\n",
+        xml_escape(id),
+        rendered.trim()
+    )
+}
+
+/// Entries of a page's annotations in the search index
+pub(crate) fn search_entries(
+    path: &str,
+    page: &str,
+    annotations: &[AnnotationTemplate],
+) -> Vec<SearchEntry> {
+    annotations
+        .iter()
+        .map(|a| SearchEntry {
+            url: format!("{}#{}", path.replace('\\', "/"), a.annotation.id),
+            page: page.to_owned(),
+            text: utils::get_quotes(&a.annotation)
+                .into_iter()
+                .chain(Some(a.annotation.text.as_str()))
+                .chain(a.annotation.tags.iter().map(String::as_str))
+                .filter(|text| !text.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        })
+        .collect()
+}
+
+/// Renders a markdown page as HTML with the layout, and the search index of the knowledge base if one is given
+pub(crate) fn layout(
+    hbs: &Handlebars,
+    title: &str,
+    markdown: &str,
+    index_link: Option<String>,
+    index: &[SearchEntry],
+) -> color_eyre::Result<String> {
+    let script = if index.is_empty() {
+        SCRIPT.to_owned()
+    } else {
+        // `</script>` in the annotations would end the script
+        format!(
+            "var GOOSEBERRY_INDEX = {};\n{}",
+            serde_json::to_string(index)?.replace("</", "<\\/"),
+            SCRIPT
+        )
+    };
+    Ok(hbs.render(
+        "html_layout",
+        &LayoutTemplate {
+            title: xml_escape(title),
+            content: markdown_to_html(markdown),
+            index_link,
+            style: STYLE,
+            script,
+        },
+    )?)
+}
+
+/// Files of an HTML knowledge base (paths relative to the knowledge base directory):
+/// the pages, and an `index.html` listing them nested by folder, with the search index of all annotations
+pub(crate) fn site_files(
+    hbs: &Handlebars,
+    title: &str,
+    mut pages: Vec<SitePage>,
+) -> color_eyre::Result<Vec<(PathBuf, String)>> {
+    pages.sort_by(|a, b| a.slugs.cmp(&b.slugs));
+    let list = nested_list(&pages);
+    let mut index = Vec::new();
+    let mut files = Vec::with_capacity(pages.len() + 1);
+    for page in pages {
+        let index_link = format!("{}index.html", "../".repeat(page.slugs.len() - 1));
+        files.push((
+            page.path(),
+            layout(hbs, page.title(), &page.contents, Some(index_link), &[])?,
+        ));
+        index.extend(page.search_entries);
+    }
+    files.push((
+        PathBuf::from("index.html"),
+        layout(
+            hbs,
+            title,
+            &format!("# {}\n\n{}", title, list),
+            None,
+            &index,
+        )?,
+    ));
+    Ok(files)
+}
+
+/// ## HTML
+/// Standalone HTML knowledge bases
+impl Gooseberry {
+    /// Registers the HTML layout from the config (or the default one)
+    pub(crate) fn register_html_layout(&self, hbs: &mut Handlebars) -> color_eyre::Result<()> {
+        hbs.register_template_string(
+            "html_layout",
+            self.config
+                .html_layout
+                .as_deref()
+                .unwrap_or(DEFAULT_HTML_LAYOUT),
+        )?;
+        Ok(())
+    }
+}
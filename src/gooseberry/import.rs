@@ -0,0 +1,419 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{TimeZone, Utc};
+use color_eyre::Help;
+use hypothesis::annotations::{Document, InputAnnotation, Target};
+
+use crate::errors::Apologize;
+use crate::gooseberry::cli::ImportFormat;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Separates the entries of a Kindle `My Clippings.txt` file
+const KINDLE_SEPARATOR: &str = "==========";
+
+/// A highlight or bookmark from another tool's export, imported as a Hypothesis page note
+#[derive(Debug, Clone, Default)]
+pub struct ImportedEntry {
+    pub uri: String,
+    pub title: Option<String>,
+    /// Highlighted text
+    pub quote: Option<String>,
+    pub note: Option<String>,
+    pub tags: Vec<String>,
+    /// When the entry was highlighted or saved, as written in the export
+    pub date: Option<String>,
+}
+
+impl ImportedEntry {
+    /// Page note text: the quote as a blockquote, the note, and the original date
+    pub fn text(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(quote) = &self.quote {
+            parts.push(
+                quote
+                    .lines()
+                    .map(|line| format!("> {}", line))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+        if let Some(note) = &self.note {
+            parts.push(note.to_owned());
+        }
+        if let Some(date) = &self.date {
+            parts.push(format!("*Originally added on {}*", date));
+        }
+        parts.join("\n\n")
+    }
+}
+
+/// `Some` if the text isn't just whitespace
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_owned())
+    }
+}
+
+/// Splits a list of tags, leaving out empty ones
+fn split_tags(tags: &str, separator: char) -> Vec<String> {
+    tags.split(separator).filter_map(non_empty).collect()
+}
+
+/// URI for documents without one (books, and Readwise highlights),
+/// e.g. `urn:x-gooseberry:kindle:The+Selfish+Gene`
+fn document_urn(source: &str, title: &str) -> String {
+    format!(
+        "urn:x-gooseberry:{}:{}",
+        source,
+        url::form_urlencoded::byte_serialize(title.trim().as_bytes()).collect::<String>()
+    )
+}
+
+/// A row of a Readwise CSV export
+#[derive(Debug, Deserialize)]
+struct ReadwiseRow {
+    #[serde(rename = "Highlight")]
+    highlight: String,
+    #[serde(rename = "Book Title", default)]
+    title: String,
+    #[serde(rename = "Book Author", default)]
+    author: String,
+    #[serde(rename = "Note", default)]
+    note: String,
+    /// Comma-separated
+    #[serde(rename = "Tags", default)]
+    tags: String,
+    #[serde(rename = "Highlighted at", default)]
+    highlighted_at: String,
+}
+
+/// Reads a Readwise CSV export (from readwise.io/export)
+fn read_readwise(contents: &str) -> color_eyre::Result<Vec<ImportedEntry>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let mut entries = Vec::new();
+    for row in reader.deserialize() {
+        let row: ReadwiseRow = row?;
+        let title = match non_empty(&row.author) {
+            Some(author) => format!("{} ({})", row.title.trim(), author),
+            None => row.title.trim().to_owned(),
+        };
+        entries.push(ImportedEntry {
+            uri: document_urn("readwise", &title),
+            title: non_empty(&title),
+            quote: non_empty(&row.highlight),
+            note: non_empty(&row.note),
+            tags: split_tags(&row.tags, ','),
+            date: non_empty(&row.highlighted_at),
+        });
+    }
+    Ok(entries)
+}
+
+/// A row of a Raindrop.io CSV export
+#[derive(Debug, Deserialize)]
+struct RaindropRow {
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    note: String,
+    #[serde(default)]
+    excerpt: String,
+    /// Comma-separated
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    created: String,
+    /// Highlights made in Raindrop, one per line
+    #[serde(default)]
+    highlights: String,
+}
+
+/// Reads a Raindrop.io CSV export. Highlights made in Raindrop become the quote,
+/// or the excerpt if there aren't any
+fn read_raindrop(contents: &str) -> color_eyre::Result<Vec<ImportedEntry>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let mut entries = Vec::new();
+    for row in reader.deserialize() {
+        let row: RaindropRow = row?;
+        let highlights: Vec<_> = row
+            .highlights
+            .lines()
+            .map(|line| line.trim_start_matches("Highlight:").trim())
+            .filter(|line| !line.is_empty())
+            .collect();
+        entries.push(ImportedEntry {
+            uri: row.url.trim().to_owned(),
+            title: non_empty(&row.title),
+            quote: non_empty(&highlights.join("\n")).or_else(|| non_empty(&row.excerpt)),
+            note: non_empty(&row.note),
+            tags: split_tags(&row.tags, ','),
+            date: non_empty(&row.created),
+        });
+    }
+    Ok(entries)
+}
+
+/// A row of a Pocket CSV export
+#[derive(Debug, Deserialize)]
+struct PocketRow {
+    url: String,
+    #[serde(default)]
+    title: String,
+    /// Unix timestamp
+    #[serde(default)]
+    time_added: String,
+    /// Separated by `|`
+    #[serde(default)]
+    tags: String,
+}
+
+/// Value of an attribute of an HTML tag, e.g. `href` in `<a href="...">`
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let end = start + tag[start..].find('"')?;
+    Some(utils::decode_entities(&tag[start..end]))
+}
+
+/// Date from a Unix timestamp
+fn format_timestamp(timestamp: &str) -> Option<String> {
+    let timestamp = timestamp.trim().parse().ok()?;
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|date| date.to_rfc3339())
+}
+
+/// Reads a Pocket export: the CSV export, or the HTML list of links of the older export
+fn read_pocket(contents: &str) -> color_eyre::Result<Vec<ImportedEntry>> {
+    let mut entries = Vec::new();
+    if !contents.trim_start().starts_with('<') {
+        let mut reader = csv::Reader::from_reader(contents.as_bytes());
+        for row in reader.deserialize() {
+            let row: PocketRow = row?;
+            entries.push(ImportedEntry {
+                uri: row.url.trim().to_owned(),
+                title: non_empty(&row.title),
+                tags: split_tags(&row.tags, '|'),
+                date: format_timestamp(&row.time_added),
+                ..ImportedEntry::default()
+            });
+        }
+        return Ok(entries);
+    }
+    let mut rest = contents;
+    while let Some(start) = rest.find("<a ") {
+        rest = &rest[start..];
+        let tag_end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..tag_end];
+        let text_end = rest.find("</a>").unwrap_or(tag_end + 1).max(tag_end + 1);
+        let title = utils::decode_entities(&rest[tag_end + 1..text_end]);
+        if let Some(uri) = attribute(tag, "href") {
+            entries.push(ImportedEntry {
+                uri,
+                title: non_empty(&title),
+                tags: split_tags(&attribute(tag, "tags").unwrap_or_default(), ','),
+                date: attribute(tag, "time_added").and_then(|time| format_timestamp(&time)),
+                ..ImportedEntry::default()
+            });
+        }
+        rest = &rest[text_end..];
+    }
+    Ok(entries)
+}
+
+/// Reads a Kindle `My Clippings.txt` file. Notes made at the end of a highlight
+/// are added to it, bookmarks are skipped
+fn read_kindle(contents: &str) -> Vec<ImportedEntry> {
+    let mut entries: Vec<ImportedEntry> = Vec::new();
+    // Location (e.g. "123-125") of each entry, to match notes to highlights
+    let mut locations: Vec<String> = Vec::new();
+    for clipping in contents
+        .trim_start_matches('\u{feff}')
+        .split(KINDLE_SEPARATOR)
+    {
+        let mut lines = clipping.trim().lines();
+        let (title, metadata) = match (lines.next(), lines.next()) {
+            (Some(title), Some(metadata)) => {
+                (title.trim_start_matches('\u{feff}').trim(), metadata)
+            }
+            _ => continue,
+        };
+        let content = match non_empty(&lines.collect::<Vec<_>>().join("\n")) {
+            Some(content) => content,
+            None => continue,
+        };
+        let location = metadata
+            .split('|')
+            .find_map(|part| part.split("Location ").nth(1))
+            .unwrap_or_default()
+            .trim()
+            .to_owned();
+        let date = metadata
+            .split('|')
+            .find_map(|part| part.trim().strip_prefix("Added on "))
+            .and_then(non_empty);
+        if metadata.contains("Your Note") {
+            let end = location.rsplit('-').next().unwrap_or_default();
+            if let Some(i) = locations.iter().rposition(|highlight_location| {
+                !end.is_empty() && highlight_location.rsplit('-').next() == Some(end)
+            }) {
+                if entries[i].title.as_deref() == Some(title) && entries[i].note.is_none() {
+                    entries[i].note = Some(content);
+                    continue;
+                }
+            }
+            entries.push(ImportedEntry {
+                uri: document_urn("kindle", title),
+                title: Some(title.to_owned()),
+                note: Some(content),
+                date,
+                ..ImportedEntry::default()
+            });
+        } else {
+            entries.push(ImportedEntry {
+                uri: document_urn("kindle", title),
+                title: Some(title.to_owned()),
+                quote: Some(content),
+                date,
+                ..ImportedEntry::default()
+            });
+        }
+        locations.push(location);
+    }
+    entries
+}
+
+/// Reads the entries of an export file
+pub fn read_entries(format: ImportFormat, file: &Path) -> color_eyre::Result<Vec<ImportedEntry>> {
+    let contents = fs::read_to_string(file)?;
+    match format {
+        ImportFormat::Readwise => read_readwise(&contents),
+        ImportFormat::Raindrop => read_raindrop(&contents),
+        ImportFormat::Pocket => read_pocket(&contents),
+        ImportFormat::Kindle => Ok(read_kindle(&contents)),
+    }
+}
+
+/// Cuts text to fit in a table column
+fn cell(text: &str, width: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() > width {
+        text.chars()
+            .take(width - 1)
+            .chain(std::iter::once('…'))
+            .collect()
+    } else {
+        text
+    }
+}
+
+/// Prints the entries to import as a table of titles, tags, and texts
+fn print_preview(entries: &[ImportedEntry]) {
+    println!("{:<40}  {:<20}  Text", "Title", "Tags");
+    for entry in entries {
+        let title = entry.title.as_deref().unwrap_or(&entry.uri);
+        let text = entry
+            .quote
+            .as_deref()
+            .or(entry.note.as_deref())
+            .unwrap_or_default();
+        println!(
+            "{:<40}  {:<20}  {}",
+            cell(title, 40),
+            cell(&entry.tags.join(", "), 20),
+            cell(text, 50)
+        );
+    }
+    println!("\n{} entries", entries.len());
+}
+
+/// ## Import
+/// Highlights and bookmarks from other tools, added to Hypothesis as page notes
+impl Gooseberry {
+    /// Imports an export file of another tool as page notes, with the quote, note, and original date in the text.
+    /// Shows the entries first and asks for confirmation (unless `force`), only showing them with `dry_run`
+    pub async fn import(
+        &self,
+        format: ImportFormat,
+        file: &Path,
+        tags: Vec<String>,
+        group: Option<String>,
+        dry_run: bool,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        let entries = read_entries(format, file)?;
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| !entry.uri.is_empty())
+            .collect();
+        if entries.is_empty() {
+            let error: color_eyre::Result<()> = Err(Apologize::ImportError {
+                message: format!("Nothing to import in {:?}", file),
+            }
+            .into());
+            return error.suggestion(format!("Is this a {} export?", format));
+        }
+        print_preview(&entries);
+        if dry_run
+            || !(force
+                || utils::confirm(
+                    &format!("Import {} entries into Hypothesis?", entries.len()),
+                    false,
+                )?)
+        {
+            return Ok(());
+        }
+        let group = match group {
+            Some(group) => group,
+            None => self
+                .config
+                .hypothesis_group
+                .clone()
+                .expect("This should have been set by Config"),
+        };
+        let inputs = entries
+            .iter()
+            .map(|entry| {
+                let mut entry_tags = entry.tags.clone();
+                for tag in &tags {
+                    if !tag.trim().is_empty() && !entry_tags.contains(tag) {
+                        entry_tags.push(tag.to_owned());
+                    }
+                }
+                let mut input = InputAnnotation::builder();
+                input
+                    .uri(&entry.uri)
+                    .text(entry.text())
+                    .tags(entry_tags)
+                    .group(&group)
+                    .target(
+                        Target::builder()
+                            .source(&entry.uri)
+                            .selector(Vec::new())
+                            .build()?,
+                    );
+                if let Some(title) = &entry.title {
+                    input.document(Document {
+                        title: vec![title.to_owned()],
+                        dc: None,
+                        highwire: None,
+                        link: vec![],
+                    });
+                }
+                Ok(input.build()?)
+            })
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+        let annotations = self.api.create_annotations(&inputs).await?;
+        println!("Imported {} entries", annotations.len());
+        self.sync().await?;
+        Ok(())
+    }
+}
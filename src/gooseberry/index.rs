@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use color_eyre::Help;
+use hypothesis::annotations::Annotation;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{QueryParser, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexWriter, TantivyDocument, Term};
+
+use crate::errors::Apologize;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Memory budget of the index writer, in bytes
+const WRITER_MEMORY: usize = 50_000_000;
+/// Maximum number of annotations returned by a full-text query
+const MAX_HITS: usize = 10_000;
+/// Weight of matches in the archived page text, compared to the annotation's own fields
+const PAGE_BOOST: f32 = 0.3;
+
+/// Fields stored in the full-text search index
+pub struct IndexFields {
+    id: Field,
+    quote: Field,
+    text: Field,
+    tags: Field,
+    uri: Field,
+    /// Resolved title of the annotated document
+    title: Field,
+    /// Archived text of the annotated page
+    page: Field,
+    /// The complete annotation as JSON, so that queries can be answered offline
+    json: Field,
+}
+
+impl IndexFields {
+    fn schema() -> (Schema, Self) {
+        let mut builder = Schema::builder();
+        let fields = IndexFields {
+            id: builder.add_text_field("id", STRING | STORED),
+            quote: builder.add_text_field("quote", TEXT),
+            text: builder.add_text_field("text", TEXT),
+            tags: builder.add_text_field("tags", TEXT),
+            uri: builder.add_text_field("uri", TEXT),
+            title: builder.add_text_field("title", TEXT),
+            page: builder.add_text_field("page", TEXT),
+            json: builder.add_text_field("json", STORED),
+        };
+        (builder.build(), fields)
+    }
+}
+
+/// ## Full-text index
+/// `tantivy` index over annotation quotes, text, tags, URIs, and document titles,
+/// and the archived text of annotated pages.
+/// Kept up to date during sync and used for offline, ranked search queries.
+impl Gooseberry {
+    /// Directory storing the full-text index, inside the database directory
+    /// so that it's cleared along with everything else
+    pub fn index_dir(&self) -> PathBuf {
+        self.db_dir().join("search_index")
+    }
+
+    /// Gets the full-text index.
+    /// Makes a new one the first time round
+    pub fn get_index(&self) -> color_eyre::Result<(Index, IndexFields)> {
+        let index_dir = self.index_dir();
+        if !index_dir.exists() {
+            fs::create_dir_all(&index_dir)?;
+        }
+        let (schema, fields) = IndexFields::schema();
+        let index = Index::open_or_create(MmapDirectory::open(&index_dir)?, schema)?;
+        Ok((index, fields))
+    }
+
+    /// Removes an index made with the schema of an older version of gooseberry,
+    /// so that the next sync fills a new one
+    pub fn check_index(&self) -> color_eyre::Result<()> {
+        let index_dir = self.index_dir();
+        if index_dir.exists() && self.get_index().is_err() {
+            fs::remove_dir_all(&index_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Add or update annotations in the index
+    pub fn index_annotations(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        let (index, fields) = self.get_index()?;
+        let mut writer: IndexWriter = index.writer(WRITER_MEMORY)?;
+        for annotation in annotations {
+            writer.delete_term(Term::from_field_text(fields.id, &annotation.id));
+            let mut document = doc!(
+                fields.id => annotation.id.as_str(),
+                fields.quote => utils::get_quotes(annotation).join("\n"),
+                fields.text => annotation.text.as_str(),
+                fields.uri => annotation.uri.as_str(),
+                fields.json => serde_json::to_string(annotation)?
+            );
+            for tag in &annotation.tags {
+                document.add_text(fields.tags, tag);
+            }
+            if let Some(title) = self.resolved_title(annotation)? {
+                document.add_text(fields.title, title);
+            }
+            if let Some(page_text) = self.get_archive(&annotation.uri)? {
+                document.add_text(fields.page, page_text);
+            }
+            writer.add_document(document)?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Remove annotations from the index
+    pub fn unindex_annotations(&self, ids: &[String]) -> color_eyre::Result<()> {
+        let (index, fields) = self.get_index()?;
+        let mut writer: IndexWriter = index.writer(WRITER_MEMORY)?;
+        for id in ids {
+            writer.delete_term(Term::from_field_text(fields.id, id));
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Ranked full-text query over the quote, text, tags, URI, and document title of synced annotations,
+    /// and the archived text of their pages (ranked lower).
+    ///
+    /// Supports phrases ("..."), boolean operators (AND, OR, NOT, +, -)
+    /// and field-specific terms (e.g. tags:rust)
+    pub fn query_index(&self, query: &str) -> color_eyre::Result<Vec<Annotation>> {
+        let (index, fields) = self.get_index()?;
+        let searcher = index.reader()?.searcher();
+        let mut query_parser = QueryParser::for_index(
+            &index,
+            vec![
+                fields.quote,
+                fields.text,
+                fields.tags,
+                fields.uri,
+                fields.title,
+                fields.page,
+            ],
+        );
+        query_parser.set_field_boost(fields.page, PAGE_BOOST);
+        let query = query_parser
+            .parse_query(query)
+            .suggestion("Use quotes for phrases and AND, OR, NOT to combine terms")?;
+        searcher
+            .search(&query, &TopDocs::with_limit(MAX_HITS))?
+            .into_iter()
+            .map(|(_score, address)| -> color_eyre::Result<Annotation> {
+                let document: TantivyDocument = searcher.doc(address)?;
+                let json = document
+                    .get_first(fields.json)
+                    .and_then(|value| value.as_str())
+                    .ok_or(Apologize::OutOfCheeseError {
+                        message: "Indexed annotation is missing its JSON".into(),
+                    })?;
+                Ok(serde_json::from_str(json)?)
+            })
+            .collect()
+    }
+
+    /// Annotations as they were last indexed, by ID (leaving out the ones that aren't in the index)
+    pub(crate) fn indexed_annotations(
+        &self,
+        ids: &[&str],
+    ) -> color_eyre::Result<HashMap<String, Annotation>> {
+        let (index, fields) = self.get_index()?;
+        let searcher = index.reader()?.searcher();
+        let mut annotations = HashMap::new();
+        for id in ids {
+            let query = TermQuery::new(
+                Term::from_field_text(fields.id, id),
+                IndexRecordOption::Basic,
+            );
+            if let Some((_score, address)) = searcher.search(&query, &TopDocs::with_limit(1))?.pop()
+            {
+                let document: TantivyDocument = searcher.doc(address)?;
+                if let Some(json) = document
+                    .get_first(fields.json)
+                    .and_then(|value| value.as_str())
+                {
+                    annotations.insert((*id).to_owned(), serde_json::from_str(json)?);
+                }
+            }
+        }
+        Ok(annotations)
+    }
+}
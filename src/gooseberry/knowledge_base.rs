@@ -1,25 +1,37 @@
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
 use color_eyre::Help;
-use dialoguer::theme::ColorfulTheme;
-use dialoguer::Confirm;
-use handlebars::{Handlebars, RenderError};
+use handlebars::Handlebars;
 use hypothesis::annotations::Annotation;
 use sanitize_filename::sanitize;
 use serde::Serialize;
-use serde_json::Value as Json;
 use url::Url;
 
 use crate::configuration::{
-    OrderBy, DEFAULT_ANNOTATION_TEMPLATE, DEFAULT_INDEX_LINK_TEMPLATE, DEFAULT_PAGE_TEMPLATE,
+    KbFormat, OrderBy, DEFAULT_ANNOTATION_TEMPLATE, DEFAULT_INDEX_LINK_TEMPLATE,
+    DEFAULT_INDEX_TEMPLATE, DEFAULT_PAGE_TEMPLATE,
 };
 use crate::errors::Apologize;
-use crate::gooseberry::cli::Filters;
+use crate::gooseberry::bibliography::{register_citation_helpers, Bibliography};
+use crate::gooseberry::cli::{Filters, SiteFormat, SortOptions};
+use crate::gooseberry::epub::{epub_location, EpubLocation};
+use crate::gooseberry::git::{self, KbChanges};
+use crate::gooseberry::helpers::{register_helpers, register_obsidian_helper};
+use crate::gooseberry::html;
+use crate::gooseberry::links::{describe_related, RelatedAnnotation};
+use crate::gooseberry::meta;
+use crate::gooseberry::push::EditMarker;
+use crate::gooseberry::site::{quote, SitePage};
+use crate::gooseberry::stats;
+use crate::gooseberry::storage::{Batch, Tree};
+use crate::gooseberry::todo::TodoItem;
+use crate::gooseberry::visibility::visibility;
 use crate::gooseberry::Gooseberry;
 use crate::utils;
 use crate::utils::{clean_uri, uri_to_filename};
@@ -31,16 +43,80 @@ pub struct AnnotationTemplate {
     #[serde(flatten)]
     pub annotation: Annotation,
     pub base_uri: String,
+    /// URI of the annotated document: the fingerprint URN (`urn:x-pdf:...`) for PDFs,
+    /// shared by all copies of the file, or the annotated URI otherwise
+    pub document_uri: String,
     pub title: String,
     pub incontext: String,
     pub highlight: Vec<String>,
+    /// Text just before the first highlight in the document
+    #[serde(default)]
+    pub prefix: String,
+    /// Text just after the first highlight in the document
+    #[serde(default)]
+    pub suffix: String,
     pub display_name: Option<String>,
+    /// Username of the annotation's author (without `acct:` and the authority)
+    #[serde(default)]
+    pub username: String,
+    /// Whether you're the author of the annotation (and not someone else in a shared group)
+    #[serde(default)]
+    pub mine: bool,
+    /// Paragraph of the archived page with the highlight in it
+    pub context: Option<String>,
+    /// Readable text of the archived page
+    pub page_text: Option<String>,
+    /// Wayback Machine snapshot of the page
+    pub archive_url: Option<String>,
+    /// Chapter and reading position, for annotations on EPUBs
+    pub epub: Option<EpubLocation>,
+    /// Replies to the annotation (with their own replies), oldest first
+    #[serde(default)]
+    pub replies: Vec<AnnotationTemplate>,
+    /// Annotations linked to or from this one with `gooseberry link`
+    #[serde(default)]
+    pub related: Vec<RelatedAnnotation>,
+    /// Local metadata of the annotation, set with `gooseberry meta`
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
+    /// Who can read the annotation: `private`, `group`, or `world`
+    #[serde(default)]
+    pub visibility: String,
+    /// Indentation of a reply, two spaces per level below the first
+    #[serde(default)]
+    pub indent: String,
 }
 
 pub fn replace_spaces(astring: &str) -> String {
     astring.replace(" ", "\\ ")
 }
 
+/// Title of the annotated document according to Hypothesis
+pub(crate) fn document_title(annotation: &Annotation) -> Option<&str> {
+    annotation
+        .document
+        .as_ref()
+        .and_then(|document| document.title.first())
+        .map(String::as_str)
+}
+
+/// Start of the URN Hypothesis identifies PDFs by, followed by their fingerprint
+pub(crate) const PDF_URN: &str = "urn:x-pdf:";
+
+/// Fingerprint URN of an annotated PDF, the same whether it was annotated as a local file or online
+pub(crate) fn pdf_fingerprint(annotation: &Annotation) -> Option<&str> {
+    if annotation.uri.starts_with(PDF_URN) {
+        return Some(&annotation.uri);
+    }
+    annotation
+        .document
+        .as_ref()?
+        .link
+        .iter()
+        .map(|link| link.href.as_str())
+        .find(|href| href.starts_with(PDF_URN))
+}
+
 impl AnnotationTemplate {
     pub(crate) fn from_annotation(annotation: Annotation) -> Self {
         let base_uri = if let Ok(uri) = Url::parse(&annotation.uri) {
@@ -57,42 +133,215 @@ impl AnnotationTemplate {
             .into_iter()
             .map(|s| s.to_owned())
             .collect();
+        let (prefix, suffix) = utils::get_quote_context(&annotation)
+            .map(|(prefix, suffix)| (prefix.to_owned(), suffix.to_owned()))
+            .unwrap_or_default();
+        let username = utils::username(&annotation.user.0).to_owned();
         let display_name = if let Some(user_info) = &annotation.user_info {
             user_info.display_name.clone()
         } else {
             None
         };
-        let mut title = String::from("Untitled document");
-        if let Some(document) = &annotation.document {
-            if !document.title.is_empty() {
-                title = document.title[0].to_owned();
-            }
-        }
+        let title = document_title(&annotation)
+            .unwrap_or("Untitled document")
+            .to_owned();
+        let document_uri = pdf_fingerprint(&annotation)
+            .unwrap_or(&annotation.uri)
+            .to_owned();
+        let epub = epub_location(&annotation);
+        let visibility = visibility(&annotation).to_string().to_ascii_lowercase();
         AnnotationTemplate {
             annotation,
             base_uri,
+            document_uri,
             title,
             incontext,
             highlight,
+            prefix,
+            suffix,
             display_name,
+            username,
+            mine: true,
+            context: None,
+            page_text: None,
+            archive_url: None,
+            epub,
+            replies: Vec::new(),
+            related: Vec::new(),
+            meta: BTreeMap::new(),
+            visibility,
+            indent: String::new(),
         }
     }
 }
 
-pub(crate) fn format_date<E: AsRef<str>>(
-    format: E,
-    date: &Json,
-) -> Result<String, serde_json::Error> {
-    let date: DateTime<Utc> = serde_json::from_value(date.clone())?;
-    Ok(format!("{}", date.format(format.as_ref())))
+/// Name of the annotation's author: their display name, or username if they haven't set one
+fn author(annotation: &AnnotationTemplate) -> &str {
+    annotation
+        .display_name
+        .as_deref()
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or(&annotation.username)
+}
+
+/// Compares annotations by a field
+fn compare(field: OrderBy, a: &AnnotationTemplate, b: &AnnotationTemplate) -> Ordering {
+    match field {
+        OrderBy::Tag => a
+            .annotation
+            .tags
+            .join(",")
+            .cmp(&b.annotation.tags.join(",")),
+        OrderBy::URI => clean_uri(&a.document_uri).cmp(&clean_uri(&b.document_uri)),
+        OrderBy::BaseURI => clean_uri(&a.base_uri).cmp(&clean_uri(&b.base_uri)),
+        OrderBy::Title => a.title.cmp(&b.title),
+        OrderBy::ID => a.annotation.id.cmp(&b.annotation.id),
+        OrderBy::Group => a.annotation.group.cmp(&b.annotation.group),
+        OrderBy::User => author(a).cmp(author(b)),
+        OrderBy::Created => format!("{}", a.annotation.created.format("%+"))
+            .cmp(&format!("{}", b.annotation.created.format("%+"))),
+        OrderBy::Updated => format!("{}", a.annotation.updated.format("%+"))
+            .cmp(&format!("{}", b.annotation.updated.format("%+"))),
+        // Page notes (without a position) come first
+        OrderBy::Position => a.document_uri.cmp(&b.document_uri).then_with(|| {
+            (&a.epub, utils::get_text_position(&a.annotation))
+                .cmp(&(&b.epub, utils::get_text_position(&b.annotation)))
+        }),
+        OrderBy::Domain => stats::domain(&a.annotation.uri).cmp(&stats::domain(&b.annotation.uri)),
+        OrderBy::Date => date(a).cmp(&date(b)),
+        OrderBy::Rating => match (rating(a), rating(b)) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+        OrderBy::Empty => panic!("Shouldn't happen"),
+    }
+}
+
+/// Rating in an annotation's metadata, if it's a number
+fn rating(annotation: &AnnotationTemplate) -> Option<f64> {
+    annotation
+        .meta
+        .get(meta::RATING_KEY)
+        .and_then(|rating| rating.trim().parse().ok())
+}
+
+/// Day an annotation was made on (in the local time zone), as YYYY-MM-DD
+fn date(annotation: &AnnotationTemplate) -> String {
+    annotation
+        .annotation
+        .created
+        .with_timezone(&Local)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Sorts annotations by fields in order of priority.
+/// If creation date comes first, the annotations on each EPUB are put in reading order instead
+pub(crate) fn sort_by_fields(annotations: &mut [AnnotationTemplate], order: &[OrderBy]) {
+    annotations.sort_by(|a, b| {
+        order.iter().fold(Ordering::Equal, |acc, &field| {
+            acc.then_with(|| compare(field, a, b))
+        })
+    });
+    if order.first() == Some(&OrderBy::Created) {
+        sort_by_reading_position(annotations);
+    }
+}
+
+/// Puts the annotations on each EPUB in reading order,
+/// in the places the other annotations leave for them
+fn sort_by_reading_position(annotations: &mut [AnnotationTemplate]) {
+    let mut books: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, annotation) in annotations.iter().enumerate() {
+        if annotation.epub.is_some() {
+            books
+                .entry(annotation.document_uri.to_owned())
+                .or_default()
+                .push(index);
+        }
+    }
+    for indices in books.values() {
+        let mut book: Vec<_> = indices.iter().map(|&i| annotations[i].clone()).collect();
+        book.sort_by(|a, b| a.epub.cmp(&b.epub));
+        for (&index, annotation) in indices.iter().zip(book) {
+            annotations[index] = annotation;
+        }
+    }
 }
 
-handlebars_helper!(date_format: |format: str, date: Json| format_date(format, date).map_err(|e| RenderError::from_error("serde_json", e))?);
+/// Moves replies under the annotations they reply to (if those are there too), as deep as the thread goes.
+/// Replies are known from their references, or from the reply tree for `reply_ids`
+fn nest_replies(
+    annotations: Vec<AnnotationTemplate>,
+    reply_ids: &HashMap<String, Vec<String>>,
+) -> Vec<AnnotationTemplate> {
+    let ids: HashSet<_> = annotations
+        .iter()
+        .map(|a| a.annotation.id.to_owned())
+        .collect();
+    let mut parents: HashMap<String, String> = HashMap::new();
+    for (parent, replies) in reply_ids {
+        for reply in replies {
+            parents.insert(reply.to_owned(), parent.to_owned());
+        }
+    }
+    let mut replies: HashMap<String, Vec<AnnotationTemplate>> = HashMap::new();
+    let mut top_level = Vec::new();
+    for annotation in annotations {
+        let parent = annotation
+            .annotation
+            .references
+            .last()
+            .or_else(|| parents.get(&annotation.annotation.id))
+            .filter(|parent| ids.contains(*parent))
+            .cloned();
+        match parent {
+            Some(parent) => replies.entry(parent).or_default().push(annotation),
+            None => top_level.push(annotation),
+        }
+    }
+    fn attach(
+        annotation: &mut AnnotationTemplate,
+        replies: &mut HashMap<String, Vec<AnnotationTemplate>>,
+        depth: usize,
+    ) {
+        if let Some(mut children) = replies.remove(&annotation.annotation.id) {
+            children.sort_by_key(|child| child.annotation.created);
+            for child in &mut children {
+                child.indent = "  ".repeat(depth);
+                attach(child, replies, depth + 1);
+            }
+            annotation.replies = children;
+        }
+    }
+    for annotation in &mut top_level {
+        attach(annotation, &mut replies, 0);
+    }
+    top_level
+}
+
+/// Org-mode tags can only have letters, numbers, `_`, `@`, `#`, and `%`
+pub(crate) fn org_tag(tag: &str) -> String {
+    tag.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_@#%".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+handlebars_helper!(org_tag_helper: |tag: str| org_tag(tag));
 
 pub(crate) struct Templates<'a> {
     pub(crate) annotation_template: &'a str,
     pub(crate) page_template: &'a str,
     pub(crate) index_link_template: &'a str,
+    pub(crate) index_template: &'a str,
 }
 
 impl<'a> Default for Templates<'a> {
@@ -101,17 +350,21 @@ impl<'a> Default for Templates<'a> {
             annotation_template: DEFAULT_ANNOTATION_TEMPLATE,
             page_template: DEFAULT_PAGE_TEMPLATE,
             index_link_template: DEFAULT_INDEX_LINK_TEMPLATE,
+            index_template: DEFAULT_INDEX_TEMPLATE,
         }
     }
 }
 
-pub(crate) fn get_handlebars(templates: Templates) -> color_eyre::Result<Handlebars> {
+pub(crate) fn get_handlebars(templates: Templates) -> color_eyre::Result<Handlebars<'static>> {
     let mut hbs = Handlebars::new();
     hbs.register_escape_fn(handlebars::no_escape);
-    hbs.register_helper("date_format", Box::new(date_format));
+    register_helpers(&mut hbs);
+    hbs.register_helper("org_tag", Box::new(org_tag_helper));
+    register_citation_helpers(&mut hbs, Bibliography::default());
     hbs.register_template_string("annotation", templates.annotation_template)?;
     hbs.register_template_string("page", templates.page_template)?;
     hbs.register_template_string("index_link", templates.index_link_template)?;
+    hbs.register_template_string("index", templates.index_template)?;
     Ok(hbs)
 }
 
@@ -120,24 +373,32 @@ pub(crate) fn get_handlebars(templates: Templates) -> color_eyre::Result<Handleb
 pub struct LinkTemplate {
     pub name: String,
     pub relative_path: String,
+    /// Relative path without spaces replaced by %20 (e.g. for org-mode file links)
+    pub raw_relative_path: String,
     pub absolute_path: String,
+    /// Title of the document the page's annotations are from, if they're all from the same one
+    pub title: Option<String>,
+    /// What the page's annotations were grouped by at the last level of the hierarchy,
+    /// e.g. the tag, URI, domain, or day
+    pub group_key: Option<String>,
 }
 
 fn get_link_data(path: &Path, src_dir: &Path) -> color_eyre::Result<LinkTemplate> {
+    let raw_relative_path = path
+        .strip_prefix(src_dir)?
+        .to_str()
+        .ok_or(Apologize::KBError {
+            message: format!("{:?} has non-unicode characters", path),
+        })?
+        .to_string();
     Ok(LinkTemplate {
         name: path
             .file_stem()
             .unwrap_or_else(|| "EMPTY".as_ref())
             .to_string_lossy()
             .to_string(),
-        relative_path: path
-            .strip_prefix(&src_dir)?
-            .to_str()
-            .ok_or(Apologize::KBError {
-                message: format!("{:?} has non-unicode characters", path),
-            })?
-            .to_string()
-            .replace(' ', "%20"),
+        relative_path: raw_relative_path.replace(' ', "%20"),
+        raw_relative_path,
         absolute_path: path
             .to_str()
             .ok_or(Apologize::KBError {
@@ -145,26 +406,634 @@ fn get_link_data(path: &Path, src_dir: &Path) -> color_eyre::Result<LinkTemplate
             })?
             .to_string()
             .replace(' ', "%20"),
+        title: None,
+        group_key: None,
     })
 }
 
+/// Title of the document annotations are from, if they're all from the same one
+fn common_title(annotations: &[AnnotationTemplate]) -> Option<String> {
+    let first = annotations.first()?;
+    annotations
+        .iter()
+        .all(|a| a.document_uri == first.document_uri)
+        .then(|| first.title.to_owned())
+}
+
+/// Data for the file name template, about the annotations on a page
+#[derive(Debug, Serialize)]
+pub struct FileNameTemplate {
+    /// File name gooseberry uses without a file name template, from the value the annotations were grouped by
+    pub name: String,
+    /// What the page's annotations were grouped by at the last level of the hierarchy
+    pub group_key: Option<String>,
+    /// Title of the document the annotations are from if they're all from the same one, the grouped by value otherwise
+    pub title: String,
+    /// URI of the document the annotations are from, if they're all from the same one
+    pub uri: Option<String>,
+    /// ID of the first annotation made
+    pub id: String,
+    /// When the first annotation was made
+    pub created: DateTime<Utc>,
+    /// When the annotations were last updated
+    pub updated: DateTime<Utc>,
+}
+
+/// Names pages with the `file_name_template`, numbering names already taken by another page
+/// (`name-2`, `name-3`, ...)
+struct FileNamer<'a> {
+    hbs: &'a Handlebars<'a>,
+    extension: &'a str,
+    taken: RefCell<HashSet<PathBuf>>,
+}
+
+impl<'a> FileNamer<'a> {
+    /// Path of the page for the annotations grouped into `folder` (its default path without the extension).
+    /// Slashes in the rendered name make sub-folders
+    fn path(
+        &self,
+        folder: &Path,
+        group_key: Option<String>,
+        annotations: &[AnnotationTemplate],
+    ) -> color_eyre::Result<PathBuf> {
+        let name = folder
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let first = annotations
+            .iter()
+            .min_by_key(|a| a.annotation.created)
+            .ok_or(Apologize::KBError {
+                message: format!("No annotations for {:?}", folder),
+            })?;
+        let uri = annotations
+            .iter()
+            .all(|a| a.document_uri == first.document_uri)
+            .then(|| first.document_uri.to_owned());
+        let data = FileNameTemplate {
+            title: common_title(annotations)
+                .or_else(|| group_key.clone())
+                .unwrap_or_else(|| name.clone()),
+            name,
+            group_key,
+            uri,
+            id: first.annotation.id.to_owned(),
+            created: first.annotation.created,
+            updated: annotations
+                .iter()
+                .map(|a| a.annotation.updated)
+                .max()
+                .unwrap_or(first.annotation.updated),
+        };
+        let rendered = self.hbs.render("file_name", &data)?;
+        let rendered = rendered
+            .trim()
+            .trim_end_matches(&format!(".{}", self.extension));
+        let parts: Vec<_> = rendered
+            .split('/')
+            .map(|part| sanitize(part.trim()))
+            .filter(|part| !part.is_empty())
+            .collect();
+        let mut base = folder.parent().unwrap_or(folder).to_path_buf();
+        if parts.is_empty() {
+            base.push(&data.name);
+        } else {
+            base.extend(parts);
+        }
+        let base = base.to_string_lossy().to_string();
+        let mut taken = self.taken.borrow_mut();
+        let mut path = PathBuf::from(format!("{}.{}", base, self.extension));
+        let mut number = 2;
+        while taken.contains(&path) {
+            path = PathBuf::from(format!("{}-{}.{}", base, number, self.extension));
+            number += 1;
+        }
+        taken.insert(path.clone());
+        Ok(path)
+    }
+}
+
+/// A page or folder in the index tree
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IndexNode {
+    /// Page or folder name
+    pub name: String,
+    /// Nesting level, 0 at the top
+    pub depth: usize,
+    /// Two spaces per nesting level, for nested lists
+    pub indent: String,
+    /// Rendered index link (without trailing newlines) if there's a page with this name
+    pub link: Option<String>,
+    pub children: Vec<IndexNode>,
+}
+
+/// To make the index file from the index links
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IndexTemplate {
+    /// Rendered index links, sorted by path
+    pub links: Vec<String>,
+    /// Rendered index links nested by folder
+    pub tree: Vec<IndexNode>,
+    /// Field of the last level of the hierarchy (e.g. `tag`, `domain`, or `date`), which the pages are named after
+    pub group_by: Option<String>,
+    /// Tasks (annotations with the todo tag) which aren't snoozed, oldest first
+    #[serde(default)]
+    pub todos: Vec<TodoItem>,
+}
+
+impl IndexTemplate {
+    /// Arranges (relative path, rendered index link) pairs into a tree
+    pub(crate) fn new(mut links: Vec<(String, String)>) -> Self {
+        links.sort();
+        let mut tree: Vec<IndexNode> = Vec::new();
+        for (relative_path, link) in &links {
+            let path = Path::new(relative_path).with_extension("");
+            let names: Vec<_> = path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let mut nodes = &mut tree;
+            for (depth, name) in names.iter().enumerate() {
+                let position = match nodes.iter().position(|node| &node.name == name) {
+                    Some(position) => position,
+                    None => {
+                        nodes.push(IndexNode {
+                            name: name.to_owned(),
+                            depth,
+                            indent: "  ".repeat(depth),
+                            link: None,
+                            children: Vec::new(),
+                        });
+                        nodes.len() - 1
+                    }
+                };
+                if depth + 1 == names.len() {
+                    nodes[position].link = Some(link.trim_end_matches('\n').to_owned());
+                }
+                nodes = &mut nodes[position].children;
+            }
+        }
+        IndexTemplate {
+            links: links.into_iter().map(|(_, link)| link).collect(),
+            tree,
+            group_by: None,
+            todos: Vec::new(),
+        }
+    }
+}
+
 /// To convert an annotation to text
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PageTemplate {
     #[serde(flatten)]
     pub link_data: LinkTemplate,
+    /// All tags of the annotations on the page
+    pub tags: Vec<String>,
     pub annotations: Vec<String>,
     pub raw_annotations: Vec<AnnotationTemplate>,
 }
 
+impl PageTemplate {
+    /// Renders the annotations on a page and collects their tags
+    fn new(
+        link_data: LinkTemplate,
+        raw_annotations: Vec<AnnotationTemplate>,
+        renderer: &AnnotationRenderer,
+    ) -> color_eyre::Result<Self> {
+        let mut tags: Vec<_> = raw_annotations
+            .iter()
+            .flat_map(|a| a.annotation.tags.iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        tags.sort();
+        Ok(PageTemplate {
+            link_data,
+            tags,
+            annotations: raw_annotations
+                .iter()
+                .map(|a| renderer.render(a))
+                .collect::<color_eyre::Result<Vec<String>>>()?,
+            raw_annotations,
+        })
+    }
+}
+
+/// Data for the front matter template, about the annotations on a page
+#[derive(Debug, Serialize)]
+pub struct FrontMatterTemplate {
+    /// Title of the document the annotations are from if they're all from the same one, the page name otherwise
+    pub title: String,
+    /// URI of the document the annotations are from, if they're all from the same one
+    pub uri: Option<String>,
+    /// All tags of the annotations on the page
+    pub tags: Vec<String>,
+    /// When the first annotation was made
+    pub created: Option<DateTime<Utc>>,
+    /// When the annotations were last updated
+    pub updated: Option<DateTime<Utc>>,
+    /// IDs of the annotations on the page
+    pub ids: Vec<String>,
+    /// Name of the page
+    pub name: String,
+    /// What the page's annotations were grouped by at the last level of the hierarchy
+    pub group_key: Option<String>,
+}
+
+impl FrontMatterTemplate {
+    fn new(link_data: &LinkTemplate, page: &PageTemplate) -> Self {
+        let annotations = &page.raw_annotations;
+        let uri = annotations
+            .first()
+            .map(|a| a.document_uri.to_owned())
+            .filter(|uri| annotations.iter().all(|a| &a.document_uri == uri));
+        FrontMatterTemplate {
+            title: link_data
+                .title
+                .clone()
+                .unwrap_or_else(|| link_data.name.to_owned()),
+            uri,
+            tags: page.tags.clone(),
+            created: annotations.iter().map(|a| a.annotation.created).min(),
+            updated: annotations.iter().map(|a| a.annotation.updated).max(),
+            ids: annotations
+                .iter()
+                .map(|a| a.annotation.id.to_owned())
+                .collect(),
+            name: link_data.name.to_owned(),
+            group_key: link_data.group_key.clone(),
+        }
+    }
+
+    /// YAML front matter (without the `---` lines), with strings quoted and lists in flow style
+    fn to_yaml(&self) -> String {
+        let list = |values: &[String]| {
+            format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|v| quote(v))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        };
+        let format_date = |date: DateTime<Utc>| date.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut lines = vec![format!("title: {}", quote(&self.title))];
+        if let Some(uri) = &self.uri {
+            lines.push(format!("uri: {}", quote(uri)));
+        }
+        lines.push(format!("tags: {}", list(&self.tags)));
+        if let Some(created) = self.created {
+            lines.push(format!("created: {}", format_date(created)));
+        }
+        if let Some(updated) = self.updated {
+            lines.push(format!("updated: {}", format_date(updated)));
+        }
+        lines.push(format!("annotations: {}", list(&self.ids)));
+        lines.join("\n")
+    }
+}
+
+/// Renders annotations for the knowledge base with the templates configured for their tags or group,
+/// marked for `sync --push` if the knowledge base is editable
+pub(crate) struct AnnotationRenderer<'a> {
+    pub(crate) hbs: &'a Handlebars<'a>,
+    pub(crate) marker: Option<&'a EditMarker>,
+    /// Annotation ID: its tags and group which have template overrides, in the order of the config
+    pub(crate) overrides: HashMap<String, Vec<String>>,
+    /// Start pages with YAML front matter, from the `front_matter` template if there is one
+    pub(crate) front_matter: bool,
+    /// Wrap annotations in `<article>`s with their IDs as anchors, for HTML pages
+    pub(crate) html: bool,
+}
+
+impl<'a> AnnotationRenderer<'a> {
+    /// Name of the annotation template: the override of the annotation's first tag or group which has one
+    fn annotation_template(&self, annotation: &AnnotationTemplate) -> String {
+        self.overrides
+            .get(&annotation.annotation.id)
+            .and_then(|keys| {
+                keys.iter()
+                    .map(|key| format!("annotation/{}", key))
+                    .find(|name| self.hbs.has_template(name))
+            })
+            .unwrap_or_else(|| "annotation".to_owned())
+    }
+
+    /// Name of the page template: the override of the first tag or group shared by all annotations on the page
+    fn page_template(&self, annotations: &[AnnotationTemplate]) -> String {
+        let shared_by_all = |key: &String| {
+            annotations.iter().all(|a| {
+                matches!(self.overrides.get(&a.annotation.id), Some(keys) if keys.contains(key))
+            })
+        };
+        annotations
+            .first()
+            .and_then(|first| self.overrides.get(&first.annotation.id))
+            .and_then(|keys| {
+                keys.iter()
+                    .filter(|key| shared_by_all(key))
+                    .map(|key| format!("page/{}", key))
+                    .find(|name| self.hbs.has_template(name))
+            })
+            .unwrap_or_else(|| "page".to_owned())
+    }
+
+    pub(crate) fn render(&self, annotation: &AnnotationTemplate) -> color_eyre::Result<String> {
+        let template = self.annotation_template(annotation);
+        let rendered = match self.marker {
+            Some(marker) => marker.render(self.hbs, &template, annotation)?,
+            None => self.hbs.render(&template, annotation)?,
+        };
+        if self.html {
+            Ok(html::annotation_article(
+                &annotation.annotation.id,
+                &rendered,
+            ))
+        } else {
+            Ok(rendered)
+        }
+    }
+
+    /// Renders a page with its annotations
+    #[tracing::instrument(level = "trace", skip_all, fields(page = %link_data.name, annotations = annotations.len()))]
+    fn render_page(
+        &self,
+        link_data: LinkTemplate,
+        annotations: Vec<AnnotationTemplate>,
+    ) -> color_eyre::Result<String> {
+        let template = self.page_template(&annotations);
+        let page = PageTemplate::new(link_data, annotations, self)?;
+        let contents = self.hbs.render(&template, &page)?;
+        if !self.front_matter {
+            return Ok(contents);
+        }
+        let data = FrontMatterTemplate::new(&page.link_data, &page);
+        let front_matter = if self.hbs.has_template("front_matter") {
+            self.hbs.render("front_matter", &data)?
+        } else {
+            data.to_yaml()
+        };
+        Ok(format!("---\n{}\n---\n\n{}", front_matter.trim(), contents))
+    }
+}
+
+/// Writes knowledge base files, skipping files whose contents haven't changed since the last `make`
+struct KbWriter {
+    /// Tree storing file path: content hash, from previous runs
+    hashes: Tree,
+    /// Files written (or left as is) in this run
+    seen: RefCell<HashSet<Vec<u8>>>,
+    /// Number of files actually (re)written in this run
+    written: Cell<usize>,
+    /// Tree storing annotation ID: path of its file, replaced at the end of this run
+    locations: Tree,
+    /// Annotation ID: path of the first file it was written to in this run
+    located: RefCell<HashMap<String, String>>,
+    /// Only print which files would be written and deleted
+    dry_run: bool,
+    /// With `dry_run`, whether the directory would have been cleared first (so every file would be written)
+    cleared: bool,
+}
+
+impl KbWriter {
+    fn new(hashes: Tree, locations: Tree, dry_run: bool, cleared: bool) -> Self {
+        KbWriter {
+            hashes,
+            seen: RefCell::new(HashSet::new()),
+            written: Cell::new(0),
+            locations,
+            located: RefCell::new(HashMap::new()),
+            dry_run,
+            cleared,
+        }
+    }
+
+    /// Writes a file (making its folder if needed) unless it already exists with the same contents
+    fn write(&self, path: &Path, contents: &str) -> color_eyre::Result<()> {
+        let key = path
+            .to_str()
+            .ok_or(Apologize::KBError {
+                message: format!("{:?} has non-unicode characters", path),
+            })?
+            .as_bytes()
+            .to_vec();
+        let hash = utils::stable_hash([contents.as_bytes()]);
+        if self.cleared || !path.exists() || self.hashes.get(&key)?.as_deref() != Some(&hash[..]) {
+            if self.dry_run {
+                println!("Would write {:?}", path);
+            } else {
+                tracing::debug!(?path, "writing");
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::File::create(path)?.write_all(contents.as_bytes())?;
+                self.hashes.insert(&key, &hash[..])?;
+            }
+            self.written.set(self.written.get() + 1);
+        }
+        self.seen.borrow_mut().insert(key);
+        Ok(())
+    }
+
+    /// Records the file an annotation (and its replies) is written to, unless it's already in another one
+    fn locate(&self, path: &Path, annotation: &AnnotationTemplate) {
+        self.located
+            .borrow_mut()
+            .entry(annotation.annotation.id.to_owned())
+            .or_insert_with(|| path.to_string_lossy().to_string());
+        for reply in &annotation.replies {
+            self.locate(path, reply);
+        }
+    }
+
+    /// Sets the path (relative to the knowledge base directory) of the page each task was written to
+    fn locate_todos(&self, todos: &mut [TodoItem], src_dir: &Path) {
+        let located = self.located.borrow();
+        for todo in todos {
+            todo.relative_path = located.get(&todo.id).and_then(|path| {
+                Path::new(path)
+                    .strip_prefix(src_dir)
+                    .ok()
+                    .map(|path| path.to_string_lossy().replace(' ', "%20"))
+            });
+        }
+    }
+
+    /// Deletes files from previous runs which weren't written in this one
+    /// (along with any folders left empty).
+    /// Returns the number of written and deleted files
+    fn finish(self, src_dir: &Path) -> color_eyre::Result<(usize, usize)> {
+        if !self.dry_run {
+            let mut batch = Batch::default();
+            for (id, path) in self.located.into_inner() {
+                batch.insert(id, path);
+            }
+            self.locations.clear()?;
+            self.locations.apply_batch(batch)?;
+        }
+        let mut deleted = 0;
+        let seen = self.seen.into_inner();
+        for key in self.hashes.iter().keys() {
+            let key = key?;
+            if seen.contains(&key) {
+                continue;
+            }
+            let path = PathBuf::from(std::str::from_utf8(&key)?);
+            if self.dry_run {
+                // Everything is deleted when the directory is cleared
+                if !self.cleared && path.starts_with(src_dir) && path.exists() {
+                    println!("Would delete {:?}", path);
+                    deleted += 1;
+                }
+                continue;
+            }
+            self.hashes.remove(&key)?;
+            // Files from a previous knowledge base directory are left alone
+            if path.starts_with(src_dir) && path.exists() {
+                remove_kb_file(&path, src_dir)?;
+                deleted += 1;
+            }
+        }
+        Ok((self.written.get(), deleted))
+    }
+}
+
+/// Deletes a knowledge base file, along with the folders (inside `root`) it leaves empty
+fn remove_kb_file(path: &Path, root: &Path) -> color_eyre::Result<()> {
+    fs::remove_file(path)?;
+    let mut folder = path.parent();
+    while let Some(dir) = folder {
+        if dir == root || fs::read_dir(dir)?.next().is_some() {
+            break;
+        }
+        fs::remove_dir(dir)?;
+        folder = dir.parent();
+    }
+    Ok(())
+}
+
+/// Renders a page of a static site, with its slugified path and unslugified name in the link data
+fn site_page(
+    site: SiteFormat,
+    names: Vec<String>,
+    group_key: Option<String>,
+    annotations: Vec<AnnotationTemplate>,
+    renderer: &AnnotationRenderer,
+    src_dir: &Path,
+) -> color_eyre::Result<SitePage> {
+    let mut page = SitePage::new(site, names, &annotations);
+    let mut link_data = get_link_data(&src_dir.join(page.path()), src_dir)?;
+    link_data.name = page.title().to_owned();
+    link_data.group_key = group_key;
+    page.contents = renderer.render_page(link_data, annotations)?;
+    Ok(page)
+}
+
+/// Makes an `[[wikilink]]`
+fn wikilink(name: &str) -> String {
+    format!("[[{}]]", name)
+}
+
 /// ## Markdown generation
 /// functions related to generating the `mdBook` wiki
 impl Gooseberry {
-    pub(crate) fn get_handlebars(&self) -> color_eyre::Result<Handlebars> {
-        Ok(get_handlebars(self.config.get_templates())?)
+    pub(crate) fn get_handlebars(&self) -> color_eyre::Result<Handlebars<'static>> {
+        let mut hbs = get_handlebars(self.config.get_templates())?;
+        if let Some(path) = &self.config.bibliography {
+            register_citation_helpers(&mut hbs, Bibliography::from_file(path)?);
+        }
+        register_obsidian_helper(&mut hbs, self.config.obsidian_vault());
+        Ok(hbs)
+    }
+
+    /// Registers the annotation and page templates configured for tags and groups,
+    /// as `annotation/<tag or group>` and `page/<tag or group>`
+    pub(crate) fn register_template_overrides(
+        &self,
+        hbs: &mut Handlebars,
+    ) -> color_eyre::Result<()> {
+        for (key, templates) in self.config.templates.iter().flatten() {
+            let files = [
+                ("annotation", &templates.annotation),
+                ("page", &templates.page),
+            ];
+            for (kind, path) in files.iter() {
+                let path = match path {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let template = fs::read_to_string(path)
+                    .map_err(|e| Apologize::ConfigError {
+                        message: format!("Couldn't read the {} template {:?}: {}", kind, path, e),
+                    })
+                    .suggestion("Check the [templates] table of the config file")?;
+                hbs.register_template_string(&format!("{}/{}", kind, key), template)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tags and groups of each annotation which have template overrides, in the order of the config
+    pub(crate) fn template_overrides(
+        &self,
+        annotations: &[AnnotationTemplate],
+    ) -> HashMap<String, Vec<String>> {
+        let keys: Vec<_> = self
+            .config
+            .templates
+            .iter()
+            .flat_map(|t| t.keys())
+            .collect();
+        annotations
+            .iter()
+            .filter_map(|a| {
+                let matching: Vec<_> = keys
+                    .iter()
+                    .filter(|&&key| a.annotation.tags.contains(key) || &a.annotation.group == key)
+                    .map(|&key| key.to_owned())
+                    .collect();
+                if matching.is_empty() {
+                    None
+                } else {
+                    Some((a.annotation.id.to_owned(), matching))
+                }
+            })
+            .collect()
+    }
+
+    /// Files written by `make` which are still in the knowledge base directory
+    pub(crate) fn kb_file_paths(&self) -> color_eyre::Result<Vec<PathBuf>> {
+        let kb_dir = match &self.config.kb_dir {
+            Some(kb_dir) => kb_dir,
+            None => return Ok(Vec::new()),
+        };
+        let mut paths = Vec::new();
+        for key in self.kb_files()?.iter().keys() {
+            let path = PathBuf::from(std::str::from_utf8(&key?)?);
+            if path.starts_with(kb_dir) && path.exists() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Deletes knowledge base files (see `kb_file_paths`) and forgets what `make` wrote,
+    /// so that the next `make` writes everything again
+    pub(crate) fn clear_kb(&self, paths: &[PathBuf]) -> color_eyre::Result<()> {
+        let kb_dir = self.config.kb_dir.as_ref().unwrap();
+        for path in paths {
+            remove_kb_file(path, kb_dir)?;
+        }
+        self.kb_files()?.clear()?;
+        self.kb_locations()?.clear()?;
+        self.kb_annotations()?.clear()?;
+        Ok(())
     }
 
     fn configure_kb(&mut self) -> color_eyre::Result<()> {
+        #[cfg(feature = "cli")]
         if self.config.kb_dir.is_none() {
             self.config.set_kb_all()?;
         }
@@ -179,206 +1048,395 @@ impl Gooseberry {
         Ok(())
     }
 
-    /// Make mdBook wiki
-    pub async fn make(&mut self, force: bool) -> color_eyre::Result<()> {
+    /// Make mdBook wiki, optionally only from the annotations of one group or saved view,
+    /// or a static site for `site`. Annotations on each page are sorted by `sort` (or the `sort` config option),
+    /// and grouped into pages by `group_by` (or the `hierarchy` config option)
+    ///
+    /// Only files with changed contents are rewritten, unless `rebuild` is set or the knowledge base
+    /// wasn't made by this version of gooseberry, in which case the directory is cleared first
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(self, filters, sort))]
+    pub async fn make(
+        &mut self,
+        force: bool,
+        rebuild: bool,
+        filters: Filters,
+        wiki_links: bool,
+        site: Option<SiteFormat>,
+        sort: SortOptions,
+        group_by: Option<OrderBy>,
+    ) -> color_eyre::Result<()> {
         self.configure_kb()?;
-        let kb_dir = self.config.kb_dir.as_ref().unwrap();
-        if kb_dir.exists()
-            && (force
-                || Confirm::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Clear knowledge base directory?")
-                    .default(true)
-                    .interact()?)
+        if site.is_some() && self.config.kb_format() != KbFormat::Markdown {
+            return Err(Apologize::KBError {
+                message: "Static sites can only be made from a markdown knowledge base".into(),
+            })
+            .suggestion("Change the knowledge base format using 'gooseberry config kb format'");
+        }
+        if self.config.front_matter.unwrap_or(false)
+            && self.config.kb_format() != KbFormat::Markdown
         {
+            return Err(Apologize::KBError {
+                message: "Front matter can only be added to a markdown knowledge base".into(),
+            })
+            .suggestion("Change the knowledge base format using 'gooseberry config kb format'");
+        }
+        let kb_dir = self.config.kb_dir.as_ref().unwrap();
+        let clear = (rebuild || self.kb_files()?.is_empty()?) && kb_dir.exists();
+        if clear && self.dry_run {
+            println!("Would clear {:?}", kb_dir);
+        } else if clear && (force || utils::confirm("Clear knowledge base directory?", true)?) {
             fs::remove_dir_all(&kb_dir)?;
             fs::create_dir_all(&kb_dir)?;
+            self.kb_files()?.clear()?;
         }
-        self.make_book(&kb_dir).await?;
+        let writer = KbWriter::new(
+            self.kb_files()?,
+            self.kb_locations()?,
+            self.dry_run,
+            clear && self.dry_run,
+        );
+        self.make_book(kb_dir, filters, wiki_links, site, sort, group_by, writer)
+            .await?;
         Ok(())
     }
 
-    fn group_annotations_by_order(
+    /// Path of a tag's page (or folder) in the knowledge base:
+    /// hierarchical tags are nested in folders, e.g. biology/genetics/crispr
+    fn tag_path(&self, tag: &str) -> String {
+        match self.config.tag_delimiter.as_deref() {
+            Some(delimiter) => {
+                let levels: Vec<_> = tag
+                    .split(delimiter)
+                    .map(str::trim)
+                    .filter(|level| !level.is_empty())
+                    .collect();
+                if levels.is_empty() {
+                    tag.to_owned()
+                } else {
+                    levels.join("/")
+                }
+            }
+            None => tag.to_owned(),
+        }
+    }
+
+    /// Groups annotations into folders or pages by a hierarchy field.
+    /// Returns folder / file name: (value they were grouped by, annotations)
+    pub(crate) fn group_annotations_by_order(
         &self,
         order: OrderBy,
         annotations: Vec<AnnotationTemplate>,
-    ) -> HashMap<String, Vec<AnnotationTemplate>> {
-        let mut order_to_annotations = HashMap::new();
-        match order {
-            OrderBy::Tag => {
-                for annotation in annotations {
-                    if annotation.annotation.tags.is_empty() {
-                        order_to_annotations
-                            .entry(EMPTY_TAG.to_owned())
-                            .or_insert_with(Vec::new)
-                            .push(annotation);
-                    } else {
-                        for tag in &annotation.annotation.tags {
-                            order_to_annotations
-                                .entry(tag.to_owned())
-                                .or_insert_with(Vec::new)
-                                .push(annotation.clone());
-                        }
-                    }
+    ) -> HashMap<String, (String, Vec<AnnotationTemplate>)> {
+        let mut order_to_annotations: HashMap<String, (String, Vec<AnnotationTemplate>)> =
+            HashMap::new();
+        for annotation in annotations {
+            let keys = match order {
+                OrderBy::Tag if annotation.annotation.tags.is_empty() => {
+                    vec![(EMPTY_TAG.to_owned(), EMPTY_TAG.to_owned())]
                 }
-            }
-            OrderBy::URI => {
-                for annotation in annotations {
-                    order_to_annotations
-                        .entry(uri_to_filename(&annotation.annotation.uri))
-                        .or_insert_with(Vec::new)
-                        .push(annotation);
-                }
-            }
-            OrderBy::Title => {
-                for annotation in annotations {
-                    order_to_annotations
-                        .entry(sanitize(&annotation.title))
-                        .or_insert_with(Vec::new)
-                        .push(annotation);
+                OrderBy::Tag => annotation
+                    .annotation
+                    .tags
+                    .iter()
+                    .map(|tag| (self.tag_path(tag), tag.to_owned()))
+                    .collect(),
+                OrderBy::URI => vec![(
+                    uri_to_filename(&annotation.document_uri),
+                    annotation.document_uri.to_owned(),
+                )],
+                OrderBy::Title => vec![(sanitize(&annotation.title), annotation.title.to_owned())],
+                OrderBy::BaseURI => vec![(
+                    uri_to_filename(&annotation.base_uri),
+                    annotation.base_uri.to_owned(),
+                )],
+                OrderBy::ID => vec![(
+                    annotation.annotation.id.to_owned(),
+                    annotation.annotation.id.to_owned(),
+                )],
+                OrderBy::Group => vec![(
+                    annotation.annotation.group.to_owned(),
+                    annotation.annotation.group.to_owned(),
+                )],
+                OrderBy::Domain => {
+                    let domain = stats::domain(&annotation.annotation.uri);
+                    vec![(sanitize(&domain), domain)]
                 }
-            }
-            OrderBy::BaseURI => {
-                for annotation in annotations {
-                    order_to_annotations
-                        .entry(uri_to_filename(&annotation.base_uri))
-                        .or_insert_with(Vec::new)
-                        .push(annotation);
-                }
-            }
-            OrderBy::ID => {
-                for annotation in annotations {
-                    order_to_annotations
-                        .entry(annotation.annotation.id.to_string())
-                        .or_insert_with(Vec::new)
-                        .push(annotation);
+                OrderBy::User => {
+                    let author = author(&annotation).to_owned();
+                    vec![(sanitize(&author), author)]
                 }
+                OrderBy::Date => vec![(date(&annotation), date(&annotation))],
+                OrderBy::Empty => panic!("Shouldn't happen"),
+                _ => panic!("{} shouldn't occur in hierarchy", order),
+            };
+            for (name, key) in keys {
+                order_to_annotations
+                    .entry(name)
+                    .or_insert_with(|| (key, Vec::new()))
+                    .1
+                    .push(annotation.clone());
             }
-            OrderBy::Empty => panic!("Shouldn't happen"),
-            _ => panic!("{} shouldn't occur in hierarchy", order),
         }
         order_to_annotations
     }
 
-    fn sort_annotations(&self, annotations: &mut Vec<AnnotationTemplate>) {
-        annotations.sort_by(|a, b| {
-            self.config
+    /// Sorts annotations by the `--sort` option (keeping their order if it isn't given),
+    /// reversing them with `--reverse`
+    #[cfg(feature = "cli")]
+    pub(crate) fn sort_by_options(
+        &self,
+        annotations: Vec<Annotation>,
+        options: SortOptions,
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        let mut templates = Vec::with_capacity(annotations.len());
+        for annotation in annotations {
+            let mut template = AnnotationTemplate::from_annotation(annotation);
+            template.meta = self.get_meta(&template.annotation.id)?;
+            templates.push(template);
+        }
+        if let Some(field) = options.sort {
+            sort_by_fields(&mut templates, &[field.into()]);
+        }
+        if options.reverse {
+            templates.reverse();
+        }
+        Ok(templates.into_iter().map(|t| t.annotation).collect())
+    }
+
+    /// Sorts annotations by the `--sort` option, or by the `sort` config option (creation date if not set)
+    pub(crate) fn sort_annotations(
+        &self,
+        annotations: &mut [AnnotationTemplate],
+        options: SortOptions,
+    ) {
+        let order = match options.sort {
+            Some(field) => vec![field.into()],
+            None => self
+                .config
                 .sort
-                .as_ref()
-                .unwrap_or(&vec![OrderBy::Created])
-                .iter()
-                .fold(Ordering::Equal, |acc, &field| {
-                    acc.then_with(|| match field {
-                        OrderBy::Tag => a
-                            .annotation
-                            .tags
-                            .join(",")
-                            .cmp(&b.annotation.tags.join(",")),
-                        OrderBy::URI => {
-                            clean_uri(&a.annotation.uri).cmp(&clean_uri(&b.annotation.uri))
-                        }
-                        OrderBy::BaseURI => clean_uri(&a.base_uri).cmp(&clean_uri(&b.base_uri)),
-                        OrderBy::Title => a.title.cmp(&b.title),
-                        OrderBy::ID => a.annotation.id.cmp(&b.annotation.id),
-                        OrderBy::Created => format!("{}", a.annotation.created.format("%+"))
-                            .cmp(&format!("{}", b.annotation.created.format("%+"))),
-                        OrderBy::Updated => format!("{}", a.annotation.updated.format("%+"))
-                            .cmp(&format!("{}", b.annotation.updated.format("%+"))),
-                        OrderBy::Empty => panic!("Shouldn't happen"),
-                    })
+                .clone()
+                .unwrap_or_else(|| vec![OrderBy::Created]),
+        };
+        sort_by_fields(annotations, &order);
+        if options.reverse {
+            annotations.reverse();
+        }
+    }
+    /// Annotations for the knowledge base: the ones matching the filters, without those with ignored tags
+    /// or matching the `ignore` config option.
+    /// Related annotations are described if they're among them
+    pub(crate) async fn knowledge_base_annotations(
+        &self,
+        filters: Filters,
+    ) -> color_eyre::Result<Vec<AnnotationTemplate>> {
+        let ignore = self.config.get_ignore_filter()?;
+        let mut annotations = self
+            .filter_annotations(filters, None)
+            .await?
+            .into_iter()
+            .filter(|a| {
+                !a.tags.iter().any(|t| {
+                    self.config
+                        .ignore_tags
+                        .as_ref()
+                        .map(|ignore_tags| ignore_tags.contains(t))
+                        .unwrap_or(false)
                 })
-        });
+            })
+            .filter(|a| !ignore.as_ref().is_some_and(|ignore| ignore.matches(a)))
+            .map(|a| self.annotation_template(a))
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+        describe_related(&mut annotations);
+        Ok(annotations)
     }
+
+    /// Moves replies under the annotations they reply to, including the ones only known from the reply tree
+    pub(crate) fn nest_replies(
+        &self,
+        annotations: Vec<AnnotationTemplate>,
+    ) -> color_eyre::Result<Vec<AnnotationTemplate>> {
+        let reply_ids = annotations
+            .iter()
+            .map(|a| {
+                let id = a.annotation.id.to_owned();
+                Ok((id.to_owned(), self.get_replies(&id)?))
+            })
+            .collect::<color_eyre::Result<_>>()?;
+        Ok(nest_replies(annotations, &reply_ids))
+    }
+
     /// Write markdown files for wiki
-    async fn make_book(&self, src_dir: &Path) -> color_eyre::Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    async fn make_book(
+        &self,
+        src_dir: &Path,
+        filters: Filters,
+        wiki_links: bool,
+        site: Option<SiteFormat>,
+        sort: SortOptions,
+        group_by: Option<OrderBy>,
+        writer: KbWriter,
+    ) -> color_eyre::Result<()> {
         let pb = utils::get_spinner("Building knowledge base...");
         let extension = self.config.file_extension.as_ref().unwrap();
+        // Pages of a static site, written with the site's index files once they're all rendered
+        let site_pages = RefCell::new(Vec::new());
         let index_file = src_dir.join(format!(
             "{}.{}",
             self.config.index_name.as_ref().unwrap(),
             extension
         ));
-        if index_file.exists() {
-            // Initialize
-            fs::remove_file(&index_file)?;
-        }
-
         // Register templates
-        let hbs = self.get_handlebars()?;
+        let mut hbs = self.get_handlebars()?;
+        self.register_template_overrides(&mut hbs)?;
+        if site == Some(SiteFormat::Html) {
+            self.register_html_layout(&mut hbs)?;
+        }
+        if let Some(template) = &self.config.front_matter_template {
+            hbs.register_template_string("front_matter", template)
+                .map_err(|e| Apologize::ConfigError {
+                    message: format!("Invalid front_matter_template: {}", e),
+                })
+                .suggestion("Fix the front_matter_template in the config file")?;
+        }
+        if let Some(template) = &self.config.file_name_template {
+            hbs.register_template_string("file_name", template)
+                .map_err(|e| Apologize::ConfigError {
+                    message: format!("Invalid file_name_template: {}", e),
+                })
+                .suggestion("Fix the file_name_template in the config file")?;
+        }
+        let marker = match self.config.editable_format() {
+            Some(format) => Some(EditMarker::new(format, self.annotation_hashes()?)),
+            None => None,
+        };
 
         // Get all annotations
-        let mut annotations: Vec<_> = self
-            .filter_annotations(Filters::default(), None)
-            .await?
-            .into_iter()
-            .filter(|a| {
-                !a.tags.iter().any(|t| {
-                    self.config
-                        .ignore_tags
-                        .as_ref()
-                        .map(|ignore_tags| ignore_tags.contains(t))
-                        .unwrap_or(false)
-                })
-            })
-            .map(AnnotationTemplate::from_annotation)
-            .collect();
-        self.sort_annotations(&mut annotations);
+        let annotations = self.knowledge_base_annotations(filters).await?;
+        let git_commit = self.config.git_commit.unwrap_or(false);
+        let mut changes = if git_commit {
+            self.kb_changes(&annotations)?
+        } else {
+            KbChanges::default()
+        };
+        let mut todos = self.todo_items(&annotations)?;
+        let mut annotations = self.nest_replies(annotations)?;
+        self.sort_annotations(&mut annotations, sort);
+        let renderer = AnnotationRenderer {
+            hbs: &hbs,
+            marker: marker.as_ref(),
+            overrides: self.template_overrides(&annotations),
+            front_matter: site.is_none() && self.config.front_matter.unwrap_or(false),
+            html: site == Some(SiteFormat::Html),
+        };
 
-        let order = self.config.hierarchy.as_ref().unwrap();
-        if order.is_empty() {
+        let order = match group_by {
+            Some(field) => vec![field],
+            None => self.config.hierarchy.clone().unwrap(),
+        };
+        if wiki_links {
+            self.make_wiki(src_dir, annotations, &renderer, &index_file, &writer, todos)?;
+        } else if let (Some(site), true) = (site, order.is_empty()) {
+            // Site has one page with all annotations
+            site_pages.borrow_mut().push(site_page(
+                site,
+                vec!["Annotations".to_owned()],
+                None,
+                annotations,
+                &renderer,
+                src_dir,
+            )?);
+        } else if order.is_empty() {
             // Index file has all annotations
-            fs::File::create(&index_file)?.write_all(
-                annotations
+            for annotation in &annotations {
+                writer.locate(&index_file, annotation);
+            }
+            writer.write(
+                &index_file,
+                &annotations
                     .into_iter()
-                    .map(|a| hbs.render("annotation", &a))
-                    .collect::<Result<String, _>>()?
-                    .as_bytes(),
+                    .map(|a| renderer.render(&a))
+                    .collect::<color_eyre::Result<String>>()?,
             )?;
         } else {
             // Index file has links to each page
             let mut index_links = vec![];
+            let file_namer = self.config.file_name_template.as_ref().map(|_| FileNamer {
+                hbs: &hbs,
+                extension,
+                taken: RefCell::new(HashSet::new()),
+            });
             struct RecurseFolder<'s> {
                 f: &'s dyn Fn(
                     &RecurseFolder,
                     Vec<AnnotationTemplate>,
                     PathBuf,
+                    Option<String>,
                     usize,
-                    &mut Vec<String>,
+                    &mut Vec<(String, String)>,
                 ) -> color_eyre::Result<()>,
             }
             let recurse_folder = RecurseFolder {
-                f: &|recurse_folder, inner_annotations, folder, depth, index_links| {
-                    if depth == order.len() {
-                        let folder_name = folder.to_str().ok_or(Apologize::KBError {
-                            message: format!("{:?} has non-unicode characters", folder),
-                        })?;
-                        let folder_name: String = folder_name
-                            .chars()
-                            .take(250.min(folder_name.len()))
+                f: &|recurse_folder, inner_annotations, folder, group_key, depth, index_links| {
+                    if let (Some(site), true) = (site, depth == order.len()) {
+                        let names: Vec<_> = folder
+                            .strip_prefix(src_dir)?
+                            .components()
+                            .map(|c| c.as_os_str().to_string_lossy().to_string())
                             .collect();
-                        let path = PathBuf::from(format!("{}.{}", folder_name, extension));
-                        let link_data = get_link_data(&path, &src_dir)?;
-                        index_links.push(hbs.render("index_link", &link_data)?);
-                        let page_data = PageTemplate {
-                            link_data,
-                            annotations: inner_annotations
-                                .iter()
-                                .map(|a| hbs.render("annotation", &a))
-                                .collect::<Result<Vec<String>, _>>()?,
-                            raw_annotations: inner_annotations,
+                        site_pages.borrow_mut().push(site_page(
+                            site,
+                            names,
+                            group_key,
+                            inner_annotations,
+                            &renderer,
+                            src_dir,
+                        )?);
+                    } else if depth == order.len() {
+                        let path = match &file_namer {
+                            Some(namer) => {
+                                namer.path(&folder, group_key.clone(), &inner_annotations)?
+                            }
+                            None => {
+                                let folder_name = folder.to_str().ok_or(Apologize::KBError {
+                                    message: format!("{:?} has non-unicode characters", folder),
+                                })?;
+                                let folder_name: String = folder_name
+                                    .chars()
+                                    .take(250.min(folder_name.len()))
+                                    .collect();
+                                PathBuf::from(format!("{}.{}", folder_name, extension))
+                            }
                         };
-                        fs::File::create(&path)?
-                            .write_all(hbs.render("page", &page_data)?.as_bytes())?;
+                        let mut link_data = get_link_data(&path, src_dir)?;
+                        link_data.title = common_title(&inner_annotations);
+                        link_data.group_key = group_key;
+                        index_links.push((
+                            link_data.raw_relative_path.to_owned(),
+                            hbs.render("index_link", &link_data)?,
+                        ));
+                        for annotation in &inner_annotations {
+                            writer.locate(&path, annotation);
+                        }
+                        writer
+                            .write(&path, &renderer.render_page(link_data, inner_annotations)?)?;
                     } else {
-                        if !folder.exists() {
-                            fs::create_dir(&folder)?;
+                        if site.is_none() && !self.dry_run && !folder.exists() {
+                            fs::create_dir_all(&folder)?;
                         }
-                        for (new_folder, annotations) in
-                            self.group_annotations_by_order(order[depth], inner_annotations)
-                        {
+                        // Sorted so that numbered file names are the same on every run
+                        let mut groups: Vec<_> = self
+                            .group_annotations_by_order(order[depth], inner_annotations)
+                            .into_iter()
+                            .collect();
+                        groups.sort_by(|a, b| a.0.cmp(&b.0));
+                        for (new_folder, (key, annotations)) in groups {
                             (recurse_folder.f)(
                                 recurse_folder,
                                 annotations,
                                 folder.join(new_folder),
+                                Some(key),
                                 depth + 1,
                                 index_links,
                             )?;
@@ -393,18 +1451,158 @@ impl Gooseberry {
                 &recurse_folder,
                 annotations,
                 PathBuf::from(src_dir),
+                None,
                 0,
                 &mut index_links,
             )?;
             // Make Index file
-            fs::File::create(index_file)?
-                .write_all(index_links.into_iter().collect::<String>().as_bytes())?;
+            if site.is_none() {
+                let mut index = IndexTemplate::new(index_links);
+                index.group_by = order.last().map(OrderBy::to_string);
+                writer.locate_todos(&mut todos, src_dir);
+                index.todos = todos;
+                writer.write(&index_file, &hbs.render("index", &index)?)?;
+            }
         }
+        if let Some(site) = site {
+            let title = src_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "Knowledge base".to_owned());
+            let files = match site {
+                SiteFormat::Html => html::site_files(&hbs, &title, site_pages.into_inner())?,
+                _ => site.files(&title, site_pages.into_inner()),
+            };
+            for (path, contents) in files {
+                writer.write(&src_dir.join(path), &contents)?;
+            }
+        }
+        let (written, deleted) = writer.finish(src_dir)?;
+        tracing::info!(written, deleted, "made the knowledge base");
         pb.finish_with_message("Done!");
+        if self.dry_run {
+            println!(
+                "{} file(s) would be written, {} file(s) would be deleted (dry run)",
+                written, deleted
+            );
+            return Ok(());
+        }
+        println!("{} file(s) written, {} file(s) deleted", written, deleted);
         println!(
             "Knowledge base built at: {:?}",
             self.config.kb_dir.as_ref().unwrap()
         );
+        if git_commit {
+            changes.files = written + deleted;
+            let message = hbs.render_template(self.config.git_commit_message(), &changes)?;
+            match git::commit(src_dir, &message, self.config.git_push.unwrap_or(false))? {
+                Some(id) => println!("Committed {:.7} to git", id),
+                None => println!("No changes to commit to git"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write an Obsidian / Logseq style vault (ignores the hierarchy):
+    /// a page per source document and per tag, with tags and references rendered as `[[wikilinks]]`
+    /// and a "Backlinks" section on each page listing the pages linking to it
+    fn make_wiki(
+        &self,
+        src_dir: &Path,
+        annotations: Vec<AnnotationTemplate>,
+        renderer: &AnnotationRenderer,
+        index_file: &Path,
+        writer: &KbWriter,
+        mut todos: Vec<TodoItem>,
+    ) -> color_eyre::Result<()> {
+        let hbs = renderer.hbs;
+        let extension = self.config.file_extension.as_ref().unwrap();
+        let tag_names: HashSet<_> = annotations
+            .iter()
+            .flat_map(|a| a.annotation.tags.iter().map(|tag| self.tag_path(tag)))
+            .collect();
+        let document_name = |annotation: &AnnotationTemplate| {
+            let name = sanitize(&annotation.title);
+            if tag_names.contains(&name) {
+                format!("{} (source)", name)
+            } else {
+                name
+            }
+        };
+        let id_to_document: HashMap<_, _> = annotations
+            .iter()
+            .map(|a| (a.annotation.id.to_owned(), document_name(a)))
+            .collect();
+
+        let mut pages: BTreeMap<String, Vec<AnnotationTemplate>> = BTreeMap::new();
+        let mut backlinks: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for annotation in annotations {
+            let document = document_name(&annotation);
+            // The page of its document, rather than those of its tags
+            writer.locate(
+                &src_dir.join(format!("{}.{}", document, extension)),
+                &annotation,
+            );
+            let mut wiki_annotation = annotation.clone();
+            for tag in &annotation.annotation.tags {
+                backlinks
+                    .entry(self.tag_path(tag))
+                    .or_default()
+                    .insert(document.to_owned());
+            }
+            wiki_annotation.annotation.tags = annotation
+                .annotation
+                .tags
+                .iter()
+                .map(|tag| wikilink(&self.tag_path(tag)))
+                .collect();
+            wiki_annotation.annotation.references = annotation
+                .annotation
+                .references
+                .iter()
+                .map(|id| match id_to_document.get(id) {
+                    Some(referenced) => {
+                        if referenced != &document {
+                            backlinks
+                                .entry(referenced.to_owned())
+                                .or_default()
+                                .insert(document.to_owned());
+                        }
+                        wikilink(referenced)
+                    }
+                    None => id.to_owned(),
+                })
+                .collect();
+            for tag in &annotation.annotation.tags {
+                pages
+                    .entry(self.tag_path(tag))
+                    .or_default()
+                    .push(wiki_annotation.clone());
+            }
+            pages.entry(document).or_default().push(wiki_annotation);
+        }
+
+        let mut index_links = Vec::with_capacity(pages.len());
+        for (name, page_annotations) in pages {
+            let path = src_dir.join(format!("{}.{}", name, extension));
+            let link_data = get_link_data(&path, src_dir)?;
+            index_links.push((
+                link_data.raw_relative_path.to_owned(),
+                hbs.render("index_link", &link_data)?,
+            ));
+            let mut contents = renderer.render_page(link_data, page_annotations)?;
+            if let Some(links) = backlinks.get(&name) {
+                contents.push_str("\n## Backlinks\n\n");
+                for link in links.iter().filter(|link| link != &&name) {
+                    contents.push_str(&format!("- {}\n", wikilink(link)));
+                }
+            }
+            writer.write(&path, &contents)?;
+        }
+        let mut index = IndexTemplate::new(index_links);
+        writer.locate_todos(&mut todos, src_dir);
+        index.todos = todos;
+        writer.write(index_file, &hbs.render("index", &index)?)?;
         Ok(())
     }
 }
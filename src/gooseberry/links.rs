@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+#[cfg(feature = "cli")]
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::storage::Batch;
+use crate::gooseberry::Gooseberry;
+
+/// Type of links made without `--type`
+pub const DEFAULT_LINK_TYPE: &str = "related";
+
+/// Key of a link in the links tree: the IDs of the annotation it's from and the one it's to,
+/// separated by a null byte
+fn link_key(from: &str, to: &str) -> Vec<u8> {
+    format!("{}\0{}", from, to).into_bytes()
+}
+
+/// An annotation linked to (or from) the one being rendered, for the `related` template key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RelatedAnnotation {
+    /// ID of the linked annotation
+    pub id: String,
+    /// Type of the link, e.g. `supports`, `contradicts`, `followup`
+    pub link_type: String,
+    /// Whether the link is from the linked annotation to this one
+    pub incoming: bool,
+    /// Title of the linked annotation's document (empty if it's not in the knowledge base)
+    #[serde(default)]
+    pub title: String,
+    /// Text of the linked annotation (empty if it's not in the knowledge base)
+    #[serde(default)]
+    pub text: String,
+    /// First highlight of the linked annotation (empty if it's not in the knowledge base)
+    #[serde(default)]
+    pub quote: String,
+    /// Link to the linked annotation in context (empty if it's not in the knowledge base)
+    #[serde(default)]
+    pub incontext: String,
+}
+
+/// Fills in the text, quote, title, and link in context of the related annotations
+/// which are among the given ones
+pub(crate) fn describe_related(annotations: &mut [AnnotationTemplate]) {
+    let described: HashMap<String, (String, String, String, String)> = annotations
+        .iter()
+        .map(|a| {
+            (
+                a.annotation.id.to_owned(),
+                (
+                    a.title.to_owned(),
+                    a.annotation.text.to_owned(),
+                    a.highlight.first().cloned().unwrap_or_default(),
+                    a.incontext.to_owned(),
+                ),
+            )
+        })
+        .collect();
+    for annotation in annotations.iter_mut() {
+        for related in &mut annotation.related {
+            if let Some((title, text, quote, incontext)) = described.get(&related.id) {
+                related.title = title.to_owned();
+                related.text = text.to_owned();
+                related.quote = quote.to_owned();
+                related.incontext = incontext.to_owned();
+            }
+        }
+    }
+}
+
+/// ## Links
+/// Typed links between annotations, stored in the links tree
+impl Gooseberry {
+    /// Links one annotation to another with a type (replacing the type of an existing link)
+    pub fn add_link(&self, from: &str, to: &str, link_type: &str) -> color_eyre::Result<()> {
+        if from == to {
+            return Err(Apologize::LinkError {
+                message: "An annotation can't be linked to itself".into(),
+            }
+            .into());
+        }
+        let link_type = link_type.trim();
+        if link_type.is_empty() || link_type.contains(char::is_whitespace) {
+            return Err(Apologize::LinkError {
+                message: format!("{:?} isn't a valid link type, use a single word", link_type),
+            }
+            .into());
+        }
+        // Both annotations have to be in the database
+        self.get_annotation_tags(from)?;
+        self.get_annotation_tags(to)?;
+        self.annotation_links()?
+            .insert(link_key(from, to), link_type.as_bytes())?;
+        Ok(())
+    }
+
+    /// Removes the link between two annotations, in either direction. Returns whether there was one
+    pub fn remove_link(&self, from: &str, to: &str) -> color_eyre::Result<bool> {
+        let links = self.annotation_links()?;
+        let forward = links.remove(link_key(from, to))?.is_some();
+        let backward = links.remove(link_key(to, from))?.is_some();
+        Ok(forward || backward)
+    }
+
+    /// Moves the links from and to an annotation over to its copy with a new ID
+    pub(crate) fn move_links(&self, from: &str, to: &str) -> color_eyre::Result<()> {
+        self.relink(from, to, false)
+    }
+
+    /// Gives the copy of an annotation (with a new ID) the links of the original, which keeps them too
+    pub(crate) fn copy_links(&self, from: &str, to: &str) -> color_eyre::Result<()> {
+        self.relink(from, to, true)
+    }
+
+    /// Links `to` to everything `from` is linked to, removing the links of `from` unless `keep` is set
+    fn relink(&self, from: &str, to: &str, keep: bool) -> color_eyre::Result<()> {
+        let links = self.annotation_links()?;
+        let mut batch = Batch::default();
+        for link in links.iter() {
+            let (key, value) = link?;
+            let (source, target) = match std::str::from_utf8(&key)?.split_once('\0') {
+                Some((source, target)) if source == from || target == from => (
+                    if source == from { to } else { source },
+                    if target == from { to } else { target },
+                ),
+                _ => continue,
+            };
+            if !keep {
+                batch.remove(&key);
+            }
+            batch.insert(link_key(source, target), &value);
+        }
+        links.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Links from and to an annotation, leaving out those with annotations no longer in the database
+    /// (which come back if they're restored from the trash)
+    pub fn get_links(&self, id: &str) -> color_eyre::Result<Vec<RelatedAnnotation>> {
+        let annotations = self.annotation_to_tags()?;
+        let mut related = Vec::new();
+        for link in self.annotation_links()?.iter() {
+            let (key, value) = link?;
+            let key = std::str::from_utf8(&key)?;
+            let (from, to) = key.split_once('\0').ok_or(Apologize::OutOfCheeseError {
+                message: format!("Invalid link key {:?}", key),
+            })?;
+            let (other, incoming) = if from == id {
+                (to, false)
+            } else if to == id {
+                (from, true)
+            } else {
+                continue;
+            };
+            if !annotations.contains_key(other)? {
+                continue;
+            }
+            related.push(RelatedAnnotation {
+                id: other.to_owned(),
+                link_type: std::str::from_utf8(&value)?.to_owned(),
+                incoming,
+                title: String::new(),
+                text: String::new(),
+                quote: String::new(),
+                incontext: String::new(),
+            });
+        }
+        Ok(related)
+    }
+
+    /// Links two annotations, removes the link between them, or lists the links of the first one
+    pub fn link(
+        &self,
+        from: &str,
+        to: Option<&str>,
+        link_type: &str,
+        remove: bool,
+    ) -> color_eyre::Result<()> {
+        match to {
+            Some(to) if remove => {
+                if self.remove_link(from, to)? {
+                    println!("Removed the link between {} and {}", from, to);
+                } else {
+                    println!("{} and {} weren't linked", from, to);
+                }
+            }
+            Some(to) => {
+                self.add_link(from, to, link_type)?;
+                println!("Linked {} to {} ({})", from, to, link_type.trim());
+            }
+            None => {
+                self.get_annotation_tags(from)?;
+                for related in self.get_links(from)? {
+                    if related.incoming {
+                        println!("<- {} {}", related.link_type, related.id);
+                    } else {
+                        println!("-> {} {}", related.link_type, related.id);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Links the first of the annotations selected in the search window to the others,
+    /// with a type asked for
+    #[cfg(feature = "cli")]
+    pub fn link_selected(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        let (first, others) = match annotations {
+            [first, others @ ..] if !others.is_empty() => (first, others),
+            _ => {
+                return Err(Apologize::LinkError {
+                    message: "Select (with Tab) at least two annotations to link".into(),
+                }
+                .into())
+            }
+        };
+        let link_type = crate::utils::user_input(
+            &format!("Type of the links from {}", first.id),
+            Some(DEFAULT_LINK_TYPE),
+            true,
+            false,
+        )?;
+        for other in others {
+            self.add_link(&first.id, &other.id, &link_type)?;
+        }
+        println!(
+            "Linked {} to {} annotation(s) ({})",
+            first.id,
+            others.len(),
+            link_type
+        );
+        Ok(())
+    }
+}
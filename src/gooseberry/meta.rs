@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use color_eyre::Help;
+#[cfg(feature = "cli")]
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::Gooseberry;
+
+/// Metadata of an annotation, key: value
+pub type Meta = BTreeMap<String, String>;
+
+/// Metadata key set by the rate search actions, and sorted by with `--sort rating`
+pub const RATING_KEY: &str = "rating";
+/// Metadata key set by the status search action
+pub const STATUS_KEY: &str = "status";
+
+/// Splits a `key=value` field. An empty value removes the key
+fn parse_field(field: &str) -> color_eyre::Result<(String, String)> {
+    match field.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() && !key.contains(char::is_whitespace) => {
+            Ok((key.to_owned(), value.to_owned()))
+        }
+        _ => Err(Apologize::MetaError {
+            message: format!("{:?} isn't a key=value pair", field),
+        })
+        .suggestion("Use a single word as the key, e.g. status=read"),
+    }
+}
+
+/// ## Metadata
+/// Key: value pairs of annotations which only gooseberry knows about (Hypothesis has no custom fields),
+/// stored in the metadata tree
+impl Gooseberry {
+    /// Metadata of an annotation, empty if it has none
+    pub fn get_meta(&self, id: &str) -> color_eyre::Result<Meta> {
+        match self.annotation_meta()?.get(id)? {
+            Some(meta) => Ok(serde_json::from_slice(&meta)?),
+            None => Ok(Meta::new()),
+        }
+    }
+
+    /// Replaces the metadata of an annotation
+    fn set_meta(&self, id: &str, meta: &Meta) -> color_eyre::Result<()> {
+        if meta.is_empty() {
+            self.annotation_meta()?.remove(id)?;
+        } else {
+            self.annotation_meta()?
+                .insert(id, serde_json::to_vec(meta)?)?;
+        }
+        Ok(())
+    }
+
+    /// Sets `key=value` fields of an annotation's metadata (`key=` removes the key),
+    /// or prints its metadata if no fields are given
+    pub fn meta(&self, id: &str, fields: &[String]) -> color_eyre::Result<()> {
+        // The annotation has to be in the database
+        self.get_annotation_tags(id)?;
+        let mut meta = self.get_meta(id)?;
+        if fields.is_empty() {
+            for (key, value) in &meta {
+                println!("{}={}", key, value);
+            }
+            return Ok(());
+        }
+        for field in fields {
+            let (key, value) = parse_field(field)?;
+            if value.is_empty() {
+                meta.remove(&key);
+            } else {
+                meta.insert(key, value);
+            }
+        }
+        self.set_meta(id, &meta)
+    }
+
+    /// Sets a metadata key of several annotations (an empty value removes it)
+    #[cfg(feature = "cli")]
+    pub(crate) fn set_meta_field(
+        &self,
+        annotations: &[Annotation],
+        key: &str,
+        value: &str,
+    ) -> color_eyre::Result<()> {
+        for annotation in annotations {
+            let mut meta = self.get_meta(&annotation.id)?;
+            if value.is_empty() {
+                meta.remove(key);
+            } else {
+                meta.insert(key.to_owned(), value.to_owned());
+            }
+            self.set_meta(&annotation.id, &meta)?;
+        }
+        Ok(())
+    }
+
+    /// Whether an annotation's metadata matches all the conditions of `--meta`:
+    /// `key=value` for a value, `key>=number` or `key<=number` for a range of numbers (e.g. `rating>=4`),
+    /// or `key` for any value
+    pub(crate) fn matches_meta(&self, id: &str, conditions: &[String]) -> color_eyre::Result<bool> {
+        if conditions.is_empty() {
+            return Ok(true);
+        }
+        let meta = self.get_meta(id)?;
+        let number = |key: &str| meta.get(key).and_then(|v| v.trim().parse::<f64>().ok());
+        Ok(conditions.iter().all(|condition| {
+            if let Some((key, bound)) = condition.split_once(">=") {
+                matches!((number(key), bound.trim().parse::<f64>()), (Some(v), Ok(bound)) if v >= bound)
+            } else if let Some((key, bound)) = condition.split_once("<=") {
+                matches!((number(key), bound.trim().parse::<f64>()), (Some(v), Ok(bound)) if v <= bound)
+            } else if let Some((key, value)) = condition.split_once('=') {
+                meta.get(key).is_some_and(|v| v == value)
+            } else {
+                meta.contains_key(condition.as_str())
+            }
+        }))
+    }
+
+    /// Moves the metadata of an annotation over to its copy with a new ID
+    pub(crate) fn move_meta(&self, from: &str, to: &str) -> color_eyre::Result<()> {
+        if let Some(meta) = self.annotation_meta()?.remove(from)? {
+            self.annotation_meta()?.insert(to, meta)?;
+        }
+        Ok(())
+    }
+
+    /// Gives the copy of an annotation (with a new ID) the metadata of the original, which keeps it too
+    pub(crate) fn copy_meta(&self, from: &str, to: &str) -> color_eyre::Result<()> {
+        if let Some(meta) = self.annotation_meta()?.get(from)? {
+            self.annotation_meta()?.insert(to, meta)?;
+        }
+        Ok(())
+    }
+}
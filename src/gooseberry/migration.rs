@@ -0,0 +1,123 @@
+use color_eyre::Help;
+
+#[cfg(feature = "sqlite")]
+use crate::configuration::DbBackend;
+use crate::errors::Apologize;
+#[cfg(feature = "sqlite")]
+use crate::gooseberry::storage;
+use crate::gooseberry::Gooseberry;
+
+/// Key of the schema version of the database, in the default tree
+pub(crate) const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// An upgrade of the stored data from the previous schema version
+struct Migration {
+    /// What changes, shown when upgrading
+    description: &'static str,
+    migrate: fn(&Gooseberry) -> color_eyre::Result<()>,
+}
+
+/// Migrations in order: the database is at schema version `n` once the first `n` have run.
+/// Add new ones at the end, whenever what's stored (or how) changes
+const MIGRATIONS: &[Migration] = &[Migration {
+    description: "Record the schema version of the database",
+    migrate: |_| Ok(()),
+}];
+
+/// Schema version written by this version of gooseberry
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// ## Migrations
+/// The schema version of the database, and upgrading databases written by older versions of gooseberry
+impl Gooseberry {
+    /// Fails for a schema version newer than this version of gooseberry knows
+    pub(crate) fn check_schema_version(version: u32) -> color_eyre::Result<()> {
+        if version > SCHEMA_VERSION {
+            return Err(Apologize::MigrationError {
+                message: format!(
+                    "The database has schema version {}, but this version of gooseberry only knows up to {}",
+                    version, SCHEMA_VERSION
+                ),
+            })
+            .suggestion("Update gooseberry, or restore an older backup with 'gooseberry db restore'");
+        }
+        Ok(())
+    }
+
+    /// Schema version of the database, `None` if it was never recorded
+    fn get_schema_version(&self) -> color_eyre::Result<Option<u32>> {
+        match self.default_tree()?.get(SCHEMA_VERSION_KEY)? {
+            Some(version) => Ok(Some(std::str::from_utf8(&version)?.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_schema_version(&self, version: u32) -> color_eyre::Result<()> {
+        self.default_tree()?
+            .insert(SCHEMA_VERSION_KEY, version.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    /// Moves the database over from the backend it was made with to `db_backend`, after it was changed.
+    /// The database is backed up first, and only deleted from the old backend once it's all copied
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn move_database(mut self, from: DbBackend) -> color_eyre::Result<Self> {
+        let to = self.config.db_backend();
+        if let Err(error) = self.backup_db(&format!("moving it to {:?}", to)) {
+            eprintln!(
+                "Couldn't back up the database before moving it to {:?}: {}",
+                to, error
+            );
+        }
+        let db_dir = self.db_dir().to_path_buf();
+        self.db.flush()?;
+        storage::copy_database(self.db.as_ref(), &db_dir, to)?;
+        // closes the database of the old backend
+        self.db = Self::get_db(&db_dir, to)?;
+        storage::remove_database(&db_dir, from)?;
+        eprintln!(
+            "Moved the database from {:?} to {:?}, since db_backend is {:?}",
+            from, to, to
+        );
+        Ok(self)
+    }
+
+    /// Brings the database up to the current schema version: a new database gets it right away,
+    /// and one from an older version of gooseberry is backed up and goes through the missing migrations
+    /// (storing the version after each, so an interrupted upgrade picks up where it stopped).
+    /// Fails for a database written by a newer version of gooseberry, instead of misreading it
+    pub fn migrate(&self) -> color_eyre::Result<()> {
+        let empty = self.annotation_to_tags()?.is_empty()? && self.trash()?.is_empty()?;
+        let version = match self.get_schema_version()? {
+            Some(version) => version,
+            None if empty => return self.set_schema_version(SCHEMA_VERSION),
+            None => 0,
+        };
+        Self::check_schema_version(version)?;
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        // a database that can't be backed up (e.g. with a search index from an older version) can still be upgraded
+        if let Err(error) = self.backup_db("upgrading the database") {
+            eprintln!(
+                "Couldn't back up the database before upgrading it: {}",
+                error
+            );
+        }
+        for (migration, new_version) in MIGRATIONS[version as usize..].iter().zip(version + 1..) {
+            (migration.migrate)(self).map_err(|error| Apologize::MigrationError {
+                message: format!(
+                    "Couldn't upgrade the database to schema version {} ({}): {}",
+                    new_version, migration.description, error
+                ),
+            })?;
+            self.set_schema_version(new_version)?;
+            eprintln!(
+                "Upgraded the database to schema version {}: {}",
+                new_version, migration.description
+            );
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use color_eyre::Help;
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::migration::SCHEMA_VERSION_KEY;
+use crate::gooseberry::storage::{Tree, DEFAULT_TREE};
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Version of the mirror format, in the header line
+const MIRROR_VERSION: u32 = 1;
+
+/// Bytes of a key or value in the mirror: a string if they're UTF-8, and hex-encoded otherwise
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Bytes {
+    Text(String),
+    Binary { hex: String },
+}
+
+impl Bytes {
+    fn new(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Bytes::Text(text.to_owned()),
+            Err(_) => Bytes::Binary {
+                hex: bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            },
+        }
+    }
+
+    fn to_vec(&self) -> color_eyre::Result<Vec<u8>> {
+        match self {
+            Bytes::Text(text) => Ok(text.as_bytes().to_vec()),
+            Bytes::Binary { hex } => (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                        .ok_or_else(|| {
+                            Apologize::MirrorError {
+                                message: format!("{:?} isn't valid hex", hex),
+                            }
+                            .into()
+                        })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A line of the mirror
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    /// First line, identifying the file
+    Header { format: String, version: u32 },
+    /// A synced annotation, as returned by the Hypothesis API
+    Annotation { annotation: Box<Annotation> },
+    /// A key and value of one of the database trees
+    Entry {
+        tree: String,
+        key: Bytes,
+        value: Bytes,
+    },
+}
+
+/// Error for a line of a mirror which can't be read
+fn mirror_error(line: usize, message: &str) -> color_eyre::Report {
+    color_eyre::Report::from(Apologize::MirrorError {
+        message: format!("Line {}: {}", line, message),
+    })
+}
+
+/// Contents of a mirror
+pub(crate) struct Mirror {
+    pub(crate) annotations: Vec<Annotation>,
+    /// Tree name, key, and value of each database entry
+    pub(crate) entries: Vec<(String, Vec<u8>, Vec<u8>)>,
+}
+
+/// Reads a mirror written by `write_mirror`, checking its header and that its schema version isn't too new
+pub(crate) fn read_mirror(reader: impl BufRead) -> color_eyre::Result<Mirror> {
+    let mut mirror = Mirror {
+        annotations: Vec::new(),
+        entries: Vec::new(),
+    };
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|error| mirror_error(index + 1, &error.to_string()))?;
+        match (index, record) {
+            (0, Record::Header { format, version }) => {
+                if format != "gooseberry" || version > MIRROR_VERSION {
+                    return Err(mirror_error(
+                        1,
+                        &format!("Unsupported format {:?} version {}", format, version),
+                    ))
+                    .suggestion("Update gooseberry to import this file");
+                }
+            }
+            (0, _) => {
+                return Err(mirror_error(1, "Missing header"))
+                    .suggestion("Import a file written by `gooseberry db export`")
+            }
+            (_, Record::Header { .. }) => return Err(mirror_error(index + 1, "Unexpected header")),
+            (_, Record::Annotation { annotation }) => mirror.annotations.push(*annotation),
+            (_, Record::Entry { tree, key, value }) => {
+                let (key, value) = (key.to_vec()?, value.to_vec()?);
+                if tree == DEFAULT_TREE && key == SCHEMA_VERSION_KEY.as_bytes() {
+                    Gooseberry::check_schema_version(std::str::from_utf8(&value)?.parse()?)?;
+                }
+                mirror.entries.push((tree, key, value))
+            }
+        }
+    }
+    Ok(mirror)
+}
+
+/// ## Mirror
+/// The whole database as a JSON Lines file, for backups, moving it to another machine,
+/// and looking into it without database tools
+impl Gooseberry {
+    /// The database trees with their names, starting with the default tree
+    fn trees(&self) -> color_eyre::Result<Vec<(String, Tree)>> {
+        self.db
+            .tree_names()?
+            .into_iter()
+            .map(|name| Ok((name.clone(), self.db.open_tree(&name)?)))
+            .collect()
+    }
+
+    /// Writes the mirror of the database: a header, every synced annotation (from the search index),
+    /// and every key and value of every tree (tags, groups, replies, titles, trash, history, sync times etc.).
+    /// Returns the number of annotations and entries written
+    pub(crate) fn write_mirror(
+        &self,
+        mut writer: impl Write,
+    ) -> color_eyre::Result<(usize, usize)> {
+        let ids = self
+            .annotation_to_tags()?
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect::<color_eyre::Result<Vec<String>>>()?;
+        let annotations =
+            self.indexed_annotations(&ids.iter().map(String::as_str).collect::<Vec<_>>())?;
+        let mut write = |record: &Record| -> color_eyre::Result<()> {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+            Ok(())
+        };
+        write(&Record::Header {
+            format: "gooseberry".into(),
+            version: MIRROR_VERSION,
+        })?;
+        for id in &ids {
+            if let Some(annotation) = annotations.get(id) {
+                write(&Record::Annotation {
+                    annotation: Box::new(annotation.clone()),
+                })?;
+            }
+        }
+        let mut entries = 0;
+        for (name, tree) in self.trees()? {
+            for item in tree.iter() {
+                let (key, value) = item?;
+                write(&Record::Entry {
+                    tree: name.clone(),
+                    key: Bytes::new(&key),
+                    value: Bytes::new(&value),
+                })?;
+                entries += 1;
+            }
+        }
+        writer.flush()?;
+        Ok((annotations.len(), entries))
+    }
+
+    /// Replaces everything in the database with the contents of a mirror,
+    /// rebuilds the search index from its annotations, and upgrades it to the current schema version
+    pub(crate) fn replace_db(&self, mirror: &Mirror) -> color_eyre::Result<()> {
+        for (_, tree) in self.trees()? {
+            tree.clear()?;
+        }
+        for (name, key, value) in &mirror.entries {
+            self.db.open_tree(name)?.insert(key, value)?;
+        }
+        self.db.flush()?;
+        let index_dir = self.index_dir();
+        if index_dir.exists() {
+            fs::remove_dir_all(&index_dir)?;
+        }
+        self.index_annotations(&mirror.annotations)?;
+        self.migrate()?;
+        Ok(())
+    }
+
+    /// Writes the database to a JSON Lines file
+    pub fn export_db(&self, file: &Path) -> color_eyre::Result<()> {
+        let (annotations, entries) = self.write_mirror(BufWriter::new(fs::File::create(file)?))?;
+        println!(
+            "{} annotation(s) and {} database entries written to {:?}",
+            annotations, entries, file
+        );
+        Ok(())
+    }
+
+    /// Replaces the database with the one in a JSON Lines file written by `export_db`,
+    /// backing up the current one first
+    pub fn import_db(&self, file: &Path, force: bool) -> color_eyre::Result<()> {
+        let mirror = read_mirror(BufReader::new(fs::File::open(file)?))?;
+        if self.dry_run {
+            println!(
+                "Would replace the database ({} annotation(s)) with {} annotation(s) and {} database entries from {:?} (dry run)",
+                self.annotation_to_tags()?.len()?,
+                mirror.annotations.len(),
+                mirror.entries.len(),
+                file
+            );
+            return Ok(());
+        }
+        let empty = self.annotation_to_tags()?.is_empty()?;
+        if !(empty
+            || force
+            || utils::confirm(
+                "Replace all gooseberry data with the imported database?",
+                false,
+            )?)
+        {
+            let error: color_eyre::Result<()> = Err(Apologize::DoingNothing.into());
+            return error.suggestion("Press Y next time!");
+        }
+        if !empty {
+            self.backup_db("db import")?;
+        }
+        self.replace_db(&mirror)?;
+        println!(
+            "{} annotation(s) imported from {:?}",
+            mirror.annotations.len(),
+            file
+        );
+        Ok(())
+    }
+}
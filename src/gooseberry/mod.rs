@@ -1,51 +1,173 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
+use std::path::Path;
 
 use color_eyre::Help;
-use dialoguer::Confirm;
-use hypothesis::annotations::{Annotation, Order, SearchQuery};
-use hypothesis::Hypothesis;
+use hypothesis::annotations::{Annotation, InputAnnotation, SearchQuery, Selector, Target};
 
+#[cfg(feature = "cli")]
+use crate::configuration::ColorMode;
 use crate::configuration::GooseberryConfig;
+#[cfg(feature = "cli")]
+use crate::configuration::OrderBy;
 use crate::errors::Apologize;
-use crate::gooseberry::cli::{ConfigCommand, Filters, GooseberryCLI, GooseberrySubcommand};
-use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::api::Hypothesis;
+#[cfg(feature = "cli")]
+use crate::gooseberry::cli::{
+    ConfigCommand, DbCommand, ExportFormat, GooseberryCLI, GooseberrySubcommand, GroupCommand,
+    PdfCommand, SortField, SortOptions, TagCommand, TodoCommand, TrashCommand, ViewFormat,
+};
+use crate::gooseberry::cli::{DiffFormat, Filters, UriOptions};
+use crate::gooseberry::diff::SyncDiff;
+use crate::gooseberry::encryption::WorkingCopy;
+use crate::gooseberry::filter::Filter;
+use crate::gooseberry::storage::Store;
 
+/// Opening more annotations than this in the browser at once asks first
+#[cfg(feature = "cli")]
+const OPEN_CONFIRM: usize = 10;
+
+/// Hypothesis API client for the configured API URL
+pub mod api;
+/// Readable snapshots of annotated pages
+pub mod archive;
+/// Backups of the database taken before destructive commands
+pub mod backup;
+/// Citation keys from a BibTeX or CSL JSON bibliography, for the `cite` template helpers
+pub mod bibliography;
 /// Command-line interface with `structopt`
 pub mod cli;
-/// `sled` database related
+/// Shell completion scripts, completing tags from the database
+#[cfg(feature = "cli")]
+pub mod completion;
+/// Database trees and the functions reading and writing them
 pub mod database;
+/// Find and merge duplicate annotations
+pub mod dedupe;
+/// What changed between two syncs, for `sync --diff`
+pub mod diff;
+/// Summaries of recent annotations grouped by tag and domain, for `gooseberry digest`
+pub mod digest;
+/// Checking the setup with `gooseberry doctor`
+#[cfg(feature = "cli")]
+pub mod doctor;
+/// Showing what commands would change with `--dry-run`
+pub mod dry_run;
+/// Encryption at rest of the database folder
+pub mod encryption;
+/// Chapters and reading positions of annotations on EPUBs
+pub mod epub;
+/// Export annotations in machine-readable formats
+pub mod export;
+/// Atom feeds of recent annotations
+pub mod feed;
+/// Parallel, rate-limited fetching of the annotations to sync
+pub mod fetch;
+/// Filter expressions for saved views, and local filtering
+pub mod filter;
+/// Committing the knowledge base to git after `make`
+pub mod git;
+/// Graph of tags, documents, and annotations for Graphviz and Mermaid
+pub mod graph;
+/// Listing, creating, leaving, and switching Hypothesis groups
+pub mod groups;
+/// Formatting helpers for the templates
+pub mod helpers;
+/// Previous versions of annotations, kept during sync
+pub mod history;
+/// Standalone HTML knowledge bases
+pub mod html;
+/// Importing highlights and bookmarks from other tools
+pub mod import;
+/// `tantivy` full-text search index
+pub mod index;
 /// Convert annotations to text for the wiki and for the terminal
 pub mod knowledge_base;
+/// Typed links between annotations, made with `gooseberry link`
+pub mod links;
+/// Local metadata of annotations, set with `gooseberry meta`
+pub mod meta;
+/// Schema versions of the database, and upgrading it from older versions
+pub mod migration;
+/// The database as a JSON Lines file, for backups and moving it between machines
+pub mod mirror;
+/// Markdown rendering for the search window preview
+#[cfg(feature = "cli")]
+pub mod preview;
+/// Push edits made in the knowledge base back to Hypothesis
+pub mod push;
+/// Exporting highlights to Readwise
+pub mod readwise;
+/// Going through annotations one at a time to tag or delete them
+#[cfg(feature = "cli")]
+pub mod review;
+/// Tagging rules applied during sync
+pub mod rules;
 /// `skim`-based search capabilities
+#[cfg(feature = "cli")]
 pub mod search;
+/// HTTP API for editor plugins and scripts, served with `gooseberry serve`
+#[cfg(feature = "cli")]
+pub mod server;
+/// The knowledge base as a single document
+pub mod single_file;
+/// Content folders for static site generators
+pub mod site;
+/// Statistics about annotations
+pub mod stats;
+/// Storage backends of the database: `sled` or SQLite
+pub mod storage;
+/// Titles of annotated pages
+pub mod titles;
+/// Annotations with the todo tag as tasks, for `gooseberry todo`
+pub mod todo;
+/// Deleted annotations, kept so that they can be restored
+pub mod trash;
+/// Dashboard for browsing and managing annotations
+#[cfg(feature = "cli")]
+pub mod tui;
+/// Updating annotations in Hypothesis in bulk
+pub mod update;
+/// Display names of annotation authors, cached during sync
+pub mod users;
+/// Who can read annotations: the `--visibility` filter, `gooseberry publish`, and `gooseberry unpublish`
+pub mod visibility;
+/// Scheduled sync and make
+pub mod watch;
 
 /// Gooseberry database, API client, and configuration
 pub struct Gooseberry {
-    /// database storing annotations and links
-    db: sled::Db,
+    /// database storing annotations and links, with the configured backend
+    db: Box<dyn Store>,
+    /// lock file held while the database is in use, released on drop
+    _lock: fs::File,
     /// hypothesis API client
-    api: hypothesis::Hypothesis,
+    api: Hypothesis,
     /// configuration for directories and Hypothesis authorization
     config: GooseberryConfig,
+    /// decrypted copy of the database, with `encrypt_database` on (dropped after the database is closed)
+    working_copy: Option<WorkingCopy>,
+    /// only show what tagging, deleting, pushing, clearing, and making the knowledge base would change
+    dry_run: bool,
+    /// overwrite annotations changed in Hypothesis since they were fetched without asking
+    force: bool,
 }
 
-/// ## CLI
-/// Functions related to handling CLI commands
-impl Gooseberry {
-    /// Initialize program with command line input.
-    /// Reads `sled` trees and metadata file from the locations specified in config.
-    /// (makes new ones the first time).
-    pub async fn start(cli: GooseberryCLI) -> color_eyre::Result<()> {
-        if let GooseberrySubcommand::Config { cmd } = &cli.cmd {
-            return Ok(ConfigCommand::run(cmd, cli.config.as_deref()).await?);
-        }
-        if let GooseberrySubcommand::Complete { shell } = &cli.cmd {
-            GooseberryCLI::complete(*shell);
-            return Ok(());
+impl Drop for Gooseberry {
+    /// Encrypts the database again, if it's encrypted
+    fn drop(&mut self) {
+        if let Err(error) = self.save() {
+            eprintln!("Couldn't encrypt the database: {:?}", error);
         }
-        // Reads the GOOSEBERRY_CONFIG environment variable to get config file location
-        let config = GooseberryConfig::load(cli.config.as_deref()).await?;
+    }
+}
+
+/// ## Library
+/// Functions for using gooseberry without its command-line interface
+impl Gooseberry {
+    /// Opens the database (decrypting it first with `encrypt_database` on)
+    /// and connects to the Hypothesis API with a loaded configuration
+    pub fn new(config: GooseberryConfig) -> color_eyre::Result<Self> {
         let api = Hypothesis::new(
             config
                 .hypothesis_username
@@ -59,73 +181,475 @@ impl Gooseberry {
                 .ok_or(Apologize::ConfigError {
                     message: "Hypothesis developer API key isn't stored".into(),
                 })?,
+            &config.api_url(),
         )?;
-        let mut gooseberry = Self {
-            db: Self::get_db(&config.db_dir)?,
+        let lock = Self::lock_db(&config.db_dir)?;
+        let working_copy = if config.encrypt_database.unwrap_or(false) {
+            Some(WorkingCopy::open(&config)?)
+        } else {
+            encryption::decrypt_database(&config)?;
+            None
+        };
+        let db_dir = working_copy
+            .as_ref()
+            .map_or(config.db_dir.as_path(), WorkingCopy::dir);
+        // a database left by the other backend is opened to be moved over
+        let other_backend = storage::other_backend(db_dir, config.db_backend());
+        let gooseberry = Self {
+            db: Self::get_db(db_dir, other_backend.unwrap_or(config.db_backend()))?,
+            _lock: lock,
             api,
             config,
+            working_copy,
+            dry_run: false,
+            force: false,
+        };
+        #[cfg(feature = "sqlite")]
+        let gooseberry = match other_backend {
+            Some(from) => gooseberry.move_database(from)?,
+            None => gooseberry,
         };
-        gooseberry.set_merge()?;
-        gooseberry.run(cli).await?;
+        gooseberry.migrate()?;
+        Ok(gooseberry)
+    }
+
+    /// Encrypts the database again with `encrypt_database` on (done when gooseberry is dropped too),
+    /// so that long-running commands don't lose their changes if they're killed
+    pub fn save(&self) -> color_eyre::Result<()> {
+        if let Some(working_copy) = &self.working_copy {
+            self.db.flush()?;
+            working_copy.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// ## CLI
+/// Functions related to handling CLI commands
+#[cfg(feature = "cli")]
+impl Gooseberry {
+    /// Initialize program with command line input.
+    /// Reads the database trees and metadata file from the locations specified in config.
+    /// (makes new ones the first time).
+    pub async fn start(cli: GooseberryCLI) -> color_eyre::Result<()> {
+        crate::utils::set_color_mode(cli.color.map_or(ColorMode::Auto, ColorMode::from));
+        let _log_guard = crate::utils::init_logging(cli.verbose, cli.log_dir.as_deref())?;
+        if cli.dry_run {
+            if let Some(command) = cli.cmd.ignores_dry_run() {
+                let error: color_eyre::Result<()> = Err(Apologize::DryRunError {
+                    command: command.into(),
+                }
+                .into());
+                return error.suggestion("Run it without --dry-run, or preview a `tag`, `delete`, `sync` or `clear` instead");
+            }
+        }
+        if let GooseberrySubcommand::Config { cmd } = &cli.cmd {
+            return ConfigCommand::run(cmd, cli.config.as_deref(), cli.profile.as_deref()).await;
+        }
+        if let GooseberrySubcommand::Init { force } = &cli.cmd {
+            return GooseberryConfig::init(cli.config.as_deref(), cli.profile.as_deref(), *force)
+                .await;
+        }
+        if let GooseberrySubcommand::Doctor = &cli.cmd {
+            return Self::doctor(cli.config.as_deref(), cli.profile.as_deref()).await;
+        }
+        if let GooseberrySubcommand::Complete { shell } = &cli.cmd {
+            GooseberryCLI::complete(*shell);
+            return Ok(());
+        }
+        if let GooseberrySubcommand::CompleteTags { current } = &cli.cmd {
+            return completion::print_tags(cli.config.as_deref(), cli.profile.as_deref(), current);
+        }
+        // Reads the GOOSEBERRY_CONFIG environment variable to get config file location
+        let mut config =
+            GooseberryConfig::load_profile(cli.config.as_deref(), cli.profile.as_deref()).await?;
+        if let Some(vault) = &cli.obsidian_vault {
+            config.obsidian_vault = Some(vault.to_owned());
+        }
+        if cli.color.is_none() {
+            crate::utils::set_color_mode(config.color());
+        }
+        if let GooseberrySubcommand::Watch {
+            interval,
+            no_make,
+            wiki_links,
+            daemonize,
+            log,
+        } = cli.cmd
+        {
+            if daemonize {
+                return Self::daemonize(log.as_deref());
+            }
+            return Self::watch(config, interval, !no_make, wiki_links).await;
+        }
+        if let GooseberrySubcommand::Serve {
+            port,
+            interval,
+            allow_origin,
+        } = cli.cmd
+        {
+            return Self::serve(config, port, interval, allow_origin).await;
+        }
+        Self::new(config)?.run(cli).await?;
         Ok(())
     }
 
     /// Run knowledge-base related functions
     pub async fn run(&mut self, cli: GooseberryCLI) -> color_eyre::Result<()> {
+        self.dry_run = cli.dry_run;
         match cli.cmd {
-            GooseberrySubcommand::Sync => self.sync().await,
-            GooseberrySubcommand::Search { filters, fuzzy } => {
-                let annotations: Vec<Annotation> = self.filter_annotations(filters, None).await?;
-                self.search(annotations, fuzzy).await
+            GooseberrySubcommand::Sync {
+                push,
+                dry_run,
+                archive,
+                diff,
+                format,
+                changelog,
+            } => {
+                if push {
+                    self.push().await?;
+                }
+                if dry_run || self.dry_run {
+                    return self.preview_rules().await;
+                }
+                self.sync_with_diff(archive, diff.then_some(format), changelog.as_deref())
+                    .await
+            }
+            GooseberrySubcommand::Search {
+                filters,
+                fuzzy,
+                query,
+                print_ids,
+                once,
+                terms,
+                force,
+                mut sort,
+            } => {
+                self.force = force;
+                // Annotations on one document (or a few) are easiest to go through in the order they're in
+                if sort.sort.is_none() && !filters.uri.is_empty() {
+                    sort.sort = Some(SortField::Position);
+                }
+                let (expression, text) = filter::split_query(&terms.join(" "));
+                if print_ids {
+                    // Without a search window, the words have to be in the quote, text, tags, or URI
+                    let mut refinement = vec![Filter::parse(
+                        &expression,
+                        self.config.tag_delimiter.as_deref(),
+                    )?];
+                    refinement.extend(text.split_whitespace().map(|w| Filter::Any(w.to_owned())));
+                    let annotations = self.search_matches(filters, query.as_deref(), sort).await?;
+                    for annotation in self.refine(annotations, &Filter::And(refinement))? {
+                        println!("{}", annotation.id);
+                    }
+                    return Ok(());
+                }
+                self.search_session(filters, query, sort, (expression, text), fuzzy, once)
+                    .await
             }
             GooseberrySubcommand::Tag {
                 filters,
                 delete,
                 tag,
+                force,
+                cmd,
             } => {
-                let annotations: Vec<Annotation> = self.filter_annotations(filters, None).await?;
-                let tags = if tag.is_empty() { None } else { Some(tag) };
-                self.tag(annotations, delete, tags).await
+                self.force = force;
+                match cmd {
+                    Some(TagCommand::Rename { old, new }) => self.merge_tags(vec![old], new).await,
+                    Some(TagCommand::Merge { tags, into }) => self.merge_tags(tags, into).await,
+                    Some(TagCommand::List { tree, format }) => self.list_tags(tree, format),
+                    None => {
+                        let annotations: Vec<Annotation> =
+                            self.filter_annotations(filters, None).await?;
+                        let tags = if tag.is_empty() { None } else { Some(tag) };
+                        self.tag(annotations, delete, tags).await
+                    }
+                }
             }
             GooseberrySubcommand::Delete { filters, force } => {
                 let annotations = self.filter_annotations(filters, None).await?;
                 self.delete(annotations, force).await
             }
-            GooseberrySubcommand::View { filters, id } => self.view(filters, id).await,
+            GooseberrySubcommand::Publish {
+                filters,
+                to_group,
+                force,
+            } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.publish(annotations, to_group, force).await
+            }
+            GooseberrySubcommand::Unpublish { filters, force } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.unpublish(annotations, force).await
+            }
+            GooseberrySubcommand::Review { filters, all } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.review(annotations, all).await
+            }
+            GooseberrySubcommand::Tui { filters, force } => {
+                self.force = force;
+                self.tui(filters).await
+            }
+            GooseberrySubcommand::Archive { filters, text_only } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.archive(annotations, text_only).await
+            }
+            GooseberrySubcommand::Dedupe { filters, force } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.dedupe(annotations, force).await
+            }
+            GooseberrySubcommand::Restore { ids } => self.restore(ids).await,
+            GooseberrySubcommand::Trash { cmd } => match cmd {
+                TrashCommand::List => self.list_trash(),
+            },
+            GooseberrySubcommand::History { id } => self.history(&id),
+            GooseberrySubcommand::Todo { cmd } => match cmd {
+                TodoCommand::List { filters, all } => self.list_todos(filters, all).await,
+                TodoCommand::Done { ids } => self.done_todos(ids).await,
+                TodoCommand::Snooze { ids, duration } => self.snooze_todos(ids, duration).await,
+            },
+            GooseberrySubcommand::Link {
+                from,
+                to,
+                link_type,
+                remove,
+            } => self.link(&from, to.as_deref(), &link_type, remove),
+            GooseberrySubcommand::Meta { id, fields } => self.meta(&id, &fields),
+            GooseberrySubcommand::Group { cmd } => match cmd {
+                GroupCommand::List => self.list_groups().await,
+                GroupCommand::Create { name, description } => {
+                    self.create_group(&name, description.as_deref()).await
+                }
+                GroupCommand::Leave { id, force } => self.leave_group(&id, force).await,
+                GroupCommand::Members { id } => self.list_group_members(id).await,
+                GroupCommand::Switch => self.switch_group().await,
+            },
+            GooseberrySubcommand::Pdf { cmd } => match cmd {
+                PdfCommand::List { filters } => {
+                    let annotations = self.filter_annotations(filters, None).await?;
+                    self.list_pdfs(annotations)
+                }
+                PdfCommand::Title { fingerprint, title } => self.set_pdf_title(&fingerprint, title),
+            },
+            GooseberrySubcommand::View {
+                filters,
+                id,
+                format,
+                clipboard,
+                sort,
+            } => self.view(filters, id, format, clipboard, sort).await,
             GooseberrySubcommand::Move {
                 group_id,
+                from_group,
+                to_group,
+                delete,
                 filters,
                 search,
                 fuzzy,
-            } => self.sync_group(group_id, filters, search, fuzzy).await,
-            GooseberrySubcommand::Make { force } => self.make(force).await,
-            GooseberrySubcommand::Clear { force } => self.clear(force),
-            GooseberrySubcommand::Uri { filters, ids } => {
+            } => {
+                // One of them is required by the CLI
+                let from_group = group_id.or(from_group).unwrap_or_default();
+                self.move_annotations(from_group, to_group, filters, delete, search, fuzzy)
+                    .await
+            }
+            GooseberrySubcommand::Annotate {
+                uri,
+                quote,
+                text,
+                editor,
+                tags,
+                group,
+            } => self.annotate(uri, quote, text, editor, tags, group).await,
+            GooseberrySubcommand::Import {
+                format,
+                file,
+                tags,
+                group,
+                dry_run,
+                force,
+            } => {
+                self.import(format, &file, tags, group, dry_run || self.dry_run, force)
+                    .await
+            }
+            GooseberrySubcommand::Make {
+                force,
+                rebuild,
+                group,
+                view,
+                wiki_links,
+                site,
+                include_replies,
+                sort,
+                reverse,
+                group_by,
+                front_matter,
+                single_file,
+            } => {
+                let filters = Filters {
+                    group,
+                    view,
+                    include_replies,
+                    ..Filters::default()
+                };
+                if front_matter {
+                    // Only for this run, not stored
+                    self.config.front_matter = Some(true);
+                }
+                let sort = SortOptions { sort, reverse };
+                let group_by = group_by.map(OrderBy::from);
+                match single_file {
+                    Some(file) => self.make_single_file(&file, filters, sort, group_by).await,
+                    None => {
+                        self.make(force, rebuild, filters, wiki_links, site, sort, group_by)
+                            .await
+                    }
+                }
+            }
+            GooseberrySubcommand::Clear {
+                // what clearing does by default
+                db_only: _,
+                all,
+                kb_only,
+                tag,
+                force,
+            } => match tag {
+                Some(tag) => self.forget_tag(&tag, force),
+                None => self.clear(!kb_only, all || kb_only, force),
+            },
+            GooseberrySubcommand::Db { cmd } => match cmd {
+                DbCommand::Export { file } => self.export_db(&file),
+                DbCommand::Import { file, force } => self.import_db(&file, force),
+                DbCommand::Backup => self.backup_now(),
+                DbCommand::Backups => self.list_backups(),
+                DbCommand::Restore { timestamp, force } => self.restore_backup(&timestamp, force),
+            },
+            GooseberrySubcommand::Stats {
+                filters,
+                format,
+                top,
+            } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.stats(annotations, format, top)
+            }
+            GooseberrySubcommand::Graph {
+                filters,
+                format,
+                no_annotations,
+            } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.graph(annotations, format, !no_annotations)
+            }
+            GooseberrySubcommand::Uri {
+                filters,
+                ids,
+                options,
+                clipboard,
+            } => {
                 let annotations: Vec<Annotation> = self.filter_annotations(filters, None).await?;
-                self.uri(annotations, ids)
+                if clipboard {
+                    crate::utils::copy_to_clipboard(&self.uri_list(annotations, ids, options)?)?;
+                    println!("Copied URIs");
+                    Ok(())
+                } else {
+                    self.uri(annotations, ids, options)
+                }
+            }
+            GooseberrySubcommand::Open {
+                mut filters,
+                ids,
+                print,
+                force,
+            } => {
+                filters.ids.extend(ids);
+                let annotations: Vec<Annotation> = self.filter_annotations(filters, None).await?;
+                self.open(&annotations, print, force)
+            }
+            GooseberrySubcommand::Export {
+                filters,
+                format,
+                output,
+            } => {
+                let annotations: Vec<Annotation> = self.filter_annotations(filters, None).await?;
+                if format == ExportFormat::Readwise {
+                    self.export_readwise(annotations).await
+                } else {
+                    self.export(annotations, format, output.as_deref())
+                }
+            }
+            GooseberrySubcommand::Feed {
+                filters,
+                limit,
+                feed_title,
+                link,
+                output,
+            } => {
+                let annotations = self.filter_annotations(filters, None).await?;
+                self.feed(annotations, limit, feed_title, link, output.as_deref())
+            }
+            GooseberrySubcommand::Digest {
+                filters,
+                format,
+                digest_title,
+                output,
+                sendmail,
+            } => {
+                self.digest(
+                    filters,
+                    format,
+                    digest_title,
+                    output.as_deref(),
+                    sendmail.as_deref(),
+                )
+                .await
             }
             _ => Ok(()), // Already handled
         }
     }
+}
 
+/// ## Annotations
+/// Functions for syncing, filtering, and modifying annotations
+impl Gooseberry {
     /// Sync newly added / updated annotations
     pub async fn sync(&self) -> color_eyre::Result<()> {
-        let spinner = crate::utils::get_spinner("Syncing...");
-        // Sleep to make sure the previous requests are processed
-        let duration = core::time::Duration::from_millis(500);
-        std::thread::sleep(duration);
+        self.sync_and_archive(false).await
+    }
 
-        let mut query = SearchQuery::builder()
-            .limit(200)
-            .order(Order::Asc)
-            .search_after(self.get_sync_time()?)
-            .user(&self.api.user.0)
-            .group(self.config.hypothesis_group.as_deref().unwrap())
-            .build()?;
-        let (added, updated) =
-            self.sync_annotations(&self.api.search_annotations_return_all(&mut query).await?)?;
-        self.set_sync_time(&query.search_after)?;
-        spinner.finish_with_message("Done!");
+    /// Sync newly added / updated annotations, saving their pages in the Wayback Machine if `wayback` is set
+    pub async fn sync_and_archive(&self, wayback: bool) -> color_eyre::Result<()> {
+        self.sync_with_diff(wayback, None, None).await
+    }
+
+    /// Sync newly added / updated annotations, saving their pages in the Wayback Machine if `wayback` is set.
+    ///
+    /// With `diff_format`, prints what changed since the last sync (see `SyncDiff`) instead of the number
+    /// of added and updated annotations. With `changelog`, appends it to a file (in `diff_format`, or markdown)
+    #[tracing::instrument(skip(self))]
+    pub async fn sync_with_diff(
+        &self,
+        wayback: bool,
+        diff_format: Option<DiffFormat>,
+        changelog: Option<&Path>,
+    ) -> color_eyre::Result<()> {
+        let mut diff = if diff_format.is_some() || changelog.is_some() {
+            Some(SyncDiff::default())
+        } else {
+            None
+        };
+        let (added, updated, tagged) = self.sync_changes(wayback, diff.as_mut()).await?;
+        if let Some(diff) = diff {
+            if let (Some(changelog), false) = (changelog, diff.is_empty()) {
+                diff.append_to(changelog, diff_format.unwrap_or(DiffFormat::Markdown))?;
+            }
+            if let Some(format) = diff_format {
+                match format {
+                    DiffFormat::Markdown if diff.is_empty() => println!("Everything up to date!"),
+                    _ => println!("{}", diff.format(format)?),
+                }
+                return Ok(());
+            }
+        }
         if added > 0 {
             if added == 1 {
                 println!("Added 1 annotation");
@@ -143,73 +667,387 @@ impl Gooseberry {
         if added == 0 && updated == 0 {
             println!("Everything up to date!")
         }
+        if tagged > 0 {
+            println!("Tagged {} annotation(s) using rules", tagged);
+        }
         Ok(())
     }
 
-    /// Move (optionally filtered) annotations from a different group to the group gooseberry looks at (set in config)
-    pub async fn sync_group(
+    /// Syncs the annotations of each group, adding what changed to the `diff` if given.
+    /// Returns the number of annotations added, updated, and tagged using rules
+    pub(crate) async fn sync_changes(
+        &self,
+        wayback: bool,
+        mut diff: Option<&mut SyncDiff>,
+    ) -> color_eyre::Result<(usize, usize, usize)> {
+        let progress_bar = crate::utils::get_spinner("Syncing...");
+        // Sleep to make sure the previous requests are processed
+        let duration = core::time::Duration::from_millis(500);
+        std::thread::sleep(duration);
+
+        self.check_index()?;
+        if !self.index_dir().exists() {
+            // The full-text index is new, sync everything again to fill it
+            self.reset_sync_time()?;
+        }
+        if self.config.all_users() && !self.synced_all_users()? {
+            // Fetch the older annotations of the others too
+            self.reset_sync_time()?;
+        }
+        self.set_synced_all_users(self.config.all_users())?;
+
+        let (mut added, mut updated) = (0, 0);
+        let mut tagged = 0;
+        for group in self.config.groups() {
+            tracing::info!(%group, since = %self.get_sync_time(&group)?, "syncing group");
+            let (mut annotations, mut progress) = match self.get_sync_progress(&group)? {
+                Some(progress) => {
+                    progress_bar
+                        .println(format!("Resuming the interrupted sync of group {}", group));
+                    (Vec::new(), progress)
+                }
+                None => self.start_fetch(&group).await?,
+            };
+            progress_bar.set_style(crate::utils::progress_bar_style());
+            loop {
+                progress_bar.set_length(progress.total.max(progress.fetched) as u64);
+                progress_bar.set_position(progress.fetched as u64);
+                let (batch_added, batch_updated, batch_tagged) = self
+                    .store_synced(
+                        &mut annotations,
+                        wayback,
+                        &progress_bar,
+                        diff.as_deref_mut(),
+                    )
+                    .await?;
+                added += batch_added;
+                updated += batch_updated;
+                tagged += batch_tagged;
+                if progress.is_done() {
+                    break;
+                }
+                // Stored annotations don't have to be fetched again if the sync is interrupted
+                self.set_sync_progress(&group, &progress)?;
+                annotations = self.fetch_batch(&group, &mut progress).await?;
+            }
+            if let Some(latest) = progress.latest {
+                self.set_sync_time(&group, &latest.to_rfc3339())?;
+            }
+            self.remove_sync_progress(&group)?;
+        }
+        progress_bar.finish_with_message("Done!");
+        tracing::info!(added, updated, tagged, "synced");
+        match diff {
+            Some(diff) => self.add_deletions_to_diff(diff)?,
+            None => self.deleted_since_sync()?.clear()?,
+        }
+        Ok((added, updated, tagged))
+    }
+
+    /// Applies tagging rules to synced annotations and stores them in the database,
+    /// caching their titles, archiving their pages, and adding them to the full-text index.
+    /// Keeps the previous versions of annotations with changed text or tags in their history,
+    /// and adds them to the `diff` if given.
+    /// Returns the number of annotations added, updated, and tagged
+    #[tracing::instrument(level = "debug", skip_all, fields(annotations = annotations.len()))]
+    async fn store_synced(
+        &self,
+        annotations: &mut Vec<Annotation>,
+        wayback: bool,
+        progress_bar: &indicatif::ProgressBar,
+        diff: Option<&mut SyncDiff>,
+    ) -> color_eyre::Result<(usize, usize, usize)> {
+        if annotations.is_empty() {
+            return Ok((0, 0, 0));
+        }
+        let tagged = self.apply_rules(annotations).await?;
+        self.drop_ignored(annotations)?;
+        if annotations.is_empty() {
+            return Ok((0, 0, tagged));
+        }
+        // Versions from the last sync, still in the full-text index
+        let ids: Vec<_> = annotations.iter().map(|a| a.id.as_str()).collect();
+        let indexed = self.indexed_annotations(&ids)?;
+        self.record_history(annotations, &indexed)?;
+        let previous = match diff {
+            Some(_) => self.previous_versions(annotations, &indexed)?,
+            None => HashMap::new(),
+        };
+        let (added, updated) = self.sync_annotations(annotations)?;
+        let moderated = self.store_moderation(annotations)?;
+        if moderated > 0 {
+            progress_bar.println(format!(
+                "{} annotation(s) newly hidden or flagged by moderators",
+                moderated
+            ));
+        }
+        self.cache_titles(annotations).await?;
+        self.cache_display_names(annotations).await?;
+        if let Some(diff) = diff {
+            self.add_to_diff(annotations, &previous, diff)?;
+        }
+        if wayback {
+            self.save_snapshots(annotations, progress_bar).await?;
+            progress_bar.set_message("Syncing...");
+        }
+        if wayback || self.config.archive_pages.unwrap_or(false) {
+            self.archive_pages(annotations).await?;
+        }
+        self.index_annotations(annotations)?;
+        Ok((added, updated, tagged))
+    }
+
+    /// Takes the annotations matching the `ignore` config option out of synced annotations,
+    /// forgetting those stored by earlier syncs (e.g. tagged to be ignored since then)
+    fn drop_ignored(&self, annotations: &mut Vec<Annotation>) -> color_eyre::Result<()> {
+        let ignore = match self.config.get_ignore_filter()? {
+            Some(ignore) => ignore,
+            None => return Ok(()),
+        };
+        let (ignored, kept): (Vec<_>, Vec<_>) =
+            annotations.drain(..).partition(|a| ignore.matches(a));
+        *annotations = kept;
+        let mut stored = Vec::new();
+        for annotation in &ignored {
+            if self
+                .annotation_to_tags()?
+                .contains_key(annotation.id.as_bytes())?
+            {
+                stored.push(annotation.id.to_owned());
+            }
+        }
+        if !stored.is_empty() {
+            self.delete_annotations(&stored)?;
+            self.unindex_annotations(&stored)?;
+        }
+        tracing::debug!(
+            ignored = ignored.len(),
+            forgotten = stored.len(),
+            "ignored annotations"
+        );
+        Ok(())
+    }
+
+    /// Copies (optionally filtered) annotations from one group to another (the group gooseberry looks at, set in config,
+    /// if not given). The copies get new IDs, the links and metadata of the originals, and the same sharing
+    /// (in the new group). With `delete` the originals are deleted (to the trash) and their links and metadata move over to the copies
+    pub async fn move_annotations(
         &mut self,
-        group_id: String,
+        from_group: String,
+        to_group: Option<String>,
         filters: Filters,
+        delete: bool,
         search: bool,
         fuzzy: bool,
     ) -> color_eyre::Result<()> {
-        let mut annotations = self
-            .filter_annotations(filters, Some(group_id.to_owned()))
+        let to_group = to_group.unwrap_or_else(|| {
+            self.config
+                .hypothesis_group
+                .clone()
+                .expect("This should have been set by Config")
+        });
+        if to_group == from_group {
+            eprintln!(
+                "The annotations are already in group {} (give the group to move to with --to-group)",
+                to_group
+            );
+            return Ok(());
+        }
+        let annotations = self
+            .filter_annotations(filters, Some(from_group.to_owned()))
             .await?;
-        if search || fuzzy {
+        #[cfg(feature = "cli")]
+        let annotations: Vec<_> = if search || fuzzy {
             // Run a search window.
             let annotation_ids = self.search_group(&annotations, fuzzy)?;
-            annotations = annotations
+            annotations
                 .into_iter()
                 .filter(|a| annotation_ids.contains(&a.id))
-                .collect();
-        }
-        let num = annotations.len();
-        // Change the group ID attached to each annotation
-        self.api
-            .update_annotations(
-                &annotations
-                    .into_iter()
-                    .map(|mut a| {
-                        a.group = group_id.to_owned();
-                        a
-                    })
-                    .collect::<Vec<_>>(),
-            )
-            .await?;
-        if num > 0 {
-            self.sync().await?;
+                .collect()
+        } else {
+            annotations
+        };
+        #[cfg(not(feature = "cli"))]
+        if search || fuzzy {
+            let error: color_eyre::Result<()> = Err(Apologize::SearchError.into());
+            return error.suggestion("Search windows need gooseberry's `cli` feature");
+        }
+        if annotations.is_empty() {
+            println!("No annotations to move");
+            return Ok(());
+        }
+        if self.dry_run {
+            for annotation in &annotations {
+                println!(
+                    "POST /api/annotations (copy of {} in group {})",
+                    annotation.id, to_group
+                );
+            }
+            println!(
+                "Would copy {} annotation(s) to group {} (dry run)",
+                annotations.len(),
+                to_group
+            );
+            if delete {
+                dry_run::print_deletes(&annotations);
+            }
+            return Ok(());
+        }
+        let mut copies = Vec::with_capacity(annotations.len());
+        for annotation in &annotations {
+            let mut input = trash::input_annotation(annotation.clone())?;
+            input.group = to_group.to_owned();
+            // Annotations shared with the old group are shared with the new one instead
+            let mut permissions = annotation.permissions.clone();
+            let shared = format!("group:{}", annotation.group);
+            for reader in permissions.read.iter_mut().filter(|r| **r == shared) {
+                *reader = format!("group:{}", to_group);
+            }
+            copies.push(
+                self.api
+                    .create_annotation_with_permissions(&input, &permissions)
+                    .await?,
+            );
+        }
+        for (original, copy) in annotations.iter().zip(&copies) {
+            if delete {
+                self.move_links(&original.id, &copy.id)?;
+                self.move_meta(&original.id, &copy.id)?;
+            } else {
+                self.copy_links(&original.id, &copy.id)?;
+                self.copy_meta(&original.id, &copy.id)?;
+            }
+        }
+        println!("Copied {} annotations to group {}", copies.len(), to_group);
+        if delete {
+            let ids: Vec<_> = annotations.iter().map(|a| a.id.to_owned()).collect();
+            // Only annotations from the groups gooseberry looks at are in the database
+            let synced_ids = self.annotation_to_tags()?;
+            let mut synced = Vec::new();
+            for id in &ids {
+                if synced_ids.contains_key(id)? {
+                    synced.push(id.to_owned());
+                }
+            }
+            self.trash_annotations(&annotations)?;
+            self.delete_annotations(&synced)?;
+            self.unindex_annotations(&synced)?;
+            self.api.delete_annotations(&ids).await?;
+            println!(
+                "Deleted the {} originals (undo with 'gooseberry restore <id>')",
+                ids.len()
+            );
         }
+        self.sync().await?;
         Ok(())
     }
 
     /// Filter annotations based on command-line flags
+    ///
+    /// Searches the given group, the group in the filters, or all configured groups (in that order of preference).
+    /// Saved views, hierarchical tag prefixes (e.g. `--tags biology/`), several tags with `--any-tags`, and annotation IDs
+    /// are applied locally to the results.
+    /// Replies to the results are added with `--include-replies`, whether or not they match
+    #[tracing::instrument(level = "debug", skip_all)]
     pub async fn filter_annotations(
         &self,
-        filters: Filters,
+        mut filters: Filters,
         group: Option<String>,
     ) -> color_eyre::Result<Vec<Annotation>> {
-        let mut query: SearchQuery = filters.into();
-        query.user = self.api.user.0.to_owned();
-        query.group = match group {
-            Some(group) => group,
-            None => self
-                .config
-                .hypothesis_group
-                .clone()
-                .expect("This should have been set by Config"),
+        let groups = match group.or_else(|| filters.group.clone()) {
+            Some(group) => vec![group],
+            None => self.config.groups(),
         };
-        let mut annotations: Vec<_> = self
-            .api
-            .search_annotations_return_all(&mut query)
-            .await?
-            .into_iter()
-            .collect();
+        let (view, exclusions) = self.config.get_local_filter(&mut filters)?;
+        filters.read_stdin_ids()?;
+        let ids: HashSet<String> = filters.ids.drain(..).collect();
+        let (include_replies, top_level_only) = (filters.include_replies, filters.top_level_only);
+        // Checked here: the Hypothesis API only searches dates in one direction and doesn't do regexes
+        let mut local = Filters {
+            from: filters.after(),
+            before: filters.before,
+            date_field: filters.date_field(),
+            uri_pattern: filters.uri_pattern.take(),
+            quote_pattern: filters.quote_pattern.take(),
+            text_pattern: filters.text_pattern.take(),
+            title: std::mem::take(&mut filters.title),
+            meta: std::mem::take(&mut filters.meta),
+            hidden: filters.hidden,
+            flagged: filters.flagged,
+            visibility: filters.visibility,
+            not: filters.not,
+            ..Filters::default()
+        };
+        if local.not {
+            // The Hypothesis API only searches for matches, so negated filters are all checked here
+            local.uri = std::mem::take(&mut filters.uri);
+            local.any = std::mem::take(&mut filters.any);
+            local.tags = std::mem::take(&mut filters.tags);
+        }
+        if filter::is_glob(&filters.uri) {
+            // The Hypothesis API only matches parts of URIs
+            local.uri = std::mem::take(&mut filters.uri);
+        }
+        let own = &self.api.user.0;
+        let user = match (&filters.user, filters.mine) {
+            (Some(user), _) => crate::utils::account_id(user, own),
+            (None, false) if self.config.all_users() => String::new(),
+            _ => own.to_owned(),
+        };
+        let mut query: SearchQuery = filters.into();
+        query.user = user;
+        let mut annotations = Vec::new();
+        for group in groups {
+            let mut group_query = query.clone();
+            group_query.group = group;
+            let found = self
+                .api
+                .search_annotations_return_all(&mut group_query)
+                .await?;
+            tracing::debug!(group = %group_query.group, found = found.len(), "searched group");
+            annotations.extend(found);
+        }
+        let mut matching = Vec::new();
+        for annotation in annotations {
+            if self.matches_locally(&annotation, &local, &view, &exclusions, &ids)? {
+                matching.push(annotation);
+            }
+        }
+        let mut annotations = matching;
+        if include_replies {
+            let replies = self.fetch_replies(&annotations).await?;
+            let known: HashSet<_> = annotations.iter().map(|a| a.id.to_owned()).collect();
+            annotations.extend(replies.into_iter().filter(|a| !known.contains(&a.id)));
+        }
+        if top_level_only {
+            annotations.retain(|a| a.references.is_empty());
+        }
         annotations.sort_by(|a, b| a.created.cmp(&b.created));
         Ok(annotations)
     }
 
+    /// Checks filters, a saved view, annotation IDs (if any), and exclusions against an annotation locally,
+    /// with the resolved title of its document (see `resolved_title`).
+    /// With `--not`, annotations matching the filters, view, and IDs are the ones left out (within the dates and group)
+    fn matches_locally(
+        &self,
+        annotation: &Annotation,
+        filters: &Filters,
+        view: &Filter,
+        exclusions: &Filter,
+        ids: &HashSet<String>,
+    ) -> color_eyre::Result<bool> {
+        let title = self.resolved_title(annotation)?;
+        let title = title.as_deref();
+        let matches = (ids.is_empty() || ids.contains(&annotation.id))
+            && filters.matches_content(annotation, title)
+            && view.matches_titled(annotation, title)
+            && self.matches_meta(&annotation.id, &filters.meta)?;
+        Ok(filters.matches_scope(annotation)
+            && matches != filters.not
+            && !exclusions.matches_titled(annotation, title))
+    }
+
     async fn add_tags(
         &self,
         annotations: Vec<Annotation>,
@@ -223,27 +1061,32 @@ impl Gooseberry {
             println!("All of the selected annotations already have all of those tags.");
             return Ok(());
         }
+        let updated: Vec<_> = annotations
+            .clone()
+            .into_iter()
+            .map(|mut a| {
+                a.tags.extend_from_slice(&tags);
+                a
+            })
+            .collect();
+        if self.dry_run {
+            dry_run::print_updates(&annotations, &updated);
+            return Ok(());
+        }
         println!(
             "Adding {} tag(s) to {} annotation(s)",
             tags.len(),
             annotations.len()
         );
-        self.api
-            .update_annotations(
-                &annotations
-                    .clone()
-                    .into_iter()
-                    .map(|mut a| {
-                        a.tags.extend_from_slice(&tags);
-                        a
-                    })
-                    .collect::<Vec<_>>(),
-            )
-            .await?;
-
+        tracing::info!(
+            annotations = updated.len(),
+            ?tags,
+            "adding tags in Hypothesis"
+        );
+        let updated = self.update_concurrently(&updated).await;
+        // Some annotations may have been tagged even if others failed
         self.sync().await?;
-
-        Ok(())
+        updated
     }
 
     async fn delete_tags(
@@ -259,26 +1102,78 @@ impl Gooseberry {
             println!("None of the selected annotations have any of those tags.");
             return Ok(());
         }
+        let updated: Vec<_> = annotations
+            .clone()
+            .into_iter()
+            .map(|mut a| {
+                a.tags.retain(|t| tags.iter().all(|tag| t != tag));
+                a
+            })
+            .collect();
+        if self.dry_run {
+            dry_run::print_updates(&annotations, &updated);
+            return Ok(());
+        }
         println!(
             "Deleting {} tag(s) from {} annotation(s)",
             tags.len(),
             annotations.len()
         );
-        self.api
-            .update_annotations(
-                &annotations
-                    .clone()
-                    .into_iter()
-                    .map(|mut a| {
-                        a.tags.retain(|t| tags.iter().all(|tag| t != tag));
-                        a
-                    })
-                    .collect::<Vec<_>>(),
-            )
-            .await?;
+        tracing::info!(
+            annotations = updated.len(),
+            ?tags,
+            "deleting tags in Hypothesis"
+        );
+        let updated = self.update_concurrently(&updated).await;
+        // Some annotations may have been untagged even if others failed
         self.sync().await?;
-        Ok(())
+        updated
     }
+
+    /// Replace tags with a single tag on every annotation that has any of them (also used to rename a tag)
+    ///
+    /// All affected annotations are updated in Hypothesis before the local trees are synced
+    pub async fn merge_tags(&self, tags: Vec<String>, into: String) -> color_eyre::Result<()> {
+        let mut annotations: Vec<Annotation> = Vec::new();
+        for tag in &tags {
+            let filters = Filters {
+                tags: vec![tag.to_owned()],
+                ..Filters::default()
+            };
+            for annotation in self.filter_annotations(filters, None).await? {
+                if annotations.iter().all(|a| a.id != annotation.id) {
+                    annotations.push(annotation);
+                }
+            }
+        }
+        if annotations.is_empty() {
+            println!("No annotations tagged with {}", tags.join(", "));
+            return Ok(());
+        }
+        let updated: Vec<_> = annotations
+            .clone()
+            .into_iter()
+            .map(|mut a| {
+                a.tags.retain(|t| !tags.contains(t) && t != &into);
+                a.tags.push(into.to_owned());
+                a
+            })
+            .collect();
+        if self.dry_run {
+            dry_run::print_updates(&annotations, &updated);
+            return Ok(());
+        }
+        println!(
+            "Merging {} tag(s) into {:?} on {} annotation(s)",
+            tags.len(),
+            into,
+            annotations.len()
+        );
+        let updated = self.update_concurrently(&updated).await;
+        self.sync().await?;
+        updated
+    }
+
     /// Tag a filtered set of annotations with given tags
     pub async fn tag(
         &self,
@@ -292,6 +1187,7 @@ impl Gooseberry {
         }
         let tags = match tags {
             Some(tags) => tags,
+            #[cfg(feature = "cli")]
             None => {
                 if delete {
                     self.search_tags(&annotations, false)?
@@ -299,6 +1195,8 @@ impl Gooseberry {
                     self.search_tags(&annotations, true)?
                 }
             }
+            #[cfg(not(feature = "cli"))]
+            None => Vec::new(),
         };
         if tags.is_empty() {
             println!("No tags selected");
@@ -313,6 +1211,78 @@ impl Gooseberry {
         Ok(())
     }
 
+    /// Edit the text of annotations in $EDITOR, one after the other
+    #[cfg(feature = "cli")]
+    pub async fn edit(&self, annotations: Vec<Annotation>) -> color_eyre::Result<()> {
+        let mut edited = Vec::new();
+        for mut annotation in annotations {
+            let text = crate::utils::external_editor_input(Some(&annotation.text), ".md")?
+                .trim()
+                .to_owned();
+            if text != annotation.text.trim() {
+                annotation.text = text;
+                edited.push(annotation);
+            }
+        }
+        if edited.is_empty() {
+            println!("No changes made");
+            return Ok(());
+        }
+        println!("Updating {} annotation(s)", edited.len());
+        let updated = self.update_concurrently(&edited).await;
+        self.sync().await?;
+        updated
+    }
+
+    /// Create a new annotation (or page note, if no quote is given)
+    pub async fn annotate(
+        &self,
+        uri: String,
+        quote: Option<String>,
+        text: Option<String>,
+        editor: bool,
+        tags: Vec<String>,
+        group: Option<String>,
+    ) -> color_eyre::Result<()> {
+        #[cfg(feature = "cli")]
+        let text = if editor {
+            crate::utils::external_editor_input(text.as_deref(), ".md")?
+                .trim()
+                .to_owned()
+        } else {
+            text.unwrap_or_default()
+        };
+        #[cfg(not(feature = "cli"))]
+        let text = if editor {
+            let error: color_eyre::Result<()> = Err(Apologize::EditorError.into());
+            return error.suggestion("Editing in $EDITOR needs gooseberry's `cli` feature");
+        } else {
+            text.unwrap_or_default()
+        };
+        let selector = match quote {
+            Some(quote) => vec![Selector::new_quote(&quote, "", "")],
+            None => Vec::new(),
+        };
+        let annotation = InputAnnotation::builder()
+            .uri(&uri)
+            .text(text)
+            .tags(tags.into_iter().filter(|t| !t.trim().is_empty()).collect())
+            .group(match group {
+                Some(group) => group,
+                None => self
+                    .config
+                    .hypothesis_group
+                    .clone()
+                    .expect("This should have been set by Config"),
+            })
+            .target(Target::builder().source(&uri).selector(selector).build()?)
+            .build()?;
+        let annotation = self.api.create_annotation(&annotation).await?;
+        println!("Created annotation {}", annotation.id);
+        self.sync().await?;
+        Ok(())
+    }
+
     /// Delete filtered annotations from gooseberry (by adding an ignore tag) or also from Hypothesis
     pub async fn delete(
         &self,
@@ -320,26 +1290,64 @@ impl Gooseberry {
         force: bool,
     ) -> color_eyre::Result<()> {
         let num_annotations = annotations.len();
+        if self.dry_run {
+            dry_run::print_deletes(&annotations);
+            return Ok(());
+        }
         if !annotations.is_empty()
             && (force
-                || Confirm::new()
-                    .with_prompt(&format!("Delete {} annotations?", num_annotations))
-                    .default(false)
-                    .interact()?)
+                || crate::utils::confirm(
+                    &format!("Delete {} annotations?", num_annotations),
+                    false,
+                )?)
         {
             let ids = annotations
                 .iter()
                 .map(|a| a.id.to_owned())
                 .collect::<Vec<_>>();
+            if num_annotations >= backup::BULK_DELETE {
+                self.backup_db("delete")?;
+            }
+            self.trash_annotations(&annotations)?;
             self.delete_annotations(&ids)?;
+            self.unindex_annotations(&ids)?;
+            tracing::info!(
+                annotations = ids.len(),
+                "deleting annotations in Hypothesis"
+            );
             self.api.delete_annotations(&ids).await?;
-            println!("{} annotations deleted", num_annotations);
+            println!(
+                "{} annotations deleted (undo with 'gooseberry restore <id>')",
+                num_annotations
+            );
         }
         Ok(())
     }
 
-    /// View optionally filtered annotations in the terminal
-    pub async fn view(&mut self, filters: Filters, id: Option<String>) -> color_eyre::Result<()> {
+    /// View optionally filtered annotations in the terminal, print them in a given format,
+    /// or copy them to the clipboard
+    #[cfg(feature = "cli")]
+    pub async fn view(
+        &mut self,
+        filters: Filters,
+        id: Option<String>,
+        format: Option<ViewFormat>,
+        clipboard: bool,
+        sort: SortOptions,
+    ) -> color_eyre::Result<()> {
+        if let Some(format) = format {
+            let annotations = match id {
+                Some(id) => vec![self
+                    .api
+                    .fetch_annotation(&id)
+                    .await
+                    .suggestion("Are you sure this is a valid and existing annotation ID?")?],
+                None => {
+                    self.sort_by_options(self.filter_annotations(filters, None).await?, sort)?
+                }
+            };
+            return self.print_annotations(annotations, format);
+        }
         if self.config.annotation_template.is_none() {
             self.config.set_annotation_template()?;
         }
@@ -350,73 +1358,238 @@ impl Gooseberry {
                 .fetch_annotation(&id)
                 .await
                 .suggestion("Are you sure this is a valid and existing annotation ID?")?;
-            let markdown = hbs.render(
-                "annotation",
-                &AnnotationTemplate::from_annotation(annotation),
-            )?;
+            if clipboard {
+                return self.copy(&[annotation], &hbs);
+            }
+            let markdown = hbs.render("annotation", &self.annotation_template(annotation)?)?;
             bat::PrettyPrinter::new()
                 .language("markdown")
+                .colored_output(dialoguer::console::colors_enabled())
                 .input_from_bytes(markdown.as_ref())
                 .print()
                 .unwrap();
             return Ok(());
         }
-        let annotations: Vec<Annotation> = self
-            .filter_annotations(filters, None)
-            .await?
-            .into_iter()
-            .collect();
+        let annotations =
+            self.sort_by_options(self.filter_annotations(filters, None).await?, sort)?;
+        if clipboard {
+            return self.copy(&annotations, &hbs);
+        }
         let inputs: Vec<_> = annotations
             .into_iter()
-            .map(|annotation| {
-                hbs.render(
-                    "annotation",
-                    &AnnotationTemplate::from_annotation(annotation),
-                )
-            })
-            .collect::<Result<_, _>>()?;
+            .map(|annotation| Ok(hbs.render("annotation", &self.annotation_template(annotation)?)?))
+            .collect::<color_eyre::Result<_>>()?;
         bat::PrettyPrinter::new()
             .language("markdown")
+            .colored_output(dialoguer::console::colors_enabled())
             .inputs(inputs.iter().map(|i| bat::Input::from_bytes(i.as_bytes())))
             .print()
             .unwrap();
         Ok(())
     }
 
-    pub fn uri(&self, annotations: Vec<Annotation>, ids: Vec<String>) -> color_eyre::Result<()> {
-        let mut annotations = annotations;
-        if !ids.is_empty() {
-            annotations.retain(|a| ids.contains(&a.id));
+    /// Opens annotations in context in the default browser, or prints their links (one per line).
+    /// Asks first before opening more than `OPEN_CONFIRM` at once, unless forced
+    #[cfg(feature = "cli")]
+    pub fn open(
+        &self,
+        annotations: &[Annotation],
+        print: bool,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        if annotations.is_empty() {
+            return Err(Apologize::NoMatches.into());
         }
-        let uris: HashSet<_> = annotations.into_iter().map(|a| a.uri).collect();
-        for uri in uris {
-            println!("{}", uri);
+        if print {
+            for annotation in annotations {
+                println!("{}", crate::utils::annotation_link(annotation));
+            }
+            return Ok(());
         }
+        if !(force
+            || annotations.len() <= OPEN_CONFIRM
+            || crate::utils::confirm(
+                &format!("Open {} annotations in the browser?", annotations.len()),
+                false,
+            )?)
+        {
+            let error: color_eyre::Result<()> = Err(Apologize::DoingNothing.into());
+            return error.suggestion("Press Y next time!");
+        }
+        self.open_in_browser(annotations)
+    }
+
+    /// Prints the URIs of (optionally filtered) annotations, see `uri_list`
+    pub fn uri(
+        &self,
+        annotations: Vec<Annotation>,
+        ids: Vec<String>,
+        options: UriOptions,
+    ) -> color_eyre::Result<()> {
+        print!("{}", self.uri_list(annotations, ids, options)?);
         Ok(())
     }
 
-    /// Removes all `sled` trees
-    /// Deletes everything in the `db_dir`
-    pub fn clear(&self, force: bool) -> color_eyre::Result<()> {
-        if force
-            || Confirm::new()
-                .with_prompt("Clear all gooseberry data?")
-                .default(false)
-                .interact()?
-        {
-            for path in fs::read_dir(&self.config.db_dir)? {
-                let path = path?.path();
-                if path.is_dir() {
-                    fs::remove_dir_all(path)?;
+    /// The URIs of (optionally filtered) annotations, one per line and sorted by URI or domain,
+    /// optionally with page titles or as markdown links and grouped by tag
+    pub fn uri_list(
+        &self,
+        annotations: Vec<Annotation>,
+        ids: Vec<String>,
+        options: UriOptions,
+    ) -> color_eyre::Result<String> {
+        let mut annotations = annotations;
+        if !ids.is_empty() {
+            annotations.retain(|a| ids.contains(&a.id));
+        }
+        let titles = if options.titles || options.markdown {
+            self.uri_titles(annotations.clone())?
+        } else {
+            BTreeMap::new()
+        };
+        let mut list = String::new();
+        // Tag: URIs, with a single group (without a tag) unless grouping by tag
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for annotation in annotations {
+            let tags = if !options.by_tag {
+                vec![String::new()]
+            } else if annotation.tags.is_empty() {
+                vec![crate::EMPTY_TAG.to_owned()]
+            } else {
+                annotation.tags
+            };
+            for tag in tags {
+                groups
+                    .entry(tag)
+                    .or_default()
+                    .push(annotation.uri.to_owned());
+            }
+        }
+        for (i, (tag, mut uris)) in groups.into_iter().enumerate() {
+            if options.by_domain {
+                uris.sort_by_cached_key(|uri| (stats::domain(uri), uri.to_owned()));
+            } else {
+                uris.sort();
+            }
+            uris.dedup();
+            if options.dedup {
+                let mut seen = HashSet::new();
+                uris.retain(|uri| seen.insert(crate::utils::dedup_uri(uri)));
+            }
+            if options.by_tag && options.markdown {
+                if i > 0 {
+                    list.push('\n');
+                }
+                list.push_str(&format!("## {}\n\n", tag));
+            }
+            for uri in uris {
+                let title = titles.get(&uri).cloned().flatten();
+                let line = if options.markdown {
+                    format!(
+                        "- {}",
+                        crate::utils::markdown_link(title.as_deref().unwrap_or(&uri), &uri)
+                    )
+                } else if options.titles {
+                    format!("{}\t{}", uri, title.unwrap_or_default())
                 } else {
-                    fs::remove_file(path)?;
+                    uri
+                };
+                if options.by_tag && !options.markdown {
+                    list.push_str(&format!("{}\t{}\n", tag, line));
+                } else {
+                    list.push_str(&format!("{}\n", line));
                 }
             }
-            self.reset_sync_time()?;
-            Ok(())
+        }
+        Ok(list)
+    }
+
+    /// Deletes everything in the `db_dir` if `db` is set,
+    /// and the knowledge base files written by `make` if `kb` is set
+    pub fn clear(&self, db: bool, kb: bool, force: bool) -> color_eyre::Result<()> {
+        let kb_files = if kb {
+            self.kb_file_paths()?
         } else {
+            Vec::new()
+        };
+        if !db && kb_files.is_empty() {
+            println!("No knowledge base files to delete");
+            return Ok(());
+        }
+        if self.dry_run {
+            if db {
+                println!(
+                    "Would delete all gooseberry data in {:?}: {} annotation(s) and {} in the trash (dry run)",
+                    self.config.db_dir,
+                    self.annotation_to_tags()?.len()?,
+                    self.trash()?.len()?
+                );
+            }
+            if !kb_files.is_empty() {
+                println!(
+                    "Would delete {} knowledge base file(s) in {:?} (dry run)",
+                    kb_files.len(),
+                    self.config.kb_dir.as_ref().unwrap()
+                );
+            }
+            return Ok(());
+        }
+        let question = match (db, kb_files.len()) {
+            (true, 0) => "Clear all gooseberry data?".to_owned(),
+            (true, files) => format!(
+                "Clear all gooseberry data, and delete {} knowledge base file(s)?",
+                files
+            ),
+            (false, files) => format!("Delete {} knowledge base file(s)?", files),
+        };
+        if !force && !crate::utils::confirm(&question, false)? {
+            let error: color_eyre::Result<()> = Err(Apologize::DoingNothing.into());
+            return error.suggestion("Press Y next time!");
+        }
+        if !kb_files.is_empty() {
+            self.clear_kb(&kb_files)?;
+            println!("Deleted {} knowledge base file(s)", kb_files.len());
+        }
+        if db {
+            self.backup_db("clear")?;
+            encryption::remove_contents(self.db_dir())?;
+            self.reset_sync_time()?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the annotations with a tag from the database, keeping them in Hypothesis,
+    /// e.g. to index them again. The next sync fetches every annotation again
+    pub fn forget_tag(&self, tag: &str, force: bool) -> color_eyre::Result<()> {
+        let ids = self
+            .get_tagged_annotations(tag)
+            .suggestion("Check the tag with 'gooseberry tag list'")?;
+        if self.dry_run {
+            println!(
+                "Would forget {} annotation(s) tagged {:?} (dry run)",
+                ids.len(),
+                tag
+            );
+            return Ok(());
+        }
+        let question = format!(
+            "Forget the {} annotation(s) tagged {:?} from the database?",
+            ids.len(),
+            tag
+        );
+        if !force && !crate::utils::confirm(&question, false)? {
             let error: color_eyre::Result<()> = Err(Apologize::DoingNothing.into());
-            error.suggestion("Press Y next time!")
+            return error.suggestion("Press Y next time!");
         }
+        self.backup_db("clear")?;
+        self.delete_annotations(&ids)?;
+        self.unindex_annotations(&ids)?;
+        self.reset_sync_time()?;
+        println!(
+            "Forgot {} annotation(s) tagged {:?}, the next sync fetches them again",
+            ids.len(),
+            tag
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,93 @@
+use dialoguer::console::{style, Color};
+
+/// Finds the end of an inline span starting after `open`, returning the span and the rest of the line
+fn span<'a>(text: &'a str, open: &str, close: &str) -> Option<(&'a str, &'a str)> {
+    let inner = text.strip_prefix(open)?;
+    let end = inner.find(close)?;
+    if end == 0 {
+        return None;
+    }
+    Some((&inner[..end], &inner[end + close.len()..]))
+}
+
+/// Styles bold, italics, code, links, and wikilinks in a line of markdown
+fn render_inline(line: &str) -> String {
+    let mut rendered = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if let Some((inner, after)) = span(rest, "**", "**") {
+            rendered.push_str(&style(inner).bold().to_string());
+            rest = after;
+        } else if let Some((inner, after)) = span(rest, "`", "`") {
+            rendered.push_str(&style(inner).fg(Color::Yellow).to_string());
+            rest = after;
+        } else if let Some((inner, after)) = span(rest, "[[", "]]") {
+            rendered.push_str(&style(inner).fg(Color::Cyan).to_string());
+            rest = after;
+        } else if let Some((link, after)) = span(rest, "[", ")") {
+            match link.split_once("](") {
+                Some((text, url)) => {
+                    rendered.push_str(&format!(
+                        "{} {}",
+                        style(text).underlined(),
+                        style(format!("({})", url)).fg(Color::Cyan).dim()
+                    ));
+                    rest = after;
+                }
+                None => {
+                    rendered.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        } else if let Some((inner, after)) =
+            span(rest, "*", "*").filter(|(inner, _)| !inner.starts_with(' '))
+        {
+            rendered.push_str(&style(inner).italic().to_string());
+            rest = after;
+        } else {
+            rendered.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    rendered
+}
+
+/// Renders markdown (as made by the annotation template) with terminal colors for the search window preview
+pub(crate) fn markdown_to_ansi(markdown: &str) -> String {
+    let mut rendered = Vec::new();
+    let mut in_code = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            rendered.push(style(line).fg(Color::Yellow).to_string());
+        } else if trimmed.starts_with('#') {
+            let heading = trimmed.trim_start_matches('#');
+            if heading.starts_with(' ') || heading.is_empty() {
+                rendered.push(style(heading.trim()).fg(Color::Magenta).bold().to_string());
+            } else {
+                rendered.push(render_inline(line));
+            }
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            rendered.push(format!(
+                "{} {}",
+                style("│").dim(),
+                style(render_inline(quote.trim_start())).italic()
+            ));
+        } else if trimmed == "---" || trimmed == "***" {
+            rendered.push(style("─".repeat(40)).dim().to_string());
+        } else if let Some(item) = trimmed
+            .strip_prefix("- ")
+            .or_else(|| trimmed.strip_prefix("* "))
+        {
+            let indent = &line[..line.len() - trimmed.len()];
+            rendered.push(format!("{}• {}", indent, render_inline(item)));
+        } else {
+            rendered.push(render_inline(line));
+        }
+    }
+    rendered.join("\n")
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use color_eyre::Help;
+use handlebars::Handlebars;
+
+use crate::configuration::KbFormat;
+use crate::errors::Apologize;
+use crate::gooseberry::dry_run;
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::storage::Tree;
+use crate::gooseberry::Gooseberry;
+
+/// Comment marking the start of an annotation, followed by its ID
+const ID_MARKER: &str = "gooseberry id:";
+/// Comment with an annotation's comma-separated tags
+const TAGS_MARKER: &str = "gooseberry tags:";
+/// Comments around an annotation's text
+const TEXT_START: &str = "gooseberry text";
+const TEXT_END: &str = "gooseberry /text";
+
+/// Length of the hash at the start of each value in the annotation hashes tree
+const HASH_LENGTH: usize = 8;
+
+/// Hash of an annotation's text and tags as rendered in the knowledge base
+fn edit_hash(text: &str, tags: &[String]) -> [u8; HASH_LENGTH] {
+    crate::utils::stable_hash(
+        std::iter::once(text.trim().as_bytes()).chain(tags.iter().map(|tag| tag.as_bytes())),
+    )
+}
+
+/// Value stored in the annotation hashes tree: the render hash followed by the annotation's update time
+fn hash_value(text: &str, tags: &[String], updated: &DateTime<Utc>) -> Vec<u8> {
+    let mut value = edit_hash(text, tags).to_vec();
+    value.extend_from_slice(updated.to_rfc3339().as_bytes());
+    value
+}
+
+/// Tags are written without `[[wikilink]]` brackets
+fn clean_tag(tag: &str) -> String {
+    tag.trim()
+        .trim_start_matches("[[")
+        .trim_end_matches("]]")
+        .to_owned()
+}
+
+/// Text and tags of an annotation as found in a knowledge base file
+#[derive(Debug, Clone, PartialEq)]
+struct Edit {
+    text: String,
+    tags: Vec<String>,
+}
+
+/// Finds the marked annotations in the contents of a knowledge base file
+fn parse_marked(contents: &str, format: KbFormat) -> Vec<(String, Edit)> {
+    let mut marked = Vec::new();
+    let mut current: Option<(String, Vec<String>)> = None;
+    let mut text: Option<Vec<&str>> = None;
+    for line in contents.lines() {
+        match format.uncomment(line) {
+            Some(comment) if comment.starts_with(ID_MARKER) => {
+                current = Some((comment[ID_MARKER.len()..].trim().to_owned(), Vec::new()));
+                text = None;
+            }
+            Some(comment) if comment.starts_with(TAGS_MARKER) => {
+                if let Some((_, tags)) = current.as_mut() {
+                    *tags = comment[TAGS_MARKER.len()..]
+                        .split(',')
+                        .map(clean_tag)
+                        .filter(|tag| !tag.is_empty())
+                        .collect();
+                }
+            }
+            Some(comment) if comment == TEXT_START => text = Some(Vec::new()),
+            Some(comment) if comment == TEXT_END => {
+                if let (Some((id, tags)), Some(lines)) = (current.take(), text.take()) {
+                    marked.push((
+                        id,
+                        Edit {
+                            text: lines.join("\n").trim().to_owned(),
+                            tags,
+                        },
+                    ));
+                }
+            }
+            _ => {
+                if let Some(lines) = text.as_mut() {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+    marked
+}
+
+/// Marks annotations in knowledge base files so that edits to them can be pushed back to Hypothesis
+pub(crate) struct EditMarker {
+    format: KbFormat,
+    /// Tree storing annotation ID: render hash and update time
+    hashes: Tree,
+}
+
+impl EditMarker {
+    pub(crate) fn new(format: KbFormat, hashes: Tree) -> Self {
+        EditMarker { format, hashes }
+    }
+
+    /// Renders an annotation preceded by comments with its ID and tags and with its text between comments,
+    /// storing a hash of the rendered text and tags
+    pub(crate) fn render(
+        &self,
+        hbs: &Handlebars,
+        template: &str,
+        annotation: &AnnotationTemplate,
+    ) -> color_eyre::Result<String> {
+        let tags: Vec<_> = annotation
+            .annotation
+            .tags
+            .iter()
+            .map(|tag| clean_tag(tag))
+            .collect();
+        let text = annotation.annotation.text.trim();
+        self.hashes.insert(
+            annotation.annotation.id.as_bytes(),
+            hash_value(text, &tags, &annotation.annotation.updated),
+        )?;
+
+        let mut marked = annotation.clone();
+        marked.annotation.text = format!(
+            "{}\n{}\n{}",
+            self.format.comment(TEXT_START),
+            text,
+            self.format.comment(TEXT_END)
+        );
+        Ok(format!(
+            "{}\n{}\n{}",
+            self.format
+                .comment(&format!("{} {}", ID_MARKER, annotation.annotation.id)),
+            self.format
+                .comment(&format!("{} {}", TAGS_MARKER, tags.join(", "))),
+            hbs.render(template, &marked)?
+        ))
+    }
+}
+
+/// ## Push
+/// Two-way sync: edits to annotation text and tags made in knowledge base files are pushed back to Hypothesis
+impl Gooseberry {
+    /// Updates the annotations whose text or tags were edited in the knowledge base since the last `make`.
+    /// Annotations which were also changed on Hypothesis since then,
+    /// or which were edited differently in two files, are skipped with a warning.
+    pub async fn push(&self) -> color_eyre::Result<()> {
+        let format = match self.config.editable_format() {
+            Some(format) => format,
+            None => {
+                return Err(Apologize::ConfigError {
+                    message: "Knowledge base annotations aren't marked as editable".into(),
+                })
+                .suggestion(
+                    "Set editable = true using 'gooseberry config kb editable' and run 'gooseberry make'",
+                )
+            }
+        };
+        let hashes = self.annotation_hashes()?;
+        let mut edits: HashMap<String, Edit> = HashMap::new();
+        let mut conflicts = HashSet::new();
+        for key in self.kb_files()?.iter().keys() {
+            let path = PathBuf::from(std::str::from_utf8(&key?)?);
+            if !path.exists() {
+                continue;
+            }
+            for (id, edit) in parse_marked(&fs::read_to_string(&path)?, format) {
+                let unchanged = match hashes.get(&id)? {
+                    Some(stored) => {
+                        stored.len() < HASH_LENGTH
+                            || stored[..HASH_LENGTH] == edit_hash(&edit.text, &edit.tags)
+                    }
+                    // Not rendered by gooseberry
+                    None => true,
+                };
+                if unchanged {
+                    continue;
+                }
+                match edits.get(&id) {
+                    Some(other) if other != &edit => {
+                        conflicts.insert(id);
+                    }
+                    _ => {
+                        edits.insert(id, edit);
+                    }
+                }
+            }
+        }
+
+        let mut unedited = Vec::with_capacity(edits.len());
+        let mut edited = Vec::with_capacity(edits.len());
+        for (id, edit) in edits {
+            if conflicts.contains(&id) {
+                eprintln!("Skipping {}: edited differently in different files", id);
+                continue;
+            }
+            let mut annotation = self
+                .api
+                .fetch_annotation(&id)
+                .await
+                .suggestion("Was this annotation deleted on Hypothesis?")?;
+            let rendered_update = hashes
+                .get(&id)?
+                .map(|stored| stored[HASH_LENGTH..].to_vec())
+                .unwrap_or_default();
+            if annotation.updated.to_rfc3339().as_bytes() != &rendered_update[..] {
+                eprintln!(
+                    "Skipping {}: also changed on Hypothesis since the last make",
+                    id
+                );
+                continue;
+            }
+            unedited.push(annotation.clone());
+            annotation.text = edit.text;
+            annotation.tags = edit.tags;
+            edited.push(annotation);
+        }
+        if edited.is_empty() {
+            println!("No edits to push");
+            return Ok(());
+        }
+        if self.dry_run {
+            dry_run::print_updates(&unedited, &edited);
+            return Ok(());
+        }
+        println!("Pushing {} edited annotation(s)", edited.len());
+        for annotation in self.api.update_annotations(&edited).await? {
+            // So that the same edits aren't pushed again before the next make
+            hashes.insert(
+                annotation.id.as_bytes(),
+                hash_value(&annotation.text, &annotation.tags, &annotation.updated),
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use color_eyre::Help;
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::fetch::{is_transient, retry_delay, MAX_RETRIES};
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::titles::http_client;
+use crate::gooseberry::Gooseberry;
+
+/// Highlight creation endpoint of the Readwise API
+const READWISE_HIGHLIGHTS: &str = "https://readwise.io/api/v2/highlights/";
+/// Environment variable with the Readwise access token, used instead of `readwise_token`
+pub const READWISE_TOKEN_VARIABLE: &str = "READWISE_TOKEN";
+/// Number of highlights sent in one request
+const BATCH_SIZE: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest highlight text and note Readwise accepts
+const MAX_TEXT_LENGTH: usize = 8191;
+/// Longest document title Readwise accepts
+const MAX_TITLE_LENGTH: usize = 511;
+
+/// A highlight in the Readwise API's format
+#[derive(Debug, Serialize)]
+struct ReadwiseHighlight {
+    text: String,
+    title: String,
+    source_url: String,
+    source_type: &'static str,
+    category: &'static str,
+    /// Annotation text, starting with the tags as Readwise inline tags (`.tag`)
+    note: String,
+    highlighted_at: String,
+    /// Link to the annotation in context
+    highlight_url: String,
+}
+
+/// Body of a highlight creation request
+#[derive(Debug, Serialize)]
+struct ReadwiseRequest<'a> {
+    highlights: &'a [ReadwiseHighlight],
+}
+
+/// Truncates text to at most `length` characters
+fn truncate(text: &str, length: usize) -> String {
+    text.chars().take(length).collect()
+}
+
+impl From<AnnotationTemplate> for ReadwiseHighlight {
+    fn from(annotation: AnnotationTemplate) -> Self {
+        // Readwise highlights need text, so page notes become highlights of their text
+        let quote = annotation.highlight.join("\n");
+        let (text, note) = if quote.trim().is_empty() {
+            (annotation.annotation.text.to_owned(), String::new())
+        } else {
+            (quote, annotation.annotation.text.to_owned())
+        };
+        // Readwise tags can't have spaces
+        let tags: Vec<_> = annotation
+            .annotation
+            .tags
+            .iter()
+            .map(|tag| format!(".{}", tag.replace(' ', "_")))
+            .collect();
+        let note = if tags.is_empty() {
+            note
+        } else {
+            format!("{}\n{}", tags.join(" "), note)
+        };
+        ReadwiseHighlight {
+            text: truncate(&text, MAX_TEXT_LENGTH),
+            title: truncate(&annotation.title, MAX_TITLE_LENGTH),
+            source_url: annotation.annotation.uri.to_owned(),
+            source_type: "gooseberry",
+            category: "articles",
+            note: truncate(note.trim(), MAX_TEXT_LENGTH),
+            highlighted_at: annotation.annotation.created.to_rfc3339(),
+            highlight_url: annotation.incontext,
+        }
+    }
+}
+
+/// Sends a batch of highlights, retrying rate-limited requests and server errors
+async fn send_highlights(
+    client: &reqwest::Client,
+    token: &str,
+    highlights: &[ReadwiseHighlight],
+) -> color_eyre::Result<()> {
+    let body = serde_json::to_vec(&ReadwiseRequest { highlights })?;
+    let mut attempt = 0;
+    loop {
+        let response = client
+            .post(READWISE_HIGHLIGHTS)
+            .header(reqwest::header::AUTHORIZATION, format!("Token {}", token))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        if !is_transient(response.status()) || attempt >= MAX_RETRIES {
+            return Err(Apologize::ReadwiseError {
+                message: format!(
+                    "The Readwise API returned {}: {}",
+                    response.status(),
+                    response.text().await.unwrap_or_default()
+                ),
+            }
+            .into());
+        }
+        tokio::time::sleep(retry_delay(Some(&response), attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// ## Readwise
+/// Exporting highlights to Readwise, keeping track of the annotations already sent
+impl Gooseberry {
+    /// Readwise access token, from the environment or the config
+    fn readwise_token(&self) -> color_eyre::Result<String> {
+        std::env::var(READWISE_TOKEN_VARIABLE)
+            .ok()
+            .or_else(|| self.config.readwise_token.clone())
+            .ok_or(Apologize::ConfigError {
+                message: "No Readwise access token".into(),
+            })
+            .suggestion(format!(
+                "Get one from https://readwise.io/access_token and set readwise_token in the config or {}",
+                READWISE_TOKEN_VARIABLE
+            ))
+    }
+
+    /// Sends (optionally filtered) annotations to Readwise as highlights, with their notes, tags,
+    /// source URL, and title. Only annotations which are new or were updated since they were last sent are sent,
+    /// and each batch is recorded as soon as Readwise accepts it
+    pub async fn export_readwise(&self, annotations: Vec<Annotation>) -> color_eyre::Result<()> {
+        let token = self.readwise_token()?;
+        let exported = self.annotation_to_readwise()?;
+        let total = annotations.len();
+        let mut to_send = Vec::new();
+        for annotation in annotations {
+            let updated = annotation.updated.to_rfc3339();
+            if exported.get(annotation.id.as_bytes())?.as_deref() != Some(updated.as_bytes()) {
+                to_send.push(annotation);
+            }
+        }
+        let client = http_client(REQUEST_TIMEOUT)?;
+        for batch in to_send.chunks(BATCH_SIZE) {
+            let highlights = batch
+                .iter()
+                .map(|a| Ok(self.annotation_template(a.clone())?.into()))
+                .collect::<color_eyre::Result<Vec<ReadwiseHighlight>>>()?;
+            send_highlights(&client, &token, &highlights).await?;
+            for annotation in batch {
+                exported.insert(
+                    annotation.id.as_bytes(),
+                    annotation.updated.to_rfc3339().as_bytes(),
+                )?;
+            }
+            exported.flush()?;
+        }
+        println!(
+            "Sent {} annotation(s) to Readwise ({} already up to date)",
+            to_send.len(),
+            total - to_send.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use dialoguer::console::style;
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::preview::markdown_to_ansi;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// What can be done with the annotation being reviewed
+const ACTIONS: [&str; 5] = ["Add tags", "Skip", "Delete", "Open in browser", "Quit"];
+
+/// ## Review
+/// Triage of annotations one at a time
+impl Gooseberry {
+    /// Goes through annotations one after the other, showing each rendered with the annotation template
+    /// and asking whether to add tags to it (picked with fuzzy search, or typed in), skip it, or delete it.
+    /// Only untagged annotations are reviewed unless `all` is set
+    pub async fn review(
+        &mut self,
+        annotations: Vec<Annotation>,
+        all: bool,
+    ) -> color_eyre::Result<()> {
+        let annotations: Vec<_> = annotations
+            .into_iter()
+            .filter(|a| all || a.tags.iter().all(|tag| tag.trim().is_empty()))
+            .collect();
+        if annotations.is_empty() {
+            println!("No annotations to review");
+            return Ok(());
+        }
+        if self.config.annotation_template.is_none() {
+            self.config.set_annotation_template()?;
+        }
+        let hbs = self.get_handlebars()?;
+        let (mut tagged, mut deleted, mut skipped) = (0, 0, 0);
+        for (index, annotation) in annotations.iter().enumerate() {
+            let template = self.annotation_template(annotation.clone())?;
+            println!(
+                "\n{}\n{}",
+                style(format!(
+                    "[{}/{}] {}",
+                    index + 1,
+                    annotations.len(),
+                    annotation.uri
+                ))
+                .bold(),
+                markdown_to_ansi(&hbs.render("annotation", &template)?)
+            );
+            loop {
+                match utils::select("Action", &ACTIONS)? {
+                    Some(0) => {
+                        let tags: Vec<_> = self
+                            .search_tags(std::slice::from_ref(annotation), true)?
+                            .into_iter()
+                            .filter(|tag| !tag.is_empty())
+                            .collect();
+                        if tags.is_empty() {
+                            println!("No tags selected");
+                            continue;
+                        }
+                        self.tag(vec![annotation.clone()], false, Some(tags))
+                            .await?;
+                        tagged += 1;
+                    }
+                    Some(1) => skipped += 1,
+                    Some(2) => {
+                        if !self.dry_run && !utils::confirm("Delete this annotation?", false)? {
+                            continue;
+                        }
+                        self.delete(vec![annotation.clone()], true).await?;
+                        deleted += 1;
+                    }
+                    Some(3) => {
+                        self.open_in_browser(std::slice::from_ref(annotation))?;
+                        continue;
+                    }
+                    _ => {
+                        println!(
+                            "Reviewed {} of {} annotation(s): {} tagged, {} deleted, {} skipped",
+                            index,
+                            annotations.len(),
+                            tagged,
+                            deleted,
+                            skipped
+                        );
+                        return Ok(());
+                    }
+                }
+                break;
+            }
+        }
+        println!(
+            "Reviewed {} annotation(s): {} tagged, {} deleted, {} skipped",
+            annotations.len(),
+            tagged,
+            deleted,
+            skipped
+        );
+        Ok(())
+    }
+}
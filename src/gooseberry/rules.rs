@@ -0,0 +1,75 @@
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::filter::Filter;
+use crate::gooseberry::Gooseberry;
+
+/// Tags of the rules matching an annotation, which it doesn't have yet
+fn rule_tags(rules: &[(String, Filter)], annotation: &Annotation) -> Vec<String> {
+    rules
+        .iter()
+        .filter(|(tag, filter)| !annotation.tags.contains(tag) && filter.matches(annotation))
+        .map(|(tag, _)| tag.to_owned())
+        .collect()
+}
+
+/// ## Rules
+/// Tagging rules from the `[rules]` config table, applied to new and updated annotations during sync
+impl Gooseberry {
+    /// Adds the tags of matching rules to annotations and updates them on Hypothesis,
+    /// leaving out those changed there since they were fetched (see `skip_conflicts_since`).
+    /// Returns the number of annotations tagged
+    pub(crate) async fn apply_rules(
+        &self,
+        annotations: &mut [Annotation],
+    ) -> color_eyre::Result<usize> {
+        let rules = self.config.get_rules()?;
+        if rules.is_empty() {
+            return Ok(0);
+        }
+        let mut tagged = Vec::new();
+        for annotation in annotations.iter() {
+            let tags = rule_tags(&rules, annotation);
+            if !tags.is_empty() {
+                let mut annotation = annotation.clone();
+                annotation.tags.extend(tags);
+                tagged.push(annotation);
+            }
+        }
+        if tagged.is_empty() {
+            return Ok(0);
+        }
+        // only changes made in Hypothesis since they were fetched for this sync would be overwritten
+        let fetched = tagged.iter().map(|a| (a.id.clone(), a.updated)).collect();
+        let tagged = self.skip_conflicts_since(&tagged, &fetched).await?;
+        self.send_updates(&tagged).await?;
+        for annotation in annotations.iter_mut() {
+            if let Some(tagged) = tagged.iter().find(|a| a.id == annotation.id) {
+                annotation.tags = tagged.tags.clone();
+            }
+        }
+        Ok(tagged.len())
+    }
+
+    /// Prints the tags the rules would add to the annotations added or updated since the last sync,
+    /// without changing anything
+    pub async fn preview_rules(&self) -> color_eyre::Result<()> {
+        let rules = self.config.get_rules()?;
+        let mut tagged = 0;
+        for group in self.config.groups() {
+            for annotation in self.fetch_since_sync(&group).await? {
+                let tags = rule_tags(&rules, &annotation);
+                if !tags.is_empty() {
+                    println!(
+                        "{} ({}): +{}",
+                        annotation.id,
+                        annotation.uri,
+                        tags.join(", +")
+                    );
+                    tagged += 1;
+                }
+            }
+        }
+        println!("Rules would tag {} annotation(s)", tagged);
+        Ok(())
+    }
+}
@@ -1,29 +1,71 @@
 use std::borrow::Cow;
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, OnceLock};
 
+use color_eyre::Help;
 use dialoguer::console::style;
+use handlebars::Handlebars;
 use hypothesis::annotations::Annotation;
-use skim::prelude::{unbounded, Key, SkimOptionsBuilder};
+use skim::prelude::{unbounded, Event, Key, SkimOptionsBuilder};
 use skim::{
     AnsiString, DisplayContext, ItemPreview, Matches, PreviewContext, Skim, SkimItem,
     SkimItemReceiver, SkimItemSender,
 };
 
+use crate::configuration::SearchAction;
 use crate::errors::Apologize;
+use crate::gooseberry::cli::{Filters, SortField, SortOptions, UriOptions};
+use crate::gooseberry::filter::{self, Filter};
+use crate::gooseberry::helpers::obsidian_uri;
 use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::meta;
+use crate::gooseberry::preview::markdown_to_ansi;
 use crate::gooseberry::Gooseberry;
 use crate::utils;
 
 /// searchable annotation information
-#[derive(Debug)]
 pub struct SearchAnnotation {
     /// Annotation ID
     id: String,
     /// Highlighted text, quote, URL, and tag information on a single line
     highlight: String,
-    /// text, quote, URL, and tag information in markdown format
-    markdown: String,
+    /// Data for the annotation template, rendered the first time the annotation is previewed
+    template: AnnotationTemplate,
+    hbs: Arc<Handlebars<'static>>,
+    /// text, quote, URL, and tag information in markdown format, once rendered
+    markdown: OnceLock<String>,
+    /// External command the markdown is piped to for the preview, instead of rendering it in-process
+    preview_command: Option<String>,
+}
+
+impl SearchAnnotation {
+    fn new(
+        highlight: String,
+        template: AnnotationTemplate,
+        hbs: Arc<Handlebars<'static>>,
+        preview_command: Option<String>,
+    ) -> Self {
+        Self {
+            id: template.annotation.id.to_owned(),
+            highlight,
+            template,
+            hbs,
+            markdown: OnceLock::new(),
+            preview_command,
+        }
+    }
+
+    /// The annotation rendered with the annotation template, rendered once and then cached
+    fn markdown(&self) -> &str {
+        self.markdown.get_or_init(|| {
+            self.hbs
+                .render("annotation", &self.template)
+                .unwrap_or_else(|error| format!("Couldn't render the annotation: {}", error))
+        })
+    }
 }
 
 impl<'a> SkimItem for SearchAnnotation {
@@ -55,115 +97,502 @@ impl<'a> SkimItem for SearchAnnotation {
         text
     }
 
-    fn preview(&self, _context: PreviewContext) -> ItemPreview {
-        ItemPreview::Command(format!(
-            "echo \"{}\" | bat -l markdown --color=always -p",
-            self.markdown
-        ))
+    fn preview(&self, context: PreviewContext) -> ItemPreview {
+        match &self.preview_command {
+            Some(command) => ItemPreview::AnsiText(
+                run_preview_command(command, self.markdown(), &context)
+                    .unwrap_or_else(|error| format!("{:?} failed: {}", command, error)),
+            ),
+            None => ItemPreview::AnsiText(markdown_to_ansi(self.markdown())),
+        }
+    }
+}
+
+/// Placeholder in `preview_command` for the path of a file with the markdown
+const PREVIEW_PATH: &str = "{}";
+
+/// Output of the preview command given the markdown, with the size of the preview window in `COLUMNS` and `LINES`.
+/// The markdown is in a temporary file whose (quoted) path replaces `{}` in the command, or on stdin without `{}`:
+/// annotation text never goes through the shell, so quotes, backticks, `$`, and `$(...)` in it aren't interpreted
+fn run_preview_command(
+    command: &str,
+    markdown: &str,
+    context: &PreviewContext,
+) -> std::io::Result<String> {
+    let file = if command.contains(PREVIEW_PATH) {
+        let mut file = tempfile::Builder::new()
+            .prefix("gooseberry-preview-")
+            .suffix(".md")
+            .tempfile()?;
+        file.write_all(markdown.as_bytes())?;
+        file.flush()?;
+        Some(file)
+    } else {
+        None
+    };
+    let (command, stdin) = match &file {
+        Some(file) => (
+            command.replace(
+                PREVIEW_PATH,
+                &utils::shell_quote(&file.path().to_string_lossy()),
+            ),
+            Stdio::null(),
+        ),
+        None => (command.to_owned(), Stdio::piped()),
+    };
+    let mut child = utils::shell_command(&command)
+        .env("COLUMNS", context.width.to_string())
+        .env("LINES", context.height.to_string())
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Written from another thread, as the command may fill its stdout before reading all of stdin
+    let writer = child.stdin.take().map(|mut stdin| {
+        let markdown = markdown.to_owned();
+        std::thread::spawn(move || stdin.write_all(markdown.as_bytes()))
+    });
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        // a command that stops reading early (e.g. `head`) closes the pipe
+        let _ = writer.join();
+    }
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(std::io::Error::other(if stderr.trim().is_empty() {
+            output.status.to_string()
+        } else {
+            stderr.trim().to_owned()
+        }))
+    }
+}
+
+/// How a search window was closed
+pub(crate) enum SearchOutcome {
+    /// With Esc or Ctrl-C
+    Aborted,
+    /// With an action key (or none, e.g. from the preview) and the selected annotations
+    Selected(Vec<Annotation>, Option<SearchAction>),
+    /// With the refine key, to narrow the annotations down with a filter expression
+    Refine,
+}
+
+/// Color scheme of the search windows: black and white with colored output off
+/// (they're drawn on the terminal like prompts, so they follow stderr)
+fn skim_color() -> Option<&'static str> {
+    if !dialoguer::console::colors_enabled_stderr() {
+        Some("bw")
+    } else {
+        None
     }
 }
 
 /// ## Search
 /// `skim` search window functions
 impl Gooseberry {
-    /// Makes a skim search window for given annotations
-    pub async fn search(
+    /// Opens search windows on the annotations matching the filters (and the full-text `query`, if given)
+    /// until one is closed with Esc: after each action, the annotations are fetched again (so that changed tags
+    /// and deleted annotations show up) and the search window comes back with the same search text.
+    /// The search line (see `filter::split_query`) narrows the annotations down with its filter expression
+    /// and starts off the search text, and so does the refine action. With `once`, the first action ends the session
+    pub async fn search_session(
         &mut self,
-        annotations: Vec<Annotation>,
+        mut filters: Filters,
+        query: Option<String>,
+        sort: SortOptions,
+        (expression, text): (String, String),
         fuzzy: bool,
+        once: bool,
     ) -> color_eyre::Result<()> {
-        let mut annotations = annotations;
         if self.config.annotation_template.is_none() {
             self.config.set_annotation_template()?;
         }
-        let hbs = self.get_handlebars()?;
-        let options = SkimOptionsBuilder::default()
-            .height(Some("100%"))
-            .preview(Some(""))
-            .preview_window(Some("up:40%:wrap"))
-            .bind(vec![
-                "ctrl-a:select-all",
-                "left:scroll-left",
-                "right:scroll-right",
-                "ctrl-c:abort",
-                "shift-left:accept",
-                "shift-right:accept",
-                "shift-up:accept",
-                "Enter:accept"
-            ])
-            .exact(!fuzzy)
-            .header(Some("Arrow keys to scroll, Tab to toggle selection, Ctrl-A to select all, Esc to abort\n\
-            Enter to add a tag, Shift-Left to delete a tag, Shift-Right to delete annotation, Shift-Up to print the set of URIs"))
-            .multi(true)
-            .reverse(true)
-            .build()
-            .map_err(|_| Apologize::SearchError)?;
+        // Only read once, stdin is empty after that
+        filters.read_stdin_ids()?;
+        let positions = sort.sort == Some(SortField::Position);
+        let mut refinements: Vec<(String, Filter)> = Vec::new();
+        let mut search_text = text;
+        if !expression.is_empty() {
+            let filter = Filter::parse(&expression, self.config.tag_delimiter.as_deref())?;
+            refinements.push((expression, filter));
+        }
+        loop {
+            let mut annotations = self
+                .search_matches(filters.clone(), query.as_deref(), sort)
+                .await?;
+            for (_, filter) in &refinements {
+                annotations = self.refine(annotations, filter)?;
+            }
+            let expressions: Vec<_> = refinements.iter().map(|(e, _)| e.as_str()).collect();
+            match self.search(
+                annotations,
+                fuzzy,
+                positions,
+                &expressions,
+                &mut search_text,
+            )? {
+                SearchOutcome::Aborted => return Ok(()),
+                SearchOutcome::Selected(annotations, action) => {
+                    self.search_action(annotations, action).await?;
+                    if once {
+                        return Ok(());
+                    }
+                }
+                SearchOutcome::Refine => {
+                    let line = utils::user_input(
+                        "Refine with a search line, e.g. tag:rust site:github.com after:2021-01 (empty to start over)",
+                        None,
+                        false,
+                        true,
+                    )?;
+                    if line.is_empty() {
+                        refinements.clear();
+                        continue;
+                    }
+                    let (expression, text) = filter::split_query(&line);
+                    if !text.is_empty() {
+                        search_text = text;
+                    }
+                    if expression.is_empty() {
+                        continue;
+                    }
+                    match Filter::parse(&expression, self.config.tag_delimiter.as_deref()) {
+                        Ok(filter) => refinements.push((expression, filter)),
+                        // A typo shouldn't end the session
+                        Err(error) => eprintln!("{}", error),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Annotations matching a filter expression, checked with the resolved titles of their documents
+    pub(crate) fn refine(
+        &self,
+        annotations: Vec<Annotation>,
+        filter: &Filter,
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        let mut refined = Vec::new();
+        for annotation in annotations {
+            if filter.matches_titled(&annotation, self.resolved_title(&annotation)?.as_deref()) {
+                refined.push(annotation);
+            }
+        }
+        Ok(refined)
+    }
 
+    /// Annotations matching the filters (and the full-text `query`, if given), sorted with the sort options
+    pub(crate) async fn search_matches(
+        &self,
+        mut filters: Filters,
+        query: Option<&str>,
+        sort: SortOptions,
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        let annotations = match query {
+            Some(query) => {
+                filters.read_stdin_ids()?;
+                let ids: HashSet<String> = filters.ids.drain(..).collect();
+                let (view, exclusions) = self.config.get_local_filter(&mut filters)?;
+                let mut matching = Vec::new();
+                for annotation in self.query_index(query)? {
+                    if self.matches_locally(&annotation, &filters, &view, &exclusions, &ids)? {
+                        matching.push(annotation);
+                    }
+                }
+                matching
+            }
+            None => self.filter_annotations(filters, None).await?,
+        };
+        self.sort_by_options(annotations, sort)
+    }
+
+    /// Makes a skim search window for given annotations, starting with `search_text` in the search box
+    /// (which is set to the search text it's closed with).
+    /// With `positions`, each line starts with the position of the annotation in its document
+    /// (for annotations sorted by position; page notes get a `-`).
+    /// The `refinements` (filter expressions) narrowing the annotations down are shown in the header
+    fn search(
+        &self,
+        mut annotations: Vec<Annotation>,
+        fuzzy: bool,
+        positions: bool,
+        refinements: &[&str],
+        search_text: &mut String,
+    ) -> color_eyre::Result<SearchOutcome> {
+        let hbs = Arc::new(self.get_handlebars()?);
+        let keybindings = self.config.get_keybindings();
+        let mut bindings = vec![
+            "ctrl-a:select-all".to_owned(),
+            "left:scroll-left".to_owned(),
+            "right:scroll-right".to_owned(),
+            "ctrl-c:abort".to_owned(),
+        ];
+        // The action is passed back in the final event
+        bindings.extend(
+            keybindings
+                .iter()
+                .map(|(key, action)| format!("{}:accept({})", key, action)),
+        );
+        let header = format!(
+            "Arrow keys to scroll, Tab to toggle selection, Ctrl-A to select all, Esc to abort\n{}",
+            keybindings
+                .iter()
+                .map(|(key, action)| format!("{}: {}", key, action))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let header = if refinements.is_empty() {
+            header
+        } else {
+            format!("{}\nRefined by: {}", header, refinements.join(" AND "))
+        };
         let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
-        for annotation in &annotations {
+        let (query, header, bindings) = (search_text.as_str(), &header, &bindings);
+        // skim runs while the items are prepared, so the window opens right away and fills up as they come
+        let (output, sent) = std::thread::scope(|scope| {
+            let skim = scope.spawn(move || {
+                // The options aren't `Send`, so they're made in the thread running skim
+                let options = SkimOptionsBuilder::default()
+                    .color(skim_color())
+                    .height(Some("100%"))
+                    .preview(Some(""))
+                    .preview_window(Some("up:40%:wrap"))
+                    .bind(bindings.iter().map(String::as_str).collect())
+                    .exact(!fuzzy)
+                    .header(Some(header))
+                    .query(Some(query))
+                    .multi(true)
+                    .reverse(true)
+                    .build()
+                    .ok()?;
+                Skim::run_with(&options, Some(rx_item))
+            });
+            let sent = self.send_search_items(tx_item, &annotations, positions, &hbs);
+            (skim.join().ok().flatten(), sent)
+        });
+        sent?;
+        let output = output.ok_or(Apologize::SearchError)?;
+        if output.is_abort {
+            return Ok(SearchOutcome::Aborted);
+        }
+        *search_text = output.query;
+        let action = match output.final_event {
+            Event::EvActAccept(Some(action)) => SearchAction::from_name(&action),
+            _ => None,
+        };
+        if action == Some(SearchAction::Refine) {
+            return Ok(SearchOutcome::Refine);
+        }
+        let annotation_ids: HashSet<String> = output
+            .selected_items
+            .into_iter()
+            .map(|s| {
+                s.as_any()
+                    .downcast_ref::<SearchAnnotation>()
+                    .unwrap()
+                    .id
+                    .to_string()
+            })
+            .collect();
+        annotations.retain(|a| annotation_ids.contains(&a.id));
+        Ok(SearchOutcome::Selected(annotations, action))
+    }
+
+    /// Sends the lines of the search window to skim, stopping early if it's closed before they're all sent.
+    /// With `positions`, each line starts with the position of the annotation in its document
+    fn send_search_items(
+        &self,
+        tx_item: SkimItemSender,
+        annotations: &[Annotation],
+        positions: bool,
+        hbs: &Arc<Handlebars<'static>>,
+    ) -> color_eyre::Result<()> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for annotation in annotations {
+            let template = self.annotation_template(annotation.clone())?;
+            let position = if !positions {
+                String::new()
+            } else if utils::get_quotes(annotation).is_empty() {
+                format!("{:>3} ", "-")
+            } else {
+                let count = counts.entry(annotation.uri.as_str()).or_default();
+                *count += 1;
+                format!("{:>3} ", count)
+            };
+            // Others' annotations (with `all_users`) say who wrote them
+            let author = if template.mine {
+                String::new()
+            } else {
+                format!(
+                    "@{} ",
+                    template
+                        .display_name
+                        .as_deref()
+                        .unwrap_or(&template.username)
+                )
+            };
             let highlight = format!(
-                "{} | {} |{}| {}",
-                style(&utils::get_quotes(&annotation).join(" ").replace("\n", " ")),
+                "{}{} | {} |{}| {}{} {}",
+                style(position).dim(),
+                style(&utils::get_quotes(annotation).join(" ").replace("\n", " ")),
                 annotation.text.replace("\n", " "),
                 style(&annotation.tags.join("|")).fg(dialoguer::console::Color::Red),
+                style(author).fg(dialoguer::console::Color::Yellow),
+                style(&template.title).fg(dialoguer::console::Color::Cyan),
                 style(&annotation.uri)
                     .fg(dialoguer::console::Color::Cyan)
                     .italic()
                     .underlined()
             );
-            let _ = tx_item.send(Arc::new(SearchAnnotation {
+            let item = SearchAnnotation::new(
                 highlight,
-                markdown: hbs.render(
-                    "annotation",
-                    &AnnotationTemplate::from_annotation(annotation.clone()),
-                )?,
-                id: annotation.id.to_owned(),
-            }));
+                template,
+                Arc::clone(hbs),
+                self.config.preview_command.clone(),
+            );
+            if tx_item.send(Arc::new(item)).is_err() {
+                // skim is closed
+                break;
+            }
         }
-        drop(tx_item); // so that skim could know when to stop waiting for more items.
+        // dropping the sender lets skim know there are no more items
+        Ok(())
+    }
 
-        if let Some(output) = Skim::run_with(&options, Some(rx_item)) {
-            let annotation_ids: HashSet<String> = output
-                .selected_items
-                .into_iter()
-                .map(|s| {
-                    s.as_any()
-                        .downcast_ref::<SearchAnnotation>()
-                        .unwrap()
-                        .id
-                        .to_string()
-                })
-                .collect();
-            annotations = annotations
-                .into_iter()
-                .filter(|a| annotation_ids.contains(&a.id))
-                .collect();
-            if annotations.is_empty() {
-                println!("Nothing selected");
-                return Ok(());
+    /// Runs a search window action on the selected annotations
+    async fn search_action(
+        &self,
+        annotations: Vec<Annotation>,
+        action: Option<SearchAction>,
+    ) -> color_eyre::Result<()> {
+        if annotations.is_empty() {
+            println!("Nothing selected");
+            return Ok(());
+        }
+        if let Some(rating) = action.and_then(SearchAction::rating) {
+            self.set_meta_field(&annotations, meta::RATING_KEY, &rating.to_string())?;
+            println!("Rated {} annotation(s) {}", annotations.len(), rating);
+            return Ok(());
+        }
+        match action {
+            Some(SearchAction::Tag) => {
+                let tags = self.search_tags(&annotations, true)?;
+                self.tag(annotations, false, Some(tags)).await?;
             }
-            let key = output.final_key;
-            match key {
-                Key::Enter => {
-                    let tags = self.search_tags(&annotations, true)?;
-                    self.tag(annotations, false, Some(tags)).await?;
-                }
-                Key::ShiftLeft => {
-                    let tags = self.search_tags(&annotations, false)?;
-                    self.tag(annotations, true, Some(tags)).await?;
-                }
-                Key::ShiftRight => {
-                    self.delete(annotations, false).await?;
-                }
-                Key::ShiftUp => {
-                    self.uri(annotations, Vec::new())?;
-                }
-                _ => (),
+            Some(SearchAction::Untag) => {
+                let tags = self.search_tags(&annotations, false)?;
+                self.tag(annotations, true, Some(tags)).await?;
             }
-            Ok(())
-        } else {
-            Err(Apologize::SearchError.into())
+            Some(SearchAction::Delete) => {
+                self.delete(annotations, false).await?;
+            }
+            Some(SearchAction::PrintUri) => {
+                self.uri(
+                    annotations,
+                    Vec::new(),
+                    UriOptions {
+                        titles: true,
+                        ..UriOptions::default()
+                    },
+                )?;
+            }
+            Some(SearchAction::Open) => {
+                self.open_in_browser(&annotations)?;
+            }
+            Some(SearchAction::Obsidian) => {
+                self.open_in_obsidian(&annotations)?;
+            }
+            Some(SearchAction::Link) => {
+                self.link_selected(&annotations)?;
+            }
+            Some(SearchAction::Edit) => {
+                self.edit(annotations).await?;
+            }
+            Some(SearchAction::Copy) => {
+                self.copy(&annotations, &self.get_handlebars()?)?;
+            }
+            Some(SearchAction::Status) => {
+                let current = self.get_meta(&annotations[0].id)?;
+                let status = utils::user_input(
+                    "Status (empty to remove it)",
+                    current.get(meta::STATUS_KEY).map(String::as_str),
+                    true,
+                    true,
+                )?;
+                self.set_meta_field(&annotations, meta::STATUS_KEY, &status)?;
+            }
+            // Rated above
+            Some(
+                SearchAction::Rate1
+                | SearchAction::Rate2
+                | SearchAction::Rate3
+                | SearchAction::Rate4
+                | SearchAction::Rate5,
+            ) => (),
+            // Handled by the search session
+            Some(SearchAction::Refine) | None => (),
+        }
+        Ok(())
+    }
+
+    /// Opens annotations in context (on the annotated page, with the Hypothesis sidebar) in the default browser
+    pub fn open_in_browser(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        for annotation in annotations {
+            utils::open_in_browser(&utils::annotation_link(annotation))?;
+        }
+        Ok(())
+    }
+
+    /// Opens the knowledge base notes with the annotations (as written by the last `make`) in Obsidian
+    pub fn open_in_obsidian(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        let kb_dir = self.config.kb_dir.as_ref().ok_or(Apologize::ConfigError {
+            message: "Knowledge base directory not set".into(),
+        })?;
+        let vault = self
+            .config
+            .obsidian_vault()
+            .ok_or(Apologize::ConfigError {
+                message: "Obsidian vault not set".into(),
+            })
+            .suggestion("Set obsidian_vault in the config file or use --obsidian-vault")?;
+        let locations = self.kb_locations()?;
+        let mut files = Vec::new();
+        for annotation in annotations {
+            let path = locations
+                .get(&annotation.id)?
+                .ok_or(Apologize::KBError {
+                    message: format!("Annotation {} isn't in the knowledge base", annotation.id),
+                })
+                .suggestion("Add it with 'gooseberry make'")?;
+            let path = PathBuf::from(std::str::from_utf8(&path)?);
+            let file = path
+                .strip_prefix(kb_dir)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .to_string();
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        for file in files {
+            utils::open_in_browser(&obsidian_uri(&vault, &file))?;
+        }
+        Ok(())
+    }
+
+    /// Copies annotations rendered with the annotation template to the clipboard
+    pub fn copy(&self, annotations: &[Annotation], hbs: &Handlebars) -> color_eyre::Result<()> {
+        let mut text = String::new();
+        for annotation in annotations {
+            text.push_str(
+                &hbs.render("annotation", &self.annotation_template(annotation.clone())?)?,
+            );
         }
+        utils::copy_to_clipboard(&text)?;
+        println!("Copied {} annotation(s)", annotations.len());
+        Ok(())
     }
 
     pub fn search_tags(
@@ -208,6 +637,7 @@ impl Gooseberry {
         };
         message.push_str("\nArrow keys to scroll, Tab to toggle selection, Ctrl-A to select all, Esc to abort, Enter to accept");
         let options = SkimOptionsBuilder::default()
+            .color(skim_color())
             .height(Some("20%"))
             .exact(true)
             .header(Some(&message))
@@ -252,8 +682,9 @@ impl Gooseberry {
         annotations: &[Annotation],
         fuzzy: bool,
     ) -> color_eyre::Result<HashSet<String>> {
-        let hbs = self.get_handlebars()?;
+        let hbs = Arc::new(self.get_handlebars()?);
         let options = SkimOptionsBuilder::default()
+            .color(skim_color())
             .height(Some("100%"))
             .preview(Some(""))
             .preview_window(Some("up:40%:wrap"))
@@ -274,24 +705,24 @@ impl Gooseberry {
 
         let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
         for annotation in annotations {
+            let template = self.annotation_template(annotation.clone())?;
             let highlight = format!(
-                "{} | {} |{}| {}",
+                "{} | {} |{}| {} {}",
                 style(&utils::get_quotes(&annotation).join(" ").replace("\n", " ")),
                 annotation.text.replace("\n", " "),
                 style(&annotation.tags.join("|")).fg(dialoguer::console::Color::Red),
+                style(&template.title).fg(dialoguer::console::Color::Cyan),
                 style(&annotation.uri)
                     .fg(dialoguer::console::Color::Cyan)
                     .italic()
                     .underlined()
             );
-            let _ = tx_item.send(Arc::new(SearchAnnotation {
+            let _ = tx_item.send(Arc::new(SearchAnnotation::new(
                 highlight,
-                markdown: hbs.render(
-                    "annotation",
-                    &AnnotationTemplate::from_annotation(annotation.clone()),
-                )?,
-                id: annotation.id.to_owned(),
-            }));
+                template,
+                Arc::clone(&hbs),
+                self.config.preview_command.clone(),
+            )));
         }
         drop(tx_item); // so that skim could know when to stop waiting for more items.
 
@@ -0,0 +1,320 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use color_eyre::Help;
+use hyper::header::{HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::configuration::GooseberryConfig;
+use crate::errors::Apologize;
+use crate::gooseberry::cli::Filters;
+use crate::gooseberry::diff::SyncDiff;
+use crate::gooseberry::Gooseberry;
+
+/// Events kept for each `/events` client before it misses some
+const EVENT_CAPACITY: usize = 256;
+/// Time between the comments keeping `/events` connections open
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Work needing the database, done one at a time by the loop owning it
+enum Job {
+    Sync,
+    Annotations(Box<Filters>),
+    Render(String),
+}
+
+/// A job and where to send its response (`None` for the syncs done every `--interval`)
+type Task = (Job, Option<oneshot::Sender<Response<Body>>>);
+
+/// ## Server
+/// A small HTTP API for editor plugins and scripts
+impl Gooseberry {
+    /// Serves the API on `127.0.0.1:<port>` until stopped with Ctrl-C:
+    /// * `POST /sync` syncs and responds with what changed (as `sync --diff --format json`)
+    /// * `GET /annotations` responds with the annotations matching the query as JSON
+    /// * `GET /render/<id>` responds with the annotation rendered with the annotation template
+    /// * `GET /events` streams what each sync changes as server-sent events
+    ///
+    /// Like `watch`, the database is only opened (and locked) while answering a request,
+    /// and encrypted again after each sync with `encrypt_database` on.
+    /// Web pages can only read the responses if their origin is `allow_origin`
+    pub async fn serve(
+        config: GooseberryConfig,
+        port: u16,
+        interval: Option<Duration>,
+        allow_origin: Option<String>,
+    ) -> color_eyre::Result<()> {
+        let allow_origin = match allow_origin {
+            Some(origin) => Some(
+                HeaderValue::from_str(&origin)
+                    .map_err(|_| Apologize::ConfigError {
+                        message: format!("{:?} isn't a valid origin", origin),
+                    })
+                    .suggestion("Use an origin like http://localhost:3000")?,
+            ),
+            None => None,
+        };
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (tasks, queue) = mpsc::channel(32);
+        let make_service = {
+            let events = events.clone();
+            make_service_fn(move |_| {
+                let (tasks, events, allow_origin) =
+                    (tasks.clone(), events.clone(), allow_origin.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        handle(request, tasks.clone(), events.clone(), allow_origin.clone())
+                    }))
+                }
+            })
+        };
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let server = hyper::Server::try_bind(&address)
+            .suggestion("Is another server using this port? Pick another one with --port")?
+            .serve(make_service);
+        println!("Serving on http://{}", address);
+        tokio::select! {
+            result = server => Ok(result?),
+            result = Self::work(&config, queue, interval, &events) => result,
+            // Dropping the job being done drops its open database too, which deletes its decrypted copy
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        }
+    }
+
+    /// Does the jobs sent by the server, and syncs every `interval` if given
+    async fn work(
+        config: &GooseberryConfig,
+        mut queue: mpsc::Receiver<Task>,
+        interval: Option<Duration>,
+        events: &broadcast::Sender<String>,
+    ) -> color_eyre::Result<()> {
+        let mut ticks = interval.map(tokio::time::interval);
+        loop {
+            let (job, reply) = tokio::select! {
+                task = queue.recv() => match task {
+                    Some(task) => task,
+                    None => return Ok(()),
+                },
+                _ = tick(&mut ticks) => (Job::Sync, None),
+            };
+            let response = Self::answer(config, job, events).await;
+            match (reply, response) {
+                (Some(reply), response) => {
+                    // the client may have gone away already
+                    let _ = reply.send(response.unwrap_or_else(|error| error_response(&error)));
+                }
+                (None, Err(error)) => eprintln!("{:?}", error),
+                (None, Ok(_)) => (),
+            }
+        }
+    }
+
+    async fn answer(
+        config: &GooseberryConfig,
+        job: Job,
+        events: &broadcast::Sender<String>,
+    ) -> color_eyre::Result<Response<Body>> {
+        let gooseberry = Self::new(config.clone())?;
+        match job {
+            Job::Sync => {
+                let mut diff = SyncDiff::default();
+                gooseberry.sync_changes(false, Some(&mut diff)).await?;
+                gooseberry.save()?;
+                let changes = [
+                    ("added", &diff.added),
+                    ("edited", &diff.edited),
+                    ("retagged", &diff.retagged),
+                    ("deleted", &diff.deleted),
+                ];
+                for (event, entries) in changes.iter() {
+                    for entry in entries.iter() {
+                        // no one may be listening
+                        let _ = events.send(format!(
+                            "event: {}\ndata: {}\n\n",
+                            event,
+                            serde_json::to_string(entry)?
+                        ));
+                    }
+                }
+                json_response(&diff)
+            }
+            Job::Annotations(filters) => {
+                json_response(&gooseberry.filter_annotations(*filters, None).await?)
+            }
+            Job::Render(id) => {
+                let annotation = gooseberry
+                    .api
+                    .fetch_annotation(&id)
+                    .await
+                    .map_err(|_| Apologize::AnnotationNotFound { id })?;
+                let hbs = gooseberry.get_handlebars()?;
+                let markdown =
+                    hbs.render("annotation", &gooseberry.annotation_template(annotation)?)?;
+                Ok(Response::builder()
+                    .header(CONTENT_TYPE, "text/markdown; charset=utf-8")
+                    .body(markdown.into())?)
+            }
+        }
+    }
+}
+
+/// Waits for the next tick, or forever without an interval
+async fn tick(ticks: &mut Option<tokio::time::Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+async fn handle(
+    request: Request<Body>,
+    tasks: mpsc::Sender<Task>,
+    events: broadcast::Sender<String>,
+    allow_origin: Option<HeaderValue>,
+) -> Result<Response<Body>, Infallible> {
+    let mut response = route(request, &tasks, &events)
+        .await
+        .unwrap_or_else(|error| error_response(&error));
+    if let Some(origin) = allow_origin {
+        response
+            .headers_mut()
+            .insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    Ok(response)
+}
+
+async fn route(
+    request: Request<Body>,
+    tasks: &mpsc::Sender<Task>,
+    events: &broadcast::Sender<String>,
+) -> color_eyre::Result<Response<Body>> {
+    let path = request.uri().path();
+    let job = match (request.method(), path) {
+        (&Method::POST, "/sync") => Job::Sync,
+        (&Method::GET, "/annotations") => Job::Annotations(Box::new(query_filters(
+            request.uri().query().unwrap_or_default(),
+        )?)),
+        (&Method::GET, "/events") => return event_stream(events.subscribe()),
+        (&Method::GET, _) if path.starts_with("/render/") && path.len() > "/render/".len() => {
+            Job::Render(path["/render/".len()..].to_owned())
+        }
+        (method, _) => {
+            return Err(Apologize::RouteNotFound {
+                method: method.to_string(),
+                path: path.to_owned(),
+            }
+            .into())
+        }
+    };
+    let stopped = || Apologize::OutOfCheeseError {
+        message: "The server stopped answering requests".into(),
+    };
+    let (reply, response) = oneshot::channel();
+    tasks
+        .send((job, Some(reply)))
+        .await
+        .map_err(|_| stopped())?;
+    Ok(response.await.map_err(|_| stopped())?)
+}
+
+/// Value of a `true` / `false` query parameter
+fn flag(key: &str, value: &str) -> color_eyre::Result<bool> {
+    Ok(value.parse().map_err(|_| Apologize::RequestError {
+        message: format!("{} has to be true or false, not {:?}", key, value),
+    })?)
+}
+
+/// Filters from the query of `/annotations`: `tag` (repeated or comma-separated), `exclude_tag`,
+/// `uri`, `any`, `group`, `since` (e.g. 2w), `from`, `before`, `hidden`, `flagged`, and `mine`
+/// (`true` or `false`), `visibility` (`private`, `group`, or `world`), `user`, and `meta` (repeated)
+fn query_filters(query: &str) -> color_eyre::Result<Filters> {
+    let mut filters = Filters::default();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        let tags = || value.split(',').map(|tag| tag.trim().to_owned());
+        match key.as_ref() {
+            "tag" => filters.tags.extend(tags()),
+            "exclude_tag" => filters.exclude_tags.extend(tags()),
+            "uri" => filters.uri = value.into_owned(),
+            "any" => filters.any = value.into_owned(),
+            "group" => filters.group = Some(value.into_owned()),
+            "since" => filters.since = Some(crate::utils::parse_since(&value)?),
+            "from" => filters.from = Some(crate::utils::parse_datetime(&value)?),
+            "before" => filters.before = Some(crate::utils::parse_datetime(&value)?),
+            "hidden" => filters.hidden = flag(&key, &value)?,
+            "flagged" => filters.flagged = flag(&key, &value)?,
+            "user" => filters.user = Some(value.into_owned()),
+            "meta" => filters.meta.push(value.into_owned()),
+            "mine" => filters.mine = flag(&key, &value)?,
+            "visibility" => {
+                filters.visibility = Some(value.parse().map_err(|_| Apologize::RequestError {
+                    message: format!(
+                        "visibility has to be private, group, or world, not {:?}",
+                        value
+                    ),
+                })?)
+            }
+            _ => {
+                return Err(Apologize::RequestError {
+                    message: format!("Unknown query parameter {:?}", key),
+                }
+                .into())
+            }
+        }
+    }
+    Ok(filters)
+}
+
+/// Forwards sync events to a client as they come, with comments in between to keep the connection open
+fn event_stream(mut events: broadcast::Receiver<String>) -> color_eyre::Result<Response<Body>> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+        loop {
+            let chunk = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_owned(),
+            };
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)?)
+}
+
+fn json_response<T: Serialize>(value: &T) -> color_eyre::Result<Response<Body>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(value)?.into())?)
+}
+
+/// The error as JSON (see `--error-format json`), with a status depending on its code
+fn error_response(error: &color_eyre::Report) -> Response<Body> {
+    let json = crate::errors::error_json(error);
+    let status = match json["error"]["code"].as_str() {
+        Some("annotation_not_found" | "group_not_found" | "tag_not_found" | "route_not_found") => {
+            StatusCode::NOT_FOUND
+        }
+        Some("request_error" | "filter_error") => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let mut response = Response::new(Body::from(json.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
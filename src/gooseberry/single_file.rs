@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::configuration::{KbFormat, OrderBy};
+use crate::gooseberry::cli::{Filters, SortOptions};
+use crate::gooseberry::html;
+use crate::gooseberry::knowledge_base::{AnnotationRenderer, AnnotationTemplate};
+use crate::gooseberry::site::slugify;
+use crate::gooseberry::Gooseberry;
+
+/// A heading of the document, for a group of annotations at one level of the hierarchy
+struct Section {
+    /// What the annotations were grouped by, e.g. the tag or the title of the document
+    title: String,
+    /// ID linked to from the table of contents, unique in the document
+    anchor: String,
+    /// Level in the hierarchy, 0 at the top
+    depth: usize,
+    /// Rendered annotations, for the sections at the last level of the hierarchy
+    contents: String,
+}
+
+/// Brackets would end the link text in the table of contents
+fn link_text(title: &str) -> String {
+    title.replace('[', "(").replace(']', ")")
+}
+
+/// The document: a title, a table of contents linking to the sections, and the sections
+/// (or just the annotations, without a hierarchy)
+fn document(
+    format: KbFormat,
+    title: &str,
+    sections: &[Section],
+    annotations: Option<String>,
+) -> String {
+    let mut lines = Vec::new();
+    match format {
+        KbFormat::Markdown => lines.push(format!("# {}\n", title)),
+        KbFormat::Org => lines.push(format!("#+TITLE: {}\n", title)),
+    }
+    if !sections.is_empty() {
+        lines.push(match format {
+            KbFormat::Markdown => "## Contents\n".to_owned(),
+            KbFormat::Org => "* Contents".to_owned(),
+        });
+        for section in sections {
+            let indent = "  ".repeat(section.depth);
+            let text = link_text(&section.title);
+            lines.push(match format {
+                KbFormat::Markdown => format!("{}- [{}](#{})", indent, text, section.anchor),
+                KbFormat::Org => format!("{}- [[#{}][{}]]", indent, section.anchor, text),
+            });
+        }
+        lines.push(String::new());
+    }
+    for section in sections {
+        lines.push(match format {
+            KbFormat::Markdown => format!(
+                "<a id=\"{}\"></a>\n\n{} {}\n",
+                section.anchor,
+                "#".repeat((section.depth + 2).min(6)),
+                section.title
+            ),
+            KbFormat::Org => format!(
+                "{} {}\n:PROPERTIES:\n:CUSTOM_ID: {}\n:END:\n",
+                "*".repeat(section.depth + 1),
+                section.title,
+                section.anchor
+            ),
+        });
+        if !section.contents.is_empty() {
+            lines.push(section.contents.to_owned());
+        }
+    }
+    lines.extend(annotations);
+    lines.join("\n")
+}
+
+/// ## Single file
+/// The whole knowledge base as one document
+impl Gooseberry {
+    /// Adds the sections for annotations grouped by the hierarchy (from `depth` on), in order,
+    /// with anchors nested under `parent` and made unique with `anchors`
+    #[allow(clippy::too_many_arguments)]
+    fn add_sections(
+        &self,
+        order: &[OrderBy],
+        depth: usize,
+        annotations: Vec<AnnotationTemplate>,
+        parent: &str,
+        renderer: &AnnotationRenderer,
+        anchors: &mut HashSet<String>,
+        sections: &mut Vec<Section>,
+    ) -> color_eyre::Result<()> {
+        let mut groups: Vec<_> = self
+            .group_annotations_by_order(order[depth], annotations)
+            .into_iter()
+            .collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, (title, annotations)) in groups {
+            let slug = if parent.is_empty() {
+                slugify(&title)
+            } else {
+                format!("{}-{}", parent, slugify(&title))
+            };
+            let mut anchor = slug.clone();
+            let mut number = 2;
+            while !anchors.insert(anchor.clone()) {
+                anchor = format!("{}-{}", slug, number);
+                number += 1;
+            }
+            let last = depth + 1 == order.len();
+            sections.push(Section {
+                title,
+                anchor: anchor.clone(),
+                depth,
+                contents: if last {
+                    annotations
+                        .iter()
+                        .map(|a| renderer.render(a))
+                        .collect::<color_eyre::Result<String>>()?
+                } else {
+                    String::new()
+                },
+            });
+            if !last {
+                self.add_sections(
+                    order,
+                    depth + 1,
+                    annotations,
+                    &anchor,
+                    renderer,
+                    anchors,
+                    sections,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the knowledge base to one file instead of a directory: a heading per group of annotations
+    /// in the hierarchy (or `group_by`), each with its annotations rendered with the annotation template,
+    /// and a table of contents linking to them. The knowledge base directory isn't touched
+    pub async fn make_single_file(
+        &self,
+        file: &Path,
+        filters: Filters,
+        sort: SortOptions,
+        group_by: Option<OrderBy>,
+    ) -> color_eyre::Result<()> {
+        let html =
+            matches!(file.extension(), Some(extension) if extension.eq_ignore_ascii_case("html"));
+        let mut hbs = self.get_handlebars()?;
+        self.register_template_overrides(&mut hbs)?;
+        if html {
+            self.register_html_layout(&mut hbs)?;
+        }
+        let annotations = self.knowledge_base_annotations(filters).await?;
+        let count = annotations.len();
+        let mut annotations = self.nest_replies(annotations)?;
+        self.sort_annotations(&mut annotations, sort);
+        let renderer = AnnotationRenderer {
+            hbs: &hbs,
+            marker: None,
+            overrides: self.template_overrides(&annotations),
+            front_matter: false,
+            html,
+        };
+        let order = match group_by {
+            Some(field) => vec![field],
+            None => self.config.hierarchy.clone().unwrap_or_default(),
+        };
+        let title = file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Annotations".to_owned());
+        let mut sections = Vec::new();
+        let contents = if order.is_empty() {
+            Some(
+                annotations
+                    .iter()
+                    .map(|a| renderer.render(a))
+                    .collect::<color_eyre::Result<String>>()?,
+            )
+        } else {
+            self.add_sections(
+                &order,
+                0,
+                annotations,
+                "",
+                &renderer,
+                &mut HashSet::new(),
+                &mut sections,
+            )?;
+            None
+        };
+        let format = if html {
+            KbFormat::Markdown
+        } else {
+            self.config.kb_format()
+        };
+        let mut document = document(format, &title, &sections, contents);
+        if html {
+            document = html::layout(&hbs, &title, &document, None, &[])?;
+        }
+        if self.dry_run {
+            println!(
+                "Would write {} annotation(s) to {:?} (dry run)",
+                count, file
+            );
+            return Ok(());
+        }
+        fs::write(file, document)?;
+        println!("{} annotation(s) written to {:?}", count, file);
+        Ok(())
+    }
+}
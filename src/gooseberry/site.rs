@@ -0,0 +1,227 @@
+use std::collections::{BTreeSet, HashSet};
+use std::path::PathBuf;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::Value as Json;
+
+use crate::gooseberry::cli::SiteFormat;
+use crate::gooseberry::html::{search_entries, SearchEntry};
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+
+/// Longest slug made from a folder or page name
+const MAX_SLUG_LENGTH: usize = 100;
+
+/// Lowercase letters and numbers, with everything else collapsed into dashes,
+/// e.g. "CRISPR Screens (2021)" becomes "crispr-screens-2021"
+pub(crate) fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug: String = slug.chars().take(MAX_SLUG_LENGTH).collect();
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "untitled".to_owned()
+    } else {
+        slug.to_owned()
+    }
+}
+
+/// Quoted string, valid in TOML and YAML front matter
+pub(crate) fn quote(value: &str) -> String {
+    Json::from(value).to_string()
+}
+
+/// A page of the static site, made from a leaf of the hierarchy
+pub(crate) struct SitePage {
+    site: SiteFormat,
+    /// Folder and page names in the hierarchy
+    names: Vec<String>,
+    /// Slugs of the names, used for the path of the page
+    pub(crate) slugs: Vec<String>,
+    /// Front matter of the page
+    front_matter: String,
+    /// Rendered page template
+    pub(crate) contents: String,
+    /// The page's annotations in the search index, for HTML
+    pub(crate) search_entries: Vec<SearchEntry>,
+}
+
+impl SitePage {
+    /// A page without contents yet
+    pub(crate) fn new(
+        site: SiteFormat,
+        names: Vec<String>,
+        annotations: &[AnnotationTemplate],
+    ) -> Self {
+        let title = names.last().map(String::as_str).unwrap_or_default();
+        let mut page = SitePage {
+            site,
+            front_matter: site.front_matter(title, annotations),
+            slugs: names.iter().map(|name| slugify(name)).collect(),
+            names,
+            contents: String::new(),
+            search_entries: Vec::new(),
+        };
+        if site == SiteFormat::Html {
+            page.search_entries =
+                search_entries(&page.path().to_string_lossy(), page.title(), annotations);
+        }
+        page
+    }
+
+    /// Name of the page, unslugified
+    pub(crate) fn title(&self) -> &str {
+        self.names.last().map(String::as_str).unwrap_or_default()
+    }
+
+    /// Path relative to the knowledge base directory, e.g. `biology/crispr-screens.md`
+    pub(crate) fn path(&self) -> PathBuf {
+        let mut path: PathBuf = self.slugs.iter().collect();
+        path.set_extension(match self.site {
+            SiteFormat::Html => "html",
+            _ => "md",
+        });
+        path
+    }
+}
+
+/// Markdown list of the pages (sorted by path), nested by folder, with links to the pages
+pub(crate) fn nested_list(pages: &[SitePage]) -> String {
+    let mut list = String::new();
+    let mut listed = HashSet::new();
+    for page in pages {
+        for depth in 0..page.slugs.len() {
+            if !listed.insert(page.slugs[..=depth].to_vec()) {
+                continue;
+            }
+            let link = if depth + 1 == page.slugs.len() {
+                page.path().to_string_lossy().replace('\\', "/")
+            } else {
+                String::new()
+            };
+            list.push_str(&format!(
+                "{}- [{}]({})\n",
+                "  ".repeat(depth),
+                page.names[depth],
+                link
+            ));
+        }
+    }
+    list
+}
+
+impl SiteFormat {
+    /// Front matter of a page: its title, the dates of its first and last annotation,
+    /// and the tags of its annotations as taxonomy terms
+    fn front_matter(self, title: &str, annotations: &[AnnotationTemplate]) -> String {
+        let date = annotations.iter().map(|a| a.annotation.created).min();
+        let updated = annotations.iter().map(|a| a.annotation.updated).max();
+        let tags: BTreeSet<_> = annotations
+            .iter()
+            .flat_map(|a| a.annotation.tags.iter().cloned())
+            .collect();
+        let tags = Json::from(tags.into_iter().collect::<Vec<_>>()).to_string();
+        let format_date = |date: DateTime<Utc>| date.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut lines = Vec::new();
+        match self {
+            SiteFormat::Zola => {
+                lines.push("+++".to_owned());
+                lines.push(format!("title = {}", quote(title)));
+                if let Some(date) = date {
+                    lines.push(format!("date = {}", format_date(date)));
+                }
+                if let Some(updated) = updated {
+                    lines.push(format!("updated = {}", format_date(updated)));
+                }
+                lines.push("[taxonomies]".to_owned());
+                lines.push(format!("tags = {}", tags));
+                lines.push("+++".to_owned());
+            }
+            SiteFormat::Hugo => {
+                lines.push("---".to_owned());
+                lines.push(format!("title: {}", quote(title)));
+                if let Some(date) = date {
+                    lines.push(format!("date: {}", format_date(date)));
+                }
+                if let Some(updated) = updated {
+                    lines.push(format!("lastmod: {}", format_date(updated)));
+                }
+                lines.push(format!("tags: {}", tags));
+                lines.push("---".to_owned());
+            }
+            // mdBook takes page titles from SUMMARY.md, and HTML pages get theirs from the layout
+            SiteFormat::Mdbook | SiteFormat::Html => return String::new(),
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Front matter of a section (a folder of pages)
+    fn section_front_matter(self, title: &str) -> String {
+        match self {
+            SiteFormat::Zola => format!("+++\ntitle = {}\nsort_by = \"date\"\n+++\n", quote(title)),
+            SiteFormat::Hugo => format!("---\ntitle: {}\n---\n", quote(title)),
+            SiteFormat::Mdbook | SiteFormat::Html => String::new(),
+        }
+    }
+
+    /// Files of the site (paths relative to the knowledge base directory): the pages, and
+    /// - for Zola and Hugo, an `_index.md` section file for the site and each folder.
+    ///   A page with the same path as a folder becomes the folder's `_index.md`
+    /// - for mdBook, a `SUMMARY.md` listing the pages nested by folder
+    ///
+    /// (HTML pages are written by `html::site_files`)
+    pub(crate) fn files(self, title: &str, mut pages: Vec<SitePage>) -> Vec<(PathBuf, String)> {
+        pages.sort_by(|a, b| a.slugs.cmp(&b.slugs));
+        let mut files = Vec::with_capacity(pages.len() + 1);
+        if self == SiteFormat::Mdbook {
+            let summary = format!("# Summary\n\n{}", nested_list(&pages));
+            files.push((PathBuf::from("SUMMARY.md"), summary));
+            files.extend(pages.into_iter().map(|page| (page.path(), page.contents)));
+            return files;
+        }
+
+        files.push((PathBuf::from("_index.md"), self.section_front_matter(title)));
+        let page_slugs: HashSet<_> = pages.iter().map(|page| page.slugs.clone()).collect();
+        let mut sections = HashSet::new();
+        for page in &pages {
+            for depth in 0..page.slugs.len() - 1 {
+                let folder = page.slugs[..=depth].to_vec();
+                if !page_slugs.contains(&folder) && sections.insert(folder.clone()) {
+                    let path: PathBuf = folder.iter().collect();
+                    files.push((
+                        path.join("_index.md"),
+                        self.section_front_matter(&page.names[depth]),
+                    ));
+                }
+            }
+        }
+        for page in pages {
+            let is_folder = page_slugs
+                .iter()
+                .any(|slugs| slugs.len() > page.slugs.len() && slugs.starts_with(&page.slugs));
+            if is_folder {
+                let path: PathBuf = page.slugs.iter().collect();
+                files.push((
+                    path.join("_index.md"),
+                    format!(
+                        "{}\n{}",
+                        self.section_front_matter(page.title()),
+                        page.contents
+                    ),
+                ));
+            } else {
+                files.push((
+                    page.path(),
+                    format!("{}\n{}", page.front_matter, page.contents),
+                ));
+            }
+        }
+        files
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::Datelike;
+use color_eyre::Help;
+use hypothesis::annotations::Annotation;
+use url::Url;
+
+use crate::errors::Apologize;
+use crate::gooseberry::cli::StatsFormat;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+use crate::EMPTY_TAG;
+
+/// Bars of a sparkline, from lowest to highest
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// A tag, domain, or document and its number of annotations
+#[derive(Debug, Serialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+/// An annotated document and its number of annotations
+#[derive(Debug, Serialize)]
+pub struct DocumentCount {
+    pub uri: String,
+    pub title: Option<String>,
+    pub count: usize,
+}
+
+/// Statistics about a set of annotations
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub annotations: usize,
+    /// Annotations without tags
+    pub untagged: usize,
+    /// Highlights (annotations with a quote), as opposed to page notes
+    pub highlights: usize,
+    /// Average number of characters in the annotation text
+    pub average_text_length: f64,
+    /// Average number of characters in the highlighted quote, for highlights
+    pub average_quote_length: f64,
+    /// Tags, most used first
+    pub tags: Vec<Count>,
+    /// Domains of annotated pages, most annotated first
+    pub domains: Vec<Count>,
+    /// Documents, most annotated first
+    pub documents: Vec<DocumentCount>,
+    /// Month (YYYY-MM): number of annotations created in it, with months without any included
+    pub months: BTreeMap<String, usize>,
+}
+
+/// A level of hierarchical tags, e.g. `biology` for `biology/genetics` and `biology/crispr`
+#[derive(Debug, Default, Serialize)]
+pub struct TagTree {
+    /// Annotations with exactly this tag
+    pub count: usize,
+    /// Annotations with this tag or a tag below it
+    pub total: usize,
+    /// Tags one level below, by name
+    pub children: BTreeMap<String, TagTree>,
+    /// IDs of the annotations counted in `total`
+    #[serde(skip)]
+    ids: HashSet<String>,
+}
+
+impl TagTree {
+    /// Adds a tag's annotations at the position given by its levels
+    fn insert(&mut self, levels: &[&str], ids: &[String]) {
+        self.ids.extend(ids.iter().cloned());
+        self.total = self.ids.len();
+        match levels.split_first() {
+            Some((level, rest)) => self
+                .children
+                .entry((*level).to_owned())
+                .or_default()
+                .insert(rest, ids),
+            None => self.count += ids.len(),
+        }
+    }
+
+    /// Prints the tags below this level, indented by depth, with the number of annotations in each subtree
+    fn print(&self, depth: usize) {
+        for (name, child) in &self.children {
+            println!("  {:>6}  {}{}", child.total, "  ".repeat(depth), name);
+            child.print(depth + 1);
+        }
+    }
+}
+
+/// Sorts counts from highest to lowest, then by name
+fn sorted_counts(counts: HashMap<String, usize>) -> Vec<Count> {
+    let mut counts: Vec<_> = counts
+        .into_iter()
+        .map(|(name, count)| Count { name, count })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    counts
+}
+
+/// Host of a URI without `www.`, or the URI's scheme for local files, PDFs, etc.
+pub(crate) fn domain(uri: &str) -> String {
+    match Url::parse(uri) {
+        Ok(url) => match url.host_str() {
+            Some(host) => host.trim_start_matches("www.").to_owned(),
+            None => format!("{}:", url.scheme()),
+        },
+        Err(_) => "(unknown)".to_owned(),
+    }
+}
+
+/// Months from the first to the last key, with zero for months without annotations
+fn fill_months(counts: BTreeMap<(i32, u32), usize>) -> BTreeMap<String, usize> {
+    let mut months = BTreeMap::new();
+    let (first, last) = match (counts.keys().next(), counts.keys().next_back()) {
+        (Some(&first), Some(&last)) => (first, last),
+        _ => return months,
+    };
+    let (mut year, mut month) = first;
+    while (year, month) <= last {
+        months.insert(
+            format!("{}-{:02}", year, month),
+            counts.get(&(year, month)).copied().unwrap_or(0),
+        );
+        if month == 12 {
+            year += 1;
+            month = 1;
+        } else {
+            month += 1;
+        }
+    }
+    months
+}
+
+/// Sparkline of counts, scaled to the highest one
+fn sparkline(counts: &[usize]) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    counts
+        .iter()
+        .map(|&count| {
+            if count == 0 {
+                ' '
+            } else {
+                SPARKS[count * (SPARKS.len() - 1) / max]
+            }
+        })
+        .collect()
+}
+
+/// Prints the first `top` counts as a table, with the names cut to fit
+fn print_table(heading: &str, rows: &[(String, usize)], top: usize) {
+    if rows.is_empty() {
+        return;
+    }
+    println!(
+        "\n{} (top {} of {})",
+        heading,
+        top.min(rows.len()),
+        rows.len()
+    );
+    for (name, count) in rows.iter().take(top) {
+        let name: String = if name.chars().count() > 60 {
+            name.chars().take(59).chain(std::iter::once('…')).collect()
+        } else {
+            name.to_owned()
+        };
+        println!("  {:>6}  {}", count, name);
+    }
+}
+
+impl Stats {
+    fn new(annotations: &[Annotation], titles: &HashMap<String, Option<String>>) -> Self {
+        let mut tags = HashMap::new();
+        let mut domains = HashMap::new();
+        let mut documents = HashMap::new();
+        let mut months = BTreeMap::new();
+        let (mut untagged, mut highlights) = (0, 0);
+        let (mut text_length, mut quote_length) = (0, 0);
+        for annotation in annotations {
+            if annotation.tags.is_empty() {
+                untagged += 1;
+            }
+            for tag in &annotation.tags {
+                *tags.entry(tag.to_owned()).or_default() += 1;
+            }
+            *domains.entry(domain(&annotation.uri)).or_default() += 1;
+            *documents.entry(annotation.uri.to_owned()).or_default() += 1;
+            *months
+                .entry((annotation.created.year(), annotation.created.month()))
+                .or_default() += 1;
+            text_length += annotation.text.chars().count();
+            let quote = utils::get_quotes(annotation).join(" ");
+            if !quote.trim().is_empty() {
+                highlights += 1;
+                quote_length += quote.chars().count();
+            }
+        }
+        let average = |total: usize, count: usize| {
+            if count == 0 {
+                0.0
+            } else {
+                (total as f64 / count as f64 * 10.0).round() / 10.0
+            }
+        };
+        Stats {
+            annotations: annotations.len(),
+            untagged,
+            highlights,
+            average_text_length: average(text_length, annotations.len()),
+            average_quote_length: average(quote_length, highlights),
+            tags: sorted_counts(tags),
+            domains: sorted_counts(domains),
+            documents: sorted_counts(documents)
+                .into_iter()
+                .map(|Count { name, count }| DocumentCount {
+                    title: titles.get(&name).cloned().flatten(),
+                    uri: name,
+                    count,
+                })
+                .collect(),
+            months: fill_months(months),
+        }
+    }
+
+    /// Prints the stats as tables of the `top` tags, domains, and documents and a sparkline of annotations per month
+    fn print(&self, top: usize) {
+        println!("Annotations:          {}", self.annotations);
+        println!(
+            "Highlights:           {} ({} page notes)",
+            self.highlights,
+            self.annotations - self.highlights
+        );
+        println!("Untagged:             {}", self.untagged);
+        println!(
+            "Average text length:  {} characters",
+            self.average_text_length
+        );
+        println!(
+            "Average quote length: {} characters",
+            self.average_quote_length
+        );
+        if let (Some(first), Some(last)) =
+            (self.months.keys().next(), self.months.keys().next_back())
+        {
+            let counts: Vec<_> = self.months.values().copied().collect();
+            println!(
+                "\nAnnotations per month ({} to {}, max {})",
+                first,
+                last,
+                counts.iter().max().unwrap_or(&0)
+            );
+            println!("  {}", sparkline(&counts));
+        }
+        let rows = |counts: &[Count]| -> Vec<_> {
+            counts
+                .iter()
+                .map(|c| (c.name.to_owned(), c.count))
+                .collect()
+        };
+        print_table("Tags", &rows(&self.tags), top);
+        print_table("Domains", &rows(&self.domains), top);
+        print_table(
+            "Documents",
+            &self
+                .documents
+                .iter()
+                .map(|d| (d.title.clone().unwrap_or_else(|| d.uri.to_owned()), d.count))
+                .collect::<Vec<_>>(),
+            top,
+        );
+    }
+}
+
+/// ## Stats
+/// Counts and averages over annotations
+impl Gooseberry {
+    /// Prints statistics about (optionally filtered) annotations, as tables of the `top` tags, domains,
+    /// and documents or as JSON (with all of them)
+    pub fn stats(
+        &self,
+        annotations: Vec<Annotation>,
+        format: StatsFormat,
+        top: usize,
+    ) -> color_eyre::Result<()> {
+        let titles = self.uri_titles(annotations.clone())?.into_iter().collect();
+        let stats = Stats::new(&annotations, &titles);
+        match format {
+            StatsFormat::Text => stats.print(top),
+            StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        }
+        Ok(())
+    }
+
+    /// Prints all tags in the database with their number of annotations, most used first,
+    /// or as a tree of hierarchical tags split by the `tag_delimiter`
+    pub fn list_tags(&self, tree: bool, format: StatsFormat) -> color_eyre::Result<()> {
+        let mut tags = Vec::new();
+        for tag_ids in self.tag_to_annotations()?.iter() {
+            let (tag_key, ids) = tag_ids?;
+            let tag = std::str::from_utf8(&tag_key)?.to_owned();
+            if tag != EMPTY_TAG {
+                tags.push((tag, utils::split_ids(&ids)?));
+            }
+        }
+        if !tree {
+            let counts = sorted_counts(
+                tags.into_iter()
+                    .map(|(tag, ids)| (tag, ids.len()))
+                    .collect(),
+            );
+            match format {
+                StatsFormat::Text => {
+                    for count in counts {
+                        println!("  {:>6}  {}", count.count, count.name);
+                    }
+                }
+                StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&counts)?),
+            }
+            return Ok(());
+        }
+        let delimiter = self
+            .config
+            .tag_delimiter
+            .as_deref()
+            .filter(|delimiter| !delimiter.is_empty())
+            .ok_or(Apologize::ConfigError {
+                message: "No tag delimiter set for hierarchical tags".into(),
+            })
+            .suggestion("Set one with `gooseberry config kb delimiter`")?;
+        let mut root = TagTree::default();
+        for (tag, ids) in &tags {
+            let levels: Vec<_> = tag
+                .split(delimiter)
+                .map(str::trim)
+                .filter(|level| !level.is_empty())
+                .collect();
+            root.insert(&levels, ids);
+        }
+        match format {
+            StatsFormat::Text => root.print(0),
+            StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&root.children)?),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,610 @@
+#[cfg(feature = "sqlite")]
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+
+#[cfg(not(feature = "sqlite"))]
+use color_eyre::Help;
+
+use crate::configuration::DbBackend;
+#[cfg(not(feature = "sqlite"))]
+use crate::errors::Apologize;
+use crate::gooseberry::database::merge_index;
+
+/// Name of the tree holding the time of the last sync of each group, unfinished syncs, and the schema version
+/// (the tree outside the named trees in `sled`)
+pub const DEFAULT_TREE: &str = "default";
+
+/// Name `sled` gives its default tree
+const SLED_DEFAULT_TREE: &str = "__sled__default";
+
+/// File of the SQLite database, in the database folder
+pub const SQLITE_FILE: &str = "gooseberry.sqlite3";
+
+/// A key and its value
+type Entry = color_eyre::Result<(Vec<u8>, Vec<u8>)>;
+
+/// Key-value pairs of a tree, in key order
+pub struct Entries(Box<dyn Iterator<Item = Entry>>);
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl Entries {
+    /// Just the keys
+    pub fn keys(self) -> impl Iterator<Item = color_eyre::Result<Vec<u8>>> {
+        self.map(|entry| entry.map(|(key, _)| key))
+    }
+
+    /// Just the values
+    pub fn values(self) -> impl Iterator<Item = color_eyre::Result<Vec<u8>>> {
+        self.map(|entry| entry.map(|(_, value)| value))
+    }
+}
+
+/// Inserts and removals applied to a tree all at once
+#[derive(Debug, Default)]
+pub struct Batch {
+    /// Key, and the value to insert or `None` to remove it
+    operations: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.operations
+            .push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) {
+        self.operations.push((key.as_ref().to_vec(), None));
+    }
+}
+
+/// A key-value tree of a storage backend, with keys ordered by their bytes
+pub trait TreeStore: Send + Sync {
+    fn get(&self, key: &[u8]) -> color_eyre::Result<Option<Vec<u8>>>;
+    fn insert(&self, key: &[u8], value: &[u8]) -> color_eyre::Result<()>;
+    /// Removes a key, returning its value
+    fn remove(&self, key: &[u8]) -> color_eyre::Result<Option<Vec<u8>>>;
+    /// Entries whose key starts with `prefix` (all of them for an empty prefix)
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries;
+    fn len(&self) -> color_eyre::Result<usize>;
+    fn is_empty(&self) -> color_eyre::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    fn clear(&self) -> color_eyre::Result<()>;
+    fn apply_batch(&self, batch: Batch) -> color_eyre::Result<()>;
+    fn flush(&self) -> color_eyre::Result<()>;
+}
+
+/// A storage backend: named key-value trees
+pub trait Store: Send + Sync {
+    /// Opens a tree, making it if it doesn't exist yet
+    fn open_tree(&self, name: &str) -> color_eyre::Result<Tree>;
+    /// Names of the trees, starting with `DEFAULT_TREE`
+    fn tree_names(&self) -> color_eyre::Result<Vec<String>>;
+    /// Makes sure everything written so far is on disk
+    fn flush(&self) -> color_eyre::Result<()>;
+}
+
+/// A tree of the database, whichever the backend
+#[derive(Clone)]
+pub struct Tree(Arc<dyn TreeStore>);
+
+impl Tree {
+    pub fn get(&self, key: impl AsRef<[u8]>) -> color_eyre::Result<Option<Vec<u8>>> {
+        self.0.get(key.as_ref())
+    }
+
+    pub fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> color_eyre::Result<()> {
+        self.0.insert(key.as_ref(), value.as_ref())
+    }
+
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> color_eyre::Result<Option<Vec<u8>>> {
+        self.0.remove(key.as_ref())
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> color_eyre::Result<bool> {
+        Ok(self.0.get(key.as_ref())?.is_some())
+    }
+
+    /// Appends a value to the values of a key (with `merge_index`)
+    pub fn merge(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> color_eyre::Result<()> {
+        let key = key.as_ref();
+        if let Some(merged) = merge_index(key, self.0.get(key)?.as_deref(), value.as_ref()) {
+            self.0.insert(key, &merged)?;
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> Entries {
+        self.0.scan_prefix(&[])
+    }
+
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Entries {
+        self.0.scan_prefix(prefix.as_ref())
+    }
+
+    pub fn len(&self) -> color_eyre::Result<usize> {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> color_eyre::Result<bool> {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&self) -> color_eyre::Result<()> {
+        self.0.clear()
+    }
+
+    pub fn apply_batch(&self, batch: Batch) -> color_eyre::Result<()> {
+        self.0.apply_batch(batch)
+    }
+
+    pub fn flush(&self) -> color_eyre::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl TreeStore for sled::Tree {
+    fn get(&self, key: &[u8]) -> color_eyre::Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> color_eyre::Result<()> {
+        sled::Tree::insert(self, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> color_eyre::Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::remove(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries {
+        Entries(Box::new(sled::Tree::scan_prefix(self, prefix).map(
+            |entry| {
+                entry
+                    .map(|(key, value)| (key.to_vec(), value.to_vec()))
+                    .map_err(color_eyre::Report::from)
+            },
+        )))
+    }
+
+    fn len(&self) -> color_eyre::Result<usize> {
+        Ok(sled::Tree::len(self))
+    }
+
+    fn clear(&self) -> color_eyre::Result<()> {
+        sled::Tree::clear(self)?;
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: Batch) -> color_eyre::Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        for (key, value) in batch.operations {
+            match value {
+                Some(value) => sled_batch.insert(key, value),
+                None => sled_batch.remove(key),
+            }
+        }
+        sled::Tree::apply_batch(self, sled_batch)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> color_eyre::Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+}
+
+impl Store for sled::Db {
+    fn open_tree(&self, name: &str) -> color_eyre::Result<Tree> {
+        let tree = if name == DEFAULT_TREE {
+            (**self).clone()
+        } else {
+            sled::Db::open_tree(self, name)?
+        };
+        Ok(Tree(Arc::new(tree)))
+    }
+
+    fn tree_names(&self) -> color_eyre::Result<Vec<String>> {
+        sled::Db::tree_names(self)
+            .into_iter()
+            .map(|name| {
+                let name = String::from_utf8(name.to_vec())?;
+                Ok(if name == SLED_DEFAULT_TREE {
+                    DEFAULT_TREE.to_owned()
+                } else {
+                    name
+                })
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> color_eyre::Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+}
+
+/// SQLite database, with a table (of `key` and `value` columns) per tree.
+/// Keys and values are stored as text when they're UTF-8, so that the tables can be queried directly
+#[cfg(feature = "sqlite")]
+struct SqliteStore {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+}
+
+/// A table of the SQLite database
+#[cfg(feature = "sqlite")]
+struct SqliteTree {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+    /// Quoted table name
+    table: String,
+}
+
+/// Text for UTF-8 bytes, and a blob otherwise
+#[cfg(feature = "sqlite")]
+fn sql_value(bytes: &[u8]) -> rusqlite::types::Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => rusqlite::types::Value::Text(text.to_owned()),
+        Err(_) => rusqlite::types::Value::Blob(bytes.to_vec()),
+    }
+}
+
+/// Bytes of a column, whatever its type
+#[cfg(feature = "sqlite")]
+fn sql_bytes(value: rusqlite::types::ValueRef) -> Vec<u8> {
+    use rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => bytes.to_vec(),
+        ValueRef::Integer(number) => number.to_string().into_bytes(),
+        ValueRef::Real(number) => number.to_string().into_bytes(),
+        ValueRef::Null => Vec::new(),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteTree {
+    fn connection(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl TreeStore for SqliteTree {
+    fn get(&self, key: &[u8]) -> color_eyre::Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+        Ok(self
+            .connection()
+            .query_row(
+                &format!("SELECT value FROM {} WHERE key = ?1", self.table),
+                [sql_value(key)],
+                |row| Ok(sql_bytes(row.get_ref(0)?)),
+            )
+            .optional()?)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> color_eyre::Result<()> {
+        self.connection().execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                self.table
+            ),
+            [sql_value(key), sql_value(value)],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> color_eyre::Result<Option<Vec<u8>>> {
+        let value = self.get(key)?;
+        if value.is_some() {
+            self.connection().execute(
+                &format!("DELETE FROM {} WHERE key = ?1", self.table),
+                [sql_value(key)],
+            )?;
+        }
+        Ok(value)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Entries {
+        let entries = || -> color_eyre::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let connection = self.connection();
+            let mut statement = connection.prepare(&format!(
+                "SELECT key, value FROM {} WHERE substr(CAST(key AS BLOB), 1, ?1) = ?2 ORDER BY CAST(key AS BLOB)",
+                self.table
+            ))?;
+            let rows = statement
+                .query_map(rusqlite::params![prefix.len() as i64, prefix], |row| {
+                    Ok((sql_bytes(row.get_ref(0)?), sql_bytes(row.get_ref(1)?)))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        };
+        match entries() {
+            Ok(entries) => Entries(Box::new(entries.into_iter().map(Ok))),
+            Err(error) => Entries(Box::new(std::iter::once(Err(error)))),
+        }
+    }
+
+    fn len(&self) -> color_eyre::Result<usize> {
+        let count: i64 = self.connection().query_row(
+            &format!("SELECT COUNT(*) FROM {}", self.table),
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn clear(&self) -> color_eyre::Result<()> {
+        self.connection()
+            .execute(&format!("DELETE FROM {}", self.table), [])?;
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: Batch) -> color_eyre::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for (key, value) in batch.operations {
+            match value {
+                Some(value) => transaction.execute(
+                    &format!(
+                        "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                        self.table
+                    ),
+                    [sql_value(&key), sql_value(&value)],
+                )?,
+                None => transaction.execute(
+                    &format!("DELETE FROM {} WHERE key = ?1", self.table),
+                    [sql_value(&key)],
+                )?,
+            };
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> color_eyre::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    fn open(path: &Path) -> color_eyre::Result<Self> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Opens the database file for reading only (its tables can't be made or changed)
+    fn open_read_only(path: &Path) -> color_eyre::Result<Self> {
+        let connection = rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?;
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Store for SqliteStore {
+    fn open_tree(&self, name: &str) -> color_eyre::Result<Tree> {
+        let table = format!("\"{}\"", name.replace('"', "\"\""));
+        self.connection
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key PRIMARY KEY, value NOT NULL)",
+                    table
+                ),
+                [],
+            )?;
+        Ok(Tree(Arc::new(SqliteTree {
+            connection: self.connection.clone(),
+            table,
+        })))
+    }
+
+    fn tree_names(&self) -> color_eyre::Result<Vec<String>> {
+        let connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let mut statement = connection.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?;
+        let mut names = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        names.sort_by_key(|name| name != DEFAULT_TREE);
+        Ok(names)
+    }
+
+    /// Moves everything from the write-ahead log into the database file
+    fn flush(&self) -> color_eyre::Result<()> {
+        self.connection
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+}
+
+/// Whether there's a `sled` database in the folder
+fn has_sled(db_dir: &Path) -> bool {
+    db_dir.join("conf").exists()
+}
+
+/// Deletes the files of a `sled` database (leaving the search index and the SQLite database)
+#[cfg(feature = "sqlite")]
+fn remove_sled(db_dir: &Path) -> color_eyre::Result<()> {
+    for entry in fs::read_dir(db_dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if name == "blobs" {
+            fs::remove_dir_all(path)?;
+        } else if name == "conf" || name == "db" || name.starts_with("snap.") {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies every tree of one backend into another
+#[cfg(feature = "sqlite")]
+fn copy_trees(from: &dyn Store, to: &dyn Store) -> color_eyre::Result<()> {
+    for name in from.tree_names()? {
+        let mut batch = Batch::default();
+        for entry in from.open_tree(&name)?.iter() {
+            let (key, value) = entry?;
+            batch.insert(key, value);
+        }
+        to.open_tree(&name)?.apply_batch(batch)?;
+    }
+    to.flush()
+}
+
+/// Backend of the database in the folder if it isn't `backend`, i.e. `db_backend` was changed since it was made.
+/// `Gooseberry::new` moves it over (with `copy_database` and `remove_database`) before opening it
+pub fn other_backend(db_dir: &Path, backend: DbBackend) -> Option<DbBackend> {
+    let has_sqlite = db_dir.join(SQLITE_FILE).exists();
+    match backend {
+        DbBackend::Sled if cfg!(feature = "sqlite") && has_sqlite && !has_sled(db_dir) => {
+            Some(DbBackend::Sqlite)
+        }
+        DbBackend::Sqlite if cfg!(feature = "sqlite") && !has_sqlite && has_sled(db_dir) => {
+            Some(DbBackend::Sled)
+        }
+        _ => None,
+    }
+}
+
+/// Copies a database into a new one of the `to` backend in the folder.
+///
+/// The copy is made under a temporary name and only renamed into place once it's complete,
+/// so that an interrupted copy is never opened as the database (and is started over the next time)
+#[cfg(feature = "sqlite")]
+pub fn copy_database(from: &dyn Store, db_dir: &Path, to: DbBackend) -> color_eyre::Result<()> {
+    match to {
+        DbBackend::Sled => {
+            let partial = db_dir.join("sled.partial");
+            if partial.exists() {
+                fs::remove_dir_all(&partial)?;
+            }
+            // left by an interrupted copy, without the `conf` file marking a sled database
+            remove_sled(db_dir)?;
+            {
+                let db = sled::open(&partial)?;
+                copy_trees(from, &db)?;
+            }
+            for entry in fs::read_dir(&partial)? {
+                let path = entry?.path();
+                if let Some(name) = path.file_name().filter(|name| *name != "conf") {
+                    fs::rename(&path, db_dir.join(name))?;
+                }
+            }
+            // last, since the folder has a sled database once it's there
+            fs::rename(partial.join("conf"), db_dir.join("conf"))?;
+            fs::remove_dir(partial)?;
+        }
+        DbBackend::Sqlite => {
+            let partial = db_dir.join(format!("{}.partial", SQLITE_FILE));
+            for suffix in &["", "-wal", "-shm"] {
+                let path = db_dir.join(format!("{}.partial{}", SQLITE_FILE, suffix));
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            {
+                let store = SqliteStore::open(&partial)?;
+                copy_trees(from, &store)?;
+            }
+            fs::rename(partial, db_dir.join(SQLITE_FILE))?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the files of the database of a backend from the folder (leaving the search index, and the
+/// database of the other backend)
+#[cfg(feature = "sqlite")]
+pub fn remove_database(db_dir: &Path, backend: DbBackend) -> color_eyre::Result<()> {
+    match backend {
+        DbBackend::Sled => remove_sled(db_dir),
+        DbBackend::Sqlite => {
+            for suffix in &["", "-wal", "-shm"] {
+                let path = db_dir.join(format!("{}{}", SQLITE_FILE, suffix));
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Opens (or makes) the database in the folder with the given backend.
+///
+/// A database left by the other backend (after changing `db_backend`, see `other_backend`) isn't moved over
+pub fn open(db_dir: &Path, backend: DbBackend) -> color_eyre::Result<Box<dyn Store>> {
+    match backend {
+        DbBackend::Sled => {
+            #[cfg(not(feature = "sqlite"))]
+            if db_dir.join(SQLITE_FILE).exists() && !has_sled(db_dir) {
+                return Err(Apologize::ConfigError {
+                    message: "The database is in SQLite, but gooseberry was built without the sqlite feature".into(),
+                })
+                .suggestion("Build gooseberry with the sqlite feature to move the database over to sled");
+            }
+            Ok(Box::new(sled::open(db_dir)?))
+        }
+        #[cfg(feature = "sqlite")]
+        DbBackend::Sqlite => {
+            fs::create_dir_all(db_dir)?;
+            Ok(Box::new(SqliteStore::open(&db_dir.join(SQLITE_FILE))?))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        DbBackend::Sqlite => Err(Apologize::ConfigError {
+            message: "db_backend is sqlite, but gooseberry was built without the sqlite feature"
+                .into(),
+        })
+        .suggestion("Build gooseberry with the sqlite feature, or set db_backend to sled"),
+    }
+}
+
+/// Opens the database in the folder with the given backend for reading, e.g. for completion.
+/// Unlike `open`, it never makes a database or moves one over from the other backend: it's `None` if there's
+/// no database of this backend in the folder.
+///
+/// (`sled` can't open a database read-only, so a `sled` database is opened as usual, the caller just doesn't
+/// write to it)
+pub fn open_read_only(
+    db_dir: &Path,
+    backend: DbBackend,
+) -> color_eyre::Result<Option<Box<dyn Store>>> {
+    match backend {
+        DbBackend::Sled if has_sled(db_dir) => Ok(Some(Box::new(sled::open(db_dir)?))),
+        #[cfg(feature = "sqlite")]
+        DbBackend::Sqlite if db_dir.join(SQLITE_FILE).exists() => Ok(Some(Box::new(
+            SqliteStore::open_read_only(&db_dir.join(SQLITE_FILE))?,
+        ))),
+        _ => Ok(None),
+    }
+}
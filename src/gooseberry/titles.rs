@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use futures::stream::{self, StreamExt};
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::archive::quote_context;
+use crate::gooseberry::knowledge_base::{
+    document_title, pdf_fingerprint, AnnotationTemplate, PDF_URN,
+};
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Number of pages fetched at the same time
+pub(crate) const CONCURRENT_FETCHES: usize = 8;
+
+/// Time to wait for an annotated page
+pub(crate) const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client for fetching annotated pages
+pub(crate) fn http_client(timeout: Duration) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .user_agent(concat!("gooseberry/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+/// Finds the contents of the first `<title>` tag of an HTML page
+fn parse_title(html: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets the same
+    let lowercase = html.to_ascii_lowercase();
+    let start = lowercase.find("<title")?;
+    let start = start + lowercase[start..].find('>')? + 1;
+    let end = start + lowercase[start..].find("</title")?;
+    let title = utils::decode_entities(
+        &html[start..end]
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+/// Fetches the title of a web page.
+/// Only connection errors are errors, pages without a title (or which don't exist) give `None`
+async fn fetch_title(client: &reqwest::Client, uri: &str) -> reqwest::Result<Option<String>> {
+    let response = client.get(uri).send().await?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    Ok(parse_title(&response.text().await?))
+}
+
+/// ## Titles
+/// Titles of annotated pages, cached in the URI: title tree during sync
+impl Gooseberry {
+    /// Caches the titles of annotated URIs, using the document title from Hypothesis if there is one.
+    /// Otherwise the `<title>` of web pages without a cached title is fetched (unless `fetch_titles = false`).
+    ///
+    /// Pages without a title are cached with an empty one so that they aren't fetched again on every sync,
+    /// pages which couldn't be reached are tried again next time
+    pub(crate) async fn cache_titles(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        let titles = self.uri_to_title()?;
+        let mut to_fetch = HashSet::new();
+        for annotation in annotations {
+            if let Some(title) = document_title(annotation) {
+                titles.insert(annotation.uri.as_bytes(), title.as_bytes())?;
+            } else if !titles.contains_key(annotation.uri.as_bytes())? {
+                if annotation.uri.starts_with("http") {
+                    to_fetch.insert(annotation.uri.as_str());
+                } else {
+                    titles.insert(annotation.uri.as_bytes(), "")?;
+                }
+            }
+        }
+        if to_fetch.is_empty() || !self.config.fetch_titles.unwrap_or(true) {
+            return Ok(());
+        }
+        let client = http_client(FETCH_TIMEOUT)?;
+        let fetched: Vec<_> = stream::iter(to_fetch)
+            .map(|uri| {
+                let client = &client;
+                async move { (uri, fetch_title(client, uri).await) }
+            })
+            .buffer_unordered(CONCURRENT_FETCHES)
+            .collect()
+            .await;
+        for (uri, title) in fetched {
+            if let Ok(title) = title {
+                titles.insert(uri.as_bytes(), title.unwrap_or_default().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cached title of a URI, if it has one
+    pub(crate) fn get_title(&self, uri: &str) -> color_eyre::Result<Option<String>> {
+        Ok(self
+            .uri_to_title()?
+            .get(uri.as_bytes())?
+            .map(|title| String::from_utf8_lossy(&title).to_string())
+            .filter(|title| !title.is_empty()))
+    }
+
+    /// Title of an annotation's document: the title registered for its PDF fingerprint,
+    /// the title Hypothesis has for it, or the cached page title (in that order of preference)
+    pub(crate) fn resolved_title(
+        &self,
+        annotation: &Annotation,
+    ) -> color_eyre::Result<Option<String>> {
+        if let Some(fingerprint) = pdf_fingerprint(annotation) {
+            if let Some(title) = self.get_pdf_title(fingerprint)? {
+                return Ok(Some(title));
+            }
+        }
+        match document_title(annotation) {
+            Some(title) => Ok(Some(title.to_owned())),
+            None => self.get_title(&annotation.uri),
+        }
+    }
+
+    /// Converts an annotation for the templates, with its resolved title (see `resolved_title`)
+    /// and the archived text and Wayback Machine snapshot of the page if there are any
+    pub(crate) fn annotation_template(
+        &self,
+        annotation: Annotation,
+    ) -> color_eyre::Result<AnnotationTemplate> {
+        let mut template = AnnotationTemplate::from_annotation(annotation);
+        template.mine = template.annotation.user == self.api.user;
+        if template.display_name.is_none() {
+            template.display_name = self.display_name(&template.annotation.user.0)?;
+        }
+        if let Some(title) = self.resolved_title(&template.annotation)? {
+            template.title = title;
+        }
+        template.archive_url = self.get_snapshot(&template.annotation.uri)?;
+        template.related = self.get_links(&template.annotation.id)?;
+        template.meta = self.get_meta(&template.annotation.id)?;
+        if let Some(page_text) = self.get_archive(&template.annotation.uri)? {
+            template.context = template
+                .highlight
+                .first()
+                .and_then(|quote| quote_context(&page_text, quote));
+            template.page_text = Some(page_text);
+        }
+        Ok(template)
+    }
+
+    /// Annotated URIs along with their titles (if known), sorted by URI
+    pub(crate) fn uri_titles(
+        &self,
+        annotations: Vec<Annotation>,
+    ) -> color_eyre::Result<BTreeMap<String, Option<String>>> {
+        let mut uri_titles = BTreeMap::new();
+        for annotation in annotations {
+            if uri_titles.contains_key(&annotation.uri) {
+                continue;
+            }
+            let title = self.resolved_title(&annotation)?;
+            uri_titles.insert(annotation.uri, title);
+        }
+        Ok(uri_titles)
+    }
+}
+
+/// Fingerprint URN of a PDF, with or without the `urn:x-pdf:` prefix
+fn fingerprint_urn(fingerprint: &str) -> String {
+    let fingerprint = fingerprint.trim();
+    if fingerprint.starts_with(PDF_URN) {
+        fingerprint.to_owned()
+    } else {
+        format!("{}{}", PDF_URN, fingerprint)
+    }
+}
+
+/// ## PDF titles
+/// Friendly titles for PDFs, registered by fingerprint in the fingerprint: title tree.
+/// The fingerprint is the same for every copy of a PDF, so annotations made on local files
+/// and online copies end up on the same page
+impl Gooseberry {
+    /// Title registered for a PDF fingerprint, if there is one
+    pub(crate) fn get_pdf_title(&self, fingerprint: &str) -> color_eyre::Result<Option<String>> {
+        Ok(self
+            .fingerprint_to_title()?
+            .get(fingerprint_urn(fingerprint).as_bytes())?
+            .map(|title| String::from_utf8_lossy(&title).to_string()))
+    }
+
+    /// Registers a title for a PDF fingerprint, or removes the registered title if `title` is `None`
+    pub fn set_pdf_title(
+        &self,
+        fingerprint: &str,
+        title: Option<String>,
+    ) -> color_eyre::Result<()> {
+        let fingerprint = fingerprint_urn(fingerprint);
+        let titles = self.fingerprint_to_title()?;
+        match title {
+            Some(title) => {
+                titles.insert(fingerprint.as_bytes(), title.trim().as_bytes())?;
+                println!("{} is now titled {:?}", fingerprint, title.trim());
+            }
+            None => {
+                titles.remove(fingerprint.as_bytes())?;
+                println!("Removed the title of {}", fingerprint);
+            }
+        }
+        titles.flush()?;
+        Ok(())
+    }
+
+    /// Prints the fingerprint, resolved title, number of annotations, and URIs of each annotated PDF
+    pub fn list_pdfs(&self, annotations: Vec<Annotation>) -> color_eyre::Result<()> {
+        let mut pdfs: BTreeMap<String, (usize, BTreeMap<String, Option<String>>)> = BTreeMap::new();
+        for annotation in &annotations {
+            if let Some(fingerprint) = pdf_fingerprint(annotation) {
+                let (count, uris) = pdfs.entry(fingerprint.to_owned()).or_default();
+                *count += 1;
+                if !uris.contains_key(&annotation.uri) {
+                    uris.insert(annotation.uri.to_owned(), self.resolved_title(annotation)?);
+                }
+            }
+        }
+        if pdfs.is_empty() {
+            println!("No annotated PDFs");
+        }
+        for (fingerprint, (count, uris)) in pdfs {
+            let title = match self.get_pdf_title(&fingerprint)? {
+                Some(title) => title,
+                None => format!(
+                    "{} (not registered)",
+                    uris.values()
+                        .flatten()
+                        .next()
+                        .map_or("Untitled document", String::as_str)
+                ),
+            };
+            println!("{}\t{}\t{} annotation(s)", fingerprint, title, count);
+            for uri in uris.keys() {
+                println!("  {}", uri);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::cli::Filters;
+use crate::gooseberry::dry_run;
+use crate::gooseberry::knowledge_base::AnnotationTemplate;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// A task (an annotation with the todo tag) for the agenda in the index template
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TodoItem {
+    pub id: String,
+    pub text: String,
+    /// First highlight of the annotation
+    pub quote: String,
+    pub title: String,
+    pub uri: String,
+    pub incontext: String,
+    pub created: DateTime<Utc>,
+    /// Time since the annotation was made, e.g. `3d`
+    pub age: String,
+    /// Path of the knowledge base page with the annotation, relative to the index file
+    pub relative_path: Option<String>,
+}
+
+/// Time since a date, in the largest unit that fits: minutes, hours, days, weeks, months, or years
+pub(crate) fn age(created: DateTime<Utc>) -> String {
+    let elapsed = Utc::now() - created;
+    let days = elapsed.num_days();
+    if elapsed.num_hours() < 1 {
+        format!("{}m", elapsed.num_minutes().max(0))
+    } else if days < 1 {
+        format!("{}h", elapsed.num_hours())
+    } else if days < 14 {
+        format!("{}d", days)
+    } else if days < 60 {
+        format!("{}w", days / 7)
+    } else if days < 365 {
+        format!("{}mo", days / 30)
+    } else {
+        format!("{}y", days / 365)
+    }
+}
+
+/// ## Tasks
+/// Annotations with the todo tag are tasks, a reading queue of sorts:
+/// they're done once the todo tag is swapped for the done tag, and can be snoozed until later
+impl Gooseberry {
+    /// Time until which a task is snoozed, if it is
+    fn snoozed_until(&self, id: &str) -> color_eyre::Result<Option<DateTime<Utc>>> {
+        match self.todo_snoozed()?.get(id)? {
+            Some(until) => Ok(Some(
+                DateTime::parse_from_rfc3339(std::str::from_utf8(&until)?)?.with_timezone(&Utc),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a task is snoozed right now
+    fn is_snoozed(&self, id: &str) -> color_eyre::Result<bool> {
+        Ok(matches!(self.snoozed_until(id)?, Some(until) if until > Utc::now()))
+    }
+
+    /// Tasks matching the filters, oldest first
+    async fn tasks(&self, mut filters: Filters) -> color_eyre::Result<Vec<Annotation>> {
+        filters.tags.push(self.config.todo_tag().to_owned());
+        let mut annotations = self.filter_annotations(filters, None).await?;
+        annotations.sort_by_key(|a| a.created);
+        Ok(annotations)
+    }
+
+    /// Prints the age, ID, quote or text, and title of each (optionally filtered) task, oldest first.
+    /// Snoozed tasks are left out unless `all` is set
+    pub async fn list_todos(&self, filters: Filters, all: bool) -> color_eyre::Result<()> {
+        let mut listed = 0;
+        for annotation in self.tasks(filters).await? {
+            let snoozed = self
+                .snoozed_until(&annotation.id)?
+                .filter(|until| until > &Utc::now());
+            if snoozed.is_some() && !all {
+                continue;
+            }
+            let quote = utils::get_quotes(&annotation).join(" ");
+            let summary = if annotation.text.trim().is_empty() {
+                &quote
+            } else {
+                &annotation.text
+            };
+            let title = self
+                .resolved_title(&annotation)?
+                .unwrap_or_else(|| annotation.uri.to_owned());
+            let snoozed = match snoozed {
+                Some(until) => format!(" (snoozed until {})", until.format("%Y-%m-%d")),
+                None => String::new(),
+            };
+            println!(
+                "{:>4}\t{}\t{}\t{}{}",
+                age(annotation.created),
+                annotation.id,
+                summary.replace('\n', " "),
+                title,
+                snoozed
+            );
+            listed += 1;
+        }
+        if listed == 0 {
+            println!("Nothing to do");
+        }
+        Ok(())
+    }
+
+    /// Marks tasks as done, swapping the todo tag for the done tag in Hypothesis
+    pub async fn done_todos(&self, ids: Vec<String>) -> color_eyre::Result<()> {
+        let (todo_tag, done_tag) = (self.config.todo_tag(), self.config.done_tag());
+        let annotations = self
+            .tasks(Filters {
+                ids,
+                ..Filters::default()
+            })
+            .await?;
+        if annotations.is_empty() {
+            println!("No matching tasks (annotations tagged {:?})", todo_tag);
+            return Ok(());
+        }
+        let updated: Vec<_> = annotations
+            .iter()
+            .cloned()
+            .map(|mut a| {
+                a.tags.retain(|t| t != todo_tag);
+                if !a.tags.iter().any(|t| t == done_tag) {
+                    a.tags.push(done_tag.to_owned());
+                }
+                a
+            })
+            .collect();
+        if self.dry_run {
+            dry_run::print_updates(&annotations, &updated);
+            return Ok(());
+        }
+        let updated = self.skip_conflicts(&updated).await?;
+        println!("Marking {} task(s) as done", updated.len());
+        self.send_updates(&updated).await?;
+        let snoozed = self.todo_snoozed()?;
+        for annotation in &updated {
+            snoozed.remove(&annotation.id)?;
+        }
+        self.sync().await?;
+        Ok(())
+    }
+
+    /// Hides tasks from `todo list` and the agenda in the index for a while
+    pub async fn snooze_todos(
+        &self,
+        ids: Vec<String>,
+        duration: Duration,
+    ) -> color_eyre::Result<()> {
+        let annotations = self
+            .tasks(Filters {
+                ids,
+                ..Filters::default()
+            })
+            .await?;
+        if annotations.is_empty() {
+            println!(
+                "No matching tasks (annotations tagged {:?})",
+                self.config.todo_tag()
+            );
+            return Ok(());
+        }
+        let until = Utc::now() + chrono::Duration::from_std(duration)?;
+        if self.dry_run {
+            for annotation in &annotations {
+                println!(
+                    "Would snooze {} until {}",
+                    annotation.id,
+                    until.format("%Y-%m-%d %H:%M")
+                );
+            }
+            return Ok(());
+        }
+        let snoozed = self.todo_snoozed()?;
+        for annotation in &annotations {
+            snoozed.insert(annotation.id.as_bytes(), until.to_rfc3339().as_bytes())?;
+        }
+        println!(
+            "Snoozed {} task(s) until {}",
+            annotations.len(),
+            until.format("%Y-%m-%d %H:%M")
+        );
+        Ok(())
+    }
+
+    /// Tasks among the knowledge base annotations which aren't snoozed, oldest first,
+    /// for the `todos` of the index template
+    pub(crate) fn todo_items(
+        &self,
+        annotations: &[AnnotationTemplate],
+    ) -> color_eyre::Result<Vec<TodoItem>> {
+        let todo_tag = self.config.todo_tag();
+        let mut todos = Vec::new();
+        for a in annotations {
+            if !a.annotation.tags.iter().any(|t| t == todo_tag)
+                || self.is_snoozed(&a.annotation.id)?
+            {
+                continue;
+            }
+            todos.push(TodoItem {
+                id: a.annotation.id.to_owned(),
+                text: a.annotation.text.to_owned(),
+                quote: a.highlight.first().cloned().unwrap_or_default(),
+                title: a.title.to_owned(),
+                uri: a.annotation.uri.to_owned(),
+                incontext: a.incontext.to_owned(),
+                created: a.annotation.created,
+                age: age(a.annotation.created),
+                relative_path: None,
+            });
+        }
+        todos.sort_by_key(|todo| todo.created);
+        Ok(todos)
+    }
+}
@@ -0,0 +1,112 @@
+use hypothesis::annotations::{Annotation, InputAnnotation, Target};
+
+use crate::errors::Apologize;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// The annotation as it's sent to Hypothesis to create it again (with a new ID)
+pub(crate) fn input_annotation(annotation: Annotation) -> color_eyre::Result<InputAnnotation> {
+    let target = match annotation.target.first() {
+        Some(target) => target.clone(),
+        None => Target::builder().source(&annotation.uri).build()?,
+    };
+    Ok(InputAnnotation {
+        uri: annotation.uri,
+        text: annotation.text,
+        tags: Some(annotation.tags),
+        document: annotation.document,
+        group: annotation.group,
+        target,
+        references: annotation.references,
+    })
+}
+
+/// ## Trash
+/// Annotations deleted from Hypothesis are kept in the trash tree so that they can be re-created
+impl Gooseberry {
+    /// Stores the full annotations in the trash tree, before deleting them,
+    /// and records their deletion for the next `sync --diff`
+    pub(crate) fn trash_annotations(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        let trash = self.trash()?;
+        let deleted = self.deleted_since_sync()?;
+        for annotation in annotations {
+            trash.insert(annotation.id.as_bytes(), serde_json::to_vec(annotation)?)?;
+            deleted.insert(annotation.id.as_bytes(), [])?;
+        }
+        trash.flush()?;
+        Ok(())
+    }
+
+    /// Annotations in the trash, oldest first
+    pub(crate) fn get_trash(&self) -> color_eyre::Result<Vec<Annotation>> {
+        let mut annotations = self
+            .trash()?
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect::<color_eyre::Result<Vec<Annotation>>>()?;
+        annotations.sort_by_key(|a| a.created);
+        Ok(annotations)
+    }
+
+    /// Prints the ID, creation date, URI, and quote or text of each annotation in the trash
+    pub fn list_trash(&self) -> color_eyre::Result<()> {
+        let annotations = self.get_trash()?;
+        if annotations.is_empty() {
+            println!("The trash is empty");
+        }
+        for annotation in annotations {
+            let quote = utils::get_quotes(&annotation).join(" ");
+            let summary = if quote.trim().is_empty() {
+                &annotation.text
+            } else {
+                &quote
+            };
+            println!(
+                "{}\t{}\t{}\t{}",
+                annotation.id,
+                annotation.created.format("%Y-%m-%d"),
+                annotation.uri,
+                summary.replace('\n', " ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Re-creates annotations from the trash on Hypothesis (they get new IDs, and keep their sharing)
+    /// and syncs them back
+    pub async fn restore(&self, ids: Vec<String>) -> color_eyre::Result<()> {
+        let trash = self.trash()?;
+        let num_ids = ids.len();
+        for id in ids {
+            let annotation: Annotation = match trash.get(id.as_bytes())? {
+                Some(value) => serde_json::from_slice(&value)?,
+                None => return Err(Apologize::AnnotationNotFound { id }.into()),
+            };
+            if self.dry_run {
+                println!(
+                    "POST /api/annotations (restore of {} from {})",
+                    id, annotation.uri
+                );
+                continue;
+            }
+            let permissions = annotation.permissions.clone();
+            let restored = self
+                .api
+                .create_annotation_with_permissions(&input_annotation(annotation)?, &permissions)
+                .await?;
+            trash.remove(id.as_bytes())?;
+            println!("Restored {} as {}", id, restored.id);
+        }
+        if self.dry_run {
+            println!(
+                "Would restore {} annotation(s) from the trash to Hypothesis (dry run)",
+                num_ids
+            );
+            return Ok(());
+        }
+        trash.flush()?;
+        self.sync().await?;
+        Ok(())
+    }
+}
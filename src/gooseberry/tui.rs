@@ -0,0 +1,601 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use handlebars::Handlebars;
+use hypothesis::annotations::Annotation;
+use skim::AnsiString;
+use tuikit::prelude::{
+    Attr, Canvas, Draw, DrawResult, Effect, Event as TermEvent, HSplit, Key, Size, Term, VSplit,
+    Widget, Win,
+};
+
+use crate::gooseberry::cli::Filters;
+use crate::gooseberry::preview::markdown_to_ansi;
+use crate::gooseberry::Gooseberry;
+use crate::utils;
+
+/// Lines moved by PageUp / PageDown
+const PAGE: usize = 10;
+
+const HELP: &str =
+    "Tab: switch pane  Space: mark  t: add tags  u: remove tags  d: delete  e: edit  o: open  s: sync  q: quit";
+
+/// Levels of a tag, split by the tag delimiter if there is one
+fn tag_levels(tag: &str, delimiter: Option<&str>) -> Vec<String> {
+    let levels: Vec<String> = match delimiter {
+        Some(delimiter) => tag
+            .split(delimiter)
+            .map(str::trim)
+            .filter(|level| !level.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        None => Vec::new(),
+    };
+    if levels.is_empty() {
+        vec![tag.to_owned()]
+    } else {
+        levels
+    }
+}
+
+/// Keeps the cursor in view, returning the first row to show
+fn scroll(offset: &Cell<usize>, cursor: usize, height: usize) -> usize {
+    let mut first = offset.get();
+    if cursor < first {
+        first = cursor;
+    } else if height > 0 && cursor >= first + height {
+        first = cursor + 1 - height;
+    }
+    offset.set(first);
+    first
+}
+
+/// Cuts a line to the width of a pane, padding it so that highlighting covers the whole row
+fn fit(line: &str, width: usize) -> String {
+    let fitted: String = line.chars().take(width).collect();
+    format!("{:width$}", fitted, width = width)
+}
+
+/// A row of the tag pane: a tag (or a level of a hierarchical tag) and its number of annotations
+struct TagRow {
+    /// Empty for the row with all annotations
+    levels: Vec<String>,
+    count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Tags,
+    Annotations,
+}
+
+/// What the input line is asking for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    AddTags,
+    RemoveTags,
+    Delete,
+}
+
+/// Actions run on the marked (or highlighted) annotations, outside the dashboard's key handling
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Tag { tags: Vec<String>, delete: bool },
+    Delete,
+    Edit,
+    Open,
+    Sync,
+    Quit,
+}
+
+/// State of the dashboard
+struct Dashboard {
+    annotations: Vec<Annotation>,
+    /// Annotation ID: annotation rendered with the annotation template
+    markdown: HashMap<String, String>,
+    delimiter: Option<String>,
+    tag_rows: Vec<TagRow>,
+    tag_cursor: usize,
+    /// Indices of the annotations with the selected tag
+    shown: Vec<usize>,
+    cursor: usize,
+    marked: HashSet<String>,
+    focus: Pane,
+    preview_scroll: usize,
+    input: Option<(Prompt, String)>,
+    status: String,
+    tag_offset: Cell<usize>,
+    list_offset: Cell<usize>,
+}
+
+impl Dashboard {
+    fn new(delimiter: Option<String>) -> Self {
+        Dashboard {
+            annotations: Vec::new(),
+            markdown: HashMap::new(),
+            delimiter,
+            tag_rows: Vec::new(),
+            tag_cursor: 0,
+            shown: Vec::new(),
+            cursor: 0,
+            marked: HashSet::new(),
+            focus: Pane::Annotations,
+            preview_scroll: 0,
+            input: None,
+            status: String::new(),
+            tag_offset: Cell::new(0),
+            list_offset: Cell::new(0),
+        }
+    }
+
+    /// Replaces the annotations, keeping the selected tag and annotation if they're still there
+    fn set_annotations(&mut self, annotations: Vec<Annotation>, markdown: HashMap<String, String>) {
+        let selected_tag = self
+            .tag_rows
+            .get(self.tag_cursor)
+            .map(|row| row.levels.clone())
+            .unwrap_or_default();
+        let selected_id = self.current().map(|a| a.id.to_owned());
+
+        let mut counts: BTreeMap<Vec<String>, usize> = BTreeMap::new();
+        for annotation in &annotations {
+            let mut paths = HashSet::new();
+            for tag in &annotation.tags {
+                let levels = tag_levels(tag, self.delimiter.as_deref());
+                for depth in 1..=levels.len() {
+                    paths.insert(levels[..depth].to_vec());
+                }
+            }
+            for path in paths {
+                *counts.entry(path).or_default() += 1;
+            }
+        }
+        self.tag_rows = vec![TagRow {
+            levels: Vec::new(),
+            count: annotations.len(),
+        }];
+        self.tag_rows.extend(
+            counts
+                .into_iter()
+                .map(|(levels, count)| TagRow { levels, count }),
+        );
+        self.tag_cursor = self
+            .tag_rows
+            .iter()
+            .position(|row| row.levels == selected_tag)
+            .unwrap_or(0);
+        self.marked
+            .retain(|id| annotations.iter().any(|a| &a.id == id));
+        self.annotations = annotations;
+        self.markdown = markdown;
+        self.filter_by_tag();
+        if let Some(id) = selected_id {
+            if let Some(position) = self
+                .shown
+                .iter()
+                .position(|&i| self.annotations[i].id == id)
+            {
+                self.cursor = position;
+            }
+        }
+    }
+
+    /// Shows the annotations with the selected tag (or a tag nested under it)
+    fn filter_by_tag(&mut self) {
+        let levels = match self.tag_rows.get(self.tag_cursor) {
+            Some(row) => &row.levels,
+            None => return,
+        };
+        let delimiter = self.delimiter.as_deref();
+        self.shown = self
+            .annotations
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| {
+                levels.is_empty()
+                    || a.tags
+                        .iter()
+                        .any(|tag| tag_levels(tag, delimiter).starts_with(levels))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        self.cursor = self.cursor.min(self.shown.len().saturating_sub(1));
+        self.preview_scroll = 0;
+    }
+
+    /// The highlighted annotation
+    fn current(&self) -> Option<&Annotation> {
+        self.shown.get(self.cursor).map(|&i| &self.annotations[i])
+    }
+
+    /// The marked annotations, or the highlighted one if none are marked
+    fn selected(&self) -> Vec<Annotation> {
+        if self.marked.is_empty() {
+            self.current().cloned().into_iter().collect()
+        } else {
+            self.annotations
+                .iter()
+                .filter(|a| self.marked.contains(&a.id))
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn move_cursor(&mut self, down: bool, by: usize) {
+        let (cursor, length) = match self.focus {
+            Pane::Tags => (&mut self.tag_cursor, self.tag_rows.len()),
+            Pane::Annotations => (&mut self.cursor, self.shown.len()),
+        };
+        *cursor = if down {
+            (*cursor + by).min(length.saturating_sub(1))
+        } else {
+            cursor.saturating_sub(by)
+        };
+        match self.focus {
+            Pane::Tags => {
+                self.cursor = 0;
+                self.filter_by_tag();
+            }
+            Pane::Annotations => self.preview_scroll = 0,
+        }
+    }
+
+    /// Handles a key press, returning an action to run if it asks for one
+    fn handle_key(&mut self, key: Key) -> Option<Action> {
+        if let Some((prompt, mut input)) = self.input.take() {
+            match key {
+                Key::Enter => return self.submit(prompt, &input),
+                Key::ESC | Key::Ctrl('c') => (),
+                Key::Backspace => {
+                    input.pop();
+                    self.input = Some((prompt, input));
+                }
+                Key::Char(c) => {
+                    input.push(c);
+                    self.input = Some((prompt, input));
+                }
+                _ => self.input = Some((prompt, input)),
+            }
+            return None;
+        }
+        match key {
+            Key::Char('q') | Key::ESC | Key::Ctrl('c') => return Some(Action::Quit),
+            Key::Tab | Key::BackTab => {
+                self.focus = match self.focus {
+                    Pane::Tags => Pane::Annotations,
+                    Pane::Annotations => Pane::Tags,
+                }
+            }
+            Key::Left => self.focus = Pane::Tags,
+            Key::Right => self.focus = Pane::Annotations,
+            Key::Up | Key::Char('k') => self.move_cursor(false, 1),
+            Key::Down | Key::Char('j') => self.move_cursor(true, 1),
+            Key::PageUp => self.move_cursor(false, PAGE),
+            Key::PageDown => self.move_cursor(true, PAGE),
+            Key::ShiftUp => self.preview_scroll = self.preview_scroll.saturating_sub(1),
+            Key::ShiftDown => self.preview_scroll += 1,
+            Key::Char(' ') => {
+                if let Some(id) = self.current().map(|a| a.id.to_owned()) {
+                    if !self.marked.remove(&id) {
+                        self.marked.insert(id);
+                    }
+                    self.focus = Pane::Annotations;
+                    self.move_cursor(true, 1);
+                }
+            }
+            Key::Char('s') => return Some(Action::Sync),
+            Key::Char(c @ 't')
+            | Key::Char(c @ 'u')
+            | Key::Char(c @ 'd')
+            | Key::Char(c @ 'e')
+            | Key::Char(c @ 'o') => {
+                if self.current().is_none() {
+                    self.status = "No annotation selected".into();
+                    return None;
+                }
+                match c {
+                    't' => self.input = Some((Prompt::AddTags, String::new())),
+                    'u' => self.input = Some((Prompt::RemoveTags, String::new())),
+                    'd' => self.input = Some((Prompt::Delete, String::new())),
+                    'e' => return Some(Action::Edit),
+                    _ => return Some(Action::Open),
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn submit(&mut self, prompt: Prompt, input: &str) -> Option<Action> {
+        let tags: Vec<String> = input
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_owned)
+            .collect();
+        match prompt {
+            Prompt::AddTags | Prompt::RemoveTags if !tags.is_empty() => Some(Action::Tag {
+                tags,
+                delete: prompt == Prompt::RemoveTags,
+            }),
+            Prompt::Delete if input.trim().eq_ignore_ascii_case("y") => Some(Action::Delete),
+            _ => None,
+        }
+    }
+
+    fn prompt(&self, prompt: Prompt) -> String {
+        match prompt {
+            Prompt::AddTags => "Add tags (comma-separated): ".into(),
+            Prompt::RemoveTags => "Remove tags (comma-separated): ".into(),
+            Prompt::Delete => format!("Delete {} annotation(s)? [y/N] ", self.selected().len()),
+        }
+    }
+}
+
+/// Attribute of a row, highlighted if it's under the cursor
+fn row_attr(highlighted: bool, focused: bool) -> Attr {
+    match (highlighted, focused) {
+        (true, true) => Effect::REVERSE.into(),
+        (true, false) => Effect::BOLD.into(),
+        _ => Attr::default(),
+    }
+}
+
+struct TagPane<'a>(&'a Dashboard);
+
+impl<'a> Draw for TagPane<'a> {
+    fn draw(&self, canvas: &mut dyn Canvas) -> DrawResult<()> {
+        let dashboard = self.0;
+        let (width, height) = canvas.size()?;
+        let first = scroll(&dashboard.tag_offset, dashboard.tag_cursor, height);
+        for (row, (i, tag_row)) in dashboard
+            .tag_rows
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(height)
+            .enumerate()
+        {
+            let label = match tag_row.levels.last() {
+                Some(level) => format!(
+                    "{}{} ({})",
+                    "  ".repeat(tag_row.levels.len() - 1),
+                    level,
+                    tag_row.count
+                ),
+                None => format!("All annotations ({})", tag_row.count),
+            };
+            canvas.print_with_attr(
+                row,
+                0,
+                &fit(&label, width),
+                row_attr(i == dashboard.tag_cursor, dashboard.focus == Pane::Tags),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Widget for TagPane<'a> {}
+
+struct AnnotationPane<'a>(&'a Dashboard);
+
+impl<'a> Draw for AnnotationPane<'a> {
+    fn draw(&self, canvas: &mut dyn Canvas) -> DrawResult<()> {
+        let dashboard = self.0;
+        let (width, height) = canvas.size()?;
+        let first = scroll(&dashboard.list_offset, dashboard.cursor, height);
+        for (row, (i, &index)) in dashboard
+            .shown
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(height)
+            .enumerate()
+        {
+            let annotation = &dashboard.annotations[index];
+            let quote = utils::get_quotes(annotation).join(" ");
+            let summary = if quote.trim().is_empty() {
+                &annotation.text
+            } else {
+                &quote
+            };
+            let line = format!(
+                "{} {} {}",
+                if dashboard.marked.contains(&annotation.id) {
+                    "*"
+                } else {
+                    " "
+                },
+                annotation.created.format("%Y-%m-%d"),
+                summary.replace('\n', " ")
+            );
+            canvas.print_with_attr(
+                row,
+                0,
+                &fit(&line, width),
+                row_attr(i == dashboard.cursor, dashboard.focus == Pane::Annotations),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Widget for AnnotationPane<'a> {}
+
+struct PreviewPane<'a>(&'a Dashboard);
+
+impl<'a> Draw for PreviewPane<'a> {
+    fn draw(&self, canvas: &mut dyn Canvas) -> DrawResult<()> {
+        let dashboard = self.0;
+        let (width, height) = canvas.size()?;
+        let markdown = match dashboard
+            .current()
+            .and_then(|a| dashboard.markdown.get(&a.id))
+        {
+            Some(markdown) => markdown,
+            None => return Ok(()),
+        };
+        // Wraps lines to the width of the pane
+        let mut rows: Vec<Vec<(char, Attr)>> = Vec::new();
+        for line in markdown_to_ansi(markdown).lines() {
+            let line = AnsiString::parse(line);
+            let mut row = Vec::new();
+            for cell in line.iter() {
+                if row.len() == width {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.push(cell);
+            }
+            rows.push(row);
+        }
+        for (i, row) in rows
+            .iter()
+            .skip(dashboard.preview_scroll)
+            .take(height)
+            .enumerate()
+        {
+            for (col, &(ch, attr)) in row.iter().enumerate() {
+                canvas.put_char_with_attr(i, col, ch, attr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Widget for PreviewPane<'a> {}
+
+struct StatusBar<'a>(&'a Dashboard);
+
+impl<'a> Draw for StatusBar<'a> {
+    fn draw(&self, canvas: &mut dyn Canvas) -> DrawResult<()> {
+        let dashboard = self.0;
+        match &dashboard.input {
+            Some((prompt, input)) => {
+                let prompt = dashboard.prompt(*prompt);
+                let col = canvas.print_with_attr(0, 0, &prompt, Effect::BOLD.into())?;
+                let col = col + canvas.print(0, col, input)?;
+                canvas.set_cursor(0, col)?;
+                canvas.show_cursor(true)?;
+            }
+            None => {
+                canvas.show_cursor(false)?;
+                let col = if dashboard.status.is_empty() {
+                    0
+                } else {
+                    canvas.print_with_attr(0, 0, &dashboard.status, Effect::BOLD.into())? + 2
+                };
+                canvas.print_with_attr(0, col, HELP, Effect::DIM.into())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Widget for StatusBar<'a> {}
+
+/// ## TUI
+/// A dashboard with a tag tree, the annotation list, and a preview, which stays open between actions
+impl Gooseberry {
+    /// Loads (optionally filtered) annotations into the dashboard, rendered with the annotation template
+    async fn load_dashboard(
+        &self,
+        dashboard: &mut Dashboard,
+        filters: &Filters,
+        hbs: &Handlebars<'_>,
+    ) -> color_eyre::Result<()> {
+        let annotations = self.filter_annotations(filters.clone(), None).await?;
+        let mut markdown = HashMap::with_capacity(annotations.len());
+        for annotation in &annotations {
+            markdown.insert(
+                annotation.id.to_owned(),
+                hbs.render("annotation", &self.annotation_template(annotation.clone())?)?,
+            );
+        }
+        dashboard.set_annotations(annotations, markdown);
+        Ok(())
+    }
+
+    /// Opens the dashboard for (optionally filtered) annotations.
+    /// Actions run with the dashboard paused, and the annotations are reloaded after each one
+    pub async fn tui(&mut self, mut filters: Filters) -> color_eyre::Result<()> {
+        if self.config.annotation_template.is_none() {
+            self.config.set_annotation_template()?;
+        }
+        // Only read once, not on every reload
+        filters.read_stdin_ids()?;
+        let hbs = self.get_handlebars()?;
+        let mut dashboard = Dashboard::new(self.config.tag_delimiter.clone());
+        self.load_dashboard(&mut dashboard, &filters, &hbs).await?;
+
+        let term: Term<()> = Term::new()?;
+        loop {
+            {
+                let tags = TagPane(&dashboard);
+                let list = AnnotationPane(&dashboard);
+                let preview = PreviewPane(&dashboard);
+                let status = StatusBar(&dashboard);
+                let panes = HSplit::default()
+                    .split(
+                        Win::new(&tags)
+                            .basis(Size::Percent(20))
+                            .border(true)
+                            .title("Tags"),
+                    )
+                    .split(
+                        Win::new(&list)
+                            .basis(Size::Percent(40))
+                            .border(true)
+                            .title("Annotations"),
+                    )
+                    .split(
+                        Win::new(&preview)
+                            .basis(Size::Percent(40))
+                            .border(true)
+                            .title("Preview"),
+                    );
+                let screen = VSplit::default()
+                    .split(panes)
+                    .split(Win::new(&status).basis(1).grow(0).shrink(0));
+                term.draw(&screen)?;
+                term.present()?;
+            }
+
+            let key = match term.poll_event()? {
+                TermEvent::Key(key) => key,
+                _ => continue,
+            };
+            let action = match dashboard.handle_key(key) {
+                Some(Action::Quit) => break,
+                Some(action) => action,
+                None => continue,
+            };
+            let annotations = dashboard.selected();
+            term.pause()?;
+            let result = match action {
+                Action::Tag { tags, delete } => self
+                    .tag(annotations, delete, Some(tags))
+                    .await
+                    .map(|_| "Tags updated"),
+                Action::Delete => self
+                    .delete(annotations, true)
+                    .await
+                    .map(|_| "Deleted (undo with 'gooseberry restore <id>')"),
+                Action::Edit => self.edit(annotations).await.map(|_| "Edited"),
+                Action::Open => self.open_in_browser(&annotations).map(|_| "Opened"),
+                Action::Sync => self.sync().await.map(|_| "Synced"),
+                Action::Quit => unreachable!(),
+            };
+            dashboard.status = match result {
+                Ok(message) => {
+                    dashboard.marked.clear();
+                    message.to_owned()
+                }
+                Err(error) => format!("Error: {}", error),
+            };
+            self.load_dashboard(&mut dashboard, &filters, &hbs).await?;
+            term.restart()?;
+        }
+        Ok(())
+    }
+}
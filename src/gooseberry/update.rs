@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::io::IsTerminal;
+
+use chrono::{DateTime, Utc};
+use color_eyre::Help;
+use futures::stream::{self, StreamExt};
+use hypothesis::annotations::Annotation;
+
+use crate::errors::Apologize;
+use crate::gooseberry::Gooseberry;
+
+/// Number of annotation updates in flight at the same time
+const CONCURRENT_UPDATES: usize = 8;
+
+/// ## Updates
+/// Changing many annotations in Hypothesis at once
+impl Gooseberry {
+    /// Updates annotations in Hypothesis, with up to `CONCURRENT_UPDATES` requests in flight and a progress bar.
+    /// A failed update doesn't stop the others: the annotations which couldn't be updated are listed
+    /// with the reason once the rest are done, and the error counts them.
+    /// Annotations changed in Hypothesis since they were last synced are checked for first (see `skip_conflicts`)
+    pub(crate) async fn update_concurrently(
+        &self,
+        annotations: &[Annotation],
+    ) -> color_eyre::Result<()> {
+        let annotations = self.skip_conflicts(annotations).await?;
+        self.send_updates(&annotations).await
+    }
+
+    /// Updates annotations in Hypothesis like `update_concurrently`, without checking them for changes
+    pub(crate) async fn send_updates(&self, annotations: &[Annotation]) -> color_eyre::Result<()> {
+        if annotations.is_empty() {
+            return Ok(());
+        }
+        let progress_bar = indicatif::ProgressBar::new(annotations.len() as u64);
+        progress_bar.set_style(crate::utils::progress_bar_style());
+        let failures: Vec<_> = stream::iter(annotations)
+            .map(|annotation| async move {
+                let result = self.api.update_annotation(annotation).await;
+                (annotation, result)
+            })
+            .buffer_unordered(CONCURRENT_UPDATES)
+            .filter_map(|(annotation, result)| {
+                progress_bar.inc(1);
+                async move { result.err().map(|error| (annotation, error)) }
+            })
+            .collect()
+            .await;
+        progress_bar.finish_and_clear();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        for (annotation, error) in &failures {
+            tracing::warn!(id = %annotation.id, %error, "couldn't update annotation");
+            eprintln!("Couldn't update {}: {}", annotation.id, error);
+        }
+        Err(Apologize::UpdateError {
+            failed: failures.len(),
+            total: annotations.len(),
+        }
+        .into())
+    }
+
+    /// Leaves out the annotations about to be updated which were changed in Hypothesis (e.g. edited in the browser)
+    /// since they were last synced, i.e. since the versions stored in the database, unless you choose to overwrite them.
+    /// Annotations which were never synced aren't checked
+    pub(crate) async fn skip_conflicts(
+        &self,
+        annotations: &[Annotation],
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        if self.force {
+            return Ok(annotations.to_vec());
+        }
+        let ids: Vec<_> = annotations.iter().map(|a| a.id.as_str()).collect();
+        let synced = self
+            .indexed_annotations(&ids)?
+            .into_iter()
+            .map(|(id, annotation)| (id, annotation.updated))
+            .collect();
+        self.skip_conflicts_since(annotations, &synced).await
+    }
+
+    /// Compares the annotations about to be updated with their current versions in Hypothesis:
+    /// those updated there after the times in `seen` would be overwritten,
+    /// so they're listed and left out unless you choose to overwrite them (or `force` is set).
+    /// Without a terminal to ask in, they're left out.
+    /// Annotations which aren't in `seen` or can't be fetched aren't checked (the latter are left to fail when updated)
+    pub(crate) async fn skip_conflicts_since(
+        &self,
+        annotations: &[Annotation],
+        seen: &HashMap<String, DateTime<Utc>>,
+    ) -> color_eyre::Result<Vec<Annotation>> {
+        if self.force {
+            return Ok(annotations.to_vec());
+        }
+        let to_check: Vec<_> = annotations
+            .iter()
+            .filter_map(|annotation| Some((annotation, *seen.get(&annotation.id)?)))
+            .collect();
+        if to_check.is_empty() {
+            return Ok(annotations.to_vec());
+        }
+        let spinner = crate::utils::get_spinner("Checking for changes made in Hypothesis...");
+        let changed: HashMap<String, DateTime<Utc>> = stream::iter(to_check)
+            .map(|(annotation, seen)| async move {
+                match self.api.fetch_annotation(&annotation.id).await {
+                    Ok(current) if current.updated > seen => Some((annotation.id.to_owned(), seen)),
+                    _ => None,
+                }
+            })
+            .buffer_unordered(CONCURRENT_UPDATES)
+            .filter_map(futures::future::ready)
+            .collect()
+            .await;
+        spinner.finish_and_clear();
+        if changed.is_empty() {
+            return Ok(annotations.to_vec());
+        }
+        for annotation in annotations.iter().filter(|a| changed.contains_key(&a.id)) {
+            eprintln!(
+                "{} was changed in Hypothesis after {}",
+                annotation.id,
+                changed[&annotation.id]
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+            );
+        }
+        let message = format!(
+            "{} annotation(s) changed in Hypothesis since they were synced",
+            changed.len()
+        );
+        let choice = if std::io::stdin().is_terminal() {
+            crate::utils::select(&message, &["Skip them", "Overwrite them", "Cancel"])?
+        } else {
+            eprintln!("{}, skipping them (use --force to overwrite them)", message);
+            Some(0)
+        };
+        match choice {
+            Some(0) => Ok(annotations
+                .iter()
+                .filter(|a| !changed.contains_key(&a.id))
+                .cloned()
+                .collect()),
+            Some(1) => Ok(annotations.to_vec()),
+            _ => {
+                let error: color_eyre::Result<_> = Err(Apologize::DoingNothing.into());
+                error.suggestion("Sync to get the changes, or use --force to overwrite them")
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::Gooseberry;
+
+/// Display name Hypothesis sent along with an annotation, if its author has set one
+fn sent_display_name(annotation: &Annotation) -> Option<&str> {
+    annotation
+        .user_info
+        .as_ref()
+        .and_then(|user_info| user_info.display_name.as_deref())
+        .filter(|name| !name.trim().is_empty())
+}
+
+/// ## Users
+/// Display names of annotation authors, cached during sync so that templates and search windows
+/// can show them instead of `acct:` account IDs
+impl Gooseberry {
+    /// Caches the display names of the authors of synced annotations.
+    /// Those Hypothesis didn't send along with the annotations are looked up among the members
+    /// of the annotations' groups, once: users without a display name are remembered as such
+    pub(crate) async fn cache_display_names(
+        &self,
+        annotations: &[Annotation],
+    ) -> color_eyre::Result<()> {
+        let names = self.display_names()?;
+        let (mut users, mut groups) = (HashSet::new(), HashSet::new());
+        for annotation in annotations {
+            let user = annotation.user.0.as_str();
+            match sent_display_name(annotation) {
+                Some(name) => names.insert(user, name)?,
+                None if !names.contains_key(user)? => {
+                    users.insert(user);
+                    groups.insert(annotation.group.as_str());
+                }
+                None => (),
+            }
+        }
+        if users.is_empty() {
+            return Ok(());
+        }
+        for group in groups {
+            match self.api.get_group_members(group).await {
+                Ok(members) => {
+                    for member in members {
+                        if let Some(name) = member.display_name {
+                            names.insert(member.userid, name)?;
+                        }
+                    }
+                }
+                // e.g. the public group, whose members aren't listed
+                Err(error) => tracing::debug!(%group, %error, "couldn't fetch group members"),
+            }
+        }
+        for user in users {
+            if !names.contains_key(user)? {
+                names.insert(user, [])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cached display name of a user (given by account ID), if they have one
+    pub fn display_name(&self, user: &str) -> color_eyre::Result<Option<String>> {
+        Ok(self
+            .display_names()?
+            .get(user)?
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .filter(|name| !name.is_empty()))
+    }
+}
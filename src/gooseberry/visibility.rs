@@ -0,0 +1,109 @@
+use hypothesis::annotations::Annotation;
+
+use crate::gooseberry::cli::Visibility;
+use crate::gooseberry::dry_run;
+use crate::gooseberry::Gooseberry;
+
+/// ID of the Hypothesis group of public annotations
+pub const WORLD_GROUP: &str = "__world__";
+
+/// Who can read an annotation: private ones are only readable by their author,
+/// shared ones by the members of their group (everyone, for the public group)
+pub fn visibility(annotation: &Annotation) -> Visibility {
+    if !annotation
+        .permissions
+        .read
+        .iter()
+        .any(|reader| reader.starts_with("group:"))
+    {
+        Visibility::Private
+    } else if annotation.group == WORLD_GROUP {
+        Visibility::World
+    } else {
+        Visibility::Group
+    }
+}
+
+/// ## Visibility
+/// Sharing annotations with their group (or another one) and making them private, in bulk
+impl Gooseberry {
+    /// Shares annotations with the members of their group, or of `to_group` (moving them there).
+    /// Annotations in the public group (`__world__`) become readable by everyone
+    pub async fn publish(
+        &self,
+        annotations: Vec<Annotation>,
+        to_group: Option<String>,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        let updated: Vec<_> = annotations
+            .iter()
+            .cloned()
+            .map(|mut a| {
+                if let Some(group) = &to_group {
+                    a.group = group.to_owned();
+                }
+                a.permissions.read = vec![format!("group:{}", a.group)];
+                a
+            })
+            .collect();
+        let question = match &to_group {
+            Some(group) => format!("Share {} annotations in group {}?", updated.len(), group),
+            None => format!("Share {} annotations with their groups?", updated.len()),
+        };
+        self.change_visibility(&annotations, updated, &question, force)
+            .await
+    }
+
+    /// Makes annotations private, readable only by their author
+    pub async fn unpublish(
+        &self,
+        annotations: Vec<Annotation>,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        let updated: Vec<_> = annotations
+            .iter()
+            .cloned()
+            .map(|mut a| {
+                a.permissions.read = vec![a.user.0.to_owned()];
+                a
+            })
+            .collect();
+        let question = format!("Make {} annotations private?", updated.len());
+        self.change_visibility(&annotations, updated, &question, force)
+            .await
+    }
+
+    /// Updates the annotations whose permissions or group changed, after asking for confirmation
+    async fn change_visibility(
+        &self,
+        annotations: &[Annotation],
+        updated: Vec<Annotation>,
+        question: &str,
+        force: bool,
+    ) -> color_eyre::Result<()> {
+        let (before, after): (Vec<_>, Vec<_>) = annotations
+            .iter()
+            .cloned()
+            .zip(updated)
+            .filter(|(old, new)| {
+                old.group != new.group || old.permissions.read != new.permissions.read
+            })
+            .unzip();
+        if after.is_empty() {
+            println!("No annotations to change");
+            return Ok(());
+        }
+        if self.dry_run {
+            dry_run::print_updates(&before, &after);
+            return Ok(());
+        }
+        if !force && !crate::utils::confirm(question, false)? {
+            return Ok(());
+        }
+        let after = self.skip_conflicts(&after).await?;
+        self.send_updates(&after).await?;
+        println!("Changed the visibility of {} annotations", after.len());
+        self.sync().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use chrono::Local;
+use color_eyre::Help;
+
+use crate::configuration::{get_project_dir, GooseberryConfig};
+use crate::errors::Apologize;
+use crate::gooseberry::cli::{Filters, SortOptions};
+use crate::gooseberry::Gooseberry;
+
+/// ## Watch
+/// Sync and make on a schedule
+impl Gooseberry {
+    /// Syncs (and optionally makes the knowledge base) every `interval`, forever.
+    ///
+    /// The database is only opened (and locked) while syncing and making,
+    /// so other gooseberry commands can run in between.
+    /// Errors are printed instead of stopping the loop, e.g. to survive network outages.
+    /// Stops on Ctrl-C, closing the database first (which encrypts it with `encrypt_database` on)
+    pub async fn watch(
+        config: GooseberryConfig,
+        interval: Duration,
+        make: bool,
+        wiki_links: bool,
+    ) -> color_eyre::Result<()> {
+        if make && config.kb_dir.is_none() {
+            let error: color_eyre::Result<()> = Err(Apologize::ConfigError {
+                message: "Knowledge base directory not set".into(),
+            }
+            .into());
+            return error.suggestion(
+                "Set it using 'gooseberry config kb all' or only sync with 'gooseberry watch --no-make'",
+            );
+        }
+        loop {
+            let run = async {
+                println!("{}", Local::now().format("[%Y-%m-%d %H:%M:%S]"));
+                if let Err(error) = Self::sync_and_make(&config, make, wiki_links).await {
+                    eprintln!("{:?}", error);
+                }
+                tokio::time::sleep(interval).await;
+            };
+            tokio::select! {
+                _ = run => {}
+                // Dropping `run` drops the open database too, which deletes its decrypted copy
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            }
+        }
+    }
+
+    async fn sync_and_make(
+        config: &GooseberryConfig,
+        make: bool,
+        wiki_links: bool,
+    ) -> color_eyre::Result<()> {
+        let mut gooseberry = Self::new(config.clone())?;
+        gooseberry.sync().await?;
+        gooseberry.save()?;
+        if make {
+            gooseberry
+                .make(
+                    true,
+                    false,
+                    Filters::default(),
+                    wiki_links,
+                    None,
+                    SortOptions::default(),
+                    None,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Runs this gooseberry command again (without `--daemonize`) as a background process
+    /// with its output going to a log file
+    pub fn daemonize(log: Option<&Path>) -> color_eyre::Result<()> {
+        let log = match log {
+            Some(log) => log.to_path_buf(),
+            None => get_project_dir()?.data_dir().join("watch.log"),
+        };
+        let log_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)?;
+        let mut command = Command::new(std::env::current_exe()?);
+        command
+            .args(
+                std::env::args_os()
+                    .skip(1)
+                    .filter(|arg| arg != "--daemonize"),
+            )
+            .stdin(Stdio::null())
+            .stdout(log_file.try_clone()?)
+            .stderr(log_file);
+        // Detach from the terminal's process group so closing it doesn't stop the watcher
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let child = command.spawn()?;
+        println!(
+            "Watching in the background (PID {}), logging to {:?}",
+            child.id(),
+            log
+        );
+        Ok(())
+    }
+}
@@ -9,6 +9,13 @@ async fn main() -> color_eyre::Result<()> {
         .display_env_section(false)
         .install()?;
     let cli = GooseberryCLI::from_args();
-    Gooseberry::start(cli).await?;
+    let json_errors = cli.json_errors();
+    if let Err(report) = Gooseberry::start(cli).await {
+        if !json_errors {
+            return Err(report);
+        }
+        eprintln!("{}", gooseberry::errors::error_json(&report));
+        std::process::exit(1);
+    }
     Ok(())
 }
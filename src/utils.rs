@@ -1,25 +1,60 @@
-use chrono::{DateTime, Local, Utc};
+use chrono::{DateTime, Local, TimeZone, Utc};
 use chrono_english::{parse_date_string, Dialect};
+#[cfg(feature = "cli")]
 use color_eyre::Section;
-use dialoguer::{theme, Editor, Input};
+#[cfg(feature = "cli")]
+use dialoguer::{console, theme, Confirm, Editor, Input, Password, Select};
 use hypothesis::annotations::Selector;
 use url::Url;
 
+#[cfg(feature = "cli")]
+use crate::configuration::ColorMode;
+#[cfg(feature = "cli")]
 use crate::errors::Apologize;
 
 /// ASCII code of semicolon
 /// TODO: Tag cannot have semicolon in it, remember to add this to the README
 pub const SEMICOLON: u8 = 59;
 
-/// Makes `DateTime` from a string, can be colloquial like "last Friday 8pm"
+/// Midnight at the start of today (in local time)
+pub fn start_of_today() -> DateTime<Utc> {
+    Local::now().date().and_hms(0, 0, 0).with_timezone(&Utc)
+}
+
+/// Makes `DateTime` from a string, can be colloquial like "last Friday 8pm".
+/// A month like `2024-01` is the start of the month
 pub fn parse_datetime(datetime_string: &str) -> color_eyre::Result<DateTime<Utc>> {
     if datetime_string.to_ascii_lowercase() == "today" {
-        Ok(Local::now().date().and_hms(0, 0, 0).with_timezone(&Utc))
+        Ok(start_of_today())
+    } else if let Some(month) = start_of_month(datetime_string) {
+        Ok(month)
     } else {
         Ok(parse_date_string(datetime_string, Local::now(), Dialect::Uk)?.with_timezone(&Utc))
     }
 }
 
+/// Midnight (in local time) at the start of a month given as `YYYY-MM`
+fn start_of_month(month: &str) -> Option<DateTime<Utc>> {
+    let (year, month) = month.split_once('-')?;
+    if year.len() != 4 || month.len() != 2 {
+        return None;
+    }
+    let start = chrono::NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, 1)?
+        .and_hms_opt(0, 0, 0)?;
+    Some(
+        Local
+            .from_local_datetime(&start)
+            .single()?
+            .with_timezone(&Utc),
+    )
+}
+
+/// Makes `DateTime` from a time span before now, e.g. "2w", "3days", or "12h"
+pub fn parse_since(span: &str) -> color_eyre::Result<DateTime<Utc>> {
+    let span = humantime::parse_duration(span)?;
+    Ok(Utc::now() - chrono::Duration::from_std(span)?)
+}
+
 /// Splits byte array by semicolon into list of Annotation IDs
 pub fn split_ids(index_list: &[u8]) -> color_eyre::Result<Vec<String>> {
     let index_list_string = std::str::from_utf8(index_list)?;
@@ -35,6 +70,7 @@ pub fn join_ids(index_list: &[String]) -> color_eyre::Result<Vec<u8>> {
 }
 
 /// Takes user input from terminal, optionally has a default and optionally displays it.
+#[cfg(feature = "cli")]
 pub fn user_input(
     message: &str,
     default: Option<&str>,
@@ -62,6 +98,7 @@ pub fn user_input(
 }
 
 /// Gets input from external editor, optionally displays default text in editor
+#[cfg(feature = "cli")]
 pub fn external_editor_input(default: Option<&str>, extension: &str) -> color_eyre::Result<String> {
     Ok(Editor::new()
         .trim_newlines(false)
@@ -72,6 +109,258 @@ pub fn external_editor_input(default: Option<&str>, extension: &str) -> color_ey
         .suggestion("Make sure to save next time!")?)
 }
 
+/// Turns colored output on or off, for stdout and stderr separately: styled text, prompts, and printed annotations
+/// check `console::colors_enabled` (and `colors_enabled_stderr`). `Auto` colors a stream only if it's a terminal
+/// and `NO_COLOR` isn't set, so that piped output has no escape sequences
+#[cfg(feature = "cli")]
+pub fn set_color_mode(mode: ColorMode) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let enabled = |term: &console::Term| match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => !no_color && term.features().is_attended(),
+    };
+    console::set_colors_enabled(enabled(&console::Term::stdout()));
+    console::set_colors_enabled_stderr(enabled(&console::Term::stderr()));
+}
+
+/// Environment variable with a filter (e.g. `gooseberry=debug,reqwest=trace`) overriding `--verbose`
+#[cfg(feature = "cli")]
+pub const LOG_VARIABLE: &str = "GOOSEBERRY_LOG";
+
+/// Logs to stderr, or to a file in `log_dir` started every day (`gooseberry.log.<date>`).
+/// `-v` logs what gooseberry does (syncs, makes, database changes), `-vv` also each API request,
+/// and `-vvv` everything down to the libraries it uses. Logs at `-vv` in a `log_dir` by default.
+///
+/// Logs are written in the background until the returned guard is dropped
+#[cfg(feature = "cli")]
+pub fn init_logging(
+    verbose: u8,
+    log_dir: Option<&std::path::Path>,
+) -> color_eyre::Result<Option<tracing_appender::non_blocking::WorkerGuard>> {
+    let filter = match std::env::var(LOG_VARIABLE) {
+        Ok(filter) => filter,
+        Err(_) => match (verbose, log_dir) {
+            (0, None) => return Ok(None),
+            (1, _) => "gooseberry=info".to_owned(),
+            (0, Some(_)) | (2, _) => "gooseberry=debug,reqwest=debug".to_owned(),
+            _ => "trace".to_owned(),
+        },
+    };
+    let filter =
+        tracing_subscriber::EnvFilter::try_new(&filter).map_err(|error| Apologize::LogError {
+            message: format!("Couldn't parse {}={:?}: {}", LOG_VARIABLE, filter, error),
+        })?;
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    let (initialized, guard) = match log_dir {
+        Some(log_dir) => {
+            std::fs::create_dir_all(log_dir)?;
+            let (writer, guard) = tracing_appender::non_blocking(tracing_appender::rolling::daily(
+                log_dir,
+                "gooseberry.log",
+            ));
+            (
+                subscriber.with_ansi(false).with_writer(writer).try_init(),
+                Some(guard),
+            )
+        }
+        None => (
+            subscriber
+                .with_ansi(console::colors_enabled_stderr())
+                .with_writer(std::io::stderr)
+                .try_init(),
+            None,
+        ),
+    };
+    initialized.map_err(|error| Apologize::LogError {
+        message: error.to_string(),
+    })?;
+    Ok(guard)
+}
+
+/// Username in a Hypothesis account ID (`acct:<username>@hypothes.is`)
+pub fn username(user: &str) -> &str {
+    let user = user.strip_prefix("acct:").unwrap_or(user);
+    user.split('@').next().unwrap_or(user)
+}
+
+/// Account ID of a user given by username (on the same authority as `own`, e.g. `hypothes.is`),
+/// `username@authority`, or account ID
+pub fn account_id(user: &str, own: &str) -> String {
+    if user.starts_with("acct:") {
+        user.to_owned()
+    } else if user.contains('@') {
+        format!("acct:{}", user)
+    } else {
+        let authority = own.rsplit_once('@').map_or("hypothes.is", |(_, a)| a);
+        format!("acct:{}@{}", user, authority)
+    }
+}
+
+/// Link to see an annotation in context: its `incontext` link from Hypothesis,
+/// or its URI with an `#annotations:<id>` fragment (which the Hypothesis client opens the annotation on)
+pub fn annotation_link(annotation: &hypothesis::annotations::Annotation) -> String {
+    if let Some(link) = annotation.links.get("incontext") {
+        return link.to_owned();
+    }
+    match Url::parse(&annotation.uri) {
+        Ok(mut uri) => {
+            uri.set_fragment(Some(&format!("annotations:{}", annotation.id)));
+            uri.to_string()
+        }
+        Err(_) => format!("{}#annotations:{}", annotation.uri, annotation.id),
+    }
+}
+
+/// A command running `command` with the platform's shell (`sh -c`, or `cmd /C` on Windows),
+/// for the commands set in the config file or on the command line
+pub fn shell_command(command: &str) -> std::process::Command {
+    let mut shell = if cfg!(target_os = "windows") {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Quotes an argument (e.g. a file path) for `shell_command`, so the shell passes it on as is
+pub fn shell_quote(argument: &str) -> String {
+    if cfg!(target_os = "windows") {
+        format!("\"{}\"", argument.replace('"', "\"\""))
+    } else {
+        format!("'{}'", argument.replace('\'', "'\\''"))
+    }
+}
+
+/// Opens a URL in the default browser
+#[cfg(feature = "cli")]
+pub fn open_in_browser(url: &str) -> color_eyre::Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        // Not `cmd /C start`: cmd would split the URL at each `&` and run the rest as commands
+        let mut command = std::process::Command::new("rundll32");
+        command.arg("url.dll,FileProtocolHandler");
+        command
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    let status = command.arg(url).status().suggestion(
+        "Couldn't find a command to open the browser with (open / xdg-open / rundll32)",
+    )?;
+    if !status.success() {
+        return Err(Apologize::BrowserError {
+            url: url.to_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Copies text to the system clipboard, falling back to the platform's clipboard command
+/// if there's no clipboard to connect to (e.g. on Wayland without XWayland)
+#[cfg(feature = "cli")]
+pub fn copy_to_clipboard(text: &str) -> color_eyre::Result<()> {
+    // On Linux the text is handed over to the clipboard manager when the clipboard is dropped
+    match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text)) {
+        Ok(()) => Ok(()),
+        Err(_) => copy_with_command(text),
+    }
+}
+
+/// Copies text to the clipboard with the platform's clipboard command
+#[cfg(feature = "cli")]
+fn copy_with_command(text: &str) -> color_eyre::Result<()> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("pbcopy")
+    } else if cfg!(target_os = "windows") {
+        Command::new("clip")
+    } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Command::new("wl-copy")
+    } else {
+        let mut command = Command::new("xclip");
+        command.arg("-selection").arg("clipboard");
+        command
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .spawn()
+        .suggestion("Couldn't find a clipboard command (pbcopy / wl-copy / xclip)")?;
+    child
+        .stdin
+        .take()
+        .ok_or(Apologize::ClipboardError)?
+        .write_all(text.as_bytes())?;
+    if !child.wait()?.success() {
+        return Err(Apologize::ClipboardError.into());
+    }
+    Ok(())
+}
+
+/// Asks the user for a yes/no confirmation
+#[cfg(feature = "cli")]
+pub fn confirm(message: &str, default: bool) -> color_eyre::Result<bool> {
+    Ok(Confirm::with_theme(&theme::ColorfulTheme::default())
+        .with_prompt(message)
+        .default(default)
+        .interact()?)
+}
+
+/// Without the `cli` feature there's no one to ask, so destructive operations have to be forced
+#[cfg(not(feature = "cli"))]
+pub fn confirm(_message: &str, _default: bool) -> color_eyre::Result<bool> {
+    Ok(false)
+}
+
+/// Asks the user for a passphrase without showing it, twice if it's a `new` one
+#[cfg(feature = "cli")]
+pub fn password(message: &str, new: bool) -> color_eyre::Result<Option<String>> {
+    let theme = theme::ColorfulTheme::default();
+    let mut prompt = Password::with_theme(&theme);
+    prompt.with_prompt(message);
+    if new {
+        prompt.with_confirmation("Repeat passphrase", "Passphrases don't match");
+    }
+    Ok(Some(prompt.interact()?))
+}
+
+/// Without the `cli` feature there's no one to ask
+#[cfg(not(feature = "cli"))]
+pub fn password(_message: &str, _new: bool) -> color_eyre::Result<Option<String>> {
+    Ok(None)
+}
+
+/// Asks the user to pick one of a list of options, `None` if they press Esc
+#[cfg(feature = "cli")]
+pub fn select(message: &str, options: &[&str]) -> color_eyre::Result<Option<usize>> {
+    Ok(Select::with_theme(&theme::ColorfulTheme::default())
+        .with_prompt(message)
+        .items(options)
+        .default(0)
+        .interact_opt()?)
+}
+
+/// Without the `cli` feature there's no one to ask, so nothing is picked
+#[cfg(not(feature = "cli"))]
+pub fn select(_message: &str, _options: &[&str]) -> color_eyre::Result<Option<usize>> {
+    Ok(None)
+}
+
+/// Style of a progress bar with counts, for turning a spinner into one once the length is known
+pub fn progress_bar_style() -> indicatif::ProgressStyle {
+    indicatif::ProgressStyle::default_bar()
+        .template("{spinner:.dim.bold.blue} [{bar:30.blue/dim}] {pos}/{len} {wide_msg}")
+        .progress_chars("=> ")
+}
+
 pub fn get_spinner(message: &str) -> indicatif::ProgressBar {
     let spinner = indicatif::ProgressBar::new_spinner();
     spinner.enable_steady_tick(200);
@@ -107,6 +396,32 @@ pub fn get_quotes(annotation: &hypothesis::annotations::Annotation) -> Vec<&str>
         .collect::<Vec<_>>()
 }
 
+/// Text just before and after the first highlight in the annotated document, from its quote selector
+pub fn get_quote_context(annotation: &hypothesis::annotations::Annotation) -> Option<(&str, &str)> {
+    annotation
+        .target
+        .iter()
+        .flat_map(|target| target.selector.iter())
+        .find_map(|selector| match selector {
+            Selector::TextQuoteSelector(selector) => {
+                Some((selector.prefix.as_str(), selector.suffix.as_str()))
+            }
+            _ => None,
+        })
+}
+
+/// Start of the first highlight in the text of the annotated document, from its text position selector
+pub fn get_text_position(annotation: &hypothesis::annotations::Annotation) -> Option<u64> {
+    annotation
+        .target
+        .iter()
+        .flat_map(|target| target.selector.iter())
+        .find_map(|selector| match selector {
+            Selector::TextPositionSelector(selector) => Some(selector.start),
+            _ => None,
+        })
+}
+
 pub fn clean_uri(uri: &str) -> String {
     match Url::parse(uri) {
         Ok(parsed_uri) => {
@@ -123,6 +438,24 @@ pub fn clean_uri(uri: &str) -> String {
     }
 }
 
+/// URI without its scheme, `www.`, fragment, or trailing slash,
+/// the same for different links to the same page
+pub fn dedup_uri(uri: &str) -> String {
+    let uri = uri.split('#').next().unwrap_or(uri);
+    clean_uri(uri).trim_start_matches("www.").to_owned()
+}
+
+/// Markdown link, with brackets in the text and spaces and parentheses in the URI escaped
+pub fn markdown_link(text: &str, uri: &str) -> String {
+    format!(
+        "[{}]({})",
+        text.replace('[', "\\[").replace(']', "\\]"),
+        uri.replace(' ', "%20")
+            .replace('(', "%28")
+            .replace(')', "%29")
+    )
+}
+
 /// Converts a URI into something that can be used as a folder/filename
 pub fn uri_to_filename(uri: &str) -> String {
     clean_uri(uri)
@@ -131,3 +464,61 @@ pub fn uri_to_filename(uri: &str) -> String {
         .replace("/", "_")
         .replace(":", "_")
 }
+
+/// 64-bit FNV-1a hash of some byte strings (each followed by a separator byte).
+/// Unlike `DefaultHasher`, whose algorithm can change between Rust releases,
+/// it always gives the same hash, so hashes stored in the database stay valid
+pub fn stable_hash<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> [u8; 8] {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let mut hash = OFFSET_BASIS;
+    for part in parts {
+        for byte in part.iter().chain(&[0xff]) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash.to_be_bytes()
+}
+
+/// Decodes the HTML entities common in page titles and text (named ones like `&amp;` and numeric ones like `&#8217;`)
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..=end]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let code = entity.strip_prefix('#')?;
+                let code = match code.strip_prefix('x').or_else(|| code.strip_prefix('X')) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => code.parse().ok()?,
+                };
+                std::char::from_u32(code)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
@@ -5,13 +5,17 @@ use std::ffi::OsStr;
 /// This is used to delete and clear created annotations after each test
 /// MAKE SURE TO RUN SINGLE-THREADED cargo test -- --test-threads=1
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
 use assert_cmd::Command;
 use color_eyre::eyre::WrapErr;
 use eyre::eyre;
 use futures::future::{join_all, try_join_all};
+use predicates::prelude::PredicateBooleanExt;
 use tempfile::{tempdir, TempDir};
 
 fn make_config_file(
@@ -315,28 +319,150 @@ async fn tag() -> color_eyre::Result<()> {
 }
 
 #[tokio::test]
-async fn make() -> color_eyre::Result<()> {
+async fn search_query() -> color_eyre::Result<()> {
     // get test_data
     let test_data = TestData::populate().await;
     assert!(test_data.is_ok());
     let test_data = test_data?;
     let duration = time::Duration::from_millis(500);
 
-    // sync
+    // sync fills the full-text index
     thread::sleep(duration);
     let mut cmd = Command::cargo_bin("gooseberry")?;
     cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
         .arg("sync")
         .assert()
-        .stdout(predicates::str::contains("Added 2 annotations"));
+        .success();
 
-    // add a tag with spaces
+    // phrase
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--query")
+        .arg("\"another test\"")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // boolean operators
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--query")
+        .arg("comment AND NOT another")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[0].id));
+
+    // words in the wrong order aren't the phrase
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--query")
+        .arg("\"test another\"")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout("");
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn tag_rename_merge() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // rename a tag
     thread::sleep(duration);
     let mut cmd = Command::cargo_bin("gooseberry")?;
     cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
         .arg("tag")
-        .arg("--tags=test_tag")
-        .arg("test tag5")
+        .arg("rename")
+        .arg("test_tag1")
+        .arg("test_tag_renamed")
+        .assert()
+        .success();
+    let futures: Vec<_> = test_data
+        .annotations
+        .iter()
+        .map(|a| test_data.hypothesis_client.fetch_annotation(&a.id))
+        .collect();
+    assert!(async { try_join_all(futures).await }
+        .await?
+        .iter()
+        .all(|x| x.tags.contains(&"test_tag_renamed".to_owned())
+            && !x.tags.contains(&"test_tag1".to_owned())));
+
+    // merge tags
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("merge")
+        .arg("test_tag_renamed")
+        .arg("test_tag2")
+        .arg("--into=test_tag_merged")
+        .assert()
+        .success();
+    let futures: Vec<_> = test_data
+        .annotations
+        .iter()
+        .map(|a| test_data.hypothesis_client.fetch_annotation(&a.id))
+        .collect();
+    assert!(async { try_join_all(futures).await }
+        .await?
+        .iter()
+        .all(|x| x.tags == vec!["test_tag".to_owned(), "test_tag_merged".to_owned()]));
+
+    // local tag tree is updated
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--tags=test_tag_merged")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("www.example.com"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn ids_from_stdin() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // print IDs instead of searching
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--tags=test_tag2")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // tag only the annotations whose IDs are piped in
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("--ids")
+        .arg("-")
+        .arg("test_tag7")
+        .write_stdin(format!("{}\n", test_data.annotations[0].id))
         .assert()
         .success();
     assert!(test_data
@@ -344,39 +470,4556 @@ async fn make() -> color_eyre::Result<()> {
         .fetch_annotation(&test_data.annotations[0].id)
         .await?
         .tags
-        .contains(&"test tag5".to_owned()));
+        .contains(&"test_tag7".to_owned()));
+    assert!(!test_data
+        .hypothesis_client
+        .fetch_annotation(&test_data.annotations[1].id)
+        .await?
+        .tags
+        .contains(&"test_tag7".to_owned()));
 
-    // make
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_document() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // annotations on documents matching a glob, in document order
     thread::sleep(duration);
     let mut cmd = Command::cargo_bin("gooseberry")?;
     cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
-        .arg("make")
-        .arg("-f")
+        .arg("search")
+        .arg("--uri=https://www.example.*")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}\n{}\n",
+            test_data.annotations[0].id, test_data.annotations[1].id
+        ));
+
+    // along with other filters
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--uri=https://www.example.*")
+        .arg("--tags=test_tag2")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // the glob has to match the whole URI
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--uri=www.example.*")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout("");
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_line() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // key:value terms in the search line filter the annotations
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--print-ids")
+        .arg("site:example.com")
+        .arg("tag:test_tag2")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // -key:value leaves matches out, and the other words have to be in the annotations
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--print-ids")
+        .arg("--")
+        .arg("-tag:test_tag2")
+        .arg("test comment")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[0].id));
+
+    // sites include their subdomains, but not other sites ending the same way
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--print-ids")
+        .arg("site:ample.com")
+        .assert()
+        .success()
+        .stdout("");
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[test]
+fn error_format() -> color_eyre::Result<()> {
+    let temp_dir = tempdir()?;
+    dotenv::dotenv()?;
+    let username = dotenv::var("HYPOTHESIS_NAME")?;
+    let key = dotenv::var("HYPOTHESIS_KEY")?;
+    let group_id = dotenv::var("TEST_GROUP_ID")?;
+    let config_file = make_config_file(&temp_dir, &username, &key, &group_id)?;
+
+    // a missing config file is a config_error, with a suggestion on how to make one
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", temp_dir.path().join("missing.toml"))
+        .arg("--error-format=json")
+        .arg("uri")
+        .output()?;
+    assert!(!output.status.success());
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr)?;
+    assert_eq!(error["error"]["code"], "config_error");
+    assert!(!error["error"]["suggestions"].as_array().unwrap().is_empty());
+
+    // nothing matching a tag no annotation has
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &config_file)
+        .arg("--error-format")
+        .arg("json")
+        .arg("open")
+        .arg("--print")
+        .arg("--tags=no_such_tag_anywhere")
+        .output()?;
+    assert!(!output.status.success());
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr)?;
+    assert_eq!(error["error"]["code"], "no_matches");
+    assert_eq!(error["error"]["message"], "No matching annotations");
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+/// Stand-in for a self-hosted Hypothesis API, answering with one user and group and no annotations.
+/// Returns its API URL and the requests it gets (method, path, and Authorization header)
+fn stub_api_server() -> color_eyre::Result<(String, Arc<Mutex<Vec<String>>>)> {
+    stub_api_server_with(Arc::new(Mutex::new(Vec::new())))
+}
+
+/// Stand-in for a self-hosted Hypothesis API like `stub_api_server`, with annotations which can be changed
+/// while it runs (e.g. as if edited in the browser)
+fn stub_api_server_with(
+    annotations: Arc<Mutex<Vec<serde_json::Value>>>,
+) -> color_eyre::Result<(String, Arc<Mutex<Vec<String>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let api_url = format!("http://{}/api", listener.local_addr()?);
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = Arc::clone(&requests);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut authorization = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("authorization") {
+                        authorization = value.trim().to_owned();
+                    } else if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap_or_default();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default();
+            let path = parts.next().unwrap_or_default();
+            received
+                .lock()
+                .unwrap()
+                .push(format!("{} {} {}", method, path, authorization));
+            let annotations = annotations.lock().unwrap();
+            let annotation = path
+                .strip_prefix("/api/annotations/")
+                .and_then(|id| annotations.iter().find(|a| a["id"] == id));
+            let (status, body) = if path.starts_with("/api/profile") {
+                (
+                    "200 OK",
+                    r#"{"authority": "localhost", "features": {}, "preferences": {}, "userid": "acct:tester@localhost"}"#.to_owned(),
+                )
+            } else if path.starts_with("/api/search") {
+                // the annotations updated after search_after, all at once
+                let search_after = url::Url::parse(&format!("http://localhost{}", path))
+                    .unwrap()
+                    .query_pairs()
+                    .find(|(key, _)| key == "search_after")
+                    .map(|(_, value)| value.into_owned())
+                    .unwrap_or_default();
+                let rows: Vec<_> = annotations
+                    .iter()
+                    .filter(|a| a["updated"].as_str().unwrap() > search_after.as_str())
+                    .collect();
+                (
+                    "200 OK",
+                    serde_json::json!({"rows": rows, "total": rows.len()}).to_string(),
+                )
+            } else if path.starts_with("/api/groups") {
+                (
+                    "200 OK",
+                    r#"[{"id": "stubgroup", "groupid": null, "name": "Stub group", "links": {}, "organization": "__default__", "scoped": false, "type": "private"}]"#.to_owned(),
+                )
+            } else if let Some(annotation) = annotation {
+                ("200 OK", annotation.to_string())
+            } else {
+                (
+                    "404 Not Found",
+                    r#"{"status": "failure", "reason": "not found"}"#.to_owned(),
+                )
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    });
+    Ok((api_url, requests))
+}
+
+#[test]
+fn self_hosted_api_url() -> color_eyre::Result<()> {
+    let temp_dir = tempdir()?;
+    let (api_url, requests) = stub_api_server()?;
+    let config_file = make_config_file(&temp_dir, "tester", "stub-key", "stubgroup")?;
+
+    // sync asks the configured server for the profile and the annotations
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("sync")
         .assert()
         .success();
 
-    // check that the kb folder has tag files and an index file
-    let file_names = fs::read_dir(test_data.temp_dir.path().join("kb").as_os_str())?
-        .map(|entry| {
-            entry.wrap_err("File I/O error").and_then(|e| {
-                e.path()
-                    .file_name()
-                    .ok_or(eyre!("filename ends in ."))
-                    .and_then(|f: &OsStr| {
-                        f.to_str()
-                            .map(String::from)
-                            .ok_or(eyre!("non-unicode characters in filename"))
-                    })
-            })
-        })
-        .collect::<Result<HashSet<String>, _>>()?;
-    // index file
-    assert!(file_names.contains("SUMMARY.md"));
+    // and so do the other commands
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("group")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("stubgroup\tStub group\tprivate"));
 
-    // check all tag files
-    assert!(["test_tag", "test_tag1", "test_tag2", "test tag5"]
+    let requests = requests.lock().unwrap();
+    assert!(requests
         .iter()
-        .all(|t| file_names.contains(&format!("{}.md", t))));
+        .any(|request| request.starts_with("GET /api/profile ")));
+    assert!(requests
+        .iter()
+        .any(|request| request.starts_with("GET /api/search?")));
+    assert!(requests
+        .iter()
+        .any(|request| request.starts_with("GET /api/groups")));
+    // the developer key only goes to the configured server
+    assert!(requests
+        .iter()
+        .all(|request| request.ends_with("Bearer stub-key")));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn update_conflicts() -> color_eyre::Result<()> {
+    let temp_dir = tempdir()?;
+    let synced = serde_json::json!({
+        "id": "stub1",
+        "created": "2021-01-01T00:00:00+00:00",
+        "updated": "2021-01-01T00:00:00+00:00",
+        "user": "acct:tester@localhost",
+        "uri": "https://example.com",
+        "text": "a stub annotation",
+        "tags": ["stub_tag"],
+        "group": "stubgroup",
+        "permissions": {
+            "read": ["group:stubgroup"],
+            "admin": ["acct:tester@localhost"],
+            "update": ["acct:tester@localhost"],
+            "delete": ["acct:tester@localhost"]
+        },
+        "target": [{"source": "https://example.com"}],
+        "links": {},
+        "hidden": false,
+        "flagged": false
+    });
+    let annotations = Arc::new(Mutex::new(vec![synced.clone()]));
+    let (api_url, requests) = stub_api_server_with(Arc::clone(&annotations))?;
+    let config_file = make_config_file(&temp_dir, "tester", "stub-key", "stubgroup")?;
+    let patched = || {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.starts_with("PATCH /api/annotations/stub1 "))
+    };
+    // edits the annotation in "the browser"
+    let edit = |updated: &str| {
+        let mut annotation = synced.clone();
+        annotation["updated"] = updated.into();
+        annotation["text"] = "edited in the browser".into();
+        *annotations.lock().unwrap() = vec![annotation];
+    };
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // the annotation changed since the sync is skipped instead of overwritten
+    edit("2021-02-01T00:00:00+00:00");
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("tag")
+        .arg("--tags=stub_tag")
+        .arg("new_tag")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("stub1 was changed in Hypothesis"))
+        .stderr(predicates::str::contains("skipping them"));
+    assert!(!patched());
+
+    // unless it's forced
+    edit("2021-03-01T00:00:00+00:00");
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("tag")
+        .arg("--force")
+        .arg("--tags=stub_tag")
+        .arg("new_tag")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("was changed in Hypothesis").not());
+    assert!(patched());
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn verbose() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    thread::sleep(duration);
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("-v")
+        .arg("sync")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("syncing group"))
+        .stderr(predicates::str::contains("added=2"));
+
+    // logs go to a file in the log folder instead of stderr
+    let log_dir = test_data.temp_dir.path().join("logs");
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--log-dir")
+        .arg(&log_dir)
+        .arg("sync")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("syncing group").not());
+    let mut log = String::new();
+    for entry in fs::read_dir(&log_dir)? {
+        log.push_str(&fs::read_to_string(entry?.path())?);
+    }
+    assert!(log.contains("syncing group"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn serve() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("gooseberry"))
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("serve")
+        .arg("--port=18040")
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    let duration = time::Duration::from_millis(1000);
+    thread::sleep(duration);
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://127.0.0.1:18040{}", path);
+
+    // syncing responds with the new annotations
+    let diff: serde_json::Value =
+        serde_json::from_str(&client.post(url("/sync")).send().await?.text().await?)?;
+    assert_eq!(diff["added"].as_array().map(Vec::len), Some(2));
+
+    // annotations matching the query
+    let annotations: Vec<hypothesis::annotations::Annotation> = serde_json::from_str(
+        &client
+            .get(url("/annotations?tag=test_tag2"))
+            .send()
+            .await?
+            .text()
+            .await?,
+    )?;
+    assert_eq!(annotations.len(), 1);
+    assert_eq!(annotations[0].id, test_data.annotations[1].id);
+
+    // an annotation rendered with the annotation template
+    let markdown = client
+        .get(url(&format!("/render/{}", test_data.annotations[0].id)))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(markdown.contains("this is a test comment"));
+
+    // unknown annotations and endpoints are 404s
+    let response = client.get(url("/render/no_such_id")).send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    let error: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    assert_eq!(error["error"]["code"], "annotation_not_found");
+    let response = client.get(url("/no_such_endpoint")).send().await?;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+    server.kill()?;
+    server.wait()?;
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn doctor() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("doctor")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("authorized as"))
+        .stdout(predicates::str::contains("is writable"));
+
+    // a knowledge base "directory" which is a file fails the check
+    let config = fs::read_to_string(&test_data.config_file)?;
+    let not_a_dir = test_data.temp_dir.path().join("not_a_dir");
+    fs::write(&not_a_dir, "")?;
+    let config = config
+        .lines()
+        .map(|line| {
+            if line.starts_with("kb_dir") {
+                format!("kb_dir = {:?}", not_a_dir)
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    fs::write(&test_data.config_file, config)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("doctor")
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("isn't a directory"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn init() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    // a config file which is already set up is left alone unless confirmed
+    let config = fs::read_to_string(&test_data.config_file)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("init")
+        .assert()
+        .failure();
+    assert_eq!(fs::read_to_string(&test_data.config_file)?, config);
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn color() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // --color overrides the config file
+    thread::sleep(duration);
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\ncolor = 'Always'\n", config),
+    )?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--color=never")
+        .arg("view")
+        .arg("--tags=test_tag2")
+        .arg("--format=plain")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("\u{1b}[").not());
+
+    // unknown modes are refused
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--color=sometimes")
+        .arg("view")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn open() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // links of filtered annotations
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("open")
+        .arg("--tags=test_tag2")
+        .arg("--print")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[1].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+
+    // links of annotations by ID
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("open")
+        .arg(&test_data.annotations[0].id)
+        .arg("--print")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[0].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[1].id.as_str()).not());
+
+    // nothing matches
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("open")
+        .arg("--tags=test_tag_missing")
+        .arg("--print")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn view_format() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // ids of matching annotations
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag2")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // newest first
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--sort=created")
+        .arg("--reverse")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}\n{}\n",
+            test_data.annotations[1].id, test_data.annotations[0].id
+        ));
+
+    // set of URIs
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--format=uris")
+        .assert()
+        .success()
+        .stdout("https://www.example.com\n");
+
+    // nothing matches
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag_missing")
+        .arg("--format=json")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn watch() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // first round syncs, then waits
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("watch")
+        .arg("--interval=1h")
+        .arg("--no-make")
+        .timeout(time::Duration::from_secs(20))
+        .assert()
+        .interrupted()
+        .stdout(predicates::str::contains("Added 2 annotations"));
+
+    // the lock is released when watch stops
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .timeout(time::Duration::from_secs(20))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Everything up to date!"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .stdout(predicates::str::contains("Added 2 annotations"));
+
+    // add a tag with spaces
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("--tags=test_tag")
+        .arg("test tag5")
+        .assert()
+        .success();
+    assert!(test_data
+        .hypothesis_client
+        .fetch_annotation(&test_data.annotations[0].id)
+        .await?
+        .tags
+        .contains(&"test tag5".to_owned()));
+
+    // make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    // check that the kb folder has tag files and an index file
+    let file_names = fs::read_dir(test_data.temp_dir.path().join("kb").as_os_str())?
+        .map(|entry| {
+            entry.wrap_err("File I/O error").and_then(|e| {
+                e.path()
+                    .file_name()
+                    .ok_or(eyre!("filename ends in ."))
+                    .and_then(|f: &OsStr| {
+                        f.to_str()
+                            .map(String::from)
+                            .ok_or(eyre!("non-unicode characters in filename"))
+                    })
+            })
+        })
+        .collect::<Result<HashSet<String>, _>>()?;
+    // index file
+    assert!(file_names.contains("SUMMARY.md"));
+
+    // check all tag files
+    assert!(["test_tag", "test_tag1", "test_tag2", "test tag5"]
+        .iter()
+        .all(|t| file_names.contains(&format!("{}.md", t))));
+
+    // quickfix entries point into the first page with the annotation
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag2")
+        .arg("--format=quickfix")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test_tag.md:"))
+        .stdout(predicates::str::contains(": this is another test comment"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn export() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // export as JSON
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("--tags=test_tag")
+        .output()?;
+    assert!(output.status.success());
+    let exported: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(exported.len(), 2);
+
+    // export as CSV, only a2 has test_tag2
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--tags=test_tag2")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[1].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+
+    // export highlights with the flashcard tag for Anki
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("annotate")
+        .arg("https://www.example.com")
+        .arg("--quote=Example Domain")
+        .arg("--text=the back of the card")
+        .arg("--tags=test_tag,flashcard")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("anki")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("#guid column:1"))
+        .stdout(predicates::str::contains(
+            "\tExample Domain\tthe back of the card\ttest_tag\n",
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn group_filter() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let group_id = dotenv::var("TEST_GROUP_ID")?;
+
+    // test annotations are in the test group
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("--tags=test_tag")
+        .arg(format!("--group={}", group_id))
+        .output()?;
+    assert!(output.status.success());
+    let exported: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(exported.len(), 2);
+
+    // and not in the public group
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("--tags=test_tag")
+        .arg("--group=__world__")
+        .output()?;
+    assert!(output.status.success());
+    let exported: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    assert!(exported.is_empty());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn annotate() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // make a page note and an annotation with a quote
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("annotate")
+        .arg("https://www.example.com")
+        .arg("--text=this is a test page note")
+        .arg("--tags=test_tag,test_tag3")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("annotate")
+        .arg("https://www.example.com")
+        .arg("--quote=Example Domain")
+        .arg("--tags=test_tag,test_tag3")
+        .assert()
+        .success();
+
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("--tags=test_tag3")
+        .output()?;
+    assert!(output.status.success());
+    let exported: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(exported.len(), 2);
+    assert!(exported
+        .iter()
+        .any(|a| a["text"] == "this is a test page note"));
+    assert!(exported
+        .iter()
+        .any(|a| a["highlight"][0] == "Example Domain"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make_org() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // switch config to org-mode
+    let config = fs::read_to_string(&test_data.config_file)?
+        .replace(
+            gooseberry::configuration::DEFAULT_ANNOTATION_TEMPLATE,
+            gooseberry::configuration::DEFAULT_ORG_ANNOTATION_TEMPLATE,
+        )
+        .replace(
+            gooseberry::configuration::DEFAULT_PAGE_TEMPLATE,
+            gooseberry::configuration::DEFAULT_ORG_PAGE_TEMPLATE,
+        )
+        .replace(
+            gooseberry::configuration::DEFAULT_INDEX_LINK_TEMPLATE,
+            gooseberry::configuration::DEFAULT_ORG_INDEX_LINK_TEMPLATE,
+        )
+        .replace("file_extension = 'md'", "file_extension = 'org'");
+    fs::write(
+        &test_data.config_file,
+        format!("{}\nkb_format = 'Org'", config),
+    )?;
+
+    // make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    // check the index file and a tag page
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    assert!(fs::read_to_string(kb_dir.join("SUMMARY.org"))?
+        .contains("- [[file:test_tag2.org][test_tag2]]"));
+    let page = fs::read_to_string(kb_dir.join("test_tag2.org"))?;
+    assert!(page.contains("#+TITLE: test_tag2"));
+    assert!(page.contains("#+FILETAGS: :test_tag:test_tag1:test_tag2:"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make_wiki_links() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--wiki-links")
+        .assert()
+        .success();
+
+    // tag pages link back to the document page and vice versa
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    let tag_page = fs::read_to_string(kb_dir.join("test_tag2.md"))?;
+    assert!(tag_page.contains("## Backlinks"));
+    assert!(tag_page.contains("- [[Untitled document]]"));
+    let document_page = fs::read_to_string(kb_dir.join("Untitled document.md"))?;
+    assert!(document_page.contains("[[test_tag2]]"));
+
+    // index links opening the pages in Obsidian
+    let config = fs::read_to_string(&test_data.config_file)?
+        .lines()
+        .map(|line| {
+            if line.starts_with("index_link_template") {
+                "index_link_template = '''- [{{name}}]({{obsidian_uri raw_relative_path}})'''"
+                    .to_owned()
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    fs::write(&test_data.config_file, config)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--obsidian-vault=My Notes")
+        .arg("make")
+        .arg("-f")
+        .arg("--wiki-links")
+        .assert()
+        .success();
+    let index = fs::read_to_string(kb_dir.join(format!(
+        "{}.md",
+        gooseberry::configuration::DEFAULT_INDEX_FILENAME
+    )))?;
+    assert!(index.contains("(obsidian://open?vault=My%20Notes&file=test_tag2.md)"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn dry_run() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // tagging only prints the updates
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--dry-run")
+        .arg("tag")
+        .arg("--tags=test_tag")
+        .arg("test_tag_dry")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("PATCH /api/annotations/"))
+        .stdout(predicates::str::contains("+test_tag_dry"))
+        .stdout(predicates::str::contains("Would update 2 annotation(s)"));
+    let futures: Vec<_> = test_data
+        .annotations
+        .iter()
+        .map(|a| test_data.hypothesis_client.fetch_annotation(&a.id))
+        .collect();
+    assert!(!async { try_join_all(futures).await }
+        .await?
+        .iter()
+        .any(|x| x.tags.contains(&"test_tag_dry".to_owned())));
+
+    // deleting only prints the deletions
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--dry-run")
+        .arg("delete")
+        .arg("--tags=test_tag")
+        .arg("-f")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Would delete 2 annotation(s)"));
+    let futures: Vec<_> = test_data
+        .annotations
+        .iter()
+        .map(|a| test_data.hypothesis_client.fetch_annotation(&a.id))
+        .collect();
+    assert!(async { try_join_all(futures).await }.await.is_ok());
+
+    // making the knowledge base only lists the files
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--dry-run")
+        .arg("make")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Would write"))
+        .stdout(predicates::str::contains("would be written"));
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    assert!(!kb_dir.join("test_tag.md").exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[test]
+fn dry_run_commands() -> color_eyre::Result<()> {
+    let temp_dir = tempdir()?;
+    let (api_url, requests) = stub_api_server()?;
+    let config_file = make_config_file(&temp_dir, "tester", "stub-key", "stubgroup")?;
+
+    // commands that can't preview their changes refuse --dry-run
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("--dry-run")
+        .arg("link")
+        .arg("id1")
+        .arg("id2")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("link doesn't support --dry-run"));
+
+    // group create and leave only print the API calls
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("--dry-run")
+        .arg("group")
+        .arg("create")
+        .arg("dry_group")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("POST /api/groups (dry_group)"))
+        .stdout(predicates::str::contains("Would create group dry_group"));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("--dry-run")
+        .arg("group")
+        .arg("leave")
+        .arg("stubgroup")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Would leave group stubgroup"));
+    assert!(requests
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|request| request.starts_with("GET ")));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make_group_by() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // a page per domain
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--rebuild")
+        .arg("--group-by=domain")
+        .assert()
+        .success();
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    let domain_page = fs::read_to_string(kb_dir.join("example.com.md"))?;
+    assert!(domain_page.contains("this is a test comment"));
+    assert!(domain_page.contains("this is another test comment"));
+    assert!(!kb_dir.join("test_tag.md").exists());
+
+    // a page per day
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--rebuild")
+        .arg("--group-by=date")
+        .assert()
+        .success();
+    let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+    assert!(kb_dir.join(format!("{}.md", today)).exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make_incremental() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // full make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    // nothing changed
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "0 file(s) written, 0 file(s) deleted",
+        ));
+
+    // new tag makes a new page
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("--tags=test_tag2")
+        .arg("test_tag6")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .assert()
+        .success();
+    assert!(kb_dir.join("test_tag6.md").exists());
+
+    // removed tag deletes the page
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("-d")
+        .arg("--tags=test_tag6")
+        .arg("test_tag6")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("1 file(s) deleted"));
+    assert!(!kb_dir.join("test_tag6.md").exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn hierarchical_tags() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // set tag delimiter
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\ntag_delimiter = '/'\n", config),
+    )?;
+
+    // add nested tags
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("--tags=test_tag2")
+        .arg("test_parent/test_child")
+        .assert()
+        .success();
+
+    // prefixes match nested tags
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--tags=test_parent/")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[1].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+
+    // nested tags make nested folders and a nested index
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+    assert!(kb_dir.join("test_parent").join("test_child.md").exists());
+    let index = fs::read_to_string(kb_dir.join("SUMMARY.md"))?;
+    assert!(index.contains("- [test_parent]()\n  - [test_child](test_parent/test_child.md)"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sync_push() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // pushing needs an editable knowledge base
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--push")
+        .assert()
+        .failure();
+
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\neditable = true\n", config),
+    )?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    // nothing edited yet
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--push")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("No edits to push"));
+
+    // edit the text and tags of the second annotation
+    let page = kb_dir.join("test_tag2.md");
+    let contents = fs::read_to_string(&page)?
+        .replace(
+            "this is another test comment",
+            "this is an edited test comment",
+        )
+        .replace(
+            "gooseberry tags: test_tag, test_tag1, test_tag2 -->",
+            "gooseberry tags: test_tag, test_tag1, test_tag2, test_tag7 -->",
+        );
+    fs::write(&page, contents)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--push")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Pushing 1 edited annotation(s)"));
+    let annotation = test_data
+        .hypothesis_client
+        .fetch_annotation(&test_data.annotations[1].id)
+        .await?;
+    assert_eq!(annotation.text, "this is an edited test comment");
+    assert!(annotation.tags.contains(&"test_tag7".to_owned()));
+
+    // the same edits aren't pushed twice
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--push")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("No edits to push"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn cite() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // cite annotated documents from a BibTeX file
+    let bibliography = test_data.temp_dir.path().join("library.bib");
+    fs::write(
+        &bibliography,
+        "@online{example2021,\n  title = {Example {Domain}},\n  url = {https://www.example.com/},\n}\n",
+    )?;
+    let config = fs::read_to_string(&test_data.config_file)?.replacen(
+        "{{text}}",
+        "{{text}} {{cite uri}}",
+        1,
+    );
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\nbibliography = '{}'\n",
+            config,
+            bibliography.to_str().unwrap()
+        ),
+    )?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--format=plain")
+        .arg("--tags=test_tag2")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "this is another test comment [@example2021]",
+        ));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn titles() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // page titles are fetched during sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--titles")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "https://www.example.com\tExample Domain",
+        ));
+
+    // or as markdown links, grouped by tag
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--markdown")
+        .arg("--dedup")
+        .arg("--by-tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("## test_tag\n"))
+        .stdout(predicates::str::contains(
+            "- [Example Domain](https://www.example.com)",
+        ));
+
+    // and used as the title in templates
+    let config = fs::read_to_string(&test_data.config_file)?.replacen(
+        "{{text}}",
+        "{{text}} from {{title}}",
+        1,
+    );
+    fs::write(&test_data.config_file, config)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--format=plain")
+        .arg("--tags=test_tag2")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "this is another test comment from Example Domain",
+        ));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn uri_options() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let mut test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // another link to the same page, with a different scheme, no www., and a fragment
+    let annotation = hypothesis::annotations::InputAnnotation::builder()
+        .uri("http://example.com/#intro")
+        .text("this is a test comment on a section")
+        .tags(vec!["test_tag".into(), "test_tag3".into()])
+        .group(&dotenv::var("TEST_GROUP_ID")?)
+        .build()?;
+    let annotation = test_data
+        .hypothesis_client
+        .create_annotation(&annotation)
+        .await?;
+    test_data.annotations.push(annotation);
+
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // both links are printed
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("http://example.com/#intro\n"))
+        .stdout(predicates::str::contains("https://www.example.com\n"));
+
+    // only the first one with --dedup
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--tags=test_tag")
+        .arg("--dedup")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("http://example.com/#intro\n"))
+        .stdout(predicates::str::contains("https://www.example.com").not());
+
+    // each tag with the links of its annotations
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--tags=test_tag")
+        .arg("--by-tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "test_tag3\thttp://example.com/#intro\n",
+        ))
+        .stdout(predicates::str::contains("test_tag3\thttps://www.example.com").not())
+        .stdout(predicates::str::contains(
+            "test_tag2\thttps://www.example.com\n",
+        ));
+
+    // as markdown links under a heading per tag
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--tags=test_tag")
+        .arg("--by-tag")
+        .arg("--markdown")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("## test_tag3\n\n- ["))
+        .stdout(predicates::str::contains("](http://example.com/#intro)\n"))
+        .stdout(predicates::str::contains(
+            "- [Example Domain](https://www.example.com)\n",
+        ));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn rules() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\n[rules]\ntest_tag9 = 'text:another'\n", config),
+    )?;
+
+    // preview
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--dry-run")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(format!(
+            "{} (https://www.example.com): +test_tag9",
+            test_data.annotations[1].id
+        )))
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+    let annotation = test_data
+        .hypothesis_client
+        .fetch_annotation(&test_data.annotations[1].id)
+        .await?;
+    assert!(!annotation.tags.contains(&"test_tag9".to_owned()));
+
+    // apply during sync
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "Tagged 1 annotation(s) using rules",
+        ));
+    let annotation = test_data
+        .hypothesis_client
+        .fetch_annotation(&test_data.annotations[1].id)
+        .await?;
+    assert!(annotation.tags.contains(&"test_tag9".to_owned()));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn view() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // add saved views
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\n[views]\nfirst = 'tags:[test_tag, test_tag1] AND NOT tag:test_tag2'\nboth = 'tag:test_tag2 OR (uri:*example.com* AND any:\"another test\")'\n",
+            config
+        ),
+    )?;
+
+    // only a1 doesn't have test_tag2
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--view=first")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[0].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[1].id.as_str()).not());
+
+    // unknown views are an error
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("--view=missing")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn trash() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let id = test_data.annotations[0].id.as_str();
+
+    // delete the first annotation
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("delete")
+        .arg(format!("--ids={}", id))
+        .arg("-f")
+        .assert()
+        .success();
+
+    // it's in the trash
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("trash")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(id))
+        .stdout(predicates::str::contains(test_data.annotations[1].id.as_str()).not());
+
+    // restore it
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("restore")
+        .arg(id)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Restored"));
+
+    // and it's gone from the trash
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("trash")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(id).not());
+
+    // the restored annotation is synced
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("this is a test comment"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn dedupe() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // make a duplicate of the first annotation with a different tag
+    let duplicate = hypothesis::annotations::InputAnnotation::builder()
+        .uri("https://www.example.com")
+        .text("this is a test comment")
+        .tags(vec!["test_tag".into(), "test_tag3".into()])
+        .group(&test_data.annotations[0].group)
+        .build()?;
+    let duplicate = test_data
+        .hypothesis_client
+        .create_annotation(&duplicate)
+        .await?;
+
+    // merge the duplicates into the first annotation
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("dedupe")
+        .arg("--tags=test_tag")
+        .arg("-f")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Merging into 1 annotation(s)"));
+
+    // the duplicate is deleted and its tag is on the first annotation
+    thread::sleep(duration);
+    assert!(test_data
+        .hypothesis_client
+        .fetch_annotation(&duplicate.id)
+        .await
+        .is_err());
+    let merged = test_data
+        .hypothesis_client
+        .fetch_annotation(&test_data.annotations[0].id)
+        .await?;
+    assert!(merged.tags.contains(&"test_tag3".to_owned()));
+
+    // nothing left to dedupe
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("dedupe")
+        .arg("--tags=test_tag")
+        .arg("-f")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("No duplicate annotations"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn tag_templates() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // use different templates for annotations tagged test_tag2
+    let annotation_template = test_data.temp_dir.path().join("annotation.hbs");
+    fs::write(&annotation_template, "OVERRIDDEN {{text}}\n")?;
+    let page_template = test_data.temp_dir.path().join("page.hbs");
+    fs::write(
+        &page_template,
+        "OVERRIDDEN PAGE\n{{#each annotations}}{{this}}{{/each}}",
+    )?;
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\n[templates]\ntest_tag2 = {{ annotation = '{}', page = '{}' }}\n",
+            config,
+            annotation_template.to_str().unwrap(),
+            page_template.to_str().unwrap()
+        ),
+    )?;
+
+    // make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    // only the second annotation has test_tag2
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    let tag2_page = fs::read_to_string(kb_dir.join("test_tag2.md"))?;
+    assert!(tag2_page.starts_with("OVERRIDDEN PAGE"));
+    assert!(tag2_page.contains("OVERRIDDEN this is another test comment"));
+    let tag_page = fs::read_to_string(kb_dir.join("test_tag.md"))?;
+    assert!(!tag_page.contains("OVERRIDDEN PAGE"));
+    assert!(tag_page.contains("OVERRIDDEN this is another test comment"));
+    assert!(!tag_page.contains("OVERRIDDEN this is a test comment"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn date_filters() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let ids = format!(
+        "{}\n{}\n",
+        test_data.annotations[0].id, test_data.annotations[1].id
+    );
+
+    // both annotations were made just now
+    thread::sleep(duration);
+    for range in &[
+        vec!["--since=1h"],
+        vec!["--today"],
+        vec!["--after=yesterday", "--before=tomorrow"],
+        vec!["--since=1h", "--date-field=updated"],
+    ] {
+        let mut cmd = Command::cargo_bin("gooseberry")?;
+        cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+            .arg("view")
+            .arg("--tags=test_tag")
+            .args(range)
+            .arg("--format=ids")
+            .assert()
+            .success()
+            .stdout(ids.clone());
+    }
+
+    // and not before yesterday
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--since=2w")
+        .arg("--before=yesterday")
+        .arg("--format=ids")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn pattern_filters() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // only the second annotation's text matches
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--text-pattern=^this is an?other")
+        .arg("--uri-pattern=example\\.(com|org)")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // neither is on a .gov page
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--uri-pattern=\\.gov/")
+        .arg("--format=ids")
+        .assert()
+        .failure();
+
+    // invalid regexes are an error
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--text-pattern=(unclosed")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[test]
+fn groups() -> color_eyre::Result<()> {
+    let temp_dir = tempdir()?;
+    dotenv::dotenv()?;
+    let username = dotenv::var("HYPOTHESIS_NAME")?;
+    let key = dotenv::var("HYPOTHESIS_KEY")?;
+    let group_id = dotenv::var("TEST_GROUP_ID")?;
+    let config_file = make_config_file(&temp_dir, &username, &key, &group_id)?;
+
+    // the test group is listed as the gooseberry group
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .arg("group")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(group_id.as_str()))
+        .stdout(predicates::str::contains("(gooseberry group)"));
+
+    // the user is a member of the test group
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .arg("group")
+        .arg("members")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(username.as_str()));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn config_template() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    // the default templates render
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("config")
+        .arg("template")
+        .arg("--check")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("annotation template: OK"));
+
+    // unknown keys are reported with their line
+    let annotation_template = test_data.temp_dir.path().join("annotation.hbs");
+    fs::write(&annotation_template, "{{text}}\n{{titel}}\n")?;
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\n[templates]\ntest_tag2 = {{ annotation = '{}' }}\n",
+            config,
+            annotation_template.to_str().unwrap(),
+        ),
+    )?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("config")
+        .arg("template")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("annotation/test_tag2 template"))
+        .stderr(predicates::str::contains("line 2"))
+        .stderr(predicates::str::contains("titel"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn template_helpers() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    let annotation_template = test_data.temp_dir.path().join("annotation.hbs");
+    fs::write(
+        &annotation_template,
+        r#"[{{truncate text 12}}] [{{slugify uri}}] [{{replace text " " "_"}}] [{{#if (match uri "example[.]com$")}}EXAMPLE{{/if}}] [{{date_format created "%Y"}}] [{{quote_with_context 20 mark="**"}}]
+"#,
+    )?;
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\n[templates]\ntest_tag2 = {{ annotation = '{}' }}\n",
+            config,
+            annotation_template.to_str().unwrap(),
+        ),
+    )?;
+
+    // make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    let tag2_page = fs::read_to_string(kb_dir.join("test_tag2.md"))?;
+    assert!(tag2_page.contains("[this is anot…]"));
+    assert!(tag2_page.contains("[https-www-example-com]"));
+    assert!(tag2_page.contains("[this_is_another_test_comment]"));
+    assert!(tag2_page.contains("[EXAMPLE] [20"));
+    // page notes have no highlight to show in context
+    assert!(tag2_page.contains("] []"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make_site() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // zola pages have TOML front matter and slugified paths
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--site")
+        .arg("zola")
+        .assert()
+        .success();
+    assert!(kb_dir.join("_index.md").exists());
+    let tag_page = fs::read_to_string(kb_dir.join("test-tag.md"))?;
+    assert!(tag_page.starts_with("+++\ntitle = \"test_tag\""));
+    assert!(tag_page.contains("[taxonomies]\ntags = [\"test_tag\""));
+    assert!(tag_page.contains("this is a test comment"));
+
+    // mdbook pages are listed in SUMMARY.md
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--site")
+        .arg("mdbook")
+        .assert()
+        .success();
+    assert!(!kb_dir.join("_index.md").exists());
+    let summary = fs::read_to_string(kb_dir.join("SUMMARY.md"))?;
+    assert!(summary.contains("- [test_tag](test-tag.md)"));
+
+    // html pages have anchors per annotation, and the index has the search index
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--site")
+        .arg("html")
+        .assert()
+        .success();
+    let tag_page = fs::read_to_string(kb_dir.join("test-tag1.html"))?;
+    assert!(tag_page.starts_with("<!DOCTYPE html>"));
+    assert!(tag_page.contains(&format!(
+        "<article class=\"annotation\" id=\"{}\">",
+        test_data.annotations[0].id
+    )));
+    let index = fs::read_to_string(kb_dir.join("index.html"))?;
+    assert!(index.contains("<a href=\"test-tag1.html\">test_tag1</a>"));
+    assert!(index.contains(&format!(
+        "\"url\":\"test-tag1.html#{}\"",
+        test_data.annotations[0].id
+    )));
+
+    // or a single standalone page
+    let file = test_data.temp_dir.path().join("kb.html");
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("--single-file")
+        .arg(&file)
+        .assert()
+        .success();
+    let page = fs::read_to_string(&file)?;
+    assert!(page.contains("<h2>Contents</h2>"));
+    assert!(page.contains(&format!(
+        "<article class=\"annotation\" id=\"{}\">",
+        test_data.annotations[1].id
+    )));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn make_site_html() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // a page per tag in the default layout, linking back to the index
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--site")
+        .arg("html")
+        .assert()
+        .success();
+    let tag_page = fs::read_to_string(kb_dir.join("test-tag2.html"))?;
+    assert!(tag_page.starts_with("<!DOCTYPE html>"));
+    assert!(tag_page.contains("<title>test_tag2</title>"));
+    assert!(tag_page.contains("<a href=\"index.html\">Index</a>"));
+    assert!(tag_page.contains(&format!(
+        "<article class=\"annotation\" id=\"{}\">",
+        test_data.annotations[1].id
+    )));
+    assert!(tag_page.contains("this is another test comment"));
+    assert!(!tag_page.contains(&test_data.annotations[0].id));
+
+    // the index lists the pages, and has every annotation in its search index
+    let index = fs::read_to_string(kb_dir.join("index.html"))?;
+    assert!(index.contains("<a href=\"test-tag2.html\">test_tag2</a>"));
+    assert!(!index.contains(">Index</a>"));
+    assert!(index.contains("var GOOSEBERRY_INDEX = "));
+    assert!(index.contains(&format!(
+        "\"url\":\"test-tag2.html#{}\"",
+        test_data.annotations[1].id
+    )));
+    assert!(index.contains("this is a test comment test_tag test_tag1"));
+
+    // a custom layout
+    let mut config = fs::read_to_string(&test_data.config_file)?;
+    config.push_str(
+        "\nhtml_layout = '''<title>{{title}}</title>{{#if index_link}}<a href=\"{{index_link}}\">up</a>{{/if}}\n{{content}}'''\n",
+    );
+    fs::write(&test_data.config_file, config)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--site")
+        .arg("html")
+        .assert()
+        .success();
+    let tag_page = fs::read_to_string(kb_dir.join("test-tag2.html"))?;
+    assert!(tag_page.starts_with("<title>test_tag2</title><a href=\"index.html\">up</a>\n"));
+    assert!(tag_page.contains(&format!(
+        "<article class=\"annotation\" id=\"{}\">",
+        test_data.annotations[1].id
+    )));
+    let index = fs::read_to_string(kb_dir.join("index.html"))?;
+    assert!(index.starts_with("<title>"));
+    assert!(!index.contains(">up</a>"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn view_quickfix() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // nothing to point to before make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag2")
+        .arg("--format=quickfix")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "None of the annotations are in the knowledge base",
+        ));
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    // each entry is file:line: text, with the line of the annotation in the file
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag2")
+        .arg("--format=quickfix")
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut found = false;
+    for entry in stdout.lines() {
+        let mut parts = entry.splitn(3, ':');
+        let (path, line, text) = (
+            PathBuf::from(parts.next().unwrap()),
+            parts.next().unwrap().parse::<usize>()?,
+            parts.next().unwrap(),
+        );
+        assert!(path.starts_with(&kb_dir));
+        let file_line = fs::read_to_string(&path)?
+            .lines()
+            .nth(line - 1)
+            .map(str::to_owned);
+        assert!(file_line.is_some());
+        if file_line.unwrap().contains(&test_data.annotations[1].id) {
+            assert_eq!(path, kb_dir.join("test_tag.md"));
+            assert_eq!(text, " this is another test comment");
+            found = true;
+        }
+    }
+    assert!(found);
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn archive() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // sync without archiving
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // archive the annotated page (https://www.example.com)
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("archive")
+        .arg("--tags=test_tag")
+        .arg("--text-only")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Archived 1 page(s)"));
+
+    // the page text is searchable
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--query")
+        .arg("illustrative")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[0].id.as_str(),
+        ));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn stats() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // counts per tag
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("stats")
+        .arg("--format")
+        .arg("json")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("\"name\": \"test_tag\""))
+        .stdout(predicates::str::contains("\"domains\""));
+
+    // tables
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("stats")
+        .arg("--top=1")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Tags (top 1 of"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypt_database() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    std::env::set_var("GOOSEBERRY_PASSPHRASE", "test passphrase");
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\nencrypt_database = true\n", config),
+    )?;
+
+    // sync into an encrypted database
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+    let db_dir = test_data.temp_dir.path().join("db");
+    assert!(db_dir.with_extension("enc").exists());
+    assert_eq!(fs::read_dir(&db_dir)?.count(), 0);
+
+    // the search index is decrypted along with the rest of the database
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--query")
+        .arg("another")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[1].id.as_str(),
+        ));
+
+    // a wrong passphrase doesn't decrypt it
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .env("GOOSEBERRY_PASSPHRASE", "wrong passphrase")
+        .arg("stats")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    std::env::remove_var("GOOSEBERRY_PASSPHRASE");
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_backend() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // sync into sled first
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // switching the backend moves the database over
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\ndb_backend = 'Sqlite'\n", config),
+    )?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--tags=test_tag2")
+        .arg("--print-ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[1].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+    let db_dir = test_data.temp_dir.path().join("db");
+    assert!(db_dir.join("gooseberry.sqlite3").exists());
+    assert!(!db_dir.join("conf").exists());
+
+    // and syncing keeps working
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[test]
+fn move_database_backend() -> color_eyre::Result<()> {
+    let temp_dir = tempdir()?;
+    let (api_url, _requests) = stub_api_server()?;
+    let config_file = make_config_file(&temp_dir, "tester", "stub-key", "stubgroup")?;
+    let config = fs::read_to_string(&config_file)?;
+    let db_dir = temp_dir.path().join("db");
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("sync")
+        .assert()
+        .success();
+    assert!(db_dir.join("conf").exists());
+
+    // a copy interrupted before is started over, and only replaces the old backend once it's complete
+    fs::write(db_dir.join("gooseberry.sqlite3.partial"), "not a database")?;
+    fs::write(&config_file, format!("{}\ndb_backend = 'Sqlite'\n", config))?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("sync")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "Moved the database from Sled to Sqlite",
+        ));
+    assert!(db_dir.join("gooseberry.sqlite3").exists());
+    assert!(fs::read_dir(&db_dir)?.all(|entry| !entry
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .contains("partial")));
+    assert!(!db_dir.join("conf").exists());
+
+    // and back
+    fs::create_dir(db_dir.join("sled.partial"))?;
+    fs::write(db_dir.join("sled.partial").join("db"), "not a database")?;
+    fs::write(&config_file, format!("{}\ndb_backend = 'Sled'\n", config))?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("sync")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "Moved the database from Sqlite to Sled",
+        ));
+    assert!(db_dir.join("conf").exists());
+    assert!(!db_dir.join("sled.partial").exists());
+    assert!(!db_dir.join("gooseberry.sqlite3").exists());
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn import() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    let clippings = test_data.temp_dir.path().join("My Clippings.txt");
+    fs::write(
+        &clippings,
+        "The Test Book (Some Author)\n\
+         - Your Highlight on page 3 | Location 40-42 | Added on Monday, March 1, 2021 10:00:00 PM\n\
+         \n\
+         an imported test highlight\n\
+         ==========\n\
+         The Test Book (Some Author)\n\
+         - Your Note on page 3 | Location 42 | Added on Monday, March 1, 2021 10:01:00 PM\n\
+         \n\
+         an imported test note\n\
+         ==========\n",
+    )?;
+
+    // preview only
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("import")
+        .arg("kindle")
+        .arg(&clippings)
+        .arg("--dry-run")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("The Test Book"))
+        .stdout(predicates::str::contains("1 entries"));
+
+    // the note is added to its highlight
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("import")
+        .arg("kindle")
+        .arg(&clippings)
+        .arg("--tags=test_tag,test_tag3")
+        .arg("--force")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("--tags=test_tag3")
+        .output()?;
+    assert!(output.status.success());
+    let exported: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(exported.len(), 1);
+    assert_eq!(
+        exported[0]["text"],
+        "> an imported test highlight\n\nan imported test note\n\n*Originally added on Monday, March 1, 2021 10:00:00 PM*"
+    );
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn replies() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // reply to the first annotation
+    let reply = hypothesis::annotations::InputAnnotation::builder()
+        .uri("https://www.example.com")
+        .text("this is a test reply")
+        .tags(vec!["test_tag".into()])
+        .group(&test_data.annotations[0].group)
+        .references(vec![test_data.annotations[0].id.to_owned()])
+        .build()?;
+    let reply = test_data
+        .hypothesis_client
+        .create_annotation(&reply)
+        .await?;
+
+    // left out with --top-level-only
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--top-level-only")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(&test_data.annotations[0].id))
+        .stdout(predicates::str::contains(&reply.id).not());
+
+    // added with --include-replies, even if it doesn't match
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag1")
+        .arg("--include-replies")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(&reply.id));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn pdf_titles() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // annotation on a local copy of a PDF
+    let fingerprint = "urn:x-pdf:0123456789abcdef";
+    let pdf_annotation = hypothesis::annotations::InputAnnotation::builder()
+        .uri("file:///tmp/gooseberry_test.pdf")
+        .text("this is a test PDF annotation")
+        .tags(vec!["test_tag".into()])
+        .group(&test_data.annotations[0].group)
+        .document(hypothesis::annotations::Document {
+            title: vec![],
+            dc: None,
+            highwire: None,
+            link: vec![hypothesis::annotations::Link {
+                href: fingerprint.into(),
+                link_type: String::new(),
+            }],
+        })
+        .build()?;
+    let pdf_annotation = test_data
+        .hypothesis_client
+        .create_annotation(&pdf_annotation)
+        .await?;
+
+    // listed by fingerprint
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("pdf")
+        .arg("list")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(fingerprint))
+        .stdout(predicates::str::contains("file:///tmp/gooseberry_test.pdf"));
+
+    // register a title, with the fingerprint given without the prefix
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("pdf")
+        .arg("title")
+        .arg("0123456789abcdef")
+        .arg("A Friendly Title")
+        .assert()
+        .success();
+
+    // used by the title filter
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--title=friendly")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(&pdf_annotation.id))
+        .stdout(predicates::str::contains(&test_data.annotations[0].id).not());
+
+    // and shown with the URIs
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("uri")
+        .arg("--tags=test_tag")
+        .arg("--titles")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("A Friendly Title"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn epub_locations() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let mut test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // annotations on an EPUB, with a range CFI (with ID assertions), a CFI without IDs, and a malformed CFI
+    let epub_annotations = [
+        (
+            "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05],/2/1:1,/3:4)",
+            "this is a test range CFI annotation",
+        ),
+        (
+            "epubcfi(/6/2!/4/2/1:0)",
+            "this is a test chapter CFI annotation",
+        ),
+        (
+            "epubcfi(/6/x!/4)",
+            "this is a test malformed CFI annotation",
+        ),
+    ];
+    for (cfi, text) in epub_annotations {
+        let mut selector = std::collections::HashMap::new();
+        selector.insert("value".to_owned(), serde_json::json!(cfi));
+        selector.insert(
+            "conformsTo".to_owned(),
+            serde_json::json!("http://www.idpf.org/epub/linking/cfi/epub-cfi.html"),
+        );
+        let annotation = hypothesis::annotations::InputAnnotation::builder()
+            .uri("https://www.example.com/book.epub")
+            .text(text)
+            .tags(vec!["test_tag".into(), "test_epub".into()])
+            .group(&test_data.annotations[0].group)
+            .target(
+                hypothesis::annotations::Target::builder()
+                    .source("https://www.example.com/book.epub")
+                    .selector(vec![hypothesis::annotations::Selector::FragmentSelector(
+                        selector,
+                    )])
+                    .build()?,
+            )
+            .build()?;
+        let annotation = test_data
+            .hypothesis_client
+            .create_annotation(&annotation)
+            .await?;
+        test_data.annotations.push(annotation);
+    }
+
+    // chapter and chapter ID in the template
+    let config = fs::read_to_string(&test_data.config_file)?.replacen(
+        "{{text}}",
+        "{{text}}{{#if epub}} in chapter {{epub.chapter}}{{#if epub.chapter_id}} ({{epub.chapter_id}}){{/if}}{{/if}}.",
+        1,
+    );
+    fs::write(&test_data.config_file, config)?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--format=plain")
+        .arg("--tags=test_epub")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "this is a test range CFI annotation in chapter 2 (chap01ref).",
+        ))
+        .stdout(predicates::str::contains(
+            "this is a test chapter CFI annotation in chapter 1.",
+        ))
+        .stdout(predicates::str::contains(
+            "this is a test malformed CFI annotation.",
+        ));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sort_options() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let mut test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // a highlight far down a page, then one near the top, then a page note
+    let uri = "https://www.example.com/sorted";
+    for (text, start) in [
+        ("this is a test comment far down", Some(50)),
+        ("this is a test comment near the top", Some(10)),
+        ("this is a test page note", None),
+    ] {
+        let mut annotation = hypothesis::annotations::InputAnnotation::builder()
+            .uri(uri)
+            .text(text)
+            .tags(vec!["test_tag".into(), "test_sort".into()])
+            .group(&test_data.annotations[0].group)
+            .build()?;
+        if let Some(start) = start {
+            annotation.target = hypothesis::annotations::Target::builder()
+                .source(uri)
+                .selector(vec![
+                    hypothesis::annotations::Selector::TextPositionSelector(
+                        hypothesis::annotations::TextPositionSelector {
+                            start,
+                            end: start + 5,
+                        },
+                    ),
+                ])
+                .build()?;
+        }
+        let annotation = test_data
+            .hypothesis_client
+            .create_annotation(&annotation)
+            .await?;
+        test_data.annotations.push(annotation);
+        thread::sleep(duration);
+    }
+    let [far, near, note] = [2, 3, 4].map(|i| test_data.annotations[i].id.to_owned());
+
+    // in order of creation
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_sort")
+        .arg("--sort=created")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n{}\n{}\n", far, near, note));
+
+    // in document order, the page note first
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_sort")
+        .arg("--sort=position")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n{}\n{}\n", note, near, far));
+
+    // and the other way round
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_sort")
+        .arg("--sort=position")
+        .arg("--reverse")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n{}\n{}\n", far, near, note));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn profiles() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\n[profiles.work]\nfile_extension = 'txt'\n", config),
+    )?;
+
+    // unknown profiles are an error
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--profile")
+        .arg("home")
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("No profile named"));
+
+    // the profile gets its own database and knowledge base directories
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .env("GOOSEBERRY_PROFILE", "work")
+        .arg("sync")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--profile")
+        .arg("work")
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+
+    assert!(test_data.temp_dir.path().join("db-work").exists());
+    let kb_dir = test_data.temp_dir.path().join("kb-work");
+    assert!(kb_dir.join("test_tag.txt").exists());
+    assert!(!test_data
+        .temp_dir
+        .path()
+        .join("kb")
+        .join("test_tag.txt")
+        .exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn complete_tags() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    // sync
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // the completion scripts call complete-tags
+    for shell in &["bash", "zsh", "fish"] {
+        let mut cmd = Command::cargo_bin("gooseberry")?;
+        cmd.arg("complete")
+            .arg(shell)
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("gooseberry complete-tags"));
+    }
+
+    // tags are completed after commas
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("complete-tags")
+        .arg("other,test_tag")
+        .output()?;
+    let tags = String::from_utf8(output.stdout)?;
+    assert!(tags.lines().all(|tag| tag.starts_with("other,test_tag")));
+    assert!(tags.lines().any(|tag| tag == "other,test_tag1"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[test]
+fn complete_tags_offline() -> color_eyre::Result<()> {
+    use fs2::FileExt;
+
+    let temp_dir = tempdir()?;
+    let (api_url, _requests) = stub_api_server()?;
+    let config_file = make_config_file(&temp_dir, "tester", "stub-key", "stubgroup")?;
+    let db_dir = temp_dir.path().join("db");
+
+    // nothing to complete before the first sync, and no database is made
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .arg("complete-tags")
+        .arg("test")
+        .assert()
+        .success()
+        .stdout(predicates::str::is_empty());
+    assert!(!db_dir.exists() || fs::read_dir(&db_dir)?.next().is_none());
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .env("HYPOTHESIS_API_URL", &api_url)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // completing doesn't wait for another gooseberry process using the database
+    let lock_file = fs::File::create(db_dir.with_extension("lock"))?;
+    lock_file.lock_exclusive()?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &config_file)
+        .arg("complete-tags")
+        .arg("test")
+        .timeout(time::Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout(predicates::str::is_empty());
+    lock_file.unlock()?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn tag_list() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    // sync
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test_tag1"));
+
+    // counts as JSON
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .arg("--format")
+        .arg("json")
+        .output()?;
+    let counts: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let test_tag = counts
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|count| count["name"] == "test_tag")
+        .unwrap();
+    assert_eq!(test_tag["count"], test_data.annotations.len());
+
+    // the tree needs a tag delimiter
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .arg("--tree")
+        .assert()
+        .failure();
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("tag_delimiter = '_'\n{}", config),
+    )?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .arg("--tree")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test\n").and(predicates::str::contains("    tag1\n")));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn review() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    // the test annotations are all tagged
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("review")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("No annotations to review"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn graph() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("graph")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with("graph gooseberry {"))
+        .stdout(predicates::str::contains(
+            r#""tag:test_tag" -- "tag:test_tag1" [style=dashed, weight=2, label="2"];"#,
+        ));
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("graph")
+        .arg("--tags=test_tag")
+        .arg("--format")
+        .arg("mermaid")
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with("graph LR"))
+        .stdout(predicates::str::contains(r#"(("test_tag2"))"#));
+
+    // documents linked to tags, weighted by their number of annotations
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("graph")
+        .arg("--tags=test_tag")
+        .arg("--format")
+        .arg("json")
+        .arg("--no-annotations")
+        .output()?;
+    let graph: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert!(graph["nodes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|node| node["kind"] != "annotation"));
+    assert!(graph["edges"].as_array().unwrap().iter().any(|edge| {
+        edge["source"] == "document:https://www.example.com"
+            && edge["target"] == "tag:test_tag"
+            && edge["weight"] == 2
+    }));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn feed() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+
+    let feed_file = test_data.temp_dir.path().join("feed.xml");
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("feed")
+        .arg("--tags=test_tag")
+        .arg("-n")
+        .arg("1")
+        .arg("--feed-title")
+        .arg("Reading <log>")
+        .arg("--output")
+        .arg(&feed_file)
+        .assert()
+        .success();
+    let feed = fs::read_to_string(&feed_file)?;
+    assert!(feed.starts_with("<?xml"));
+    assert!(feed.contains("<title>Reading &lt;log&gt;</title>"));
+    // only the most recent annotation
+    assert_eq!(feed.matches("<entry>").count(), 1);
+    assert!(feed.contains(&format!(
+        "<id>https://hypothes.is/a/{}</id>",
+        test_data.annotations[1].id
+    )));
+    assert!(feed.contains(r#"<category term="test_tag2"/>"#));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn git_commit() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // commit the knowledge base after make
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\ngit_commit = true\ngit_commit_message = '{{{{added}}}} added, {{{{removed}}}} removed'\n",
+            config
+        ),
+    )?;
+
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Committed"));
+    let repo = git2::Repository::open(&kb_dir)?;
+    let commit = repo.head()?.peel_to_commit()?;
+    assert_eq!(commit.message(), Some("2 added, 0 removed"));
+    assert!(commit.tree()?.get_name("test_tag1.md").is_some());
+
+    // nothing to commit without changes
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("No changes to commit"));
+
+    // removed annotations are counted
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag2")
+        .assert()
+        .success();
+    let commit = repo.head()?.peel_to_commit()?;
+    assert_eq!(commit.message(), Some("0 added, 1 removed"));
+    assert_eq!(commit.parent_count(), 1);
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sync_diff() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let mut test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let changelog = test_data.temp_dir.path().join("changelog.md");
+
+    // new annotations
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    let output = cmd
+        .env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--diff")
+        .arg("--format")
+        .arg("json")
+        .output()?;
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let added: Vec<_> = diff["added"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["id"].as_str().unwrap())
+        .collect();
+    assert!(added.contains(&test_data.annotations[0].id.as_str()));
+    assert!(added.contains(&test_data.annotations[1].id.as_str()));
+
+    // edited text and tags
+    test_data.annotations[0].text = "Updated test annotation".into();
+    test_data.annotations[0].tags = vec!["test_tag".into(), "test_tag3".into()];
+    test_data
+        .hypothesis_client
+        .update_annotation(&test_data.annotations[0])
+        .await?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--diff")
+        .arg("--changelog")
+        .arg(&changelog)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("### Edited text (1)"))
+        .stdout(predicates::str::contains(
+            "\"this is a test comment\" → \"Updated test annotation\"",
+        ))
+        .stdout(predicates::str::contains(
+            "added `test_tag3`, removed `test_tag1`",
+        ));
+    assert!(fs::read_to_string(&changelog)?.contains("### Changed tags (1)"));
+
+    // deletions
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("delete")
+        .arg("--ids")
+        .arg(&test_data.annotations[1].id)
+        .arg("-f")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .arg("--diff")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("### Deleted (1)"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn history() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let mut test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // only one version before any edits
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("history")
+        .arg(&test_data.annotations[0].id)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Only recorded version (current)"));
+
+    // edit the text and tags
+    test_data.annotations[0].text = "Updated test annotation".into();
+    test_data.annotations[0].tags = vec!["test_tag".into()];
+    test_data
+        .hypothesis_client
+        .update_annotation(&test_data.annotations[0])
+        .await?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("history")
+        .arg(&test_data.annotations[0].id)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("First recorded version"))
+        .stdout(predicates::str::contains("Edited (current)"))
+        .stdout(predicates::str::contains("  tags: -test_tag1"))
+        .stdout(predicates::str::contains(
+            "- this is a test comment\n+ Updated test annotation",
+        ));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn exclusion_filters() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // leave out a tag
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--exclude-tags=test_tag2")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[0].id));
+
+    // leave out a URI
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag")
+        .arg("--exclude-uri=example.com")
+        .arg("--format=ids")
+        .assert()
+        .failure();
+
+    // leave out the matching annotations
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--from")
+        .arg(test_data.annotations[0].created.to_rfc3339())
+        .arg("--not")
+        .arg("--tags=test_tag2")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            test_data.annotations[0].id.as_str(),
+        ))
+        .stdout(predicates::str::contains(test_data.annotations[1].id.as_str()).not());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn any_tags() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // all of the tags
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag1")
+        .arg("--tags=test_tag2")
+        .arg("--all-tags")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!("{}\n", test_data.annotations[1].id));
+
+    // any of the tags
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag1")
+        .arg("--tags=test_tag2")
+        .arg("--any-tags")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}\n{}\n",
+            test_data.annotations[0].id, test_data.annotations[1].id
+        ));
+
+    // not both
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_tag1")
+        .arg("--any-tags")
+        .arg("--all-tags")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn file_name_template() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    let year = test_data.annotations[0].created.format("%Y").to_string();
+
+    // pages named from the template
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\nfile_name_template = '{{{{date_format created \"%Y\"}}}}-{{{{slugify group_key}}}}'\n",
+            config
+        ),
+    )?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag")
+        .assert()
+        .success();
+    for tag in &["test-tag", "test-tag1", "test-tag2"] {
+        assert!(kb_dir.join(format!("{}-{}.md", year, tag)).exists());
+    }
+    assert!(!kb_dir.join("test_tag.md").exists());
+
+    // pages with the same name are numbered
+    fs::write(
+        &test_data.config_file,
+        format!("{}\nfile_name_template = 'page.md'\n", config),
+    )?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag")
+        .assert()
+        .success();
+    for name in &["page.md", "page-2.md", "page-3.md"] {
+        assert!(kb_dir.join(name).exists());
+    }
+    assert!(!kb_dir.join("page-4.md").exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn front_matter() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let kb_dir = test_data.temp_dir.path().join("kb");
+
+    // generated front matter
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag")
+        .arg("--front-matter")
+        .assert()
+        .success();
+    let page = fs::read_to_string(kb_dir.join("test_tag2.md"))?;
+    assert!(page.starts_with("---\ntitle: "));
+    assert!(page.contains("tags: [\"test_tag\", \"test_tag1\", \"test_tag2\"]\n"));
+    assert!(page.contains(&format!(
+        "annotations: [\"{}\"]\n---\n",
+        test_data.annotations[1].id
+    )));
+    assert!(!fs::read_to_string(kb_dir.join("SUMMARY.md"))?.starts_with("---"));
+
+    // front matter template
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\nfront_matter = true\nfront_matter_template = 'aliases: [\"{{{{group_key}}}}\"]'\n",
+            config
+        ),
+    )?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .arg("--tags=test_tag")
+        .assert()
+        .success();
+    let page = fs::read_to_string(kb_dir.join("test_tag2.md"))?;
+    assert!(page.starts_with("---\naliases: [\"test_tag2\"]\n---\n\n"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn single_file() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let file = test_data.temp_dir.path().join("digest.md");
+
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("--tags=test_tag")
+        .arg("--single-file")
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("2 annotation(s) written"));
+    let document = fs::read_to_string(&file)?;
+    assert!(document.starts_with("# digest\n\n## Contents\n\n- [test_tag](#test-tag)\n"));
+    assert!(document.contains("- [test_tag2](#test-tag2)\n"));
+    assert!(document.contains("<a id=\"test-tag1\"></a>\n\n## test_tag1\n"));
+    assert!(document.contains(&test_data.annotations[1].text));
+    // the knowledge base directory isn't touched
+    assert!(!test_data
+        .temp_dir
+        .path()
+        .join("kb")
+        .join("test_tag.md")
+        .exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn db_export_import() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let file = test_data.temp_dir.path().join("gooseberry.jsonl");
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("export")
+        .arg(&file)
+        .assert()
+        .success();
+    let mirror = fs::read_to_string(&file)?;
+    assert!(mirror.starts_with("{\"kind\":\"header\",\"format\":\"gooseberry\",\"version\":1}\n"));
+    for annotation in &test_data.annotations {
+        assert!(mirror.contains(&format!(
+            "{{\"kind\":\"entry\",\"tree\":\"annotation_to_group\",\"key\":\"{}\"",
+            annotation.id
+        )));
+    }
+    assert!(mirror.contains("{\"kind\":\"entry\",\"tree\":\"default\",\"key\":\"last_sync_time:"));
+
+    // clear everything and import it back
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("-f")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("import")
+        .arg(&file)
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("history")
+        .arg(&test_data.annotations[1].id)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "tags: test_tag, test_tag1, test_tag2",
+        ));
+
+    // not a mirror
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("import")
+        .arg("-f")
+        .arg(&test_data.config_file)
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn db_backups() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // clear takes a backup first
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("-f")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "Backed up the database before clear",
+        ));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("backups")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(".jsonl"));
+
+    // restore it
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("restore")
+        .arg("latest")
+        .arg("-f")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Restored the backup from"));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("history")
+        .arg(&test_data.annotations[0].id)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("tags: test_tag, test_tag1"));
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("restore")
+        .arg("19700101-000000")
+        .arg("-f")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn clear_scopes() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // make
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    assert!(kb_dir.join("test_tag2.md").exists());
+
+    // a plain clear keeps the knowledge base files
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("-f")
+        .assert()
+        .success();
+    assert!(kb_dir.join("test_tag2.md").exists());
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // and so does a database-only clear
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("--db-only")
+        .arg("-f")
+        .assert()
+        .success();
+    assert!(kb_dir.join("test_tag2.md").exists());
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test_tag2").not());
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // only the knowledge base files are deleted
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("--kb-only")
+        .arg("-f")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("knowledge base file(s)"));
+    assert!(!kb_dir.join("test_tag2.md").exists());
+    assert!(kb_dir.exists());
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test_tag2"));
+
+    // forget one tag
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("--tag")
+        .arg("test_tag2")
+        .arg("-f")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test_tag2").not());
+
+    // the scopes can't be combined
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("--all")
+        .arg("--kb-only")
+        .assert()
+        .failure();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("clear")
+        .arg("--db-only")
+        .arg("--kb-only")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn ignore_list() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\nignore = ['tag:test_tag2']\n", config),
+    )?;
+
+    // ignored annotations aren't stored
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("tag")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("test_tag1"))
+        .stdout(predicates::str::contains("test_tag2").not());
+
+    // nor written to the knowledge base
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    assert!(!kb_dir.join("test_tag2.md").exists());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn schema_version() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let file = test_data.temp_dir.path().join("gooseberry.jsonl");
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("export")
+        .arg(&file)
+        .assert()
+        .success();
+    let version_entry =
+        "{\"kind\":\"entry\",\"tree\":\"default\",\"key\":\"schema_version\",\"value\":\"";
+    let mirror = fs::read_to_string(&file)?;
+    assert!(mirror.contains(version_entry));
+
+    // a database from a newer version of gooseberry isn't imported
+    let newer: String = mirror
+        .lines()
+        .map(|line| {
+            if line.starts_with(version_entry) {
+                format!("{}999\"}}\n", version_entry)
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect();
+    fs::write(&file, newer)?;
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("db")
+        .arg("import")
+        .arg("-f")
+        .arg(&file)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("schema version 999"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn link() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let (first, second) = (&test_data.annotations[0].id, &test_data.annotations[1].id);
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // link the first annotation to the second
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("link")
+        .arg(first)
+        .arg(second)
+        .arg("--type=supports")
+        .assert()
+        .success();
+
+    // an annotation can't be linked to itself
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("link")
+        .arg(first)
+        .arg(first)
+        .assert()
+        .failure();
+
+    // the link shows up from both ends
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("link")
+        .arg(first)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(format!("-> supports {}", second)));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("link")
+        .arg(second)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(format!("<- supports {}", first)));
+
+    // make
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+    let page = fs::read_to_string(test_data.temp_dir.path().join("kb").join("test_tag1.md"))?;
+    assert!(page.contains("Related annotations:"));
+    assert!(page.contains("- supports → [this is another test comment]("));
+    assert!(page.contains("- ← supports [this is a test comment]("));
+
+    // remove the link
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("link")
+        .arg(second)
+        .arg(first)
+        .arg("--remove")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Removed the link"));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("link")
+        .arg(first)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("supports").not());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn meta() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let (first, second) = (&test_data.annotations[0].id, &test_data.annotations[1].id);
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // set and list metadata
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("meta")
+        .arg(second)
+        .arg("rating=5")
+        .arg("status=read")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("meta")
+        .arg(second)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("rating=5\nstatus=read"));
+
+    // not a key=value pair
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("meta")
+        .arg(second)
+        .arg("rating")
+        .assert()
+        .failure();
+
+    // filter on it
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--print-ids")
+        .arg("--meta")
+        .arg("status=read")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(second.as_str()))
+        .stdout(predicates::str::contains(first.as_str()).not());
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("meta")
+        .arg(first)
+        .arg("rating=2")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--print-ids")
+        .arg("--meta")
+        .arg("rating>=4")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(second.as_str()))
+        .stdout(predicates::str::contains(first.as_str()).not());
+
+    // highest rated first
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("search")
+        .arg("--print-ids")
+        .arg("--sort")
+        .arg("rating")
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with(format!(
+            "{}\n{}",
+            second, first
+        )));
+
+    // remove a key
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("meta")
+        .arg(second)
+        .arg("status=")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("meta")
+        .arg(second)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("status").not());
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn todo() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let (first, second) = (&test_data.annotations[0].id, &test_data.annotations[1].id);
+
+    // only the second annotation is a task
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\ntodo_tag = 'test_tag2'\ndone_tag = 'test_done'\nindex_template = '''{}'''",
+            config, "{{#each todos}}TODO: {{text}} ({{relative_path}}, {{age}})\n{{/each}}"
+        ),
+    )?;
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("todo")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(second.as_str()))
+        .stdout(predicates::str::contains("this is another test comment"))
+        .stdout(predicates::str::contains(first.as_str()).not());
+
+    // the index has an agenda
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("-f")
+        .assert()
+        .success();
+    let index = fs::read_to_string(test_data.temp_dir.path().join("kb").join("SUMMARY.md"))?;
+    assert!(index.contains("TODO: this is another test comment (test_tag.md, "));
+
+    // snoozed tasks are hidden
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("todo")
+        .arg("snooze")
+        .arg(second)
+        .arg("--for=2days")
+        .assert()
+        .success();
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("todo")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Nothing to do"));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("todo")
+        .arg("list")
+        .arg("--all")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("snoozed until"));
+
+    // done swaps the tags
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("todo")
+        .arg("done")
+        .arg(second)
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("todo")
+        .arg("list")
+        .arg("--all")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Nothing to do"));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("view")
+        .arg("--tags=test_done")
+        .arg("--format=ids")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(second.as_str()));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn digest() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let file = test_data.temp_dir.path().join("digest.html");
+    let sent = test_data.temp_dir.path().join("sent.md");
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("digest")
+        .arg("--digest-title=Weekly reading")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("# Weekly reading"))
+        .stdout(predicates::str::contains("2 annotation(s) on 1 page(s)"))
+        .stdout(predicates::str::contains("## test_tag2\n\n### example.com"))
+        .stdout(predicates::str::contains("  this is another test comment"));
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("digest")
+        .arg("--format=html")
+        .arg("--output")
+        .arg(&file)
+        .assert()
+        .success();
+    let html = fs::read_to_string(&file)?;
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<h2>test_tag</h2>"));
+
+    // the digest is piped to the sendmail command
+    if cfg!(unix) {
+        let mut cmd = Command::cargo_bin("gooseberry")?;
+        cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+            .arg("digest")
+            .arg("--sendmail")
+            .arg(format!("cat > {:?}", sent))
+            .assert()
+            .success();
+        assert!(fs::read_to_string(&sent)?.contains("this is a test comment"));
+    }
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn moderation() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+
+    // add saved views
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!(
+            "{}\n[views]\nhidden = 'is:hidden'\nbad = 'is:spam'\n",
+            config
+        ),
+    )?;
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // the test annotations aren't flagged or hidden
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--flagged")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(test_data.annotations[0].id.as_str()).not());
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--view=hidden")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(test_data.annotations[1].id.as_str()).not());
+
+    // is: only takes hidden and flagged
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--view=bad")
+        .assert()
+        .failure();
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn publish() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let first = test_data.annotations[0].id.as_str();
+    let second = test_data.annotations[1].id.as_str();
+
+    // sync
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    // make the first annotation private
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("unpublish")
+        .arg(format!("--ids={}", first))
+        .arg("-f")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "Changed the visibility of 1 annotations",
+        ));
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--visibility=private")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(first))
+        .stdout(predicates::str::contains(second).not());
+
+    // and share it with the group again
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--dry-run")
+        .arg("publish")
+        .arg("--visibility=private")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("private -> group"));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("publish")
+        .arg("--visibility=private")
+        .arg("-f")
+        .assert()
+        .success();
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--visibility=group")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(first))
+        .stdout(predicates::str::contains(second));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn move_annotations() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let group_id = dotenv::var("TEST_GROUP_ID")?;
+
+    // the copies would go to the other group, along with deleting the originals
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("--dry-run")
+        .arg("move")
+        .arg(format!("--from-group={}", group_id))
+        .arg("--to-group=other_group")
+        .arg("--tags=test_tag2")
+        .arg("--delete")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(format!(
+            "copy of {} in group other_group",
+            test_data.annotations[1].id
+        )))
+        .stdout(predicates::str::contains("Would copy 1 annotation(s)"))
+        .stdout(predicates::str::contains(format!(
+            "DELETE /api/annotations/{}",
+            test_data.annotations[1].id
+        )));
+
+    // the annotations are in the gooseberry group already
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("move")
+        .arg(&group_id)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("already in group"));
+
+    test_data.clear().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn users() -> color_eyre::Result<()> {
+    // get test_data
+    let test_data = TestData::populate().await;
+    assert!(test_data.is_ok());
+    let test_data = test_data?;
+    let duration = time::Duration::from_millis(500);
+    let username = dotenv::var("HYPOTHESIS_NAME")?;
+    let first = test_data.annotations[0].id.as_str();
+
+    // sync everyone's annotations
+    let config = fs::read_to_string(&test_data.config_file)?;
+    fs::write(
+        &test_data.config_file,
+        format!("{}\nall_users = true\n", config),
+    )?;
+    thread::sleep(duration);
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("sync")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--mine")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(first));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg(format!("--user={}", username))
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(first));
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("export")
+        .arg("csv")
+        .arg("--user=acct:someone_else@hypothes.is")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(first).not());
+
+    // a page per author (named after their display name, or username)
+    let mut cmd = Command::cargo_bin("gooseberry")?;
+    cmd.env("GOOSEBERRY_CONFIG", &test_data.config_file)
+        .arg("make")
+        .arg("--mine")
+        .arg("-f")
+        .arg("--rebuild")
+        .arg("--group-by=user")
+        .assert()
+        .success();
+    let kb_dir = test_data.temp_dir.path().join("kb");
+    assert!(!kb_dir.join("test_tag.md").exists());
+    assert!(fs::read_dir(&kb_dir)?.filter_map(Result::ok).any(|entry| {
+        entry.path() != kb_dir.join("SUMMARY.md")
+            && fs::read_to_string(entry.path())
+                .unwrap_or_default()
+                .contains("this is a test comment")
+    }));
 
     test_data.clear().await?;
     Ok(())